@@ -0,0 +1,238 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::AuditOptions,
+    ipc::{self, AuditStatus, DaemonResponse},
+    ipc_client, kakoune,
+};
+
+/// One line of `--audit-log`: something an agent did to the machine, or was refused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in milliseconds.
+    pub timestamp_ms: u64,
+    pub agent: String,
+    pub session_id: String,
+    /// The prompt that was running, when there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+}
+
+/// What happened, without who and when.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// `read`, `write`, `permission` or `terminal`.
+    pub operation: String,
+    /// The path, command line or permission request title.
+    pub target: String,
+    /// `ok`, `denied` or `failed`; for permission requests the chosen option or `cancelled`.
+    pub outcome: String,
+    /// Bytes read or written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// The error, or why a permission request was decided the way it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(operation: &str, target: impl Into<String>, outcome: impl Into<String>) -> Self {
+        Self {
+            operation: operation.to_string(),
+            target: target.into(),
+            outcome: outcome.into(),
+            bytes: None,
+            detail: None,
+        }
+    }
+
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Appends JSON lines to `--audit-log`, shared by every agent.
+///
+/// Appending never fails the operation being logged; failures are counted for `status`.
+pub struct AuditLog {
+    path: PathBuf,
+    file: std::sync::Mutex<std::fs::File>,
+    entries: AtomicU64,
+    failures: AtomicU64,
+    last_error: std::sync::Mutex<Option<String>>,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it readable by the owner only.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("failed to open audit log {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: std::sync::Mutex::new(file),
+            entries: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            last_error: std::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn append(
+        &self,
+        agent: &str,
+        session_id: &acp::SessionId,
+        request_id: Option<u64>,
+        record: AuditRecord,
+    ) {
+        let entry = AuditEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            agent: agent.to_string(),
+            session_id: session_id.to_string(),
+            request_id,
+            record,
+        };
+        let mut line = serde_json::to_vec(&entry).expect("audit entries serialize");
+        line.push(b'\n');
+        // One write per line under the lock, so lines from concurrent operations never mix.
+        let written = self.file.lock().unwrap().write_all(&line);
+        match written {
+            Ok(()) => {
+                self.entries.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                tracing::warn!("failed to append to {}: {err}", self.path.display());
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap() = Some(err.to_string());
+            }
+        }
+    }
+
+    pub fn status(&self) -> AuditStatus {
+        AuditStatus {
+            path: self.path.clone(),
+            entries: self.entries.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Prints the last entries of an audit log, found through the daemon unless `--file` names it.
+pub async fn run(options: AuditOptions) -> Result<()> {
+    let path = match &options.file {
+        Some(path) => path.clone(),
+        None => {
+            let socket_path = kakoune::resolve_socket_path(
+                options.socket.clone(),
+                options.session.as_deref(),
+                options.socket_scope,
+            )?;
+            match ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::Status).await? {
+                DaemonResponse::Status { status } => status
+                    .audit_log
+                    .map(|audit| audit.path)
+                    .ok_or_else(|| anyhow!("the daemon was started without --audit-log"))?,
+                DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+                other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+            }
+        }
+    };
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let lines = text.lines().filter(|line| !line.trim().is_empty());
+    let skip = lines.clone().count().saturating_sub(options.tail);
+    for line in lines.skip(skip) {
+        if options.json {
+            println!("{line}");
+            continue;
+        }
+        match serde_json::from_str::<AuditEntry>(line) {
+            Ok(entry) => println!("{}", render_entry(&entry)),
+            Err(err) => println!("(unreadable entry: {err})"),
+        }
+    }
+    Ok(())
+}
+
+fn render_entry(entry: &AuditEntry) -> String {
+    let record = &entry.record;
+    let request = entry
+        .request_id
+        .map(|id| format!("#{id}"))
+        .unwrap_or_else(|| "-".to_string());
+    let mut line = format!(
+        "{}  {}  {request:<4}  {:<10}  {:<9}  {}",
+        format_utc(entry.timestamp_ms),
+        entry.agent,
+        record.operation,
+        record.outcome,
+        record.target
+    );
+    if let Some(bytes) = record.bytes {
+        line.push_str(&format!(" ({bytes} bytes)"));
+    }
+    if let Some(detail) = &record.detail {
+        line.push_str(&format!(": {detail}"));
+    }
+    line
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_utc(timestamp_ms: u64) -> String {
+    let seconds = timestamp_ms / 1000;
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Days since the epoch to a proleptic Gregorian date, counting eras of 400 years from
+    // 0000-03-01 so leap days fall at the end of each year.
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
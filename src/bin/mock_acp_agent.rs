@@ -1,26 +1,243 @@
-use std::{cell::Cell, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
 
 use agent_client_protocol::{self as acp, Client};
 use anyhow::Result;
+use clap::Parser;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{Notify, mpsc, oneshot},
     time::sleep,
 };
 use tokio_util::compat::{TokioAsyncReadCompatExt as _, TokioAsyncWriteCompatExt as _};
 
+/// Scripted ACP agent used by the integration tests.
+#[derive(Parser, Debug, Clone)]
+struct MockOptions {
+    /// Answer every prompt with exactly this many agent message chunks and nothing else.
+    #[arg(long)]
+    chunks: Option<usize>,
+    /// With --chunks, make every Nth chunk a thought chunk instead.
+    #[arg(long, value_name = "N", requires = "chunks")]
+    thought_every: Option<usize>,
+    /// With --chunks, pause this long (in milliseconds) before each chunk.
+    #[arg(long, default_value_t = 0, requires = "chunks")]
+    chunk_delay_ms: u64,
+    /// Pause this long (in milliseconds) in the middle of every default-scenario prompt.
+    #[arg(long, default_value_t = 0)]
+    prompt_delay_ms: u64,
+    /// Final agent message of the default scenario.
+    #[arg(long, default_value = "Here is your concise summary.")]
+    message: String,
+    /// Leave the final agent message out, so the turn ends without one.
+    #[arg(long)]
+    no_message: bool,
+    /// Stream the prompt's first text block back as two user message chunks before answering.
+    #[arg(long)]
+    echo_prompt: bool,
+    /// With --echo-prompt, add this text to the echoed prompt.
+    #[arg(long, value_name = "TEXT", requires = "echo_prompt")]
+    annotate_echo: Option<String>,
+    /// Exit with this status as soon as a prompt arrives.
+    #[arg(long)]
+    exit_on_prompt: Option<i32>,
+    /// Complain on stderr and exit with status 3 this many milliseconds after starting.
+    #[arg(long)]
+    crash_after_ms: Option<u64>,
+    /// Fail every `session/new` request.
+    #[arg(long)]
+    reject_sessions: bool,
+    /// Ignore SIGTERM, like a wedged agent.
+    #[arg(long)]
+    ignore_sigterm: bool,
+    /// Start a long `sleep` child and write its pid to this file.
+    #[arg(long, value_name = "PATH")]
+    spawn_sleeper: Option<std::path::PathBuf>,
+    /// Read this file through the client during every default-scenario prompt and answer
+    /// with its contents.
+    #[arg(long, value_name = "PATH")]
+    read_file: Option<std::path::PathBuf>,
+    /// First line (1-based) requested by `--read-file`.
+    #[arg(long, requires = "read_file")]
+    read_line: Option<u32>,
+    /// Number of lines requested by `--read-file`.
+    #[arg(long, requires = "read_file")]
+    read_limit: Option<u32>,
+    /// Write `--write-content` to this file through the client during every default-scenario
+    /// prompt.
+    #[arg(long, value_name = "PATH", requires = "write_content")]
+    write_file: Option<std::path::PathBuf>,
+    /// Given more than once, all the writes are sent at once and reported in order.
+    #[arg(long, value_name = "TEXT")]
+    write_content: Vec<String>,
+    /// Ask the client for permission to edit during every default-scenario prompt and report
+    /// the outcome.
+    #[arg(long)]
+    request_permission: bool,
+    /// JSON sent as the `raw_input` of the `--request-permission` tool call.
+    #[arg(long, value_name = "JSON", requires = "request_permission")]
+    permission_raw_input: Option<String>,
+    /// Title of the `--request-permission` tool call.
+    #[arg(
+        long,
+        value_name = "TITLE",
+        default_value = "Generate summary",
+        requires = "request_permission"
+    )]
+    permission_title: String,
+    /// Id of the allowing option offered by `--request-permission`.
+    #[arg(
+        long,
+        value_name = "ID",
+        default_value = "allow_once",
+        requires = "request_permission"
+    )]
+    permission_option_id: String,
+    /// Run this program in a client terminal during every default-scenario prompt and report
+    /// its exit status and output.
+    #[arg(long, value_name = "PROGRAM")]
+    terminal_command: Option<String>,
+    #[arg(long = "terminal-arg", value_name = "ARG", allow_hyphen_values = true)]
+    terminal_args: Vec<String>,
+    #[arg(long, value_name = "BYTES")]
+    terminal_output_limit: Option<u64>,
+    /// Kill the `--terminal-command` instead of waiting for it to exit.
+    #[arg(long)]
+    terminal_kill: bool,
+    /// Leave the `--terminal-command` running and its terminal unreleased.
+    #[arg(long, conflicts_with = "terminal_kill")]
+    terminal_detach: bool,
+    /// Report `PATH` or `PATH:LINE` as a location of the summary tool call, relative paths
+    /// resolved against the agent's directory as agents report them; repeatable.
+    #[arg(long = "tool-location", value_name = "PATH[:LINE]")]
+    tool_locations: Vec<String>,
+    /// Attach a diff of `summary.md` replacing this many lines to the completed tool call.
+    #[arg(long, value_name = "N")]
+    diff_lines: Option<usize>,
+    /// Resend the plan unchanged after the summary tool call, then again with its first step
+    /// completed and its second in progress.
+    #[arg(long)]
+    plan_refresh: bool,
+    /// Send the `--diff-lines` diff in an update of its own, with nothing else in it.
+    #[arg(long)]
+    diff_only_update: bool,
+    /// Send an image with this base64 data as an agent message chunk during every
+    /// default-scenario prompt.
+    #[arg(long, value_name = "BASE64")]
+    image_base64: Option<String>,
+    #[arg(long, value_name = "MIME", default_value = "image/png")]
+    image_mime: String,
+    /// Send a link to this URI, titled "Design doc", as an agent message chunk during every
+    /// default-scenario prompt.
+    #[arg(long, value_name = "URI")]
+    resource_link: Option<String>,
+    /// Call this extension method during every default-scenario prompt and report the
+    /// response.
+    #[arg(long, value_name = "METHOD")]
+    ext_method: Option<String>,
+    /// Send this extension notification during every default-scenario prompt.
+    #[arg(long, value_name = "METHOD")]
+    ext_notification: Option<String>,
+    /// With --ext-notification, report this error in the notification's params.
+    #[arg(long, value_name = "MESSAGE", requires = "ext_notification")]
+    ext_error: Option<String>,
+    /// Fail the default scenario's tool call without any content saying why.
+    #[arg(long)]
+    fail_tool: bool,
+    /// Write the client capabilities received in `initialize` to this file as JSON.
+    #[arg(long, value_name = "PATH")]
+    capabilities_file: Option<std::path::PathBuf>,
+}
+
+/// Requests the mock makes of the client, performed by the task that owns the connection.
+enum ClientCall {
+    Notify(Box<acp::SessionNotification>, oneshot::Sender<()>),
+    ReadTextFile(
+        acp::ReadTextFileRequest,
+        oneshot::Sender<std::result::Result<acp::ReadTextFileResponse, acp::Error>>,
+    ),
+    WriteTextFile(
+        acp::WriteTextFileRequest,
+        oneshot::Sender<std::result::Result<acp::WriteTextFileResponse, acp::Error>>,
+    ),
+    RequestPermission(
+        acp::RequestPermissionRequest,
+        oneshot::Sender<std::result::Result<acp::RequestPermissionResponse, acp::Error>>,
+    ),
+    ExtMethod(
+        acp::ExtRequest,
+        oneshot::Sender<std::result::Result<acp::ExtResponse, acp::Error>>,
+    ),
+    ExtNotification(acp::ExtNotification, oneshot::Sender<()>),
+    /// Create a terminal, then end it as told; answers with a report and the terminal's id.
+    RunTerminal(
+        acp::CreateTerminalRequest,
+        TerminalEnd,
+        oneshot::Sender<std::result::Result<(String, acp::TerminalId), acp::Error>>,
+    ),
+}
+
+/// What the mock does with a terminal once its command started.
+#[derive(Clone, Copy)]
+enum TerminalEnd {
+    /// Wait for the command to exit, then collect the output and release the terminal.
+    Wait,
+    /// Kill the command, then collect the output and release the terminal.
+    Kill,
+    /// Leave the command running and the terminal unreleased.
+    Detach,
+}
+
 struct MockAgent {
-    session_update_tx: mpsc::UnboundedSender<(acp::SessionNotification, oneshot::Sender<()>)>,
+    client_tx: mpsc::UnboundedSender<ClientCall>,
     next_session_id: Cell<u64>,
+    /// Capabilities the client advertised in `initialize`.
+    client_capabilities: RefCell<acp::ClientCapabilities>,
+    /// Woken by `session/cancel` to cut the prompt delay short.
+    cancelled: Notify,
+    options: MockOptions,
 }
 
 impl MockAgent {
-    fn new(
-        session_update_tx: mpsc::UnboundedSender<(acp::SessionNotification, oneshot::Sender<()>)>,
-    ) -> Self {
+    fn new(client_tx: mpsc::UnboundedSender<ClientCall>, options: MockOptions) -> Self {
         Self {
-            session_update_tx,
+            client_tx,
             next_session_id: Cell::new(0),
+            client_capabilities: Default::default(),
+            cancelled: Notify::new(),
+            options,
+        }
+    }
+
+    async fn stream_chunks(
+        &self,
+        session_id: &acp::SessionId,
+        count: usize,
+    ) -> std::result::Result<acp::PromptResponse, acp::Error> {
+        for index in 0..count {
+            if self.options.chunk_delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(self.options.chunk_delay_ms)).await;
+            }
+            let content = format!("chunk {index} ").into();
+            let thought = self
+                .options
+                .thought_every
+                .is_some_and(|every| every > 0 && index % every == every - 1);
+            self.send_update(
+                session_id,
+                if thought {
+                    acp::SessionUpdate::AgentThoughtChunk { content }
+                } else {
+                    acp::SessionUpdate::AgentMessageChunk { content }
+                },
+            )
+            .await?;
         }
+        Ok(acp::PromptResponse {
+            stop_reason: acp::StopReason::EndTurn,
+            meta: None,
+        })
     }
 
     async fn send_update(
@@ -29,27 +246,247 @@ impl MockAgent {
         update: acp::SessionUpdate,
     ) -> std::result::Result<(), acp::Error> {
         let (tx, rx) = oneshot::channel();
-        self.session_update_tx
-            .send((
-                acp::SessionNotification {
-                    session_id: session_id.clone(),
-                    update,
-                    meta: None,
-                },
-                tx,
-            ))
+        let notification = acp::SessionNotification {
+            session_id: session_id.clone(),
+            update,
+            meta: None,
+        };
+        self.client_tx
+            .send(ClientCall::Notify(Box::new(notification), tx))
             .map_err(|_| acp::Error::internal_error())?;
         rx.await.map_err(|_| acp::Error::internal_error())
     }
+
+    /// Reads `--read-file` through the client and describes the outcome.
+    async fn read_file(&self, session_id: &acp::SessionId, path: &std::path::Path) -> String {
+        if !self.client_capabilities.borrow().fs.read_text_file {
+            return format!("client cannot read {}", path.display());
+        }
+        let (tx, rx) = oneshot::channel();
+        let request = acp::ReadTextFileRequest {
+            session_id: session_id.clone(),
+            path: path.to_path_buf(),
+            line: self.options.read_line,
+            limit: self.options.read_limit,
+            meta: None,
+        };
+        if self
+            .client_tx
+            .send(ClientCall::ReadTextFile(request, tx))
+            .is_err()
+        {
+            return "client went away".to_string();
+        }
+        match rx.await {
+            Ok(Ok(response)) => match response.meta {
+                Some(meta) => format!("read {} ({meta}): {}", path.display(), response.content),
+                None => format!("read {}: {}", path.display(), response.content),
+            },
+            Ok(Err(error)) => format!("failed to read {}: {error}", path.display()),
+            Err(_) => "client went away".to_string(),
+        }
+    }
+
+    /// Writes each `--write-content` to `--write-file` through the client, all at once, and
+    /// describes the outcomes.
+    async fn write_file(
+        &self,
+        session_id: &acp::SessionId,
+        path: &std::path::Path,
+        contents: &[String],
+    ) -> Vec<String> {
+        if !self.client_capabilities.borrow().fs.write_text_file {
+            return vec![format!("client cannot write {}", path.display())];
+        }
+        let mut replies = Vec::new();
+        for content in contents {
+            let (tx, rx) = oneshot::channel();
+            let request = acp::WriteTextFileRequest {
+                session_id: session_id.clone(),
+                path: path.to_path_buf(),
+                content: content.clone(),
+                meta: None,
+            };
+            if self
+                .client_tx
+                .send(ClientCall::WriteTextFile(request, tx))
+                .is_err()
+            {
+                return vec!["client went away".to_string()];
+            }
+            replies.push(rx);
+        }
+        let mut reports = Vec::new();
+        for reply in replies {
+            reports.push(match reply.await {
+                Ok(Ok(_)) => format!("wrote {}", path.display()),
+                Ok(Err(error)) => format!("failed to write {}: {error}", path.display()),
+                Err(_) => "client went away".to_string(),
+            });
+        }
+        reports
+    }
+
+    /// Runs `--terminal-command` through the client and describes the outcome, with the
+    /// terminal's id when one was created.
+    async fn run_terminal(
+        &self,
+        session_id: &acp::SessionId,
+        command: &str,
+    ) -> (String, Option<acp::TerminalId>) {
+        if !self.client_capabilities.borrow().terminal {
+            return (format!("client cannot run {command}"), None);
+        }
+        let end = if self.options.terminal_kill {
+            TerminalEnd::Kill
+        } else if self.options.terminal_detach {
+            TerminalEnd::Detach
+        } else {
+            TerminalEnd::Wait
+        };
+        let (tx, rx) = oneshot::channel();
+        let request = acp::CreateTerminalRequest {
+            session_id: session_id.clone(),
+            command: command.to_string(),
+            args: self.options.terminal_args.clone(),
+            env: Vec::new(),
+            cwd: None,
+            output_byte_limit: self.options.terminal_output_limit,
+            meta: None,
+        };
+        if self
+            .client_tx
+            .send(ClientCall::RunTerminal(request, end, tx))
+            .is_err()
+        {
+            return ("client went away".to_string(), None);
+        }
+        match rx.await {
+            Ok(Ok((report, terminal_id))) => (report, Some(terminal_id)),
+            Ok(Err(error)) => (format!("failed to run {command}: {error}"), None),
+            Err(_) => ("client went away".to_string(), None),
+        }
+    }
+
+    /// Asks permission to edit the summary and describes the outcome.
+    async fn request_permission(&self, session_id: &acp::SessionId) -> String {
+        let options = [
+            (
+                self.options.permission_option_id.as_str(),
+                "Allow once",
+                acp::PermissionOptionKind::AllowOnce,
+            ),
+            (
+                "reject_once",
+                "Reject",
+                acp::PermissionOptionKind::RejectOnce,
+            ),
+        ]
+        .map(|(id, name, kind)| acp::PermissionOption {
+            id: acp::PermissionOptionId(id.into()),
+            name: name.to_string(),
+            kind,
+            meta: None,
+        });
+        let (tx, rx) = oneshot::channel();
+        let request = acp::RequestPermissionRequest {
+            session_id: session_id.clone(),
+            tool_call: acp::ToolCallUpdate {
+                id: acp::ToolCallId("call-permission".into()),
+                fields: acp::ToolCallUpdateFields {
+                    title: Some(self.options.permission_title.clone()),
+                    kind: Some(acp::ToolKind::Edit),
+                    raw_input: self
+                        .options
+                        .permission_raw_input
+                        .as_deref()
+                        .map(|input| serde_json::from_str(input).expect("raw input is JSON")),
+                    ..Default::default()
+                },
+                meta: None,
+            },
+            options: options.to_vec(),
+            meta: None,
+        };
+        if self
+            .client_tx
+            .send(ClientCall::RequestPermission(request, tx))
+            .is_err()
+        {
+            return "client went away".to_string();
+        }
+        match rx.await {
+            Ok(Ok(response)) => match response.outcome {
+                acp::RequestPermissionOutcome::Selected { option_id } => {
+                    format!("permission: selected {option_id}")
+                }
+                acp::RequestPermissionOutcome::Cancelled => "permission: cancelled".to_string(),
+            },
+            Ok(Err(error)) => format!("permission request failed: {error}"),
+            Err(_) => "client went away".to_string(),
+        }
+    }
+}
+
+/// Params of the mock's extension calls: the session and a greeting.
+fn ext_params(
+    session_id: &acp::SessionId,
+    error: Option<&str>,
+) -> std::sync::Arc<serde_json::value::RawValue> {
+    let mut params = serde_json::json!({ "sessionId": session_id, "text": "hello" });
+    if let Some(error) = error {
+        params["error"] = serde_json::json!({ "message": error });
+    }
+    serde_json::value::to_raw_value(&params)
+        .expect("params serialize")
+        .into()
+}
+
+/// The default scenario's two-step plan, its steps at `read` and `draft`.
+fn plan(read: acp::PlanEntryStatus, draft: acp::PlanEntryStatus) -> acp::SessionUpdate {
+    acp::SessionUpdate::Plan(acp::Plan {
+        entries: vec![
+            acp::PlanEntry {
+                content: "Read the provided context".into(),
+                priority: acp::PlanEntryPriority::High,
+                status: read,
+                meta: None,
+            },
+            acp::PlanEntry {
+                content: "Draft a helpful response".into(),
+                priority: acp::PlanEntryPriority::Medium,
+                status: draft,
+                meta: None,
+            },
+        ],
+        meta: None,
+    })
+}
+
+/// A diff of `summary.md` replacing `lines` old lines with new ones.
+fn summary_diff(lines: usize) -> acp::ToolCallContent {
+    let text = |prefix: &str| {
+        (0..lines)
+            .map(|line| format!("{prefix} {line}\n"))
+            .collect()
+    };
+    acp::ToolCallContent::Diff {
+        diff: acp::Diff {
+            path: "summary.md".into(),
+            old_text: Some(text("old")),
+            new_text: text("new"),
+            meta: None,
+        },
+    }
 }
 
 fn summarize_prompt_blocks(blocks: &[acp::ContentBlock]) -> String {
     let mut summary = Vec::new();
     for block in blocks {
-        if let acp::ContentBlock::Text(text) = block {
-            if !text.text.trim().is_empty() {
-                summary.push(text.text.trim().to_string());
-            }
+        if let acp::ContentBlock::Text(text) = block
+            && !text.text.trim().is_empty()
+        {
+            summary.push(text.text.trim().to_string());
         }
     }
     if summary.is_empty() {
@@ -63,13 +500,22 @@ fn summarize_prompt_blocks(blocks: &[acp::ContentBlock]) -> String {
 impl acp::Agent for MockAgent {
     async fn initialize(
         &self,
-        _: acp::InitializeRequest,
+        arguments: acp::InitializeRequest,
     ) -> std::result::Result<acp::InitializeResponse, acp::Error> {
+        if let Some(path) = &self.options.capabilities_file {
+            let json = serde_json::to_string(&arguments.client_capabilities)
+                .map_err(|_| acp::Error::internal_error())?;
+            std::fs::write(path, json).map_err(|_| acp::Error::internal_error())?;
+        }
+        *self.client_capabilities.borrow_mut() = arguments.client_capabilities;
         Ok(acp::InitializeResponse {
             protocol_version: acp::V1,
             agent_capabilities: acp::AgentCapabilities::default(),
             auth_methods: Vec::new(),
-            meta: None,
+            meta: Some(serde_json::json!({
+                "name": "mock-acp-agent",
+                "version": env!("CARGO_PKG_VERSION"),
+            })),
         })
     }
 
@@ -84,12 +530,25 @@ impl acp::Agent for MockAgent {
         &self,
         _: acp::NewSessionRequest,
     ) -> std::result::Result<acp::NewSessionResponse, acp::Error> {
+        if self.options.reject_sessions {
+            return Err(acp::Error::invalid_request());
+        }
         let session_id = self.next_session_id.get();
         self.next_session_id.set(session_id + 1);
+        let mode = |id: &str, name: &str| acp::SessionMode {
+            id: acp::SessionModeId(id.into()),
+            name: name.to_string(),
+            description: None,
+            meta: None,
+        };
         Ok(acp::NewSessionResponse {
             session_id: acp::SessionId(session_id.to_string().into()),
-            modes: None,
-            meta: None,
+            modes: Some(acp::SessionModeState {
+                current_mode_id: acp::SessionModeId("default".into()),
+                available_modes: vec![mode("default", "Default"), mode("writer", "Writer")],
+                meta: None,
+            }),
+            meta: Some(serde_json::json!({ "workspace": "mock", "model": "mock-model" })),
         })
     }
 
@@ -97,33 +556,54 @@ impl acp::Agent for MockAgent {
         &self,
         arguments: acp::PromptRequest,
     ) -> std::result::Result<acp::PromptResponse, acp::Error> {
+        if let Some(code) = self.options.exit_on_prompt {
+            std::process::exit(code);
+        }
         let session_id = arguments.session_id.clone();
+        if let Some(count) = self.options.chunks {
+            return self.stream_chunks(&session_id, count).await;
+        }
         let summary = summarize_prompt_blocks(&arguments.prompt);
 
+        if self.options.echo_prompt
+            && let Some(acp::ContentBlock::Text(text)) = arguments.prompt.first()
+        {
+            let mut echo = format!("{}\n", text.text);
+            if let Some(annotation) = &self.options.annotate_echo {
+                echo.push_str(annotation);
+            }
+            let middle = echo.floor_char_boundary(echo.len() / 2);
+            for chunk in [&echo[..middle], &echo[middle..]] {
+                self.send_update(&session_id, acp::SessionUpdate::UserMessageChunk {
+                    content: chunk.to_string().into(),
+                })
+                .await?;
+            }
+        }
+
         self.send_update(&session_id, acp::SessionUpdate::AgentThoughtChunk {
             content: format!("Thinking about: {summary}").into(),
         })
         .await?;
 
+        if self.options.prompt_delay_ms > 0 {
+            tokio::select! {
+                _ = sleep(Duration::from_millis(self.options.prompt_delay_ms)) => {}
+                _ = self.cancelled.notified() => {
+                    return Ok(acp::PromptResponse {
+                        stop_reason: acp::StopReason::Cancelled,
+                        meta: None,
+                    });
+                }
+            }
+        }
+
         self.send_update(
             &session_id,
-            acp::SessionUpdate::Plan(acp::Plan {
-                entries: vec![
-                    acp::PlanEntry {
-                        content: "Read the provided context".into(),
-                        priority: acp::PlanEntryPriority::High,
-                        status: acp::PlanEntryStatus::InProgress,
-                        meta: None,
-                    },
-                    acp::PlanEntry {
-                        content: "Draft a helpful response".into(),
-                        priority: acp::PlanEntryPriority::Medium,
-                        status: acp::PlanEntryStatus::Pending,
-                        meta: None,
-                    },
-                ],
-                meta: None,
-            }),
+            plan(
+                acp::PlanEntryStatus::InProgress,
+                acp::PlanEntryStatus::Pending,
+            ),
         )
         .await?;
 
@@ -147,57 +627,318 @@ impl acp::Agent for MockAgent {
                 title: "Generate summary".into(),
                 kind: acp::ToolKind::Edit,
                 status: acp::ToolCallStatus::InProgress,
-                content: Vec::new(),
-                locations: Vec::new(),
-                raw_input: None,
+                content: vec![format!("Summarizing: {summary}").into()],
+                locations: self
+                    .options
+                    .tool_locations
+                    .iter()
+                    .map(|location| {
+                        let (path, line) = match location.rsplit_once(':') {
+                            Some((path, line)) if line.parse::<u32>().is_ok() => {
+                                (path, line.parse().ok())
+                            }
+                            _ => (location.as_str(), None),
+                        };
+                        acp::ToolCallLocation {
+                            path: std::env::current_dir().unwrap_or_default().join(path),
+                            line,
+                            meta: None,
+                        }
+                    })
+                    .collect(),
+                raw_input: Some(serde_json::json!({ "path": "summary.md" })),
                 raw_output: None,
                 meta: None,
             }),
         )
         .await?;
 
+        let fields = if self.options.fail_tool {
+            acp::ToolCallUpdateFields {
+                status: Some(acp::ToolCallStatus::Failed),
+                ..Default::default()
+            }
+        } else {
+            acp::ToolCallUpdateFields {
+                status: Some(acp::ToolCallStatus::Completed),
+                content: Some(
+                    std::iter::once(acp::ToolCallContent::from(format!(
+                        "Summary created for: {summary}"
+                    )))
+                    .chain(
+                        self.options
+                            .diff_lines
+                            .filter(|_| !self.options.diff_only_update)
+                            .map(summary_diff),
+                    )
+                    .collect(),
+                ),
+                title: Some("Generated summary".into()),
+                ..Default::default()
+            }
+        };
         self.send_update(
             &session_id,
             acp::SessionUpdate::ToolCallUpdate(acp::ToolCallUpdate {
                 id: tool_id.clone(),
-                fields: acp::ToolCallUpdateFields {
-                    status: Some(acp::ToolCallStatus::Completed),
-                    content: Some(vec![acp::ToolCallContent::from(format!(
-                        "Summary created for: {summary}"
-                    ))]),
-                    title: Some("Generated summary".into()),
-                    ..Default::default()
-                },
+                fields,
                 meta: None,
             }),
         )
         .await?;
 
+        if let Some(lines) = self
+            .options
+            .diff_lines
+            .filter(|_| self.options.diff_only_update)
+        {
+            self.send_update(
+                &session_id,
+                acp::SessionUpdate::ToolCallUpdate(acp::ToolCallUpdate {
+                    id: tool_id.clone(),
+                    fields: acp::ToolCallUpdateFields {
+                        content: Some(vec![summary_diff(lines)]),
+                        ..Default::default()
+                    },
+                    meta: None,
+                }),
+            )
+            .await?;
+        }
+
+        if self.options.plan_refresh {
+            for (read, draft) in [
+                (
+                    acp::PlanEntryStatus::InProgress,
+                    acp::PlanEntryStatus::Pending,
+                ),
+                (
+                    acp::PlanEntryStatus::Completed,
+                    acp::PlanEntryStatus::InProgress,
+                ),
+            ] {
+                self.send_update(&session_id, plan(read, draft)).await?;
+            }
+        }
+
         self.send_update(&session_id, acp::SessionUpdate::CurrentModeUpdate {
             current_mode_id: acp::SessionModeId("writer".into()),
         })
         .await?;
 
-        self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
-            content: "Here is your concise summary.".into(),
-        })
-        .await?;
+        if self.options.request_permission {
+            let report = self.request_permission(&session_id).await;
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: report.into(),
+            })
+            .await?;
+        }
+
+        if let Some(data) = &self.options.image_base64 {
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: acp::ContentBlock::Image(acp::ImageContent {
+                    annotations: None,
+                    data: data.clone(),
+                    mime_type: self.options.image_mime.clone(),
+                    uri: None,
+                    meta: None,
+                }),
+            })
+            .await?;
+        }
+
+        if let Some(uri) = &self.options.resource_link {
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: acp::ContentBlock::ResourceLink(acp::ResourceLink {
+                    annotations: None,
+                    description: None,
+                    mime_type: Some("text/html".into()),
+                    name: "design.html".into(),
+                    size: None,
+                    title: Some("Design doc".into()),
+                    uri: uri.clone(),
+                    meta: None,
+                }),
+            })
+            .await?;
+        }
+
+        if let Some(method) = &self.options.ext_notification {
+            let (tx, rx) = oneshot::channel();
+            let notification = acp::ExtNotification {
+                method: method.as_str().into(),
+                params: ext_params(&session_id, self.options.ext_error.as_deref()),
+            };
+            if self
+                .client_tx
+                .send(ClientCall::ExtNotification(notification, tx))
+                .is_ok()
+            {
+                let _ = rx.await;
+            }
+        }
+
+        if let Some(method) = &self.options.ext_method {
+            let (tx, rx) = oneshot::channel();
+            let request = acp::ExtRequest {
+                method: method.as_str().into(),
+                params: ext_params(&session_id, None),
+            };
+            let report = match self.client_tx.send(ClientCall::ExtMethod(request, tx)) {
+                Ok(()) => match rx.await {
+                    Ok(Ok(response)) => format!("ext: {}", response.get()),
+                    Ok(Err(error)) => format!(
+                        "ext failed: {} {}",
+                        error.code,
+                        error.data.unwrap_or_default()
+                    ),
+                    Err(_) => "client went away".to_string(),
+                },
+                Err(_) => "client went away".to_string(),
+            };
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: report.into(),
+            })
+            .await?;
+        }
+
+        if let Some(command) = &self.options.terminal_command {
+            let (report, terminal_id) = self.run_terminal(&session_id, command).await;
+            if let Some(terminal_id) = terminal_id {
+                self.send_update(
+                    &session_id,
+                    acp::SessionUpdate::ToolCall(acp::ToolCall {
+                        id: acp::ToolCallId("run_command".into()),
+                        title: format!("Run {command}"),
+                        kind: acp::ToolKind::Execute,
+                        status: acp::ToolCallStatus::InProgress,
+                        content: vec![acp::ToolCallContent::Terminal { terminal_id }],
+                        locations: Vec::new(),
+                        raw_input: None,
+                        raw_output: None,
+                        meta: None,
+                    }),
+                )
+                .await?;
+            }
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: report.into(),
+            })
+            .await?;
+        }
+
+        if let Some(path) = &self.options.read_file {
+            let report = self.read_file(&session_id, path).await;
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: report.into(),
+            })
+            .await?;
+        }
+
+        if let Some(path) = &self.options.write_file {
+            let reports = self
+                .write_file(&session_id, path, &self.options.write_content)
+                .await;
+            for report in reports {
+                self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                    content: report.into(),
+                })
+                .await?;
+            }
+        }
+
+        if !self.options.no_message {
+            self.send_update(&session_id, acp::SessionUpdate::AgentMessageChunk {
+                content: self.options.message.clone().into(),
+            })
+            .await?;
+        }
 
         sleep(Duration::from_millis(50)).await;
 
         Ok(acp::PromptResponse {
             stop_reason: acp::StopReason::EndTurn,
-            meta: None,
+            meta: Some(serde_json::json!({ "turn": "default" })),
         })
     }
 
     async fn cancel(&self, _: acp::CancelNotification) -> std::result::Result<(), acp::Error> {
+        self.cancelled.notify_waiters();
         Ok(())
     }
 }
 
+async fn run_terminal(
+    connection: &acp::AgentSideConnection,
+    request: acp::CreateTerminalRequest,
+    end: TerminalEnd,
+) -> std::result::Result<(String, acp::TerminalId), acp::Error> {
+    let session_id = request.session_id.clone();
+    let terminal_id = connection.create_terminal(request).await?.terminal_id;
+    if let TerminalEnd::Detach = end {
+        return Ok(("terminal: left running".to_string(), terminal_id));
+    }
+    if let TerminalEnd::Kill = end {
+        connection
+            .kill_terminal_command(acp::KillTerminalCommandRequest {
+                session_id: session_id.clone(),
+                terminal_id: terminal_id.clone(),
+                meta: None,
+            })
+            .await?;
+    }
+    let exit = connection
+        .wait_for_terminal_exit(acp::WaitForTerminalExitRequest {
+            session_id: session_id.clone(),
+            terminal_id: terminal_id.clone(),
+            meta: None,
+        })
+        .await?
+        .exit_status;
+    let output = connection
+        .terminal_output(acp::TerminalOutputRequest {
+            session_id: session_id.clone(),
+            terminal_id: terminal_id.clone(),
+            meta: None,
+        })
+        .await?;
+    connection
+        .release_terminal(acp::ReleaseTerminalRequest {
+            session_id,
+            terminal_id: terminal_id.clone(),
+            meta: None,
+        })
+        .await?;
+    let status = match (exit.exit_code, exit.signal) {
+        (Some(code), _) => format!("exit code {code}"),
+        (None, Some(signal)) => format!("killed by {signal}"),
+        (None, None) => "unknown exit".to_string(),
+    };
+    let truncated = if output.truncated { " (truncated)" } else { "" };
+    Ok((
+        format!("terminal: {status}, output{truncated}: {}", output.output),
+        terminal_id,
+    ))
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
+    let options = MockOptions::parse();
+    if options.ignore_sigterm {
+        unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+    }
+    if let Some(path) = &options.spawn_sleeper {
+        let sleeper = std::process::Command::new("sleep").arg("60").spawn()?;
+        std::fs::write(path, sleeper.id().to_string())?;
+    }
+    if let Some(delay) = options.crash_after_ms {
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+            eprintln!("mock agent: out of patience");
+            eprintln!("mock agent: simulated crash");
+            std::process::exit(3);
+        });
+    }
     let outgoing = tokio::io::stdout().compat_write();
     let incoming = tokio::io::stdin().compat();
 
@@ -205,17 +946,53 @@ async fn main() -> Result<()> {
     local_set
         .run_until(async move {
             let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-            let (connection, io_task) =
-                acp::AgentSideConnection::new(MockAgent::new(tx), outgoing, incoming, |fut| {
+            let (connection, io_task) = acp::AgentSideConnection::new(
+                MockAgent::new(tx, options),
+                outgoing,
+                incoming,
+                |fut| {
                     tokio::task::spawn_local(fut);
-                });
+                },
+            );
+            let connection = std::rc::Rc::new(connection);
 
             tokio::task::spawn_local(async move {
-                while let Some((notification, ack)) = rx.recv().await {
-                    if connection.session_notification(notification).await.is_err() {
-                        break;
+                while let Some(call) = rx.recv().await {
+                    match call {
+                        ClientCall::Notify(notification, ack) => {
+                            if connection
+                                .session_notification(*notification)
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            let _ = ack.send(());
+                        }
+                        ClientCall::ReadTextFile(request, reply) => {
+                            let _ = reply.send(connection.read_text_file(request).await);
+                        }
+                        // Writes run side by side, as a tool call fanning out would send them.
+                        ClientCall::WriteTextFile(request, reply) => {
+                            let connection = connection.clone();
+                            tokio::task::spawn_local(async move {
+                                let _ = reply.send(connection.write_text_file(request).await);
+                            });
+                        }
+                        ClientCall::RequestPermission(request, reply) => {
+                            let _ = reply.send(connection.request_permission(request).await);
+                        }
+                        ClientCall::ExtMethod(request, reply) => {
+                            let _ = reply.send(connection.ext_method(request).await);
+                        }
+                        ClientCall::ExtNotification(notification, reply) => {
+                            let _ = connection.ext_notification(notification).await;
+                            let _ = reply.send(());
+                        }
+                        ClientCall::RunTerminal(request, end, reply) => {
+                            let _ = reply.send(run_terminal(&connection, request, end).await);
+                        }
                     }
-                    let _ = ack.send(());
                 }
             });
 
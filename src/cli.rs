@@ -1,6 +1,14 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{ffi::OsString, fmt, path::PathBuf, str::FromStr};
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, McpServerConfig},
+    daemon,
+    ipc::{ApplyDiffs, RuleDecision},
+    media, transcript,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Agent Client Protocol bridge for Kakoune")]
@@ -9,20 +17,175 @@ pub struct Cli {
     pub command: Command,
 }
 
+impl Cli {
+    /// `kakoune-acp SUBCOMMAND ARGS...` parsed, environment included.
+    fn parse_subcommand<I, T>(subcommand: &str, args: I) -> Result<Command, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let mut argv = vec![OsString::from("kakoune-acp"), OsString::from(subcommand)];
+        argv.extend(args.into_iter().map(Into::into));
+        Ok(Self::try_parse_from(argv)?.command)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Start the background daemon that manages an ACP agent connection.
     Daemon(DaemonOptions),
     /// Send a prompt to the daemon and render the response.
     Prompt(PromptOptions),
+    /// Run one of the commands the agent advertised, as the `/NAME INPUT` prompt; the menu
+    /// from `prompt --kak-commands-menu` runs this.
+    #[command(name = "command")]
+    Run(CommandOptions),
+    /// Start an agent, send it one prompt and stop it again, without leaving a daemon running.
+    Oneshot(OneshotOptions),
     /// Query the daemon for diagnostic information.
     Status(StatusOptions),
     /// Ask the daemon to shut down.
     Shutdown(ShutdownOptions),
+    /// Check that the daemon answers, exiting non-zero when it does not.
+    Ping(PingOptions),
+    /// Kill the agent process and start a fresh one, keeping the daemon socket.
+    RestartAgent(RestartAgentOptions),
+    /// Ask an agent to stop its current turn.
+    Cancel(CancelOptions),
+    /// Answer a permission request the daemon is waiting on; the menus it opens in Kakoune
+    /// run this.
+    PermissionReply(PermissionReplyOptions),
+    /// List or clear the rules stored by "always" permission answers.
+    #[command(subcommand)]
+    Permissions(PermissionsCommand),
+    /// Re-read the config file and apply what changed, like sending the daemon SIGHUP.
+    Reload(ReloadOptions),
+    /// Stream session notifications from the daemon until interrupted.
+    Watch(WatchOptions),
+    /// Follow a running prompt from another terminal, or fetch a finished prompt's result.
+    Attach(AttachOptions),
+    /// Re-render a transcript stored by `daemon --persist-transcripts`.
+    Transcript(TranscriptOptions),
+    /// Print the JSON schema of prompt results, as `prompt --output json` prints them and
+    /// `daemon --persist-transcripts` stores them.
+    Schema,
+    /// Show where the config file lives, or dump the effective configuration.
+    Config(ConfigOptions),
+    /// Manage the ACP sessions the daemon keeps per working directory.
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Show the latest entries of the daemon's `--audit-log`.
+    Audit(AuditOptions),
+    /// Print a Kakoune script defining `acp-*` commands for this binary, to source from
+    /// kakrc: `evaluate-commands %sh{ kakoune-acp init }`.
+    Init(InitOptions),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionCommand {
+    /// Forget the session opened for a `prompt --cwd` directory.
+    Close(SessionCloseOptions),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PermissionsCommand {
+    /// Show the stored permission rules.
+    List(PermissionsOptions),
+    /// Forget every stored permission rule.
+    Clear(PermissionsOptions),
 }
 
 #[derive(Args, Debug)]
+pub struct PermissionsOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+}
+
+#[derive(Args, Debug)]
+pub struct InitOptions {
+    /// Agent command line the started daemon runs, kept in the `acp_agent` option; the config
+    /// file's agent otherwise.
+    #[arg(long, value_name = "CMD")]
+    pub agent: Option<String>,
+    /// Name of the module the script provides and requires.
+    #[arg(long, value_name = "NAME", default_value = "acp")]
+    pub module_name: String,
+    /// Do not start the session's daemon when Kakoune starts, or when the module is loaded in
+    /// a running session without one.
+    #[arg(long)]
+    pub no_autostart: bool,
+    /// Accepted for scripts written when starting the daemon was opt-in; it is the default.
+    #[arg(long, hide = true, conflicts_with = "no_autostart")]
+    pub autostart: bool,
+    /// Do not shut the daemon down when Kakoune exits.
+    #[arg(long)]
+    pub no_autostop: bool,
+    /// Leave out the `acp` user mode and its `<user> a` mapping.
+    #[arg(long)]
+    pub no_user_mode: bool,
+    /// Socket the commands use instead of the one derived from the session name.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// What the commands derive the socket from without --socket; see `daemon --help`.
+    #[arg(long, value_enum, default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+}
+
+#[derive(Args, Debug)]
+pub struct AuditOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Read this audit log instead of asking the daemon where its log is.
+    #[arg(long, value_name = "PATH")]
+    pub file: Option<PathBuf>,
+    /// Number of entries to show, the newest last.
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    pub tail: usize,
+    /// Print the entries as the JSON lines they are stored as.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct SessionCloseOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Directory whose session should be closed.
+    #[arg(long, value_name = "PATH")]
+    pub cwd: PathBuf,
+    /// Named agent owning the session; defaults to the daemon's first agent.
+    #[arg(long, value_name = "NAME")]
+    pub agent: Option<String>,
+}
+
+/// Options of `daemon`; build them with `DaemonOptions::from_args`.
+#[derive(Args, Debug, Clone)]
 #[command(trailing_var_arg = true)]
+#[non_exhaustive]
 pub struct DaemonOptions {
     /// Path to the unix socket used for daemon communication.
     #[arg(long)]
@@ -30,15 +193,456 @@ pub struct DaemonOptions {
     /// Kakoune session identifier. Used to derive default socket paths.
     #[arg(long, env = "kak_session")]
     pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
     /// Working directory for the agent session.
     #[arg(long)]
     pub cwd: Option<PathBuf>,
+    /// Store every completed prompt result under `$XDG_STATE_HOME/kakoune-acp/<session>`.
+    #[arg(long)]
+    pub persist_transcripts: bool,
+    /// Number of persisted transcripts to keep before pruning the oldest.
+    #[arg(long, default_value_t = 100)]
+    pub max_transcripts: usize,
+    /// Secrets replaced in persisted transcripts.
+    #[command(flatten)]
+    pub redact: RedactOptions,
+    /// Largest request (in bytes) accepted on the socket; longer lines are rejected.
+    #[arg(long, default_value_t = 4 * 1024 * 1024)]
+    pub max_request_bytes: usize,
+    /// Largest prompt result (in bytes) sent on the socket; bigger results are written to a
+    /// file next to it, which the client reads and removes.
+    #[arg(long, value_name = "BYTES", default_value_t = 4 * 1024 * 1024)]
+    pub max_inline_result_bytes: usize,
+    /// Seconds a graceful shutdown waits for in-flight prompts before stopping anyway.
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub drain_timeout: u64,
+    /// Sessions kept open for `prompt --cwd` directories before the least recently used
+    /// one is dropped.
+    #[arg(long, value_name = "N", default_value_t = 8)]
+    pub max_cwd_sessions: usize,
+    /// Notifications buffered for each `watch` client; one that falls further behind misses
+    /// the oldest ones.
+    #[arg(long, value_name = "N", default_value_t = 256)]
+    pub notification_buffer: usize,
+    /// Seconds an answer stays available to prompts repeating its idempotency key.
+    #[arg(long, value_name = "SECS", default_value_t = 600)]
+    pub idempotency_ttl: u64,
+    /// Answer the agents' `fs/read_text_file` requests, advertising the capability to them.
+    ///
+    /// Relative paths are resolved against the session's working directory.
+    #[arg(long)]
+    pub allow_fs_read: bool,
+    /// Most bytes one read returns; longer reads are cut short and say so in their `_meta`.
+    #[arg(long, value_name = "BYTES", default_value_t = daemon::DEFAULT_MAX_READ_BYTES)]
+    pub max_read_bytes: usize,
+    /// Unchanged lines shown around each change in the diffs of `file_edit` events.
+    #[arg(long, value_name = "N", default_value_t = transcript::DEFAULT_DIFF_CONTEXT)]
+    pub diff_context: usize,
+    /// Most lines kept of each diff in a `file_edit` event; longer diffs are cut short with a
+    /// note and saved in full under the session's state directory.
+    #[arg(long, value_name = "N", default_value_t = transcript::DEFAULT_MAX_DIFF_LINES)]
+    pub max_diff_lines: usize,
+    /// Let agents write files through ACP's `fs/write_text_file`: `ask` approves each write
+    /// through a permission request, `always` writes without asking.
+    ///
+    /// Each overwritten file keeps its previous contents in `FILE.kakoune-acp.bak`.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "ask"
+    )]
+    pub allow_fs_write: Option<FsWritePolicy>,
+    /// Directory agents' file and terminal requests are confined to; defaults to each
+    /// session's working directory.
+    #[arg(long, value_name = "PATH")]
+    pub workspace_root: Option<PathBuf>,
+    /// Also let agents reach paths matching this pattern outside the workspace root; `*` and
+    /// `?` match within a path component, `**` across them. Repeatable.
+    #[arg(long = "allow-path", value_name = "PATTERN")]
+    pub allow_paths: Vec<String>,
+    /// Run commands for the agents' `terminal/*` requests, advertising the capability to them.
+    #[arg(long)]
+    pub allow_terminal: bool,
+    /// Leave images from agents out of transcripts instead of saving them under
+    /// `$XDG_STATE_HOME/kakoune-acp/<session>/images`.
+    #[arg(long)]
+    pub no_save_media: bool,
+    /// Largest image, in decoded bytes, that is saved; bigger ones are left out.
+    #[arg(long, value_name = "BYTES", default_value_t = media::DEFAULT_MAX_MEDIA_BYTES)]
+    pub max_media_bytes: u64,
+    /// Answer the agents' extension method calls by running this shell command with the
+    /// method name as its argument and the JSON params on stdin; its JSON output is the
+    /// response.
+    #[arg(long, value_name = "CMD")]
+    pub ext_handler: Option<String>,
+    /// Seconds an `--ext-handler` run may take before the call fails.
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub ext_timeout: u64,
+    /// Without `--ext-handler`, acknowledge extension method calls with `{}` instead of
+    /// failing them as unknown methods.
+    #[arg(long)]
+    pub ext_ack: bool,
+    /// Append a JSON line to this file for every file read and write, permission decision
+    /// and terminal the agents ask for. Created readable by its owner only.
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+    /// Seconds a permission request shown in Kakoune waits for an answer before
+    /// `--permission-default` settles it.
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    pub permission_timeout: u64,
+    /// What an unanswered permission request resolves to once `--permission-timeout` passes.
+    #[arg(long, value_enum, default_value_t = PermissionDefault::Cancel)]
+    pub permission_default: PermissionDefault,
+    /// Characters of a permission request's input summary (the command line, or the files
+    /// and diff stat) shown when asking; the transcript keeps it whole. `0` shows none.
+    #[arg(long, value_name = "CHARS", default_value_t = 200)]
+    pub permission_summary_chars: usize,
+    /// Keep the rules stored by "always" permission answers in
+    /// `$XDG_STATE_HOME/kakoune-acp/<session>/permissions.json`, so they survive restarts.
+    #[arg(long)]
+    pub persist_permissions: bool,
+    /// How agents' permission requests are settled before anyone is asked: `approve-all`,
+    /// `deny-all`, `ask`, or per-kind rules such as `execute=deny,edit=ask,read=allow`.
+    ///
+    /// Kinds without a rule are asked about in Kakoune.
+    #[arg(long, value_name = "POLICY", default_value = "ask")]
+    pub permission_policy: PermissionPolicy,
+    /// Open the ACP session on the first prompt instead of at startup.
+    #[arg(long)]
+    pub lazy_session: bool,
+    /// Do not tell the `--session` Kakoune session when an agent exits unexpectedly, nor keep
+    /// its `acp_state` option up to date.
+    #[arg(long)]
+    pub no_kak_notifications: bool,
+    /// Shut the daemon down once the Kakoune session given by `--session` is gone.
+    #[arg(long)]
+    pub follow_kak_session: bool,
+    /// How often `--follow-kak-session` checks `kak -l`.
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub kak_poll_interval: u64,
+    /// Shut down a daemon already listening on the socket instead of refusing to start.
+    #[arg(long)]
+    pub replace: bool,
+    /// Start each agent and open a session, print a JSON summary per agent and exit without
+    /// binding the socket.
+    ///
+    /// Exits with 2 when an agent cannot be launched, 3 when `initialize` fails and 4 when
+    /// `session/new` fails.
+    #[arg(long)]
+    pub verify: bool,
+    /// What to do when the agent exits on its own.
+    #[arg(long, value_enum)]
+    pub restart: Option<RestartPolicy>,
+    /// Write daemon logs to this file instead of stderr.
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Log filter such as `info` or `kakoune_acp=debug`; `RUST_LOG` applies when unset.
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Name of the first agent. Further agents follow its command as
+    /// `--agent-name NAME -- CMD...`, the `--` included.
+    #[arg(long, value_name = "NAME", default_value = "default")]
+    pub agent_name: String,
+    /// Extra environment for the agent process, from the config file.
+    #[arg(skip)]
+    pub env: Vec<(String, String)>,
+    /// MCP servers offered to the agent's session, from the config file.
+    #[arg(skip)]
+    pub mcp_servers: Vec<McpServerConfig>,
     /// Command used to launch the agent process (program followed by args).
-    #[arg(required = true)]
+    ///
+    /// Defaults to `agent` in the `[daemon]` section of the config file.
     pub agent: Vec<OsString>,
 }
 
+impl DaemonOptions {
+    /// Options as `kakoune-acp daemon ARGS...` takes them, for running a daemon without the
+    /// binary. The config file is not applied.
+    pub fn from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        match Cli::parse_subcommand("daemon", args)? {
+            Command::Daemon(options) => Ok(options),
+            _ => unreachable!("the arguments start with the daemon subcommand"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Shut the daemon down along with the agent.
+    Never,
+    /// Respawn the agent and open a fresh session when it fails or is killed; a clean exit
+    /// shuts the daemon down as with `never`.
+    OnFailure,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsWritePolicy {
+    /// Ask for permission before every write.
+    Ask,
+    /// Write without asking.
+    Always,
+    /// Refuse writes and do not advertise the capability.
+    Never,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum PermissionDefault {
+    /// Cancel the request.
+    Cancel,
+    /// Pick the agent's reject option.
+    Deny,
+    /// Pick the agent's allow-once option.
+    AllowOnce,
+}
+
+/// Tool kinds a `--permission-policy` rule can name, as ACP spells them.
+const TOOL_KINDS: [&str; 10] = [
+    "read",
+    "edit",
+    "delete",
+    "move",
+    "search",
+    "execute",
+    "think",
+    "fetch",
+    "switch_mode",
+    "other",
+];
+
+/// A window of lines around a position, from `--context-around LINE:COUNT`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ContextAround {
+    /// 1-based line the window is centred on.
+    pub line: usize,
+    /// Lines kept on each side of `line`.
+    pub count: usize,
+}
+
+impl ContextAround {
+    /// The 1-based first and last lines of the window, clamped to a file of `lines` lines.
+    pub fn range(&self, lines: usize) -> (usize, usize) {
+        let first = self
+            .line
+            .saturating_sub(self.count)
+            .max(1)
+            .min(lines.max(1));
+        let last = self.line.saturating_add(self.count).min(lines);
+        (first, last.max(first))
+    }
+}
+
+impl FromStr for ContextAround {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (line, count) = value
+            .split_once(':')
+            .ok_or_else(|| format!("`{value}` is not LINE:COUNT"))?;
+        let line = line
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|line| *line > 0)
+            .ok_or_else(|| format!("`{line}` is not a line number (they start at 1)"))?;
+        let count = count
+            .trim()
+            .parse()
+            .map_err(|_| format!("`{count}` is not a line count"))?;
+        Ok(Self { line, count })
+    }
+}
+
+/// A register Kakoune commands can write, from `--kak-register`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KakRegister(pub String);
+
+impl FromStr for KakRegister {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        const NAMED: &[&str] = &["arobase", "caret", "dquote", "pipe", "slash"];
+        let mut chars = value.chars();
+        let valid = match (chars.next(), chars.next()) {
+            (Some(ch), None) => ch.is_ascii_alphanumeric() || "\"@^|/".contains(ch),
+            _ => NAMED.contains(&value),
+        };
+        if valid {
+            Ok(Self(value.to_string()))
+        } else {
+            Err(format!(
+                "`{value}` is not a writable register: give a letter, a digit, one of \"@^|/ or {}",
+                NAMED.join(", ")
+            ))
+        }
+    }
+}
+
+/// Decisions made on permission requests without asking, from `--permission-policy`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PermissionPolicy {
+    /// Per-kind decisions, in the order they were given.
+    pub rules: Vec<(String, PermissionDecision)>,
+    /// Decision for kinds without a rule.
+    pub fallback: PermissionDecision,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PermissionDecision {
+    Allow,
+    Deny,
+    #[default]
+    Ask,
+}
+
+impl PermissionPolicy {
+    /// The decision for a request of `kind` and the rule that made it, if it was not the
+    /// fallback of a rule list.
+    pub fn decide(&self, kind: &str) -> (PermissionDecision, Option<String>) {
+        match self.rules.iter().find(|(rule_kind, _)| rule_kind == kind) {
+            Some((kind, decision)) => (*decision, Some(format!("{kind}={decision}"))),
+            None if self.rules.is_empty() && self.fallback != PermissionDecision::Ask => {
+                (self.fallback, Some(self.to_string()))
+            }
+            None => (self.fallback, None),
+        }
+    }
+}
+
+impl FromStr for PermissionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let fallback = match value.trim() {
+            "approve-all" => PermissionDecision::Allow,
+            "deny-all" => PermissionDecision::Deny,
+            "ask" => PermissionDecision::Ask,
+            _ => {
+                let mut rules: Vec<(String, PermissionDecision)> = Vec::new();
+                for rule in value.split(',').map(str::trim) {
+                    let Some((kind, decision)) = rule.split_once('=') else {
+                        return Err(format!(
+                            "`{rule}` is neither approve-all, deny-all, ask nor a KIND=DECISION rule"
+                        ));
+                    };
+                    let kind = kind.trim();
+                    if !TOOL_KINDS.contains(&kind) {
+                        return Err(format!(
+                            "unknown tool kind `{kind}` (expected one of {})",
+                            TOOL_KINDS.join(", ")
+                        ));
+                    }
+                    if rules.iter().any(|(seen, _)| seen == kind) {
+                        return Err(format!("tool kind `{kind}` has more than one rule"));
+                    }
+                    let decision = match decision.trim() {
+                        "allow" => PermissionDecision::Allow,
+                        "deny" => PermissionDecision::Deny,
+                        "ask" => PermissionDecision::Ask,
+                        other => {
+                            return Err(format!(
+                                "unknown decision `{other}` for {kind} (expected allow, deny or ask)"
+                            ));
+                        }
+                    };
+                    rules.push((kind.to_string(), decision));
+                }
+                return Ok(Self {
+                    rules,
+                    fallback: PermissionDecision::Ask,
+                });
+            }
+        };
+        Ok(Self {
+            rules: Vec::new(),
+            fallback,
+        })
+    }
+}
+
+impl fmt::Display for PermissionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rules.is_empty() {
+            return f.write_str(match self.fallback {
+                PermissionDecision::Allow => "approve-all",
+                PermissionDecision::Deny => "deny-all",
+                PermissionDecision::Ask => "ask",
+            });
+        }
+        let rules = self
+            .rules
+            .iter()
+            .map(|(kind, decision)| format!("{kind}={decision}"))
+            .collect::<Vec<_>>();
+        f.write_str(&rules.join(","))
+    }
+}
+
+impl fmt::Display for PermissionDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::Ask => "ask",
+        })
+    }
+}
+
+/// Secrets to hide from agents and transcripts.
+#[derive(Args, Debug, Clone, Default)]
+pub struct RedactOptions {
+    /// Replace text matching this regex with `[REDACTED:pattern-N]`, N counting the patterns
+    /// given (can be supplied multiple times).
+    #[arg(long = "redact", value_name = "PATTERN")]
+    pub patterns: Vec<String>,
+    /// Do not redact AWS keys, GitHub tokens and private keys unless a --redact pattern
+    /// matches them.
+    #[arg(long)]
+    pub no_default_redactions: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CommandOptions {
+    /// The command to run, with or without its leading `/`.
+    #[arg(long)]
+    pub name: String,
+    /// Input for commands that take some.
+    #[arg(long)]
+    pub input: Option<String>,
+    #[command(flatten)]
+    pub prompt: PromptOptions,
+}
+
 #[derive(Args, Debug)]
+#[command(trailing_var_arg = true)]
+pub struct OneshotOptions {
+    #[command(flatten)]
+    pub prompt: PromptOptions,
+    /// Option for the daemon the prompt runs through, such as `--daemon-arg=--allow-fs-read`.
+    /// Repeatable.
+    #[arg(long = "daemon-arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub daemon_args: Vec<String>,
+    /// Command used to launch the agent process (program followed by args), after `--`.
+    ///
+    /// Defaults to `agent` in the `[daemon]` section of the config file.
+    #[arg(value_name = "AGENT")]
+    pub agent_command: Vec<OsString>,
+}
+
+/// Options of `prompt`; build them with `PromptOptions::from_args`.
+#[derive(Args, Debug)]
+#[non_exhaustive]
 pub struct PromptOptions {
     /// Path to the unix socket used for daemon communication.
     #[arg(long)]
@@ -55,30 +659,232 @@ pub struct PromptOptions {
     /// Read additional context snippets from files (can be supplied multiple times).
     #[arg(long = "context-file", value_name = "PATH")]
     pub context_files: Vec<PathBuf>,
+    /// Send only the lines within COUNT of line LINE (1-based) of each `--context-file`.
+    #[arg(long, value_name = "LINE:COUNT")]
+    pub context_around: Option<ContextAround>,
+    /// Truncate each context snippet to at most this many bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub max_context_bytes: Option<usize>,
+    /// Secrets replaced in the context before it is sent and in the transcript shown.
+    #[command(flatten)]
+    pub redact: RedactOptions,
     /// Kakoune session to send responses back to.
     #[arg(long, env = "kak_session")]
     pub session: Option<String>,
-    /// Kakoune client to target when emitting commands.
-    #[arg(long, env = "kak_client")]
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Kakoune client to target when emitting commands. `auto` picks the session's most
+    /// recently focused client, as does leaving it unset with --send-to-kak.
+    #[arg(long, env = "kak_client", value_name = "auto|CLIENT")]
     pub client: Option<String>,
-    /// Output format.
-    #[arg(long, value_enum, default_value_t = PromptOutput::Plain)]
-    pub output: PromptOutput,
-    /// Optional title used when rendering Kakoune commands.
-    #[arg(long, default_value = "Agent Response")]
-    pub title: String,
+    /// Output format [default: plain].
+    #[arg(long, value_enum)]
+    pub output: Option<PromptOutput>,
+    /// Print only the agent's answer, its messages without the rest of the transcript,
+    /// instead of the plain transcript.
+    #[arg(long)]
+    pub quiet: bool,
+    /// Also write the result, with its whole transcript, to this file as JSON, in the form
+    /// `daemon --persist-transcripts` keeps it.
+    #[arg(long, value_name = "PATH")]
+    pub transcript_file: Option<PathBuf>,
+    /// Title used when rendering Kakoune commands [default: "Agent Response"].
+    #[arg(long)]
+    pub title: Option<String>,
+    /// Wrap the rendered transcript at this many columns.
+    #[arg(long, value_name = "COLUMNS")]
+    pub wrap_width: Option<usize>,
     /// Emit Kakoune commands directly instead of printing plain text.
     #[arg(long)]
     pub send_to_kak: bool,
+    /// Kakoune command to run in the client once the prompt has finished or failed.
+    /// `{stop_reason}` (`failed` when the prompt did), `{title}` and `{buffer}` (the
+    /// --kak-buffer name) are replaced with their quoted values first.
+    #[arg(long, value_name = "COMMAND")]
+    pub on_complete: Option<String>,
+    /// Send with --send-to-kak without first checking that the Kakoune session is running,
+    /// for sessions neither their socket nor `kak -l` can find.
+    #[arg(long)]
+    pub no_session_check: bool,
+    /// Do not show the elapsed time, tool calls and current plan step in the client's status
+    /// line while a --send-to-kak prompt runs.
+    #[arg(long)]
+    pub no_progress: bool,
+    /// Mirror the agent's plan as a checklist in an info box in the client while the turn
+    /// runs, updated as the agent revises it and ending with how many steps were completed.
+    #[arg(long)]
+    pub kak_plan: bool,
+    /// Show the agent's answer in the client as it streams in, in an info box or, with
+    /// `--kak-target buffer`, the --kak-buffer buffer; updated at most every 150ms unless
+    /// 4 KiB more arrived sooner.
+    #[arg(long)]
+    pub kak_stream: bool,
+    /// Where Kakoune commands show the transcript: an `info` popup, or a scratch buffer the
+    /// client switches to, which can be scrolled, searched and yanked from. With `info,buffer`
+    /// the buffer is only filled when --info-max-lines cut the popup short.
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [KakTarget::Info])]
+    pub kak_target: Vec<KakTarget>,
+    /// How the `info` popup is placed; Kakoune's default when not given.
+    #[arg(long, value_enum)]
+    pub info_style: Option<InfoStyle>,
+    /// Show at most this many lines in the `info` popup, ending it with how many more the
+    /// --kak-buffer buffer holds.
+    #[arg(long, value_name = "N")]
+    pub info_max_lines: Option<usize>,
+    /// Scratch buffer used by `--kak-target buffer`, created on first use and replaced after.
+    #[arg(long, value_name = "NAME", default_value = config::DEFAULT_KAK_BUFFER)]
+    pub kak_buffer: String,
+    /// Add the transcript to the end of the --kak-buffer scratch buffer, after a line with
+    /// the time, title and stop reason, instead of replacing what it held.
+    #[arg(long)]
+    pub kak_append: bool,
+    /// Empty the --kak-buffer scratch buffer instead of prompting.
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "kak_append"])]
+    pub kak_clear: bool,
+    /// Type the agent's answer, its messages without the rest of the transcript, into the
+    /// client's buffer at the main cursor instead of showing the transcript. Answers over
+    /// 64 KiB are refused.
+    #[arg(long, conflicts_with_all = ["kak_target", "kak_append", "kak_clear"])]
+    pub kak_insert: bool,
+    /// Also put the agent's answer, its messages without the rest of the transcript, in this
+    /// Kakoune register: a single character like `r`, or a name like `dquote`.
+    #[arg(long, value_name = "REG")]
+    pub kak_register: Option<KakRegister>,
+    /// Also list the files the agent's tool calls, edits and writes touched in a `grep` style
+    /// `*acp-locations*` buffer.
+    #[arg(long)]
+    pub kak_locations: bool,
+    /// End the Kakoune commands with a menu of the commands the agent advertised during the
+    /// prompt, each run with `kakoune-acp command` when chosen.
+    #[arg(long)]
+    pub kak_commands_menu: bool,
+    /// Named agent to prompt; defaults to the daemon's first agent.
+    #[arg(long, value_name = "NAME")]
+    pub agent: Option<String>,
+    /// Fail right away with "agent is busy" instead of waiting behind another prompt.
+    #[arg(long)]
+    pub no_queue: bool,
+    /// Keep every message and thought chunk the agent streams as its own transcript event,
+    /// rather than joining the chunks that follow one another.
+    #[arg(long)]
+    pub no_merge_chunks: bool,
+    /// Keep every plan the agent sends in the transcript in full, rather than only the steps
+    /// that changed since its previous plan.
+    #[arg(long)]
+    pub full_plans: bool,
+    /// Keep the raw input and output the agent reports for each tool call in the transcript's
+    /// `tool_call` and `tool_call_update` events.
+    #[arg(long)]
+    pub raw_tool_io: bool,
+    /// Record each tool call as one `tool_call` event holding its final state and the
+    /// statuses it went through, instead of a `tool_call_update` event per report.
+    #[arg(long)]
+    pub group_tool_calls: bool,
+    /// Record mode changes as `system_message` events ("Current mode: ID") next to their
+    /// `mode_change` events, for scripts written before mode changes had events of their own.
+    #[arg(long)]
+    pub legacy_mode_messages: bool,
+    /// Keep the agent's echo of the prompt as a second `user_message` event even when it
+    /// repeats the prompt exactly.
+    #[arg(long)]
+    pub keep_user_echo: bool,
+    /// Cancel the turn if the agent has not finished after this many seconds.
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+    /// Reuse the daemon's answer to an earlier prompt sent with the same key rather than
+    /// prompting the agent again.
+    #[arg(long, value_name = "KEY")]
+    pub idempotency_key: Option<String>,
+    /// Derive the idempotency key from the prompt and its context.
+    #[arg(long, conflicts_with = "idempotency_key")]
+    pub idempotent: bool,
+    /// Run the prompt in a session bound to this directory instead of the daemon's.
+    #[arg(long, value_name = "PATH")]
+    pub cwd: Option<PathBuf>,
+    /// Answer the agent's permission requests here instead of in a Kakoune menu. On by
+    /// default when run from a terminal without --send-to-kak.
+    #[arg(long)]
+    pub answer_permissions: bool,
+    /// Write the diffs the agent proposes once its turn ends: `ask` about each one, at the
+    /// terminal or in Kakoune like a permission request, `always` or `never`. Diffs that no
+    /// longer match the file are reported as conflicts.
+    #[arg(long, value_enum, value_name = "WHEN")]
+    pub apply_diffs: Option<ApplyDiffs>,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+impl PromptOptions {
+    /// Options as `kakoune-acp prompt ARGS...` takes them, for `prompt::execute`. The config
+    /// file is not applied.
+    pub fn from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        match Cli::parse_subcommand("prompt", args)? {
+            Command::Prompt(options) => Ok(options),
+            _ => unreachable!("the arguments start with the prompt subcommand"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PromptOutput {
     Plain,
     Json,
     KakCommands,
 }
 
+/// What `--socket-scope` derives the default daemon socket path from.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum SocketScope {
+    /// The Kakoune session name: one daemon per session.
+    #[default]
+    Session,
+    /// The project directory, the nearest one above the current directory holding `.git`:
+    /// one daemon per project, whichever sessions use it.
+    Cwd,
+    /// Nothing: one daemon for everything.
+    Global,
+}
+
+impl SocketScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Session => "session",
+            Self::Cwd => "cwd",
+            Self::Global => "global",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum KakTarget {
+    Info,
+    Buffer,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum InfoStyle {
+    /// A box in the middle of the client that stays until dismissed.
+    Modal,
+    /// Next to the prompt line.
+    Prompt,
+    /// Just above the cursor.
+    AboveCursor,
+    /// Just below the cursor.
+    BelowCursor,
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigOptions {
+    /// Print the effective configuration as TOML instead of the file path.
+    #[arg(long)]
+    pub print: bool,
+}
+
 #[derive(Args, Debug)]
 pub struct StatusOptions {
     /// Path to the unix socket used for daemon communication.
@@ -87,12 +893,123 @@ pub struct StatusOptions {
     /// Kakoune session identifier. Used to derive default socket paths.
     #[arg(long, env = "kak_session")]
     pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
     /// Render the status response as JSON.
     #[arg(long)]
     pub json: bool,
+    /// Report the session and process of this named agent instead of the default one.
+    #[arg(long, value_name = "NAME")]
+    pub agent: Option<String>,
+    /// Show the traffic counters instead of the overview.
+    #[arg(long)]
+    pub metrics: bool,
+    /// Zero the traffic counters, reporting the values they had.
+    #[arg(long)]
+    pub reset_metrics: bool,
+    /// Wait for the daemon to come up and finish starting its agent before reporting.
+    #[arg(long)]
+    pub wait: bool,
+    /// Give up waiting after this many seconds.
+    #[arg(long, value_name = "SECS", default_value_t = 10, requires = "wait")]
+    pub timeout: u64,
+}
+
+#[derive(Args, Debug)]
+#[command(after_help = "Exit codes: 0 daemon answered, 1 daemon unresponsive, \
+                        2 socket missing, 3 connection refused")]
+pub struct PingOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Milliseconds to wait for the answer before reporting the daemon unresponsive.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub timeout: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct RestartAgentOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Named agent to restart; defaults to the daemon's first agent.
+    #[arg(long, value_name = "NAME")]
+    pub agent: Option<String>,
 }
 
 #[derive(Args, Debug)]
+pub struct CancelOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Named agent whose turn should be cancelled; defaults to the daemon's first agent.
+    #[arg(long, value_name = "NAME")]
+    pub agent: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct PermissionReplyOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Id of the permission request, as given in the menu.
+    #[arg(long)]
+    pub id: u64,
+    /// Id of the chosen option; cancels the request when omitted.
+    #[arg(long = "option", value_name = "OPTION_ID", conflicts_with = "always")]
+    pub option_id: Option<String>,
+    /// Allow or deny this request and store a rule settling later requests for the same tool
+    /// the same way.
+    #[arg(long, value_enum)]
+    pub always: Option<RuleDecision>,
+}
+
+#[derive(Args, Debug)]
+pub struct ReloadOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+}
+
+#[derive(Args, Debug)]
+#[command(after_help = "Exit codes: 0 graceful shutdown, 2 forced shutdown")]
 pub struct ShutdownOptions {
     /// Path to the unix socket used for daemon communication.
     #[arg(long)]
@@ -100,4 +1017,89 @@ pub struct ShutdownOptions {
     /// Kakoune session identifier. Used to derive default socket paths.
     #[arg(long, env = "kak_session")]
     pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Stop immediately instead of waiting for in-flight prompts to finish, killing the
+    /// agents' process groups if they do not exit on SIGTERM.
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Print each notification as a line of JSON instead of a transcript summary.
+    #[arg(long)]
+    pub json: bool,
+    #[command(flatten)]
+    pub redact: RedactOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct AttachOptions {
+    /// Path to the unix socket used for daemon communication.
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    /// Kakoune session identifier. Used to derive default socket paths.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// What the socket path is derived from without --socket: the session name, the project
+    /// directory or nothing, so one daemon serves every session.
+    #[arg(long, value_enum, env = "KAKOUNE_ACP_SOCKET_SCOPE", default_value_t = SocketScope::Session)]
+    pub socket_scope: SocketScope,
+    /// Request id of the prompt, as shown by `status`.
+    #[arg(long, value_name = "ID")]
+    pub request_id: u64,
+    /// Print each event and the final result as lines of JSON.
+    #[arg(long)]
+    pub json: bool,
+    #[command(flatten)]
+    pub redact: RedactOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct TranscriptOptions {
+    /// Kakoune session whose transcripts should be read.
+    #[arg(long, env = "kak_session")]
+    pub session: Option<String>,
+    /// Number of the stored transcript to render.
+    #[arg(long, conflicts_with = "latest", required_unless_present = "latest")]
+    pub index: Option<u64>,
+    /// Render the most recently stored transcript.
+    #[arg(long)]
+    pub latest: bool,
+    /// Kakoune client to target when emitting commands.
+    #[arg(long, env = "kak_client")]
+    pub client: Option<String>,
+    /// Output format [default: plain].
+    #[arg(long, value_enum)]
+    pub output: Option<PromptOutput>,
+    /// Title used when rendering Kakoune commands [default: "Agent Response"].
+    #[arg(long)]
+    pub title: Option<String>,
+    /// Wrap the rendered transcript at this many columns.
+    #[arg(long, value_name = "COLUMNS")]
+    pub wrap_width: Option<usize>,
+    /// Emit Kakoune commands directly instead of printing plain text.
+    #[arg(long)]
+    pub send_to_kak: bool,
+    /// Show the transcript in an `info` popup or a scratch buffer.
+    #[arg(long, value_enum, default_value_t = KakTarget::Info)]
+    pub kak_target: KakTarget,
+    /// Scratch buffer used by `--kak-target buffer`.
+    #[arg(long, value_name = "NAME", default_value = config::DEFAULT_KAK_BUFFER)]
+    pub kak_buffer: String,
+    #[command(flatten)]
+    pub redact: RedactOptions,
 }
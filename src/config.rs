@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{
+    Command, CommandOptions, ConfigOptions, DaemonOptions, OneshotOptions, PromptOutput,
+    RestartPolicy,
+};
+
+/// Title used for Kakoune info boxes when neither the CLI nor the config sets one.
+pub const DEFAULT_TITLE: &str = "Agent Response";
+
+/// Scratch buffer `--kak-target buffer` fills when `--kak-buffer` names none.
+pub const DEFAULT_KAK_BUFFER: &str = "*acp*";
+
+/// Contents of `$XDG_CONFIG_HOME/kakoune-acp/config.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub daemon: DaemonConfig,
+    pub prompt: PromptConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Agent program followed by its arguments.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub agent: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables for the agent process.
+    pub env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mcp_servers: Vec<McpServerConfig>,
+    pub restart: Option<RestartPolicy>,
+    pub log_file: Option<PathBuf>,
+    pub log_level: Option<String>,
+}
+
+/// An MCP server handed to the agent when opening a session.
+///
+/// Servers with a `command` are launched over stdio; servers with a `url` use HTTP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct McpServerConfig {
+    pub name: String,
+    pub command: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptConfig {
+    pub output: Option<PromptOutput>,
+    pub title: Option<String>,
+    /// Wrap rendered transcripts at this many columns.
+    pub wrap_width: Option<usize>,
+}
+
+impl McpServerConfig {
+    pub fn to_acp(&self) -> Result<acp::McpServer> {
+        match (&self.command, &self.url) {
+            (Some(command), None) => Ok(acp::McpServer::Stdio {
+                name: self.name.clone(),
+                command: command.clone(),
+                args: self.args.clone(),
+                env: self
+                    .env
+                    .iter()
+                    .map(|(name, value)| acp::EnvVariable {
+                        name: name.clone(),
+                        value: value.clone(),
+                        meta: None,
+                    })
+                    .collect(),
+            }),
+            (None, Some(url)) => Ok(acp::McpServer::Http {
+                name: self.name.clone(),
+                url: url.clone(),
+                headers: self
+                    .headers
+                    .iter()
+                    .map(|(name, value)| acp::HttpHeader {
+                        name: name.clone(),
+                        value: value.clone(),
+                        meta: None,
+                    })
+                    .collect(),
+            }),
+            _ => Err(anyhow!(
+                "MCP server {:?} needs exactly one of `command` or `url`",
+                self.name
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file, treating a missing file as an empty config.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read config file {}", path.display()));
+            }
+        };
+        // toml's error already points at the offending line and column.
+        toml::from_str(&text)
+            .map_err(|err| anyhow!("invalid config file {}: {err}", path.display()))
+    }
+
+    /// The config with every built-in default spelled out, as `config --print` shows it.
+    fn effective(mut self) -> Self {
+        self.daemon.restart.get_or_insert(RestartPolicy::Never);
+        self.prompt.output.get_or_insert(PromptOutput::Plain);
+        self.prompt
+            .title
+            .get_or_insert_with(|| DEFAULT_TITLE.to_string());
+        self
+    }
+
+    /// Fills options the command line left unset from the config file.
+    pub fn apply(&self, command: &mut Command) {
+        match command {
+            Command::Daemon(options) => self.daemon.apply(options),
+            Command::Prompt(options)
+            | Command::Run(CommandOptions {
+                prompt: options, ..
+            })
+            | Command::Oneshot(OneshotOptions {
+                prompt: options, ..
+            }) => {
+                fill(&mut options.output, &self.prompt.output);
+                fill(&mut options.title, &self.prompt.title);
+                fill(&mut options.wrap_width, &self.prompt.wrap_width);
+            }
+            Command::Transcript(options) => {
+                fill(&mut options.output, &self.prompt.output);
+                fill(&mut options.title, &self.prompt.title);
+                fill(&mut options.wrap_width, &self.prompt.wrap_width);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DaemonConfig {
+    pub fn apply(&self, options: &mut DaemonOptions) {
+        if options.agent.is_empty() {
+            options.agent = self.agent.iter().map(OsString::from).collect();
+        }
+        fill(&mut options.cwd, &self.cwd);
+        fill(&mut options.restart, &self.restart);
+        fill(&mut options.log_file, &self.log_file);
+        fill(&mut options.log_level, &self.log_level);
+        options.env = self.env.clone().into_iter().collect();
+        options.mcp_servers = self.mcp_servers.clone();
+    }
+}
+
+fn fill<T: Clone>(option: &mut Option<T>, fallback: &Option<T>) {
+    if option.is_none() {
+        *option = fallback.clone();
+    }
+}
+
+/// Location of the config file.
+///
+/// Follows `$XDG_CONFIG_HOME`, falling back to `~/.config`.
+pub fn resolve_config_path() -> Result<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| !p.as_os_str().is_empty())
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(PathBuf::from)
+                .map(|home| home.join(".config"))
+        })
+        .ok_or_else(|| anyhow!("neither XDG_CONFIG_HOME nor HOME is set"))?;
+    Ok(base.join("kakoune-acp").join("config.toml"))
+}
+
+pub fn run(options: ConfigOptions, config: Config) -> Result<()> {
+    let path = resolve_config_path()?;
+    if !options.print {
+        println!("{}", path.display());
+        return Ok(());
+    }
+    if path.exists() {
+        println!("# loaded from {}", path.display());
+    } else {
+        println!("# no config file at {}; showing defaults", path.display());
+    }
+    print!("{}", toml::to_string_pretty(&config.effective())?);
+    Ok(())
+}
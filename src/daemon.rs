@@ -1,156 +1,311 @@
-use std::{ffi::OsString, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::OsString,
+    os::unix::{fs::MetadataExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use agent_client_protocol::{self as acp, Agent};
 use anyhow::{Context, Result};
 use serde_json::json;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{UnixListener, UnixStream},
-    process::Command,
-    sync::{Mutex, Notify, broadcast},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    process::{Child, Command},
+    signal::unix::SignalKind,
+    sync::{Mutex, Notify, broadcast, mpsc, oneshot, watch},
+};
+use tokio_util::{
+    compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt},
+    sync::CancellationToken,
 };
-use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
 use crate::{
-    cli::DaemonOptions,
-    ipc::{self, DaemonRequest, DaemonResponse, PromptPayload, PromptResultPayload},
-    kakoune,
-    transcript::TranscriptCollector,
+    audit::{AuditLog, AuditRecord},
+    cli::{
+        DaemonOptions, FsWritePolicy, PermissionDecision, PermissionDefault, PermissionPolicy,
+        RestartPolicy, SocketScope,
+    },
+    config::{self, Config, McpServerConfig},
+    diffs::{self, PathLocks},
+    ext::{self, ExtHandler},
+    history::TranscriptStore,
+    ipc::{
+        self, ApplyDiffs, DaemonRequest, DaemonResponse, DiffOutcome, DiffStatus, ErrorSource,
+        PROTOCOL_VERSION, PermissionRule, PlanProgress, PromptPayload, PromptResultPayload,
+        RequestEnvelope, ResponseEnvelope, RuleDecision, StreamDisplay, TranscriptEntry,
+        TranscriptEvent, VersionProbe,
+    },
+    ipc_client, kakoune, logging,
+    media::MediaStore,
+    permission_rules::RuleStore,
+    redact::Redactor,
+    sandbox::Sandbox,
+    terminal::{self, Terminals},
+    transcript::{self, DiffFormat, TranscriptCollector},
 };
 
+/// Pause before respawning an agent that exited on its own.
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a daemon already bound to our socket gets to answer a ping.
+const SOCKET_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lines of agent stderr kept for the message shown when the agent dies.
+const STDERR_TAIL_LINES: usize = 3;
+
+/// Answers each agent process keeps for prompts repeating an idempotency key.
+const IDEMPOTENCY_CACHE_ENTRIES: usize = 64;
+
+/// How long agents get to exit on SIGTERM during a forced shutdown before being killed.
+const FORCE_KILL_GRACE: Duration = Duration::from_millis(500);
+
+/// How long a forced shutdown waits for the daemon to wind down before exiting regardless.
+const FORCE_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Minimum spacing between `acp_state` updates; changes in between are coalesced.
+const KAK_STATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Spacing of the progress messages shown while a `progress` prompt runs.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum spacing between the plan boxes of a `plan` prompt; revisions in between are
+/// coalesced.
+const PLAN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Minimum spacing of the answer updates shown while a `stream` prompt runs, unless
+/// `STREAM_BYTES` more of the answer arrived in between.
+const STREAM_INTERVAL: Duration = Duration::from_millis(150);
+
+const STREAM_BYTES: usize = 4096;
+
+/// How often a `stream` prompt's answer is checked for news.
+const STREAM_POLL: Duration = Duration::from_millis(25);
+
+/// Finished prompts whose results `attach` can still return.
+const FINISHED_PROMPT_LOGS: usize = 32;
+
+/// Most bytes one `fs/read_text_file` response carries when `--max-read-bytes` is not given.
+pub const DEFAULT_MAX_READ_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long `--replace` waits for the previous daemon to shut down.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// Serves the agents `options` describe on the daemon socket until the daemon is shut down.
+///
+/// Reloading applies the config file to `options` again.
 pub async fn run(options: DaemonOptions) -> Result<()> {
-    let socket_path =
-        kakoune::resolve_socket_path(options.socket.clone(), options.session.as_deref())?;
-    let agent_command = options.agent.clone();
-    let cwd = options.cwd.clone();
+    run_with_command_line(options.clone(), options).await
+}
+
+/// Runs the daemon with `options`, the command line with the config file applied.
+///
+/// `command_line` is the daemon's own command line; reloading applies the re-read config file
+/// to it again so flags keep winning over the file.
+pub(crate) async fn run_with_command_line(
+    options: DaemonOptions,
+    command_line: DaemonOptions,
+) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    // Progress, plans and streamed answers are sent from many tasks at once.
+    kakoune::use_sinks();
 
-    let cleanup_path = socket_path.clone();
+    // Dropped once the daemon has returned, which calls off a forced shutdown's watchdog.
+    let (returned, watchdog) = std::sync::mpsc::channel();
     let local_set = tokio::task::LocalSet::new();
     let result = local_set
-        .run_until(async move { run_inner(socket_path, cwd, agent_command).await })
+        .run_until(async move { run_inner(socket_path, options, command_line, watchdog).await })
         .await;
-
-    if cleanup_path.exists() {
-        let _ = tokio::fs::remove_file(&cleanup_path).await;
-    }
-
+    drop(returned);
     result
 }
 
 async fn run_inner(
     socket_path: PathBuf,
-    cwd: Option<PathBuf>,
-    agent_command: Vec<OsString>,
+    options: DaemonOptions,
+    command_line: DaemonOptions,
+    watchdog: std::sync::mpsc::Receiver<()>,
 ) -> Result<()> {
-    if agent_command.is_empty() {
-        anyhow::bail!("no agent program provided");
+    let specs = agent_specs(&options)?;
+    if options.verify {
+        return verify_agents(specs, options.notification_buffer.max(1)).await;
     }
 
     if socket_path.exists() {
-        tokio::fs::remove_file(&socket_path)
-            .await
-            .with_context(|| {
-                format!(
-                    "failed to remove existing socket at {}",
-                    socket_path.display()
-                )
-            })?;
-    }
-
-    let mut command = Command::new(&agent_command[0]);
-    command.args(agent_command.iter().skip(1));
-    if let Some(dir) = &cwd {
-        command.current_dir(dir);
-    }
-    command
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::inherit());
-
-    let mut child = command
-        .spawn()
-        .with_context(|| format!("failed to launch agent {:?}", agent_command))?;
-
-    let outgoing = child
-        .stdin
-        .take()
-        .context("failed to open agent stdin")?
-        .compat_write();
-    let incoming = child
-        .stdout
-        .take()
-        .context("failed to open agent stdout")?
-        .compat();
-
-    let (session_update_tx, _) = broadcast::channel(512);
-    let client = KakouneClient::new(session_update_tx.clone());
-
-    let (connection, io_task) = acp::ClientSideConnection::new(client, outgoing, incoming, |fut| {
-        tokio::task::spawn_local(fut);
-    });
-    let connection = Arc::new(connection);
-
-    let shutdown_notify = Arc::new(Notify::new());
-    {
-        let shutdown = shutdown_notify.clone();
-        tokio::task::spawn_local(async move {
-            if let Err(err) = io_task.await {
-                tracing::error!(?err, "agent IO loop terminated");
-            }
-            shutdown.notify_waiters();
-        });
+        claim_socket(&socket_path, options.replace).await?;
     }
 
-    connection
-        .initialize(acp::InitializeRequest {
-            protocol_version: acp::V1,
-            client_capabilities: acp::ClientCapabilities::default(),
-            meta: None,
-        })
-        .await?;
-
-    let cwd = if let Some(cwd) = cwd {
-        cwd
-    } else {
-        std::env::current_dir()?
+    let stats = Arc::new(DaemonStats::new());
+    let router = Arc::new(NotificationRouter::new(options.notification_buffer.max(1)));
+    let shutdown = CancellationToken::new();
+    let permissions = Arc::new(PermissionBroker::new(
+        options.session.clone(),
+        socket_path.clone(),
+        Duration::from_secs(options.permission_timeout),
+        options.permission_default,
+        options.permission_summary_chars,
+        options.permission_policy.clone(),
+        if options.persist_permissions {
+            let file =
+                kakoune::resolve_state_dir(options.session.as_deref())?.join("permissions.json");
+            tracing::info!("persisting permission rules to {}", file.display());
+            RuleStore::load(file)?
+        } else {
+            RuleStore::in_memory()
+        },
+    ));
+    let write_locks = Arc::new(PathLocks::default());
+    let audit = match &options.audit_log {
+        Some(path) => {
+            tracing::info!("auditing agent operations to {}", path.display());
+            Some(Arc::new(AuditLog::open(path.clone())?))
+        }
+        None => None,
     };
-
-    let session_response = connection
-        .new_session(acp::NewSessionRequest {
-            cwd,
-            mcp_servers: Vec::new(),
-            meta: None,
-        })
+    let mut agents = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let spec = Arc::new(spec);
+        let session = spawn_agent(
+            spec.clone(),
+            &router,
+            &stats,
+            &permissions,
+            &write_locks,
+            audit.as_ref(),
+        )
         .await?;
+        agents.push(Arc::new(AgentSlot {
+            name: spec.name.clone(),
+            spec: std::sync::Mutex::new(spec),
+            session: std::sync::Mutex::new(Arc::new(session)),
+            restart_lock: Mutex::new(()),
+            prompt_lock: Mutex::new(()),
+            current_prompt: std::sync::Mutex::new(None),
+            last_exit: std::sync::Mutex::new(None),
+        }));
+    }
 
-    let session_id = session_response.session_id.clone();
     let status = ipc::DaemonStatus {
-        session_id: Some(session_id.to_string()),
+        session_id: None,
+        session_state: ipc::SessionState::NotStarted,
         socket_path: socket_path.clone(),
-        agent_command: agent_command
-            .iter()
-            .map(|arg| arg.to_string_lossy().into_owned())
-            .collect(),
-        agent_pid: child.id(),
+        socket_scope: options
+            .socket
+            .is_none()
+            .then(|| options.socket_scope.as_str().to_string()),
+        socket_root: match options.socket_scope {
+            SocketScope::Cwd if options.socket.is_none() => Some(kakoune::project_root()?),
+            _ => None,
+        },
+        agent_command: Vec::new(),
+        agent_pid: None,
         running: true,
+        binary_version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        max_request_bytes: options.max_request_bytes as u64,
+        started_at: unix_timestamp(stats.started_at),
+        uptime_seconds: 0,
+        prompts_completed: 0,
+        prompts_failed: 0,
+        notifications_received: 0,
+        last_prompt_at: None,
+        current_prompt: None,
+        draining: false,
+        busy: false,
+        last_exit: None,
+        last_reload_at: None,
+        reload_applied: Vec::new(),
+        reload_skipped: Vec::new(),
+        metrics: ipc::Metrics::default(),
+        agents: Vec::new(),
+        permission_policy: options.permission_policy.to_string(),
+        audit_log: None,
     };
     let status = Arc::new(Mutex::new(status));
 
+    let redactor = Redactor::new(&options.redact)?;
+    let store = if options.persist_transcripts {
+        let directory = kakoune::resolve_state_dir(options.session.as_deref())?;
+        tracing::info!("persisting transcripts to {}", directory.display());
+        Some(TranscriptStore::new(directory, options.max_transcripts))
+    } else {
+        None
+    };
+
     let state = Arc::new(InnerState {
-        connection: connection.clone(),
-        session_id: session_id.clone(),
-        updates: session_update_tx,
-        shutdown: shutdown_notify.clone(),
+        agents,
+        router,
+        shutdown: shutdown.clone(),
+        draining: AtomicBool::new(false),
+        active_prompts: AtomicUsize::new(0),
+        prompts_idle: Notify::new(),
+        drain_timeout: Duration::from_secs(options.drain_timeout),
         status: status.clone(),
+        stats,
+        store,
+        redactor,
+        max_request_bytes: options.max_request_bytes,
+        max_inline_result_bytes: options.max_inline_result_bytes,
+        kak_session: options
+            .session
+            .clone()
+            .filter(|_| !options.no_kak_notifications),
+        settings: std::sync::Mutex::new(options.clone()),
+        command_line,
+        kak_state: watch::Sender::new(KakState::Idle),
+        socket_path: socket_path.clone(),
+        socket_inode: AtomicU64::new(0),
+        watchdog: std::sync::Mutex::new(Some(watchdog)),
+        reload_lock: Mutex::new(()),
+        prompt_logs: std::sync::Mutex::new(BTreeMap::new()),
+        permissions,
+        write_locks,
+        audit,
+    });
+
+    for slot in &state.agents {
+        tokio::task::spawn_local(supervise_agent(state.clone(), slot.clone()));
+    }
+    let kak_state_publisher = state.kak_session.clone().map(|session| {
+        tokio::task::spawn_local(publish_kak_state(
+            session,
+            state.kak_state.subscribe(),
+            shutdown.clone(),
+        ))
     });
+    tokio::task::spawn_local(reload_on_hangup(state.clone()));
+    if options.follow_kak_session {
+        match options.session.clone() {
+            Some(session) => {
+                let interval = Duration::from_millis(options.kak_poll_interval.max(1));
+                tokio::task::spawn_local(follow_kak_session(state.clone(), session, interval));
+            }
+            None => tracing::warn!("--follow-kak-session needs --session; not following"),
+        }
+    }
 
     let listener = UnixListener::bind(&socket_path)
         .with_context(|| format!("failed to bind socket at {}", socket_path.display()))?;
+    let bound = BoundSocket::new(&socket_path)?;
+    state.socket_inode.store(bound.inode, Ordering::SeqCst);
     tracing::info!("daemon listening on {}", socket_path.display());
 
     loop {
         tokio::select! {
-            _ = shutdown_notify.notified() => {
+            _ = shutdown.cancelled() => {
                 tracing::info!("shutdown requested");
                 break;
             }
@@ -173,169 +328,4285 @@ async fn run_inner(
         }
     }
 
+    shutdown.cancel();
     {
         let mut status = status.lock().await;
         status.running = false;
     }
 
-    if let Err(err) = child.start_kill() {
-        tracing::debug!(?err, "failed to signal agent for shutdown");
+    for slot in &state.agents {
+        slot.session().stop().await;
+    }
+
+    if let (Some(publisher), Some(session)) = (kak_state_publisher, state.kak_session.clone()) {
+        // Let an update already on its way land first so `stopped` is the last word.
+        let _ = publisher.await;
+        send_kak_state(session, KakState::Stopped).await;
     }
-    let _ = child.wait().await;
 
     drop(listener);
     Ok(())
 }
 
-async fn handle_connection(stream: UnixStream, state: Arc<InnerState>) -> Result<()> {
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-    let read = reader.read_line(&mut line).await?;
-    if read == 0 {
-        return Ok(());
+/// Daemon state mirrored into the `acp_state` option of the `--session` Kakoune session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KakState {
+    Idle,
+    Prompting,
+    Error,
+    Stopped,
+}
+
+impl KakState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Prompting => "prompting",
+            Self::Error => "error",
+            Self::Stopped => "stopped",
+        }
     }
-    let line = line.trim_end();
-    let request: DaemonRequest =
-        serde_json::from_str(line).with_context(|| format!("failed to parse request: {line}"))?;
+}
 
-    let response = match request {
-        DaemonRequest::Prompt(payload) => match state.run_prompt(payload).await {
-            Ok(result) => DaemonResponse::Prompt { result },
-            Err(error) => {
-                tracing::error!(?error, "prompt handling failed");
-                DaemonResponse::Error {
-                    message: error.to_string(),
+/// Pushes `acp_state` changes to Kakoune, at most one per `KAK_STATE_INTERVAL`.
+async fn publish_kak_state(
+    session: String,
+    mut states: watch::Receiver<KakState>,
+    shutdown: CancellationToken,
+) {
+    states.mark_changed();
+    loop {
+        tokio::select! {
+            changed = states.changed() => {
+                if changed.is_err() {
+                    return;
                 }
             }
-        },
-        DaemonRequest::Status => {
-            let status = { state.status.lock().await.clone() };
-            DaemonResponse::Status { status }
+            _ = shutdown.cancelled() => return,
         }
-        DaemonRequest::Shutdown => {
-            {
-                let mut status = state.status.lock().await;
-                status.running = false;
+        let state = *states.borrow_and_update();
+        send_kak_state(session.clone(), state).await;
+        tokio::select! {
+            _ = tokio::time::sleep(KAK_STATE_INTERVAL) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Shows how the prompt behind `log` is going in `client`'s status line every
+/// `PROGRESS_INTERVAL` until it ends, then clears the message.
+async fn report_progress(
+    mut log: watch::Receiver<PromptLog>,
+    session: String,
+    client: Option<String>,
+) {
+    let started = Instant::now();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(PROGRESS_INTERVAL) => {}
+            _ = log.wait_for(|log| log.outcome.is_some()) => break,
+        }
+        let status = progress_status(&log.borrow().events, started.elapsed());
+        send_progress(&session, client.as_deref(), Some(&status)).await;
+    }
+    send_progress(&session, client.as_deref(), None).await;
+}
+
+/// `ACP: 12s, 3 tool calls`, followed by the plan step in progress when there is one.
+fn progress_status(events: &[TranscriptEntry], elapsed: Duration) -> String {
+    let tool_calls = events
+        .iter()
+        .filter(|entry| matches!(entry.event, TranscriptEvent::ToolCall { .. }))
+        .count();
+    let mut status = format!(
+        "ACP: {}s, {tool_calls} tool call{}",
+        elapsed.as_secs(),
+        if tool_calls == 1 { "" } else { "s" }
+    );
+    let plan = transcript::current_plan(events).unwrap_or_default();
+    let step = plan.iter().rfind(|entry| entry.status == "InProgress");
+    if let Some(step) = step {
+        status.push_str(&format!(", {}", step.content));
+    }
+    status
+}
+
+/// Shows the latest plan of the prompt behind `log` in `client` whenever the agent revises
+/// it, at most every `PLAN_INTERVAL`, then how many of its steps were completed once the
+/// prompt ends.
+async fn report_plan(mut log: watch::Receiver<PromptLog>, session: String, client: Option<String>) {
+    let mut shown = None;
+    while log.changed().await.is_ok() {
+        let command = {
+            let log = log.borrow();
+            if log.outcome.is_some() {
+                break;
             }
-            state.shutdown.notify_waiters();
-            DaemonResponse::Ok
+            transcript::current_plan(&log.events)
+                .map(|entries| kakoune::format_plan_command(client.as_deref(), &entries, None))
+        };
+        if command.is_some() && command != shown {
+            send_plan(&session, command.as_deref().unwrap_or_default()).await;
+            shown = command;
+            tokio::time::sleep(PLAN_INTERVAL).await;
+        }
+    }
+    let command = {
+        let log = log.borrow();
+        transcript::current_plan(&log.events).map(|entries| {
+            let completed = PlanProgress::of(&entries).counts.completed;
+            let footer = match &log.outcome {
+                Some(Ok(result)) => format!(
+                    "{completed} of {} steps completed ({:?})",
+                    entries.len(),
+                    result.stop_reason
+                ),
+                _ => format!("{completed} of {} steps completed (failed)", entries.len()),
+            };
+            kakoune::format_plan_command(client.as_deref(), &entries, Some(&footer))
+        })
+    };
+    if let Some(command) = command {
+        send_plan(&session, &command).await;
+    }
+}
+
+/// Shows the answer of the prompt behind `log` in `client` as the agent streams it: at most
+/// every `STREAM_INTERVAL` unless `STREAM_BYTES` more arrived sooner, and once more with the
+/// whole answer when the prompt ends.
+async fn report_stream(
+    log: watch::Receiver<PromptLog>,
+    display: StreamDisplay,
+    client: Option<String>,
+) {
+    let mut shown = 0;
+    let mut shown_at = Instant::now();
+    loop {
+        tokio::time::sleep(STREAM_POLL).await;
+        if log.has_changed().is_err() {
+            return;
+        }
+        let (bytes, done) = {
+            let log = log.borrow();
+            (answer_bytes(&log.events), log.outcome.is_some())
+        };
+        if done {
+            break;
+        }
+        let due = bytes >= shown + STREAM_BYTES
+            || (bytes != shown && shown_at.elapsed() >= STREAM_INTERVAL);
+        if due {
+            let (answer, _) = transcript::answer(&log.borrow().events);
+            send_stream(&display, client.as_deref(), &answer).await;
+            (shown, shown_at) = (bytes, Instant::now());
+        }
+    }
+    let (answer, bytes) = {
+        let log = log.borrow();
+        (transcript::answer(&log.events).0, answer_bytes(&log.events))
+    };
+    if bytes != shown {
+        send_stream(&display, client.as_deref(), &answer).await;
+    }
+}
+
+/// Length of the agent's messages in `events`, without joining them.
+fn answer_bytes(events: &[TranscriptEntry]) -> usize {
+    events
+        .iter()
+        .map(|entry| match &entry.event {
+            TranscriptEvent::AgentMessage { text } => text.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+async fn send_stream(display: &StreamDisplay, client: Option<&str>, answer: &str) {
+    let command = match &display.buffer {
+        Some(buffer) => kakoune::format_buffer_command(client, buffer, answer),
+        None => kakoune::format_info_command(client, &display.title, answer, None),
+    };
+    let session = display.session.clone();
+    let sent = tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command)).await;
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::debug!(?err, "failed to show the streamed answer"),
+        Err(err) => tracing::debug!(?err, "failed to show the streamed answer"),
+    }
+}
+
+async fn send_plan(session: &str, command: &str) {
+    let (session, command) = (session.to_string(), command.to_string());
+    let sent = tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command)).await;
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::debug!(?err, "failed to show the prompt's plan"),
+        Err(err) => tracing::debug!(?err, "failed to show the prompt's plan"),
+    }
+}
+
+async fn send_progress(session: &str, client: Option<&str>, status: Option<&str>) {
+    let command = kakoune::format_progress_command(client, status);
+    let session = session.to_string();
+    let sent = tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command)).await;
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::debug!(?err, "failed to show prompt progress"),
+        Err(err) => tracing::debug!(?err, "failed to show prompt progress"),
+    }
+}
+
+async fn send_kak_state(session: String, state: KakState) {
+    let command = kakoune::format_state_command(state.as_str());
+    let sent = tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command)).await;
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::debug!(?err, state = state.as_str(), "failed to update acp_state"),
+        Err(err) => tracing::debug!(?err, state = state.as_str(), "failed to update acp_state"),
+    }
+}
+
+/// Makes an existing socket path available for binding without hijacking a live daemon.
+///
+/// A socket that refuses connections was left behind by a crashed daemon and is removed.
+/// If a daemon answers on it, binding is refused unless `replace` is set, in which case that
+/// daemon is shut down first.
+async fn claim_socket(socket_path: &Path, replace: bool) -> Result<()> {
+    let mut connection = match ipc_client::Connection::connect(socket_path).await {
+        Ok(connection) => connection,
+        Err(err)
+            if err
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::ConnectionRefused) =>
+        {
+            tracing::info!("removing stale socket at {}", socket_path.display());
+            return remove_socket(socket_path).await;
         }
+        Err(err) => return Err(err),
+    };
+
+    let probe = tokio::time::timeout(
+        SOCKET_PROBE_TIMEOUT,
+        connection.request(&DaemonRequest::Ping),
+    )
+    .await;
+    let old_pid = match probe {
+        Ok(Ok(DaemonResponse::Pong { pid, .. })) => pid,
+        _ => None,
     };
+    if !replace {
+        let pid = old_pid.map_or_else(|| "unknown".to_string(), |pid| pid.to_string());
+        anyhow::bail!(
+            "daemon already running on {} (pid {pid}); pass --replace to take over",
+            socket_path.display()
+        );
+    }
 
-    let payload = serde_json::to_string(&response)?;
-    writer.write_all(payload.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
-    writer.flush().await?;
+    tracing::info!(
+        old_pid,
+        new_pid = std::process::id(),
+        "replacing the daemon on {}",
+        socket_path.display()
+    );
+    let shutdown = connection.request(&DaemonRequest::Shutdown { force: false });
+    if let Err(err) = tokio::time::timeout(REPLACE_TIMEOUT, shutdown).await {
+        tracing::warn!(?err, "running daemon did not acknowledge the shutdown");
+    }
+    // The old daemon unlinks its socket on the way out; give it a chance to do so.
+    let deadline = Instant::now() + REPLACE_TIMEOUT;
+    while socket_path.exists() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    if socket_path.exists() {
+        remove_socket(socket_path).await?;
+    }
     Ok(())
 }
 
-struct InnerState {
-    connection: Arc<acp::ClientSideConnection>,
-    session_id: acp::SessionId,
-    updates: broadcast::Sender<acp::SessionNotification>,
-    shutdown: Arc<Notify>,
-    status: Arc<Mutex<ipc::DaemonStatus>>,
+async fn remove_socket(socket_path: &Path) -> Result<()> {
+    tokio::fs::remove_file(socket_path).await.with_context(|| {
+        format!(
+            "failed to remove existing socket at {}",
+            socket_path.display()
+        )
+    })
 }
 
-impl InnerState {
-    async fn run_prompt(&self, payload: PromptPayload) -> Result<PromptResultPayload> {
-        let PromptPayload { prompt, context } = payload;
-        let mut collector = TranscriptCollector::new();
-        collector.push_user_prompt(prompt.clone());
+/// Unlinks the daemon's socket on drop, unless another daemon has since replaced it.
+struct BoundSocket {
+    path: PathBuf,
+    inode: u64,
+}
 
-        let mut prompt_blocks = Vec::new();
-        prompt_blocks.push(acp::ContentBlock::from(prompt.clone()));
-        for snippet in &context {
-            prompt_blocks.push(acp::ContentBlock::from(snippet.text.clone()));
+impl BoundSocket {
+    fn new(path: &Path) -> Result<Self> {
+        let inode = std::fs::metadata(path)
+            .with_context(|| format!("failed to stat socket at {}", path.display()))?
+            .ino();
+        Ok(Self {
+            path: path.to_path_buf(),
+            inode,
+        })
+    }
+}
+
+impl Drop for BoundSocket {
+    fn drop(&mut self) {
+        remove_socket_if_ours(&self.path, self.inode);
+    }
+}
+
+fn remove_socket_if_ours(path: &Path, inode: u64) {
+    let ours = std::fs::metadata(path).is_ok_and(|metadata| metadata.ino() == inode);
+    if ours {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Sends `signal` to every process in the agent's group, returning whether any received it.
+fn signal_process_group(pid: u32, signal: libc::c_int) -> bool {
+    // Agents lead their own process group (see `spawn_agent`), so its id is the agent's pid.
+    unsafe { libc::kill(-(pid as libc::pid_t), signal) == 0 }
+}
+
+/// Splits the daemon's trailing arguments into named agent commands.
+///
+/// The first command is named by `--agent-name` (before the `--`); each further agent is
+/// introduced by `--agent-name NAME --`. An `--agent-name` not followed by a name and `--`
+/// is one of the agent's own arguments.
+fn parse_agent_commands(
+    first_name: &str,
+    args: &[OsString],
+) -> Result<Vec<(String, Vec<OsString>)>> {
+    let mut agents = vec![(first_name.to_string(), Vec::new())];
+    let mut rest = args;
+    while let Some((arg, after)) = rest.split_first() {
+        if let [name, separator, next @ ..] = after
+            && arg == "--agent-name"
+            && separator == "--"
+        {
+            let name = name.to_str().context("agent names must be UTF-8")?;
+            agents.push((name.to_string(), Vec::new()));
+            rest = next;
+            continue;
+        }
+        agents.last_mut().unwrap().1.push(arg.clone());
+        rest = after;
+    }
+    for (index, (name, command)) in agents.iter().enumerate() {
+        if command.is_empty() {
+            anyhow::bail!("agent {name:?} has no command");
         }
+        if agents[..index].iter().any(|(other, _)| other == name) {
+            anyhow::bail!("agent name {name:?} is used more than once");
+        }
+    }
+    Ok(agents)
+}
 
-        let mut updates = self.updates.subscribe();
-        let mut prompt_future = Box::pin(self.connection.prompt(acp::PromptRequest {
-            session_id: self.session_id.clone(),
-            prompt: prompt_blocks,
-            meta: Some(json!({
-                "source": "kakoune",
-            })),
-        }));
+/// Launch specs for every agent named in `options`.
+fn agent_specs(options: &DaemonOptions) -> Result<Vec<AgentSpec>> {
+    if options.agent.is_empty() {
+        anyhow::bail!(
+            "no agent program provided; pass one after `--` or set `agent` in the [daemon] \
+             section of the config file"
+        );
+    }
+    let cwd = match &options.cwd {
+        Some(cwd) => cwd.clone(),
+        None => std::env::current_dir()?,
+    };
+    // Canonical, so that `prompt --cwd` pointing at the same directory reuses the session.
+    let cwd = std::fs::canonicalize(&cwd)
+        .with_context(|| format!("failed to resolve working directory {}", cwd.display()))?;
+    let sandbox = Arc::new(Sandbox::new(
+        options.workspace_root.as_deref(),
+        &options.allow_paths,
+    )?);
+    let state_dir = kakoune::resolve_state_dir(options.session.as_deref()).ok();
+    let diff_format = DiffFormat {
+        context: options.diff_context,
+        max_lines: options.max_diff_lines,
+        full_diff_dir: state_dir.as_ref().map(|directory| directory.join("diffs")),
+    };
+    let media = state_dir
+        .filter(|_| !options.no_save_media)
+        .map(|directory| {
+            Arc::new(MediaStore::new(
+                directory.join("images"),
+                options.max_media_bytes,
+            ))
+        });
+    let ext_handler = options.ext_handler.clone().map(|command| {
+        Arc::new(ExtHandler::new(
+            command,
+            Duration::from_secs(options.ext_timeout),
+        ))
+    });
+    let mcp_servers = options
+        .mcp_servers
+        .iter()
+        .map(McpServerConfig::to_acp)
+        .collect::<Result<Vec<_>>>()?;
+    let specs = parse_agent_commands(&options.agent_name, &options.agent)?
+        .into_iter()
+        .map(|(name, command)| AgentSpec {
+            name,
+            command,
+            cwd: cwd.clone(),
+            env: options.env.clone(),
+            mcp_servers: mcp_servers.clone(),
+            lazy_session: options.lazy_session,
+            max_cwd_sessions: options.max_cwd_sessions,
+            idempotency_ttl: Duration::from_secs(options.idempotency_ttl),
+            allow_fs_read: options.allow_fs_read,
+            max_read_bytes: options.max_read_bytes,
+            allow_terminal: options.allow_terminal,
+            sandbox: sandbox.clone(),
+            diff_format: diff_format.clone(),
+            media: media.clone(),
+            ext_handler: ext_handler.clone(),
+            ext_ack: options.ext_ack,
+            fs_write: options.allow_fs_write.unwrap_or(FsWritePolicy::Never),
+        })
+        .collect();
+    Ok(specs)
+}
 
-        loop {
-            tokio::select! {
-                update = updates.recv() => {
-                    match update {
-                        Ok(notification) => {
-                            if notification.session_id == self.session_id {
-                                collector.record_notification(notification);
-                            }
-                        }
-                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                            tracing::warn!(skipped, "dropped {skipped} session notifications");
-                        }
-                        Err(broadcast::error::RecvError::Closed) => {
-                            anyhow::bail!("session notification channel closed");
-                        }
-                    }
-                }
-                response = &mut prompt_future => {
-                    let response = response?;
-                    loop {
-                        match updates.try_recv() {
-                            Ok(notification) => {
-                                if notification.session_id == self.session_id {
-                                    collector.record_notification(notification);
-                                }
-                            }
-                            Err(broadcast::error::TryRecvError::Empty) => {
-                                break;
-                            }
-                            Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
-                                tracing::warn!(skipped, "dropped {skipped} pending session notifications");
-                                continue;
-                            }
-                            Err(broadcast::error::TryRecvError::Closed) => {
-                                tracing::warn!("session notification channel closed while draining");
-                                break;
-                            }
-                        }
-                    }
-                    return Ok(PromptResultPayload {
-                        stop_reason: response.stop_reason,
-                        user_prompt: prompt,
-                        context,
-                        transcript: collector.finish(),
-                    });
+/// `daemon --verify` exit codes, one per startup step that can fail.
+const VERIFY_SPAWN_FAILED: i32 = 2;
+const VERIFY_INITIALIZE_FAILED: i32 = 3;
+const VERIFY_SESSION_FAILED: i32 = 4;
+
+/// Brings every agent up far enough to open a session, printing a JSON line per agent, then
+/// stops it again.
+///
+/// Fails with [`VerifyFailed`] at the first agent that does not come up.
+async fn verify_agents(specs: Vec<AgentSpec>, notification_buffer: usize) -> Result<()> {
+    let stats = Arc::new(DaemonStats::new());
+    let router = Arc::new(NotificationRouter::new(notification_buffer));
+    let permissions = Arc::new(PermissionBroker::new(
+        None,
+        PathBuf::new(),
+        Duration::ZERO,
+        PermissionDefault::Cancel,
+        0,
+        PermissionPolicy::default(),
+        RuleStore::in_memory(),
+    ));
+    let write_locks = Arc::new(PathLocks::default());
+    for spec in specs {
+        let spec = Arc::new(spec);
+        let started = Instant::now();
+        let spawned = spawn_agent(
+            spec.clone(),
+            &router,
+            &stats,
+            &permissions,
+            &write_locks,
+            None,
+        );
+        let outcome = match spawned.await {
+            Ok(session) => match session.session_id().await {
+                Ok(session_id) => Ok((session_id.clone(), session)),
+                Err(err) => {
+                    let err = anyhow::Error::from(err)
+                        .context(StartupFailure::NewSession(spec.name.clone()));
+                    session.stop().await;
+                    Err(err)
                 }
+            },
+            Err(err) => Err(err),
+        };
+        let (session_id, session) = match outcome {
+            Ok(verified) => verified,
+            Err(err) => {
+                let code = match err.downcast_ref::<StartupFailure>() {
+                    Some(StartupFailure::Spawn(_)) => VERIFY_SPAWN_FAILED,
+                    Some(StartupFailure::Initialize(_)) => VERIFY_INITIALIZE_FAILED,
+                    Some(StartupFailure::NewSession(_)) => VERIFY_SESSION_FAILED,
+                    None => 1,
+                };
+                return Err(VerifyFailed { code, error: err }.into());
             }
-        }
+        };
+        let startup_ms = started.elapsed().as_millis() as u64;
+        session.stop().await;
+        let summary = json!({
+            "agent": spec.name,
+            "agent_command": spec.command.iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>(),
+            "protocol_version": session.protocol_version,
+            "capabilities": session.capabilities,
+            "session_id": session_id.0,
+            "startup_ms": startup_ms,
+        });
+        println!("{summary}");
     }
+    Ok(())
 }
 
-struct KakouneClient {
-    updates: broadcast::Sender<acp::SessionNotification>,
+/// `daemon --verify` could not bring an agent up; the binary exits with `code`.
+#[derive(Debug)]
+pub struct VerifyFailed {
+    /// 2 when the agent could not be launched, 3 when it failed to initialize and 4 when it
+    /// could not open a session.
+    pub code: i32,
+    error: anyhow::Error,
 }
 
-impl KakouneClient {
-    fn new(updates: broadcast::Sender<acp::SessionNotification>) -> Self {
-        Self { updates }
+impl std::fmt::Display for VerifyFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.error)
     }
 }
 
-#[async_trait::async_trait(?Send)]
-impl acp::Client for KakouneClient {
-    async fn request_permission(
-        &self,
-        _args: acp::RequestPermissionRequest,
-    ) -> Result<acp::RequestPermissionResponse, acp::Error> {
-        Ok(acp::RequestPermissionResponse {
-            outcome: acp::RequestPermissionOutcome::Cancelled,
-            meta: Some(json!({
-                "reason": "permission UI not implemented in kakoune-acp",
-            })),
-        })
+impl std::error::Error for VerifyFailed {}
+
+/// Context attached to agent startup errors, naming the step that failed.
+#[derive(Debug)]
+enum StartupFailure {
+    Spawn(Vec<OsString>),
+    Initialize(String),
+    NewSession(String),
+}
+
+impl std::fmt::Display for StartupFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Spawn(command) => write!(f, "failed to launch agent {command:?}"),
+            Self::Initialize(name) => write!(f, "agent {name:?} failed to initialize"),
+            Self::NewSession(name) => write!(f, "agent {name:?} failed to open a session"),
+        }
     }
+}
 
-    async fn session_notification(&self, args: acp::SessionNotification) -> Result<(), acp::Error> {
-        let _ = self.updates.send(args);
-        Ok(())
+/// Applies the restart policy whenever `slot`'s agent exits on its own.
+async fn supervise_agent(state: Arc<InnerState>, slot: Arc<AgentSlot>) {
+    loop {
+        let session = slot.session();
+        tokio::select! {
+            _ = session.exited.cancelled() => {}
+            _ = session.retired.cancelled() => {
+                // Replaced by `restart-agent` or a reload; watch the new process instead.
+                let _restarted = slot.restart_lock.lock().await;
+                continue;
+            }
+            _ = state.shutdown.cancelled() => return,
+        }
+        let restart = state.restart_policy();
+        let agent = &slot.name;
+        let status = session.exit_status().await;
+        if let Some(status) = status {
+            slot.record_exit(status);
+        }
+        state.set_kak_state(KakState::Error);
+        let report = session.exit_report(status).await;
+        tracing::warn!(agent, "agent exited: {report}");
+        // A signal or an unknown status counts as a failure too.
+        let failed = !status.is_some_and(|status| status.success());
+        let restarting = restart == RestartPolicy::OnFailure && failed;
+        if let Some(kak_session) = &state.kak_session {
+            let next = if restarting {
+                "restarting it"
+            } else {
+                "shutting the daemon down"
+            };
+            let command = kakoune::format_agent_exit_command(&format!(
+                "agent {agent} exited ({report})\n{next}"
+            ));
+            let kak_session = kak_session.clone();
+            match tokio::task::spawn_blocking(move || kakoune::send_to_kak(&kak_session, &command))
+                .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::warn!(?err, "failed to notify kakoune of the agent exit"),
+                Err(err) => tracing::warn!(?err, "failed to notify kakoune of the agent exit"),
+            }
+        }
+        if !restarting {
+            tracing::error!(agent, "agent exited; shutting down");
+            state.shutdown.cancel();
+            return;
+        }
+        tracing::warn!(agent, "agent exited unexpectedly; restarting it");
+        // Avoid a tight respawn loop when the agent dies right after starting.
+        tokio::time::sleep(RESTART_DELAY).await;
+        if let Err(err) = state.restart_agent(&slot).await {
+            tracing::error!(?err, agent, "giving up on the agent");
+            state.shutdown.cancel();
+            return;
+        }
+    }
+}
+
+/// Reloads the config file every time the daemon receives `SIGHUP`.
+async fn reload_on_hangup(state: Arc<InnerState>) {
+    let mut hangups = match tokio::signal::unix::signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::warn!(
+                ?err,
+                "failed to listen for SIGHUP; only `reload` reloads the config"
+            );
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        match state.reload().await {
+            Ok((applied, skipped)) => tracing::info!(?applied, ?skipped, "config reloaded"),
+            Err(err) => tracing::error!(?err, "config reload failed"),
+        }
+    }
+}
+
+/// Shuts the daemon down, draining prompts as a graceful `shutdown` would, once `session`
+/// neither accepts connections on its socket nor shows up in `kak -l`.
+///
+/// Failing to run `kak` is not taken as the session ending; the check simply tries again on
+/// the next tick.
+async fn follow_kak_session(state: Arc<InnerState>, session: String, interval: Duration) {
+    tracing::info!(session, "following kakoune session");
+    let mut kak_failing = false;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        let alive = {
+            let session = session.clone();
+            match tokio::task::spawn_blocking(move || kakoune::session_alive(&session)).await {
+                Ok(result) => result,
+                Err(err) => Err(std::io::Error::other(err)),
+            }
+        };
+        match alive {
+            Ok(alive) => {
+                kak_failing = false;
+                if !alive {
+                    tracing::info!(session, "kakoune session is gone; shutting down");
+                    break;
+                }
+            }
+            Err(err) => {
+                if !kak_failing {
+                    tracing::warn!(?err, "could not list kakoune sessions; will keep trying");
+                }
+                kak_failing = true;
+            }
+        }
+    }
+    state.drain().await;
+    state.status.lock().await.running = false;
+    state.shutdown.cancel();
+}
+
+/// How to launch an agent, kept so it can be respawned by `restart-agent`.
+struct AgentSpec {
+    name: String,
+    command: Vec<OsString>,
+    cwd: PathBuf,
+    env: Vec<(String, String)>,
+    mcp_servers: Vec<acp::McpServer>,
+    /// Defer `session/new` until the first prompt.
+    lazy_session: bool,
+    /// Cap on sessions opened for other directories by `prompt --cwd`.
+    max_cwd_sessions: usize,
+    /// How long answers stay available to prompts repeating their idempotency key.
+    idempotency_ttl: Duration,
+    /// Serve `fs/read_text_file` requests.
+    allow_fs_read: bool,
+    /// Most bytes returned by one read; longer reads are cut short.
+    max_read_bytes: usize,
+    /// Whether and how `fs/write_text_file` requests are served.
+    fs_write: FsWritePolicy,
+    /// Serve `terminal/*` requests.
+    allow_terminal: bool,
+    /// Limits which paths the file and terminal requests may touch.
+    sandbox: Arc<Sandbox>,
+    /// How diffs are written, and where those too long for a transcript are saved in full.
+    diff_format: DiffFormat,
+    /// Saves the images agents send.
+    media: Option<Arc<MediaStore>>,
+    /// Answers extension method calls.
+    ext_handler: Option<Arc<ExtHandler>>,
+    /// Answer extension method calls with `{}` when there is no handler.
+    ext_ack: bool,
+}
+
+impl AgentSpec {
+    /// What the agent is told in `initialize` that the daemon will serve.
+    ///
+    /// Permission requests are always answered, so they need no flag.
+    fn client_capabilities(&self) -> acp::ClientCapabilities {
+        acp::ClientCapabilities {
+            fs: acp::FileSystemCapability {
+                read_text_file: self.allow_fs_read,
+                write_text_file: self.fs_write != FsWritePolicy::Never,
+                meta: None,
+            },
+            terminal: self.allow_terminal,
+            meta: None,
+        }
+    }
+}
+
+/// One named agent: how to launch it and the session currently serving its prompts.
+struct AgentSlot {
+    name: String,
+    /// How the next process for this agent is launched; replaced by `reload`.
+    spec: std::sync::Mutex<Arc<AgentSpec>>,
+    session: std::sync::Mutex<Arc<AgentSession>>,
+    /// Serializes restarts of this agent.
+    restart_lock: Mutex<()>,
+    /// Serializes prompts so each session has at most one turn in flight.
+    prompt_lock: Mutex<()>,
+    /// Request id and start of the turn holding `prompt_lock`.
+    current_prompt: std::sync::Mutex<Option<RunningPrompt>>,
+    /// How the previous agent process ended.
+    last_exit: std::sync::Mutex<Option<ipc::AgentExit>>,
+}
+
+impl AgentSlot {
+    fn session(&self) -> Arc<AgentSession> {
+        self.session.lock().unwrap().clone()
+    }
+
+    fn spec(&self) -> Arc<AgentSpec> {
+        self.spec.lock().unwrap().clone()
+    }
+
+    fn record_exit(&self, status: ExitStatus) {
+        *self.last_exit.lock().unwrap() = Some(ipc::AgentExit {
+            code: status.code(),
+            signal: status.signal(),
+            exited_at: unix_timestamp(SystemTime::now()),
+        });
+    }
+
+    fn status(&self) -> ipc::AgentStatus {
+        let session = self.session();
+        ipc::AgentStatus {
+            name: self.name.clone(),
+            agent_command: session
+                .spec
+                .command
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            agent_pid: session.pid,
+            session_id: session.current_session_id().map(ToString::to_string),
+            session_state: match session.current_session_id() {
+                Some(_) => ipc::SessionState::Active,
+                None => ipc::SessionState::NotStarted,
+            },
+            cwd: session.spec.cwd.clone(),
+            sessions: session
+                .current_session_id()
+                .map(|session_id| (session.spec.cwd.clone(), session_id.to_string()))
+                .into_iter()
+                .chain(
+                    session
+                        .cwd_sessions
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|(cwd, session_id)| (cwd.clone(), session_id.to_string())),
+                )
+                .collect(),
+            capabilities: session.capabilities.clone(),
+            client_capabilities: session.spec.client_capabilities(),
+            current_prompt: self
+                .current_prompt
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(RunningPrompt::status),
+            last_exit: self.last_exit.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Returned by `run_prompt` when a `no_queue` prompt finds the agent mid-turn.
+#[derive(Debug)]
+struct AgentBusy {
+    agent: String,
+    active: Option<ipc::ActivePrompt>,
+}
+
+impl std::fmt::Display for AgentBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.active {
+            Some(active) => write!(
+                f,
+                "agent {} is busy ({}s)",
+                self.agent,
+                active.elapsed_ms / 1000
+            ),
+            None => write!(f, "agent {} is busy", self.agent),
+        }
+    }
+}
+
+impl std::error::Error for AgentBusy {}
+
+impl AgentBusy {
+    fn response(&self) -> DaemonResponse {
+        DaemonResponse::error_with_code(
+            "busy",
+            self.to_string(),
+            Some(json!({
+                "active_request_id": self.active.as_ref().map(|active| active.request_id),
+                "elapsed_ms": self.active.as_ref().map(|active| active.elapsed_ms),
+            })),
+        )
+    }
+}
+
+/// A prompt result kept for replay to a prompt repeating its idempotency key.
+struct CachedAnswer {
+    session_id: acp::SessionId,
+    key: String,
+    answered_at: Instant,
+    result: PromptResultPayload,
+}
+
+/// A running agent process and the ACP session opened on it.
+struct AgentSession {
+    /// How this process was launched.
+    spec: Arc<AgentSpec>,
+    connection: acp::ClientSideConnection,
+    /// Set by `session/new`, either right after the handshake or on the first prompt.
+    session_id: tokio::sync::OnceCell<acp::SessionId>,
+    /// Sessions opened by `prompt --cwd` for other directories, least recently used first.
+    cwd_sessions: std::sync::Mutex<Vec<(PathBuf, acp::SessionId)>>,
+    /// Session of the most recent prompt, which `cancel` interrupts.
+    last_session_id: std::sync::Mutex<Option<acp::SessionId>>,
+    /// Working directory of every session opened on this process, shared with its client.
+    session_cwds: SessionCwds,
+    /// Commands the agent started through its client.
+    terminals: Arc<Terminals>,
+    /// The modes of each session whose agent has modes, kept current after every turn.
+    session_modes: std::sync::Mutex<HashMap<acp::SessionId, acp::SessionModeState>>,
+    /// Recent answers to prompts that carried an idempotency key, oldest first.
+    answers: std::sync::Mutex<VecDeque<CachedAnswer>>,
+    /// Protocol version the agent agreed to in `initialize`.
+    protocol_version: acp::ProtocolVersion,
+    /// The `_meta` of the agent's `initialize` answer.
+    init_meta: Option<serde_json::Value>,
+    /// The `_meta` of the agent's answer to `session/new`, for the sessions that had one.
+    session_meta: std::sync::Mutex<HashMap<acp::SessionId, serde_json::Value>>,
+    capabilities: acp::AgentCapabilities,
+    pid: Option<u32>,
+    child: Mutex<Child>,
+    /// Cancelled when the daemon replaces or stops this agent on purpose.
+    retired: CancellationToken,
+    /// Cancelled when the agent's IO loop ends without the agent being retired.
+    exited: CancellationToken,
+    /// Last few lines the agent wrote to stderr.
+    stderr_tail: Arc<std::sync::Mutex<VecDeque<String>>>,
+    /// Cancelled once the agent's stderr reaches end of file.
+    stderr_closed: CancellationToken,
+}
+
+impl AgentSession {
+    /// Retires the agent and waits for its process to exit.
+    async fn stop(&self) -> Option<ExitStatus> {
+        self.retired.cancel();
+        let mut child = self.child.lock().await;
+        if let Err(err) = child.start_kill() {
+            tracing::debug!(?err, "failed to signal agent for shutdown");
+        }
+        if let Some(pid) = self.pid {
+            signal_process_group(pid, libc::SIGKILL);
+        }
+        child.wait().await.ok()
+    }
+
+    /// Retires the agent, asks its process group to terminate and kills it if it has not
+    /// exited by `deadline`.
+    async fn force_stop(&self, deadline: tokio::time::Instant) {
+        self.retired.cancel();
+        let Some(pid) = self.pid else {
+            self.stop().await;
+            return;
+        };
+        let mut child = self.child.lock().await;
+        signal_process_group(pid, libc::SIGTERM);
+        if tokio::time::timeout_at(deadline, child.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                agent = self.spec.name,
+                pid,
+                "agent ignored SIGTERM; killing it"
+            );
+        }
+        signal_process_group(pid, libc::SIGKILL);
+        let _ = child.wait().await;
+    }
+
+    /// Exit status of an agent whose connection has closed, giving the process a moment to
+    /// finish exiting.
+    async fn exit_status(&self) -> Option<ExitStatus> {
+        let mut child = self.child.lock().await;
+        tokio::time::timeout(Duration::from_secs(1), child.wait())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    fn current_session_id(&self) -> Option<&acp::SessionId> {
+        self.session_id.get()
+    }
+
+    /// Returns the ACP session, opening it first if that has not happened yet.
+    ///
+    /// Concurrent callers share a single `session/new` request.
+    async fn session_id(&self) -> Result<acp::SessionId, acp::Error> {
+        self.session_id
+            .get_or_try_init(|| self.open_session(&self.spec.cwd))
+            .await
+            .cloned()
+    }
+
+    /// Returns the session bound to `cwd`, opening one if needed.
+    ///
+    /// Only called with the slot's prompt lock held, so two prompts never race to open a
+    /// session for the same directory.
+    async fn session_for_cwd(&self, cwd: Option<&Path>) -> Result<acp::SessionId, acp::Error> {
+        let spec = &self.spec;
+        let Some(cwd) = cwd.filter(|cwd| *cwd != spec.cwd) else {
+            return self.session_id().await;
+        };
+        {
+            let mut sessions = self.cwd_sessions.lock().unwrap();
+            if let Some(index) = sessions.iter().position(|(known, _)| known == cwd) {
+                let entry = sessions.remove(index);
+                let session_id = entry.1.clone();
+                sessions.push(entry);
+                return Ok(session_id);
+            }
+        }
+        let session_id = self.open_session(cwd).await?;
+        let mut sessions = self.cwd_sessions.lock().unwrap();
+        sessions.push((cwd.to_path_buf(), session_id.clone()));
+        while sessions.len() > spec.max_cwd_sessions {
+            let (evicted, evicted_id) = sessions.remove(0);
+            tracing::info!(agent = spec.name, cwd = %evicted.display(), "dropping least recently used session");
+            self.forget_session(&evicted_id);
+        }
+        Ok(session_id)
+    }
+
+    /// The answer already given to `key` on `session_id`, if it has not expired.
+    fn cached_answer(&self, session_id: &acp::SessionId, key: &str) -> Option<PromptResultPayload> {
+        let mut answers = self.answers.lock().unwrap();
+        let ttl = self.spec.idempotency_ttl;
+        answers.retain(|answer| answer.answered_at.elapsed() < ttl);
+        answers
+            .iter()
+            .find(|answer| &answer.session_id == session_id && answer.key == key)
+            .map(|answer| answer.result.clone())
+    }
+
+    fn remember_answer(
+        &self,
+        session_id: acp::SessionId,
+        key: String,
+        result: &PromptResultPayload,
+    ) {
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() == IDEMPOTENCY_CACHE_ENTRIES {
+            answers.pop_front();
+        }
+        answers.push_back(CachedAnswer {
+            session_id,
+            key,
+            answered_at: Instant::now(),
+            result: result.clone(),
+        });
+    }
+
+    /// Forgets the session opened for `cwd`, returning whether there was one.
+    fn close_cwd_session(&self, cwd: &Path) -> bool {
+        let mut sessions = self.cwd_sessions.lock().unwrap();
+        let Some(index) = sessions.iter().position(|(known, _)| known == cwd) else {
+            return false;
+        };
+        let (_, session_id) = sessions.remove(index);
+        drop(sessions);
+        self.forget_session(&session_id);
+        true
+    }
+
+    /// Drops everything kept for a session no prompt will use again, killing the commands
+    /// the agent left running in it.
+    fn forget_session(&self, session_id: &acp::SessionId) {
+        self.session_cwds.lock().unwrap().remove(session_id);
+        self.session_meta.lock().unwrap().remove(session_id);
+        self.session_modes.lock().unwrap().remove(session_id);
+        let released = self.terminals.release_session(session_id);
+        if released > 0 {
+            tracing::info!(agent = self.spec.name, %session_id, released, "released the session's terminals");
+        }
+    }
+
+    async fn open_session(&self, cwd: &Path) -> Result<acp::SessionId, acp::Error> {
+        let spec = &self.spec;
+        let response = self
+            .connection
+            .new_session(acp::NewSessionRequest {
+                cwd: cwd.to_path_buf(),
+                mcp_servers: spec.mcp_servers.clone(),
+                meta: None,
+            })
+            .await?;
+        tracing::info!(
+            agent = spec.name,
+            cwd = %cwd.display(),
+            session_id = %response.session_id,
+            "opened session"
+        );
+        self.session_cwds
+            .lock()
+            .unwrap()
+            .insert(response.session_id.clone(), cwd.to_path_buf());
+        if let Some(meta) = response.meta {
+            self.session_meta
+                .lock()
+                .unwrap()
+                .insert(response.session_id.clone(), meta);
+        }
+        if let Some(modes) = response.modes {
+            self.session_modes
+                .lock()
+                .unwrap()
+                .insert(response.session_id.clone(), modes);
+        }
+        Ok(response.session_id)
+    }
+
+    /// The agent as it described itself in `initialize`, and in `session_meta` when given.
+    fn info(&self, session_meta: Option<&serde_json::Value>) -> ipc::AgentInfo {
+        let init_meta = self.init_meta.as_ref();
+        let field = |meta: Option<&serde_json::Value>, key: &str| {
+            meta?.get(key)?.as_str().map(str::to_string)
+        };
+        ipc::AgentInfo {
+            name: field(init_meta, "name").unwrap_or_else(|| self.spec.name.clone()),
+            version: field(init_meta, "version"),
+            model: field(session_meta, "model").or_else(|| field(init_meta, "model")),
+            protocol_version: self.protocol_version.clone(),
+        }
+    }
+
+    /// Describes how an agent that exited on its own went away: its exit status and the last
+    /// lines of its stderr.
+    async fn exit_report(&self, status: Option<ExitStatus>) -> String {
+        let mut report = describe_exit(status);
+        // Give the stderr reader a moment to pick up the agent's last words.
+        let _ =
+            tokio::time::timeout(Duration::from_millis(200), self.stderr_closed.cancelled()).await;
+        let tail = self.stderr_tail.lock().unwrap();
+        if !tail.is_empty() {
+            report.push_str("\nlast stderr:");
+            for line in tail.iter() {
+                report.push_str("\n  ");
+                report.push_str(line);
+            }
+        }
+        report
+    }
+}
+
+fn describe_exit(status: Option<ExitStatus>) -> String {
+    status.map_or_else(
+        || "exit status unknown".to_string(),
+        |status| status.to_string(),
+    )
+}
+
+/// Launches the agent and runs the ACP handshake.
+async fn spawn_agent(
+    spec: Arc<AgentSpec>,
+    router: &Arc<NotificationRouter>,
+    stats: &Arc<DaemonStats>,
+    permissions: &Arc<PermissionBroker>,
+    write_locks: &Arc<PathLocks>,
+    audit: Option<&Arc<AuditLog>>,
+) -> Result<AgentSession> {
+    let mut command = Command::new(&spec.command[0]);
+    command
+        .args(spec.command.iter().skip(1))
+        .current_dir(&spec.cwd)
+        .envs(spec.env.iter().map(|(key, value)| (key, value)))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        // Its own process group, so stopping the agent also takes down whatever it spawned.
+        .process_group(0)
+        .kill_on_drop(true);
+
+    let mut child = command
+        .spawn()
+        .with_context(|| StartupFailure::Spawn(spec.command.clone()))?;
+
+    let outgoing = child
+        .stdin
+        .take()
+        .context("failed to open agent stdin")?
+        .compat_write();
+    let incoming = child
+        .stdout
+        .take()
+        .context("failed to open agent stdout")?
+        .compat();
+    let stderr = child.stderr.take().context("failed to open agent stderr")?;
+
+    // Agent stderr still ends up on ours; the tail is kept for the exit notification.
+    let stderr_tail = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    let stderr_closed = CancellationToken::new();
+    {
+        let tail = stderr_tail.clone();
+        let closed = stderr_closed.clone();
+        tokio::task::spawn_local(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                eprintln!("{line}");
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            closed.cancel();
+        });
+    }
+
+    let session_cwds = SessionCwds::default();
+    let terminals = Arc::new(Terminals::default());
+    let client = KakouneClient {
+        agent: spec.name.clone(),
+        router: router.clone(),
+        stats: stats.clone(),
+        permissions: permissions.clone(),
+        write_locks: write_locks.clone(),
+        audit: audit.cloned(),
+        allow_fs_read: spec.allow_fs_read,
+        max_read_bytes: spec.max_read_bytes,
+        fs_write: spec.fs_write,
+        allow_terminal: spec.allow_terminal,
+        terminals: terminals.clone(),
+        sandbox: spec.sandbox.clone(),
+        diff_format: spec.diff_format.clone(),
+        ext_handler: spec.ext_handler.clone(),
+        ext_ack: spec.ext_ack,
+        cwd: spec.cwd.clone(),
+        session_cwds: session_cwds.clone(),
+    };
+    let client = ObservedClient(client);
+    let (connection, io_task) = acp::ClientSideConnection::new(client, outgoing, incoming, |fut| {
+        tokio::task::spawn_local(fut);
+    });
+
+    let retired = CancellationToken::new();
+    let exited = CancellationToken::new();
+    {
+        let retired = retired.clone();
+        let exited = exited.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(err) = io_task.await {
+                tracing::error!(?err, "agent IO loop terminated");
+            }
+            if !retired.is_cancelled() {
+                exited.cancel();
+            }
+        });
+    }
+
+    // The child is killed on drop; if the handshake fails, retire the agent first so that
+    // does not take the daemon down too.
+    let initialized = connection
+        .initialize(acp::InitializeRequest {
+            protocol_version: acp::V1,
+            client_capabilities: spec.client_capabilities(),
+            meta: None,
+        })
+        .await;
+    let initialized = match initialized {
+        Ok(initialized) => initialized,
+        Err(err) => {
+            retired.cancel();
+            return Err(
+                anyhow::Error::from(err).context(StartupFailure::Initialize(spec.name.clone()))
+            );
+        }
+    };
+    let lazy_session = spec.lazy_session;
+    let session = AgentSession {
+        spec,
+        connection,
+        session_id: tokio::sync::OnceCell::new(),
+        cwd_sessions: std::sync::Mutex::new(Vec::new()),
+        last_session_id: std::sync::Mutex::new(None),
+        session_cwds,
+        terminals,
+        session_modes: std::sync::Mutex::new(HashMap::new()),
+        answers: std::sync::Mutex::new(VecDeque::new()),
+        protocol_version: initialized.protocol_version,
+        init_meta: initialized.meta,
+        session_meta: std::sync::Mutex::new(HashMap::new()),
+        capabilities: initialized.agent_capabilities,
+        pid: child.id(),
+        child: Mutex::new(child),
+        retired,
+        exited,
+        stderr_tail,
+        stderr_closed,
+    };
+    if !lazy_session && let Err(err) = session.session_id().await {
+        session.retired.cancel();
+        let name = session.spec.name.clone();
+        return Err(anyhow::Error::from(err).context(StartupFailure::NewSession(name)));
+    }
+    Ok(session)
+}
+
+async fn handle_connection(stream: UnixStream, state: Arc<InnerState>) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let (responses_tx, responses_rx) = mpsc::unbounded_channel();
+    let writer_task =
+        tokio::task::spawn_local(write_responses(writer, responses_rx, state.clone()));
+    let closed = CancellationToken::new();
+
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    loop {
+        let line =
+            match read_bounded_line(&mut reader, &mut buffer, state.max_request_bytes).await? {
+                RequestLine::Complete(line) => line,
+                RequestLine::TooLarge { bytes } => {
+                    tracing::warn!(bytes, "rejected oversized request");
+                    let responder = Responder {
+                        id: None,
+                        tx: responses_tx.clone(),
+                    };
+                    responder.send(DaemonResponse::error_with_code(
+                        "request_too_large",
+                        format!(
+                            "request of {bytes} bytes exceeds the daemon limit of {} bytes",
+                            state.max_request_bytes
+                        ),
+                        Some(json!({
+                            "bytes": bytes,
+                            "limit": state.max_request_bytes,
+                        })),
+                    ));
+                    continue;
+                }
+                RequestLine::Eof => break,
+            };
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<RequestEnvelope>(line) {
+            Ok(envelope) if envelope.version > PROTOCOL_VERSION => {
+                let responder = Responder {
+                    id: envelope.id,
+                    tx: responses_tx.clone(),
+                };
+                responder.send(version_mismatch(envelope.version));
+            }
+            Ok(envelope) => {
+                let responder = Responder {
+                    id: envelope.id,
+                    tx: responses_tx.clone(),
+                };
+                tokio::task::spawn_local(dispatch_request(
+                    envelope.request,
+                    responder,
+                    state.clone(),
+                    closed.clone(),
+                ));
+            }
+            Err(err) => {
+                // A newer client may send request types this daemon has never heard of;
+                // report that as a version problem rather than a parse failure.
+                let probe = serde_json::from_str::<VersionProbe>(line).ok();
+                let responder = Responder {
+                    id: probe.as_ref().and_then(|probe| probe.id),
+                    tx: responses_tx.clone(),
+                };
+                match probe {
+                    Some(probe) if probe.version > PROTOCOL_VERSION => {
+                        responder.send(version_mismatch(probe.version));
+                    }
+                    _ => {
+                        tracing::warn!(?err, "failed to parse request: {line}");
+                        responder.send(DaemonResponse::error(format!(
+                            "failed to parse request: {err}"
+                        )));
+                    }
+                }
+            }
+        }
+    }
+
+    // The client stopped sending: end any subscriptions, then let in-flight requests
+    // finish writing their responses before the connection is dropped.
+    closed.cancel();
+    drop(responses_tx);
+    writer_task.await?
+}
+
+enum RequestLine {
+    Complete(String),
+    /// The line exceeded the size limit and was discarded up to its newline.
+    TooLarge {
+        bytes: usize,
+    },
+    Eof,
+}
+
+/// Reads one newline-terminated request without buffering more than `limit` bytes of it.
+async fn read_bounded_line(
+    reader: &mut BufReader<OwnedReadHalf>,
+    buffer: &mut Vec<u8>,
+    limit: usize,
+) -> Result<RequestLine> {
+    buffer.clear();
+    let mut total = 0;
+    let mut overflowed = false;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(match (total, overflowed) {
+                (0, _) => RequestLine::Eof,
+                (bytes, true) => RequestLine::TooLarge { bytes },
+                (_, false) => RequestLine::Complete(decode_request_line(buffer)?),
+            });
+        }
+        let newline = available.iter().position(|byte| *byte == b'\n');
+        let chunk = &available[..newline.unwrap_or(available.len())];
+        total += chunk.len();
+        if !overflowed {
+            if buffer.len() + chunk.len() > limit {
+                overflowed = true;
+                buffer.clear();
+            } else {
+                buffer.extend_from_slice(chunk);
+            }
+        }
+        let consumed = newline.map_or(available.len(), |index| index + 1);
+        reader.consume(consumed);
+        if newline.is_some() {
+            return Ok(if overflowed {
+                RequestLine::TooLarge { bytes: total }
+            } else {
+                RequestLine::Complete(decode_request_line(buffer)?)
+            });
+        }
+    }
+}
+
+fn decode_request_line(buffer: &[u8]) -> Result<String> {
+    String::from_utf8(buffer.to_vec()).context("request was not valid UTF-8")
+}
+
+/// A response queued for the connection's writer task.
+struct OutgoingResponse {
+    envelope: ResponseEnvelope,
+    /// Signalled once the response has been flushed to the socket.
+    flushed: Option<oneshot::Sender<()>>,
+}
+
+/// Sends the responses for one request back over its connection.
+struct Responder {
+    id: Option<u64>,
+    tx: mpsc::UnboundedSender<OutgoingResponse>,
+}
+
+impl Responder {
+    /// Queues a response, returning `false` once the connection has gone away.
+    fn send(&self, response: DaemonResponse) -> bool {
+        self.queue(response, None)
+    }
+
+    /// Queues a response and waits until the writer has flushed it.
+    async fn send_and_flush(&self, response: DaemonResponse) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.queue(response, Some(tx)) && rx.await.is_ok()
+    }
+
+    fn queue(&self, response: DaemonResponse, flushed: Option<oneshot::Sender<()>>) -> bool {
+        let envelope = ResponseEnvelope {
+            id: self.id,
+            version: PROTOCOL_VERSION,
+            response,
+        };
+        self.tx.send(OutgoingResponse { envelope, flushed }).is_ok()
+    }
+}
+
+fn version_mismatch(client_version: u32) -> DaemonResponse {
+    DaemonResponse::error_with_code(
+        "version_mismatch",
+        format!(
+            "client speaks protocol v{client_version} but the daemon only supports v{PROTOCOL_VERSION}"
+        ),
+        Some(json!({
+            "protocol_version": PROTOCOL_VERSION,
+            "binary_version": env!("CARGO_PKG_VERSION"),
+        })),
+    )
+}
+
+async fn write_responses(
+    mut writer: OwnedWriteHalf,
+    mut responses: mpsc::UnboundedReceiver<OutgoingResponse>,
+    state: Arc<InnerState>,
+) -> Result<()> {
+    while let Some(outgoing) = responses.recv().await {
+        let mut payload = serde_json::to_string(&outgoing.envelope)?;
+        let mut spilled = None;
+        if payload.len() > state.max_inline_result_bytes
+            && let DaemonResponse::Prompt { result } = &outgoing.envelope.response
+        {
+            match spill_result(&state.socket_path, result).await {
+                Ok((path, bytes)) => {
+                    payload = serde_json::to_string(&ResponseEnvelope {
+                        id: outgoing.envelope.id,
+                        version: outgoing.envelope.version,
+                        response: DaemonResponse::PromptFile {
+                            path: path.clone(),
+                            bytes,
+                        },
+                    })?;
+                    spilled = Some(path);
+                }
+                Err(err) => tracing::warn!("sending the prompt result inline: {err:#}"),
+            }
+        }
+        let written = async {
+            writer.write_all(payload.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            writer.flush().await
+        }
+        .await;
+        if written.is_err()
+            && let Some(path) = spilled
+        {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        written?;
+        if let Some(flushed) = outgoing.flushed {
+            let _ = flushed.send(());
+        }
+    }
+    Ok(())
+}
+
+/// Writes a prompt result too large to send inline next to the socket, readable only by us.
+///
+/// Returns the file and its length; the client deletes it once read.
+async fn spill_result(socket_path: &Path, result: &PromptResultPayload) -> Result<(PathBuf, u64)> {
+    static SPILLED: AtomicU64 = AtomicU64::new(0);
+    let mut name = socket_path.as_os_str().to_owned();
+    name.push(format!(
+        ".result-{}-{}.json",
+        std::process::id(),
+        SPILLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let path = PathBuf::from(name);
+    let json = serde_json::to_vec(result)?;
+    let written = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await?;
+        file.write_all(&json).await?;
+        file.flush().await
+    }
+    .await;
+    if let Err(err) = written {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(err).with_context(|| format!("failed to write {}", path.display()));
+    }
+    Ok((path, json.len() as u64))
+}
+
+async fn dispatch_request(
+    request: DaemonRequest,
+    responder: Responder,
+    state: Arc<InnerState>,
+    closed: CancellationToken,
+) {
+    let response = match request {
+        DaemonRequest::Prompt(payload) => {
+            let Some(_active) = state.begin_active_prompt() else {
+                responder.send(DaemonResponse::error_with_code(
+                    "shutting_down",
+                    "daemon is shutting down and no longer accepts prompts",
+                    None,
+                ));
+                return;
+            };
+            let slot = match state.slot(payload.agent.as_deref()) {
+                Ok(slot) => slot,
+                Err(error) => {
+                    responder.send(DaemonResponse::error(error.to_string()));
+                    return;
+                }
+            };
+            let (questions_tx, mut questions) = mpsc::unbounded_channel();
+            let questions_tx = payload.answer_permissions.then_some(questions_tx);
+            let prompt = state.run_prompt(&slot, *payload, questions_tx);
+            tokio::pin!(prompt);
+            let mut connected = true;
+            let mut asked = Vec::new();
+            let result = loop {
+                tokio::select! {
+                    result = &mut prompt => break result,
+                    Some(question) = questions.recv() => {
+                        if connected {
+                            asked.push(question.id);
+                            responder.send(DaemonResponse::PermissionRequest {
+                                permission_id: question.id,
+                                title: question.title,
+                                tool_kind: question.tool_kind,
+                                options: question.options,
+                                preview: question.preview,
+                            });
+                        } else {
+                            state.permissions.withdraw(question.id);
+                        }
+                    }
+                    _ = closed.cancelled(), if connected => {
+                        // Nobody is left to answer; the requests go to Kakoune instead.
+                        connected = false;
+                        questions.close();
+                        for id in asked.drain(..) {
+                            state.permissions.withdraw(id);
+                        }
+                    }
+                }
+            };
+            match result {
+                Ok(result) => DaemonResponse::Prompt { result },
+                Err(error) => match error.downcast_ref::<AgentBusy>() {
+                    Some(busy) => busy.response(),
+                    None => {
+                        tracing::error!(?error, "prompt handling failed");
+                        DaemonResponse::error(error.to_string())
+                    }
+                },
+            }
+        }
+        DaemonRequest::Status => DaemonResponse::Status {
+            status: Box::new(state.status_snapshot().await),
+        },
+        DaemonRequest::ResetMetrics => {
+            let mut status = state.status_snapshot().await;
+            // Counts that arrive between the snapshot and the reset go to the old window.
+            status.metrics = state.stats.reset_metrics();
+            DaemonResponse::Status {
+                status: Box::new(status),
+            }
+        }
+        DaemonRequest::Ping => DaemonResponse::Pong {
+            pid: Some(std::process::id()),
+            uptime_ms: state.stats.uptime_ms(),
+        },
+        DaemonRequest::Shutdown { force } => {
+            let abandoned_prompts = if force {
+                state.force_stop().await
+            } else {
+                state.drain().await
+            };
+            {
+                let mut status = state.status.lock().await;
+                status.running = false;
+            }
+            let response = DaemonResponse::Shutdown {
+                forced: force,
+                abandoned_prompts,
+            };
+            // Make sure the client hears back before the accept loop tears everything down.
+            responder.send_and_flush(response).await;
+            state.shutdown.cancel();
+            return;
+        }
+        DaemonRequest::RestartAgent { agent } => {
+            let restarted = match state.slot(agent.as_deref()) {
+                Ok(slot) => state
+                    .restart_agent(&slot)
+                    .await
+                    .map(|session| (slot, session)),
+                Err(error) => Err(error),
+            };
+            match restarted {
+                Ok((slot, session)) => DaemonResponse::AgentRestarted {
+                    agent: slot.name.clone(),
+                    session_id: session.current_session_id().map(ToString::to_string),
+                    agent_pid: session.pid,
+                },
+                Err(error) => {
+                    tracing::error!(?error, "agent restart failed");
+                    DaemonResponse::error(format!("{error:#}"))
+                }
+            }
+        }
+        DaemonRequest::Cancel { agent } => match state.slot(agent.as_deref()) {
+            Ok(slot) => {
+                let session = slot.session();
+                // Without a session there is no turn to cancel.
+                let session_id = session
+                    .last_session_id
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .or_else(|| session.current_session_id().cloned());
+                let cancelled = match session_id {
+                    Some(session_id) => {
+                        let prompt = state.router.request_id(&slot.name, &session_id);
+                        state
+                            .router
+                            .cancelled(&slot.name, &session_id, CANCELLED_BY_USER);
+                        let cancelled = session
+                            .connection
+                            .cancel(acp::CancelNotification {
+                                session_id,
+                                meta: None,
+                            })
+                            .await;
+                        // ACP has the client answer the turn's permission requests as cancelled.
+                        if let Some(prompt) = prompt {
+                            state
+                                .permissions
+                                .end_turn(prompt, "the prompt was cancelled");
+                        }
+                        cancelled
+                    }
+                    None => Ok(()),
+                };
+                match cancelled {
+                    Ok(()) => DaemonResponse::Ok,
+                    Err(error) => DaemonResponse::error(format!("failed to cancel: {error}")),
+                }
+            }
+            Err(error) => DaemonResponse::error(error.to_string()),
+        },
+        DaemonRequest::Reload => match state.reload().await {
+            Ok((applied, skipped)) => DaemonResponse::Reloaded { applied, skipped },
+            Err(error) => DaemonResponse::error(format!("{error:#}")),
+        },
+        DaemonRequest::PermissionReply {
+            permission_id,
+            option_id,
+            always,
+        } => match state
+            .permissions
+            .answer(permission_id, option_id, always, false)
+        {
+            Ok(()) => DaemonResponse::Ok,
+            Err(error) => DaemonResponse::error(error.to_string()),
+        },
+        DaemonRequest::PermissionAnswer {
+            permission_id,
+            option_id,
+        } => match state
+            .permissions
+            .answer(permission_id, option_id, None, true)
+        {
+            Ok(()) => DaemonResponse::Ok,
+            Err(error) => DaemonResponse::error(error.to_string()),
+        },
+        DaemonRequest::PermissionRules => DaemonResponse::PermissionRules {
+            rules: state.permissions.rules.rules(),
+        },
+        DaemonRequest::ClearPermissionRules => DaemonResponse::PermissionRules {
+            rules: state.permissions.rules.clear(),
+        },
+        DaemonRequest::CloseSession { agent, cwd } => match state.slot(agent.as_deref()) {
+            Ok(slot) => {
+                let cwd = tokio::fs::canonicalize(&cwd).await.unwrap_or(cwd);
+                let session = slot.session();
+                if cwd == session.spec.cwd {
+                    DaemonResponse::error(format!(
+                        "{} is the agent's own working directory; its session stays open",
+                        cwd.display()
+                    ))
+                } else if session.close_cwd_session(&cwd) {
+                    DaemonResponse::Ok
+                } else {
+                    DaemonResponse::error(format!("no session is open for {}", cwd.display()))
+                }
+            }
+            Err(error) => DaemonResponse::error(error.to_string()),
+        },
+        DaemonRequest::Watch => return stream_notifications(responder, state, closed).await,
+        DaemonRequest::Attach { request_id } => {
+            return attach_prompt(request_id, responder, state, closed).await;
+        }
+    };
+    responder.send(response);
+}
+
+/// Forwards every session notification to a watching client until it disconnects.
+async fn stream_notifications(
+    responder: Responder,
+    state: Arc<InnerState>,
+    closed: CancellationToken,
+) {
+    let mut updates = state.router.subscribe_watcher();
+    if !responder.send(DaemonResponse::Ok) {
+        return;
+    }
+    loop {
+        tokio::select! {
+            update = updates.recv() => {
+                match update {
+                    Ok(AgentNotification { agent, notification }) => {
+                        let frame = DaemonResponse::Notification {
+                            notification,
+                            agent: Some(agent),
+                        };
+                        if !responder.send(frame) {
+                            tracing::debug!("watch client went away");
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "watch client dropped {skipped} session notifications");
+                        state.stats.metrics.lock().unwrap().dropped_notifications += skipped;
+                        if !responder.send(DaemonResponse::NotificationsDropped { count: skipped }) {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            _ = closed.cancelled() => break,
+            _ = state.shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Replays a prompt's events to an `attach` client, then follows it until the turn ends.
+///
+/// A prompt that has already finished is answered with its result straight away.
+async fn attach_prompt(
+    request_id: u64,
+    responder: Responder,
+    state: Arc<InnerState>,
+    closed: CancellationToken,
+) {
+    let Some(mut log) = state.subscribe_prompt_log(request_id) else {
+        responder.send(state.unknown_request(request_id));
+        return;
+    };
+    if !responder.send(DaemonResponse::Ok) {
+        return;
+    }
+    // A finished prompt's result already carries its whole transcript.
+    let mut sent = match log.borrow().outcome {
+        Some(_) => usize::MAX,
+        None => 0,
+    };
+    loop {
+        let (events, outcome) = {
+            let log = log.borrow_and_update();
+            // Chunks may still be merged into the last event until the prompt ends.
+            let settled = match log.outcome {
+                Some(_) => log.events.len(),
+                None => log.events.len().saturating_sub(1),
+            };
+            let events = log.events.get(sent..settled).unwrap_or_default().to_vec();
+            (events, log.outcome.clone())
+        };
+        for event in events {
+            sent += 1;
+            if !responder.send(DaemonResponse::PromptEvent { event }) {
+                return;
+            }
+        }
+        if let Some(outcome) = outcome {
+            responder.send(match outcome {
+                Ok(result) => DaemonResponse::Prompt { result },
+                Err(message) => DaemonResponse::error(message),
+            });
+            return;
+        }
+        tokio::select! {
+            changed = log.changed() => {
+                if changed.is_err() {
+                    // The daemon stopped before the prompt finished.
+                    break;
+                }
+            }
+            _ = closed.cancelled() => break,
+            _ = state.shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// What `attach` clients see of a prompt: the events so far and, once it ends, the outcome.
+#[derive(Default)]
+struct PromptLog {
+    events: Vec<TranscriptEntry>,
+    outcome: Option<Result<PromptResultPayload, String>>,
+}
+
+impl PromptLog {
+    /// Appends the collector's events that have not been published yet.
+    ///
+    /// The last published event is copied again, since merged chunks may have extended it;
+    /// subscribers are only woken for new events.
+    fn publish(log: &watch::Sender<PromptLog>, collector: &TranscriptCollector) {
+        log.send_if_modified(|log| {
+            let events = collector.events();
+            let grown = events.len() > log.events.len();
+            let from = log.events.len().saturating_sub(1).min(events.len());
+            log.events.truncate(from);
+            log.events.extend_from_slice(&events[from..]);
+            grown
+        });
+    }
+}
+
+struct InnerState {
+    /// Every configured agent; the first one serves requests that do not name an agent.
+    agents: Vec<Arc<AgentSlot>>,
+    router: Arc<NotificationRouter>,
+    shutdown: CancellationToken,
+    /// Set once a graceful shutdown starts; new prompts are refused from then on.
+    draining: AtomicBool,
+    active_prompts: AtomicUsize,
+    prompts_idle: Notify,
+    drain_timeout: Duration,
+    status: Arc<Mutex<ipc::DaemonStatus>>,
+    stats: Arc<DaemonStats>,
+    store: Option<TranscriptStore>,
+    /// Secrets replaced in the transcripts `store` keeps.
+    redactor: Redactor,
+    max_request_bytes: usize,
+    /// Prompt results that serialize larger than this are spilled to a file.
+    max_inline_result_bytes: usize,
+    /// Kakoune session told about agents that exit unexpectedly.
+    kak_session: Option<String>,
+    /// Published to Kakoune's `acp_state` option when `kak_session` is set.
+    kak_state: watch::Sender<KakState>,
+    socket_path: PathBuf,
+    /// Inode of the socket once bound, so a forced shutdown only unlinks our own.
+    socket_inode: AtomicU64,
+    /// Disconnects once `run` returns; taken by the first forced shutdown's watchdog.
+    watchdog: std::sync::Mutex<Option<std::sync::mpsc::Receiver<()>>>,
+    /// Options in effect: the command line with the config file applied as last loaded.
+    settings: std::sync::Mutex<DaemonOptions>,
+    /// The daemon's own command line, which every reload starts from.
+    command_line: DaemonOptions,
+    reload_lock: Mutex<()>,
+    /// Running and recently finished prompts by request id, for `attach`.
+    prompt_logs: std::sync::Mutex<BTreeMap<u64, watch::Sender<PromptLog>>>,
+    permissions: Arc<PermissionBroker>,
+    /// Per-path write locks handed to every agent's client.
+    write_locks: Arc<PathLocks>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl InnerState {
+    /// Looks up an agent by name, falling back to the first one.
+    fn slot(&self, name: Option<&str>) -> Result<Arc<AgentSlot>> {
+        let Some(name) = name else {
+            return Ok(self.agents[0].clone());
+        };
+        self.agents
+            .iter()
+            .find(|slot| slot.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                let known = self
+                    .agents
+                    .iter()
+                    .map(|slot| slot.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                anyhow::anyhow!("unknown agent {name:?} (available: {known})")
+            })
+    }
+
+    /// Starts the log `attach` follows for a prompt, forgetting the oldest finished ones.
+    fn open_prompt_log(&self, request_id: u64) -> watch::Sender<PromptLog> {
+        let log = watch::Sender::new(PromptLog::default());
+        let mut logs = self.prompt_logs.lock().unwrap();
+        let finished = logs
+            .iter()
+            .filter(|(_, log)| log.borrow().outcome.is_some())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(FINISHED_PROMPT_LOGS - 1))
+        {
+            logs.remove(id);
+        }
+        logs.insert(request_id, log.clone());
+        log
+    }
+
+    fn subscribe_prompt_log(&self, request_id: u64) -> Option<watch::Receiver<PromptLog>> {
+        let logs = self.prompt_logs.lock().unwrap();
+        logs.get(&request_id).map(watch::Sender::subscribe)
+    }
+
+    fn unknown_request(&self, request_id: u64) -> DaemonResponse {
+        let (mut active, mut finished) = (Vec::new(), Vec::new());
+        for (id, log) in self.prompt_logs.lock().unwrap().iter() {
+            match log.borrow().outcome {
+                Some(_) => finished.push(*id),
+                None => active.push(*id),
+            }
+        }
+        let list = |ids: &[u64]| match ids {
+            [] => "none".to_string(),
+            ids => ids
+                .iter()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        DaemonResponse::error_with_code(
+            "unknown_request",
+            format!(
+                "no prompt with request id {request_id} (active: {}; completed: {})",
+                list(&active),
+                list(&finished)
+            ),
+            Some(json!({ "active": active, "completed": finished })),
+        )
+    }
+
+    fn set_kak_state(&self, state: KakState) {
+        self.kak_state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+
+    fn restart_policy(&self) -> RestartPolicy {
+        self.settings
+            .lock()
+            .unwrap()
+            .restart
+            .unwrap_or(RestartPolicy::Never)
+    }
+
+    /// Re-reads the config file and applies whatever changed since it was last loaded.
+    ///
+    /// Returns the changed keys that took effect and those that need a daemon restart. Agents
+    /// are restarted when their command, working directory, environment or MCP servers change;
+    /// adding, removing or renaming agents is left for the next daemon start. Skipped keys are
+    /// reported by every reload until then.
+    async fn reload(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let _reload = self.reload_lock.lock().await;
+        let config = Config::load(&config::resolve_config_path()?)?;
+        let mut next = self.command_line.clone();
+        config.daemon.apply(&mut next);
+        let previous = self.settings.lock().unwrap().clone();
+
+        let mut applied = Vec::new();
+        let mut skipped = Vec::new();
+        if next.log_file != previous.log_file {
+            skipped.push("log_file".to_string());
+            next.log_file = previous.log_file.clone();
+        }
+
+        let launch_changes = [
+            ("agent", next.agent != previous.agent),
+            ("cwd", next.cwd != previous.cwd),
+            ("env", next.env != previous.env),
+            ("mcp_servers", next.mcp_servers != previous.mcp_servers),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key.to_string())
+        .collect::<Vec<_>>();
+        let mut relaunch = Vec::new();
+        if !launch_changes.is_empty() {
+            let specs = agent_specs(&next)?;
+            let same_agents = specs.len() == self.agents.len()
+                && specs
+                    .iter()
+                    .zip(&self.agents)
+                    .all(|(spec, slot)| spec.name == slot.name);
+            if same_agents {
+                // Only a changed command leaves the agents whose command stayed the same alone.
+                let everyone = launch_changes.iter().any(|key| key != "agent");
+                for (spec, slot) in specs.into_iter().zip(&self.agents) {
+                    if everyone || spec.command != slot.spec().command {
+                        relaunch.push((slot.clone(), Arc::new(spec)));
+                    }
+                }
+                applied.extend(launch_changes);
+            } else {
+                skipped.extend(launch_changes);
+                next.agent = previous.agent.clone();
+                next.cwd = previous.cwd.clone();
+                next.env = previous.env.clone();
+                next.mcp_servers = previous.mcp_servers.clone();
+            }
+        }
+
+        if next.log_level != previous.log_level {
+            logging::set_level(next.log_level.as_deref())?;
+            applied.push("log_level".to_string());
+        }
+        if next.restart != previous.restart {
+            applied.push("restart".to_string());
+        }
+        *self.settings.lock().unwrap() = next;
+
+        for (slot, spec) in relaunch {
+            *slot.spec.lock().unwrap() = spec;
+            self.restart_agent(&slot).await?;
+        }
+
+        let mut status = self.status.lock().await;
+        status.last_reload_at = Some(unix_timestamp(SystemTime::now()));
+        status.reload_applied = applied.clone();
+        status.reload_skipped = skipped.clone();
+        Ok((applied, skipped))
+    }
+
+    /// Replaces an agent process with a fresh one and opens a new session on it.
+    ///
+    /// The old agent is retired first, which ends any prompt still running on it as cancelled.
+    async fn restart_agent(&self, slot: &AgentSlot) -> Result<Arc<AgentSession>> {
+        let _restart = slot.restart_lock.lock().await;
+        let agent = &slot.name;
+        let old = slot.session();
+        tracing::info!(agent, pid = ?old.pid, "restarting agent");
+        if let Some(status) = old.stop().await {
+            slot.record_exit(status);
+        }
+
+        let session = Arc::new(
+            spawn_agent(
+                slot.spec(),
+                &self.router,
+                &self.stats,
+                &self.permissions,
+                &self.write_locks,
+                self.audit.as_ref(),
+            )
+            .await
+            .with_context(|| format!("failed to restart agent {agent:?}"))?,
+        );
+        *slot.session.lock().unwrap() = session.clone();
+        tracing::info!(agent, pid = ?session.pid, session_id = ?session.current_session_id(), "agent restarted");
+        Ok(session)
+    }
+
+    /// Stops every agent without draining, returning how many prompts were interrupted.
+    ///
+    /// A watchdog thread unlinks the socket and exits the process should the daemon fail to
+    /// wind down on its own, e.g. with the accept loop stuck. It stands down once `run` has
+    /// returned, so a process embedding the daemon outlives it.
+    async fn force_stop(&self) -> usize {
+        let abandoned = self.active_prompt_count();
+        let socket_path = self.socket_path.clone();
+        let inode = self.socket_inode.load(Ordering::SeqCst);
+        let pids = self
+            .agents
+            .iter()
+            .filter_map(|slot| slot.session().pid)
+            .collect::<Vec<_>>();
+        if let Some(returned) = self.watchdog.lock().unwrap().take() {
+            std::thread::spawn(move || {
+                let outcome = returned.recv_timeout(FORCE_EXIT_TIMEOUT);
+                if outcome != Err(std::sync::mpsc::RecvTimeoutError::Timeout) {
+                    return;
+                }
+                tracing::error!("daemon did not stop after a forced shutdown; exiting");
+                for pid in pids {
+                    signal_process_group(pid, libc::SIGKILL);
+                }
+                remove_socket_if_ours(&socket_path, inode);
+                std::process::exit(1);
+            });
+        }
+
+        let deadline = tokio::time::Instant::now() + FORCE_KILL_GRACE;
+        for slot in &self.agents {
+            slot.session().force_stop(deadline).await;
+        }
+        abandoned
+    }
+
+    async fn status_snapshot(&self) -> ipc::DaemonStatus {
+        let mut status = self.status.lock().await.clone();
+        self.stats.fill_status(&mut status);
+        status.draining = self.draining.load(Ordering::SeqCst);
+        status.agents = self.agents.iter().map(|slot| slot.status()).collect();
+        // The top-level agent fields describe the default agent for older clients.
+        let default = &status.agents[0];
+        status.session_id = default.session_id.clone();
+        status.session_state = default.session_state;
+        status.agent_pid = default.agent_pid;
+        status.agent_command = default.agent_command.clone();
+        status.busy = default.current_prompt.is_some();
+        status.last_exit = default.last_exit.clone();
+        status.audit_log = self.audit.as_ref().map(|audit| audit.status());
+        status
+    }
+
+    /// Registers an accepted prompt request, or returns `None` while shutting down.
+    fn begin_active_prompt(&self) -> Option<ActivePromptGuard<'_>> {
+        self.active_prompts.fetch_add(1, Ordering::SeqCst);
+        if self.draining.load(Ordering::SeqCst) {
+            self.end_active_prompt();
+            return None;
+        }
+        Some(ActivePromptGuard { state: self })
+    }
+
+    fn end_active_prompt(&self) {
+        if self.active_prompts.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.prompts_idle.notify_waiters();
+        }
+    }
+
+    fn active_prompt_count(&self) -> usize {
+        self.active_prompts.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting prompts and waits for in-flight ones, up to the drain timeout.
+    ///
+    /// Returns the number of prompts still running when the timeout expired.
+    async fn drain(&self) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let wait_for_idle = async {
+            loop {
+                let idle = self.prompts_idle.notified();
+                tokio::pin!(idle);
+                idle.as_mut().enable();
+                if self.active_prompt_count() == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        };
+        match tokio::time::timeout(self.drain_timeout, wait_for_idle).await {
+            Ok(()) => 0,
+            Err(_) => {
+                let remaining = self.active_prompt_count();
+                tracing::warn!(
+                    remaining,
+                    "drain timeout expired with prompts still running"
+                );
+                remaining
+            }
+        }
+    }
+
+    async fn run_prompt(
+        &self,
+        slot: &AgentSlot,
+        payload: PromptPayload,
+        questions: Option<QuestionSender>,
+    ) -> Result<PromptResultPayload> {
+        let _turn = if payload.no_queue {
+            slot.prompt_lock.try_lock().map_err(|_| AgentBusy {
+                agent: slot.name.clone(),
+                active: slot.status().current_prompt,
+            })?
+        } else {
+            slot.prompt_lock.lock().await
+        };
+        let started = Instant::now();
+        let kak = KakAddress {
+            session: payload.kak_session.clone(),
+            client: payload.client.clone(),
+        };
+        let request_id = self.stats.begin_prompt(&kak);
+        *slot.current_prompt.lock().unwrap() = Some(RunningPrompt::new(request_id, &kak));
+        let log = self.open_prompt_log(request_id);
+        if let Some(session) = payload.progress.clone() {
+            tokio::task::spawn_local(report_progress(
+                log.subscribe(),
+                session,
+                payload.client.clone(),
+            ));
+        }
+        if let Some(session) = payload.plan.clone() {
+            tokio::task::spawn_local(report_plan(
+                log.subscribe(),
+                session,
+                payload.client.clone(),
+            ));
+        }
+        let stream = payload.stream.clone().map(|display| {
+            tokio::task::spawn_local(report_stream(
+                log.subscribe(),
+                display,
+                payload.client.clone(),
+            ))
+        });
+        self.set_kak_state(KakState::Prompting);
+        let result = self
+            .collect_prompt(slot, payload, kak, questions, request_id, &log)
+            .await;
+        log.send_modify(|log| {
+            log.outcome = Some(match &result {
+                Ok(result) => Ok(result.clone()),
+                Err(error) => Err(error.to_string()),
+            })
+        });
+        // The whole answer lands before the result, which the client may show in its place.
+        if let Some(stream) = stream {
+            let _ = stream.await;
+        }
+        *slot.current_prompt.lock().unwrap() = None;
+        let others_prompting = self
+            .agents
+            .iter()
+            .any(|slot| slot.current_prompt.lock().unwrap().is_some());
+        self.set_kak_state(match &result {
+            _ if others_prompting => KakState::Prompting,
+            Ok(_) => KakState::Idle,
+            Err(_) => KakState::Error,
+        });
+        self.stats
+            .finish_prompt(request_id, result.is_ok(), started.elapsed());
+        if let (Ok(result), Some(store)) = (&result, &self.store)
+            && !result.cached
+        {
+            let mut stored = result.clone();
+            let saved = match self.redactor.redact_result(&mut stored) {
+                Ok(()) => store.save(&stored).await,
+                Err(err) => Err(err),
+            };
+            match saved {
+                Ok(path) => tracing::debug!("stored transcript at {}", path.display()),
+                Err(err) => tracing::warn!(?err, "failed to persist transcript"),
+            }
+        }
+        result
+    }
+
+    async fn collect_prompt(
+        &self,
+        slot: &AgentSlot,
+        payload: PromptPayload,
+        kak: KakAddress,
+        questions: Option<QuestionSender>,
+        request_id: u64,
+        log: &watch::Sender<PromptLog>,
+    ) -> Result<PromptResultPayload> {
+        let PromptPayload {
+            prompt,
+            context,
+            cwd,
+            idempotency_key,
+            apply_diffs,
+            no_merge_chunks,
+            full_plans,
+            raw_tool_io,
+            group_tool_calls,
+            legacy_mode_messages,
+            keep_user_echo,
+            timeout,
+            ..
+        } = payload;
+        let spec = slot.spec();
+        let mut collector = TranscriptCollector::new()
+            .with_diff_format(spec.diff_format.clone())
+            .with_media(spec.media.clone())
+            .with_merged_chunks(!no_merge_chunks)
+            .with_plan_updates(!full_plans)
+            .with_raw_tool_io(raw_tool_io)
+            .with_grouped_tool_calls(group_tool_calls)
+            .with_legacy_mode_messages(legacy_mode_messages)
+            .with_user_echo(keep_user_echo);
+        collector.push_user_prompt(prompt.clone());
+        PromptLog::publish(log, &collector);
+
+        let mut prompt_blocks = Vec::new();
+        prompt_blocks.push(acp::ContentBlock::from(prompt.clone()));
+        for entry in &context {
+            prompt_blocks.push(acp::ContentBlock::from(entry.text.clone()));
+        }
+
+        let agent = slot.session();
+        if agent.retired.is_cancelled() || agent.exited.is_cancelled() {
+            anyhow::bail!(
+                "agent {:?} is not running; use `kakoune-acp restart-agent`",
+                slot.name
+            );
+        }
+        let cwd = match cwd {
+            Some(cwd) => Some(tokio::fs::canonicalize(&cwd).await.with_context(|| {
+                format!("cannot use {} as the working directory", cwd.display())
+            })?),
+            None => None,
+        };
+        let session_id = agent
+            .session_for_cwd(cwd.as_deref())
+            .await
+            .context("failed to open an ACP session")?;
+        *agent.last_session_id.lock().unwrap() = Some(session_id.clone());
+        let modes = agent
+            .session_modes
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .cloned();
+        collector = collector.with_modes(modes);
+        if let Some(key) = &idempotency_key
+            && let Some(mut result) = agent.cached_answer(&session_id, key)
+        {
+            tracing::info!(
+                agent = slot.name,
+                key,
+                "replaying the answer to a repeated prompt"
+            );
+            result.cached = true;
+            return Ok(result);
+        }
+        let answer_key = idempotency_key.map(|key| (session_id.clone(), key));
+        let (route_tx, mut updates) = mpsc::unbounded_channel();
+        let route = self.router.register(
+            (slot.name.clone(), session_id.clone()),
+            route_tx,
+            kak.clone(),
+            questions.clone(),
+            request_id,
+        );
+        let turn = TurnPermissions {
+            broker: &self.permissions,
+            request_id,
+        };
+        let prompt_session = session_id.clone();
+        let mut prompt_future = Box::pin(agent.connection.prompt(acp::PromptRequest {
+            session_id,
+            prompt: prompt_blocks,
+            meta: Some(json!({
+                "source": "kakoune",
+            })),
+        }));
+
+        let mut cancel_reason = None;
+        let deadline = async {
+            match timeout {
+                Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+        let (stop_reason, response_meta) = loop {
+            tokio::select! {
+                Some(update) = updates.recv() => {
+                    update.record(&mut collector, &mut cancel_reason);
+                    PromptLog::publish(log, &collector);
+                }
+                _ = &mut deadline, if cancel_reason.is_none() => {
+                    cancel_reason = Some(CANCELLED_BY_TIMEOUT);
+                    tracing::info!(agent = slot.name, request_id, "prompt timed out; cancelling");
+                    let cancelled = agent
+                        .connection
+                        .cancel(acp::CancelNotification {
+                            session_id: prompt_session.clone(),
+                            meta: None,
+                        })
+                        .await;
+                    if let Err(error) = cancelled {
+                        tracing::warn!(?error, "failed to cancel a timed out prompt");
+                    }
+                    self.permissions.end_turn(request_id, "the prompt timed out");
+                }
+                // The retired agent is gone; the turn ends with what it sent so far.
+                _ = agent.retired.cancelled() => {
+                    cancel_reason = Some(CANCELLED_BY_RESTART);
+                    break (acp::StopReason::Cancelled, None);
+                }
+                _ = agent.exited.cancelled() => {
+                    return Err(agent_exited_during_prompt(&agent).await);
+                }
+                response = &mut prompt_future => {
+                    let response = match response {
+                        Ok(response) => response,
+                        // A dropped connection usually means the agent died; say how.
+                        Err(err) => {
+                            let exited = tokio::time::timeout(
+                                Duration::from_millis(200),
+                                agent.exited.cancelled(),
+                            );
+                            if exited.await.is_ok() {
+                                return Err(agent_exited_during_prompt(&agent).await);
+                            }
+                            return Err(err.into());
+                        }
+                    };
+                    break (response.stop_reason, response.meta);
+                }
+            }
+        };
+        // The ACP connection handles each notification in its own local task, so the prompt
+        // response can resolve before handlers for earlier notifications have run. Those tasks
+        // are already queued on the LocalSet, so a task spawned now only completes once they
+        // have all delivered to the route.
+        let _ = tokio::task::spawn_local(async {}).await;
+        drop(route);
+        drop(turn);
+        while let Some(update) = updates.recv().await {
+            update.record(&mut collector, &mut cancel_reason);
+        }
+        collector.settle_echo();
+        collector.flush_tool_calls();
+        // Terminals still held when the turn ends are shown as they stand.
+        for terminal_id in collector.unresolved_terminals() {
+            let id = acp::TerminalId(terminal_id.into());
+            if let (Ok(command), Ok(output)) = (
+                agent.terminals.command_line(&prompt_session, &id),
+                agent.terminals.output(&prompt_session, &id),
+            ) {
+                collector.push_event(transcript::terminal_output(&id, &command, output));
+            }
+        }
+        PromptLog::publish(log, &collector);
+        let diffs = match apply_diffs {
+            Some(mode) => {
+                let cwd = cwd.as_deref().unwrap_or(&spec.cwd);
+                self.review_diffs(mode, &spec.sandbox, cwd, kak, questions, collector.diffs())
+                    .await
+            }
+            None => Vec::new(),
+        };
+        if let Some(audit) = &self.audit {
+            for outcome in &diffs {
+                let mut record = AuditRecord::new(
+                    "write",
+                    outcome.path.display().to_string(),
+                    format!("diff {}", outcome.status),
+                );
+                if let Some(reason) = &outcome.reason {
+                    record = record.detail(reason);
+                }
+                audit.append(&slot.name, &prompt_session, Some(request_id), record);
+            }
+        }
+        let final_plan = collector.plan().map(<[_]>::to_vec);
+        let final_plan_progress = final_plan.as_deref().map(PlanProgress::of);
+        let final_mode = collector.modes().map(|modes| {
+            agent
+                .session_modes
+                .lock()
+                .unwrap()
+                .insert(prompt_session.clone(), modes.clone());
+            modes.current_mode_id.0.to_string()
+        });
+        let event_count = collector.events().len() as u64;
+        let summary = collector.summary();
+        let (answer, answer_truncated) = collector.answer();
+        let echo_suppressed = collector.echo_suppressed();
+        let had_errors = collector.had_errors();
+        let session_meta = agent
+            .session_meta
+            .lock()
+            .unwrap()
+            .get(&prompt_session)
+            .cloned();
+        let result = PromptResultPayload {
+            schema_version: ipc::RESULT_SCHEMA_VERSION,
+            stop_reason,
+            partial: stop_reason == acp::StopReason::Cancelled,
+            cancel_reason: cancel_reason.map(str::to_string),
+            user_prompt: prompt,
+            context,
+            transcript: collector.finish(),
+            answer,
+            answer_truncated,
+            echo_suppressed,
+            had_errors,
+            event_count,
+            cached: false,
+            diffs,
+            cwd: Some(cwd.clone().unwrap_or_else(|| spec.cwd.clone())),
+            final_plan,
+            final_plan_progress,
+            final_mode,
+            agent: Some(agent.info(session_meta.as_ref())),
+            session_meta,
+            response_meta,
+            summary: Some(summary),
+        };
+        if let Some((session_id, key)) = answer_key {
+            agent.remember_answer(session_id, key, &result);
+        }
+        Ok(result)
+    }
+
+    /// Writes, asks about or skips each diff proposed during a prompt, as `mode` says.
+    async fn review_diffs(
+        &self,
+        mode: ApplyDiffs,
+        sandbox: &Sandbox,
+        cwd: &Path,
+        kak: KakAddress,
+        questions: Option<QuestionSender>,
+        proposed: &[acp::Diff],
+    ) -> Vec<DiffOutcome> {
+        let mut outcomes = Vec::new();
+        for diff in proposed {
+            let path = match sandbox.confine(cwd, &diff.path) {
+                Ok(Ok(path)) => path,
+                Ok(Err(escape)) => {
+                    let reason = format!("outside the workspace root {}", escape.root.display());
+                    outcomes.push(diffs::outcome(
+                        &escape.resolved,
+                        DiffStatus::Failed,
+                        Some(reason),
+                    ));
+                    continue;
+                }
+                Err(err) => {
+                    let reason = format!("cannot resolve the path: {err}");
+                    outcomes.push(diffs::outcome(&diff.path, DiffStatus::Failed, Some(reason)));
+                    continue;
+                }
+            };
+            let declined = match mode {
+                ApplyDiffs::Always => None,
+                ApplyDiffs::Never => Some("not applied with --apply-diffs never".to_string()),
+                ApplyDiffs::Ask => {
+                    // Only put diffs that can still apply to the user.
+                    if let Some(outcome) = diffs::check(diff, &path).await {
+                        outcomes.push(outcome);
+                        continue;
+                    }
+                    self.ask_to_apply(diff, &path, kak.clone(), questions.as_ref())
+                        .await
+                        .err()
+                }
+            };
+            outcomes.push(match declined {
+                None => {
+                    let _writing = self.write_locks.lock(&path).await;
+                    diffs::apply(diff, &path).await
+                }
+                Some(reason) => diffs::outcome(&path, DiffStatus::Skipped, Some(reason)),
+            });
+        }
+        outcomes
+    }
+
+    /// Asks whether to apply `diff` to `path`, where the prompt's permission requests go;
+    /// the error says why not.
+    async fn ask_to_apply(
+        &self,
+        diff: &acp::Diff,
+        path: &Path,
+        kak: KakAddress,
+        questions: Option<&QuestionSender>,
+    ) -> Result<(), String> {
+        let TranscriptEvent::FileEdit {
+            diff: preview,
+            added,
+            removed,
+            ..
+        } = transcript::file_edit(
+            path,
+            diff.old_text.as_deref(),
+            &diff.new_text,
+            &DiffFormat::default(),
+        )
+        else {
+            unreachable!("file_edit makes FileEdit events");
+        };
+        let title = format!("Apply diff to {} (+{added} -{removed})", path.display());
+        let options = [
+            ("apply", "Apply", acp::PermissionOptionKind::AllowOnce),
+            ("skip", "Skip", acp::PermissionOptionKind::RejectOnce),
+        ]
+        .map(|(id, name, kind)| acp::PermissionOption {
+            id: acp::PermissionOptionId(id.into()),
+            name: name.to_string(),
+            kind,
+            meta: None,
+        });
+        let asked = match questions {
+            Some(questions) => {
+                self.permissions
+                    .ask_prompt(questions, "edit", &title, Some(&preview), &options, None)
+                    .await
+            }
+            None => None,
+        };
+        let answer = match asked {
+            Some(answer) => answer,
+            None => {
+                self.permissions
+                    .ask(
+                        Origin { kak, prompt: None },
+                        "edit",
+                        &title,
+                        Some(&preview),
+                        &options,
+                        false,
+                    )
+                    .await
+            }
+        };
+        match answer {
+            Answer::Chosen(option, _) if &*option.id.0 == "apply" => Ok(()),
+            Answer::Chosen(..) => Err("declined".to_string()),
+            Answer::Refused(reason) => Err(reason),
+            Answer::TimedOut(timeout) => Err(format!("no answer after {}s", timeout.as_secs())),
+        }
+    }
+}
+
+async fn agent_exited_during_prompt(agent: &AgentSession) -> anyhow::Error {
+    let status = describe_exit(agent.exit_status().await);
+    anyhow::anyhow!("agent exited before the prompt finished ({status})")
+}
+
+/// Counts a prompt as in flight for as long as it is held.
+struct ActivePromptGuard<'a> {
+    state: &'a InnerState,
+}
+
+impl Drop for ActivePromptGuard<'_> {
+    fn drop(&mut self) {
+        self.state.end_active_prompt();
+    }
+}
+
+/// `cancel_reason` of a turn cancelled with `kakoune-acp cancel`.
+const CANCELLED_BY_USER: &str = "user";
+/// `cancel_reason` of a turn that outlived its prompt's `timeout`.
+const CANCELLED_BY_TIMEOUT: &str = "timeout";
+/// `cancel_reason` of a turn whose agent was restarted or stopped under it.
+const CANCELLED_BY_RESTART: &str = "agent_restart";
+
+/// What the router delivers to the prompt running on a session.
+enum PromptUpdate {
+    Notification(Box<acp::SessionNotification>),
+    /// Something the client did on the agent's behalf, such as reading a file.
+    Event(Box<TranscriptEvent>),
+    /// The daemon asked the agent to cancel the turn, for the reason given.
+    Cancelled(&'static str),
+}
+
+impl PromptUpdate {
+    /// Adds the update to the transcript, or notes why the turn was cancelled; the first
+    /// reason wins.
+    fn record(self, collector: &mut TranscriptCollector, cancel_reason: &mut Option<&'static str>) {
+        match self {
+            Self::Notification(notification) => collector.record_notification(*notification),
+            Self::Event(event) => collector.push_event(*event),
+            Self::Cancelled(reason) => {
+                cancel_reason.get_or_insert(reason);
+            }
+        }
+    }
+}
+
+type PromptSender = mpsc::UnboundedSender<PromptUpdate>;
+
+/// Prompt routes are keyed by agent name as well, since agents may reuse session ids.
+type RouteKey = (String, acp::SessionId);
+
+/// A session notification tagged with the agent that sent it, as broadcast to watchers.
+#[derive(Clone)]
+struct AgentNotification {
+    agent: String,
+    notification: acp::SessionNotification,
+}
+
+/// Fans session notifications out to the prompt running on that session and to watchers.
+///
+/// Prompt routes are unbounded so a chatty agent can never cause transcript events to be
+/// dropped, and each route sees notifications in the order the agent sent them. The watcher
+/// broadcast is best-effort: slow watchers lag and skip events.
+struct NotificationRouter {
+    prompts: std::sync::Mutex<HashMap<RouteKey, RoutedPrompt>>,
+    watchers: broadcast::Sender<AgentNotification>,
+}
+
+impl NotificationRouter {
+    fn new(watcher_capacity: usize) -> Self {
+        let (watchers, _) = broadcast::channel(watcher_capacity);
+        Self {
+            prompts: std::sync::Mutex::new(HashMap::new()),
+            watchers,
+        }
+    }
+
+    fn register(
+        &self,
+        key: RouteKey,
+        sender: PromptSender,
+        kak: KakAddress,
+        questions: Option<QuestionSender>,
+        request_id: u64,
+    ) -> PromptRoute<'_> {
+        let prompt = RoutedPrompt {
+            sender,
+            kak,
+            questions,
+            request_id,
+        };
+        self.prompts.lock().unwrap().insert(key.clone(), prompt);
+        PromptRoute { router: self, key }
+    }
+
+    fn subscribe_watcher(&self) -> broadcast::Receiver<AgentNotification> {
+        self.watchers.subscribe()
+    }
+
+    fn dispatch(&self, agent: &str, notification: acp::SessionNotification) {
+        if self.watchers.receiver_count() > 0 {
+            let _ = self.watchers.send(AgentNotification {
+                agent: agent.to_string(),
+                notification: notification.clone(),
+            });
+        }
+        let key = (agent.to_string(), notification.session_id.clone());
+        let prompts = self.prompts.lock().unwrap();
+        match prompts.get(&key) {
+            Some(prompt) => {
+                let _ = prompt
+                    .sender
+                    .send(PromptUpdate::Notification(Box::new(notification)));
+            }
+            None => tracing::debug!(
+                agent,
+                session_id = %notification.session_id,
+                "no active prompt for session notification"
+            ),
+        }
+    }
+
+    /// Adds `event` to the transcript of the prompt running on `session_id`, if any.
+    fn record(&self, agent: &str, session_id: &acp::SessionId, event: TranscriptEvent) {
+        let key = (agent.to_string(), session_id.clone());
+        if let Some(prompt) = self.prompts.lock().unwrap().get(&key) {
+            let _ = prompt.sender.send(PromptUpdate::Event(Box::new(event)));
+        }
+    }
+
+    /// Tells the prompt running on `session_id`, if any, that its turn was cancelled.
+    fn cancelled(&self, agent: &str, session_id: &acp::SessionId, reason: &'static str) {
+        let key = (agent.to_string(), session_id.clone());
+        if let Some(prompt) = self.prompts.lock().unwrap().get(&key) {
+            let _ = prompt.sender.send(PromptUpdate::Cancelled(reason));
+        }
+    }
+
+    /// Adds `event` to the transcripts of every prompt running on `agent`.
+    fn record_all(&self, agent: &str, event: TranscriptEvent) {
+        for ((prompt_agent, _), prompt) in self.prompts.lock().unwrap().iter() {
+            if prompt_agent == agent {
+                let _ = prompt
+                    .sender
+                    .send(PromptUpdate::Event(Box::new(event.clone())));
+            }
+        }
+    }
+
+    /// Where the prompt running on `session_id` takes permission questions, if its client
+    /// offered to answer them.
+    fn questions(&self, agent: &str, session_id: &acp::SessionId) -> Option<QuestionSender> {
+        let key = (agent.to_string(), session_id.clone());
+        let prompts = self.prompts.lock().unwrap();
+        prompts
+            .get(&key)
+            .and_then(|prompt| prompt.questions.clone())
+    }
+
+    /// Kakoune session and client of the prompt running on `session_id`, as far as it named
+    /// them.
+    fn kak(&self, agent: &str, session_id: &acp::SessionId) -> KakAddress {
+        let key = (agent.to_string(), session_id.clone());
+        let prompts = self.prompts.lock().unwrap();
+        prompts
+            .get(&key)
+            .map(|prompt| prompt.kak.clone())
+            .unwrap_or_default()
+    }
+
+    /// Request id of the prompt running on `session_id`.
+    fn request_id(&self, agent: &str, session_id: &acp::SessionId) -> Option<u64> {
+        let key = (agent.to_string(), session_id.clone());
+        let prompts = self.prompts.lock().unwrap();
+        prompts.get(&key).map(|prompt| prompt.request_id)
+    }
+}
+
+/// A running prompt as the router sees it.
+struct RoutedPrompt {
+    sender: PromptSender,
+    /// Kakoune session and client that sent the prompt, where questions on its behalf are
+    /// asked.
+    kak: KakAddress,
+    /// Set when the prompt client answers permission requests itself.
+    questions: Option<QuestionSender>,
+    request_id: u64,
+}
+
+/// Keeps a prompt's notification route registered until dropped.
+struct PromptRoute<'a> {
+    router: &'a NotificationRouter,
+    key: RouteKey,
+}
+
+impl Drop for PromptRoute<'_> {
+    fn drop(&mut self) {
+        self.router.prompts.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Counters and timestamps reported through `DaemonRequest::Status`.
+struct DaemonStats {
+    started_at: SystemTime,
+    started: Instant,
+    next_request_id: AtomicU64,
+    prompts_completed: AtomicU64,
+    prompts_failed: AtomicU64,
+    notifications_received: AtomicU64,
+    /// Unix timestamp of the last prompt start, zero when no prompt has run yet.
+    last_prompt_at: AtomicU64,
+    current_prompt: std::sync::Mutex<Option<RunningPrompt>>,
+    metrics: std::sync::Mutex<ipc::Metrics>,
+}
+
+impl DaemonStats {
+    fn new() -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            next_request_id: AtomicU64::new(1),
+            prompts_completed: AtomicU64::new(0),
+            prompts_failed: AtomicU64::new(0),
+            notifications_received: AtomicU64::new(0),
+            last_prompt_at: AtomicU64::new(0),
+            current_prompt: std::sync::Mutex::new(None),
+            metrics: std::sync::Mutex::new(ipc::Metrics {
+                since: unix_timestamp(SystemTime::now()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn uptime_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn begin_prompt(&self, kak: &KakAddress) -> u64 {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        self.last_prompt_at
+            .store(unix_timestamp(SystemTime::now()), Ordering::Relaxed);
+        *self.current_prompt.lock().unwrap() = Some(RunningPrompt::new(request_id, kak));
+        request_id
+    }
+
+    fn finish_prompt(&self, request_id: u64, succeeded: bool, elapsed: Duration) {
+        self.metrics.lock().unwrap().prompt_time_ms += elapsed.as_millis() as u64;
+        if succeeded {
+            self.prompts_completed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.prompts_failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut current = self.current_prompt.lock().unwrap();
+        if matches!(&*current, Some(running) if running.request_id == request_id) {
+            *current = None;
+        }
+    }
+
+    fn fill_status(&self, status: &mut ipc::DaemonStatus) {
+        status.uptime_seconds = self.started.elapsed().as_secs();
+        status.prompts_completed = self.prompts_completed.load(Ordering::Relaxed);
+        status.prompts_failed = self.prompts_failed.load(Ordering::Relaxed);
+        status.notifications_received = self.notifications_received.load(Ordering::Relaxed);
+        status.last_prompt_at = match self.last_prompt_at.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        };
+        status.current_prompt = self
+            .current_prompt
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(RunningPrompt::status);
+        status.metrics = self.metrics.lock().unwrap().clone();
+    }
+
+    fn record_update(&self, update: &acp::SessionUpdate) {
+        use acp::SessionUpdate;
+
+        let mut metrics = self.metrics.lock().unwrap();
+        let (kind, tool_status) = match update {
+            SessionUpdate::UserMessageChunk { .. } => ("user_message_chunk", None),
+            SessionUpdate::AgentMessageChunk { content } => {
+                if let acp::ContentBlock::Text(text) = content {
+                    metrics.agent_message_bytes += text.text.len() as u64;
+                }
+                ("agent_message_chunk", None)
+            }
+            SessionUpdate::AgentThoughtChunk { .. } => ("agent_thought_chunk", None),
+            SessionUpdate::ToolCall(tool_call) => ("tool_call", Some(tool_call.status)),
+            SessionUpdate::ToolCallUpdate(update) => ("tool_call_update", update.fields.status),
+            SessionUpdate::Plan(_) => ("plan", None),
+            SessionUpdate::AvailableCommandsUpdate { .. } => ("available_commands_update", None),
+            SessionUpdate::CurrentModeUpdate { .. } => ("current_mode_update", None),
+        };
+        *metrics.notifications.entry(kind.to_string()).or_default() += 1;
+        if let Some(status) = tool_status {
+            let status = match status {
+                acp::ToolCallStatus::Pending => "pending",
+                acp::ToolCallStatus::InProgress => "in_progress",
+                acp::ToolCallStatus::Completed => "completed",
+                acp::ToolCallStatus::Failed => "failed",
+            };
+            *metrics.tool_calls.entry(status.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Zeroes the traffic counters, returning the values they had.
+    fn reset_metrics(&self) -> ipc::Metrics {
+        let fresh = ipc::Metrics {
+            since: unix_timestamp(SystemTime::now()),
+            ..Default::default()
+        };
+        std::mem::replace(&mut *self.metrics.lock().unwrap(), fresh)
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Working directories by session id, filled in as sessions are opened.
+type SessionCwds = Arc<std::sync::Mutex<HashMap<acp::SessionId, PathBuf>>>;
+
+struct KakouneClient {
+    /// Name of the agent this client talks to.
+    agent: String,
+    router: Arc<NotificationRouter>,
+    stats: Arc<DaemonStats>,
+    permissions: Arc<PermissionBroker>,
+    /// Shared with every agent, so writes to one file take turns.
+    write_locks: Arc<PathLocks>,
+    audit: Option<Arc<AuditLog>>,
+    allow_fs_read: bool,
+    max_read_bytes: usize,
+    fs_write: FsWritePolicy,
+    allow_terminal: bool,
+    /// Commands started by this agent, shared with its `AgentSession`; dropping them kills
+    /// them.
+    terminals: Arc<Terminals>,
+    sandbox: Arc<Sandbox>,
+    diff_format: DiffFormat,
+    ext_handler: Option<Arc<ExtHandler>>,
+    ext_ack: bool,
+    /// The agent's own working directory, for sessions not in `session_cwds`.
+    cwd: PathBuf,
+    session_cwds: SessionCwds,
+}
+
+impl KakouneClient {
+    /// Resolves a path from the agent against the working directory of its session.
+    ///
+    /// Paths leading out of the sandbox are refused with an `invalid_params` error and the
+    /// attempt is recorded in the transcript.
+    fn resolve_path(
+        &self,
+        session_id: &acp::SessionId,
+        operation: &str,
+        path: &Path,
+    ) -> Result<PathBuf, acp::Error> {
+        let cwd = self.session_cwd(session_id);
+        match self.sandbox.confine(&cwd, path) {
+            Ok(Ok(resolved)) => Ok(resolved),
+            Ok(Err(escape)) => {
+                tracing::warn!(
+                    agent = self.agent,
+                    operation,
+                    path = %escape.resolved.display(),
+                    "agent request left the workspace"
+                );
+                let data = json!({
+                    "reason": "outside_workspace",
+                    "path": escape.resolved,
+                    "workspace_root": escape.root,
+                });
+                self.audit(
+                    session_id,
+                    AuditRecord::new(operation, escape.resolved.display().to_string(), "denied")
+                        .detail(format!(
+                            "outside the workspace root {}",
+                            escape.root.display()
+                        )),
+                );
+                self.router
+                    .record(&self.agent, session_id, TranscriptEvent::AccessDenied {
+                        operation: operation.to_string(),
+                        path: escape.resolved,
+                        root: escape.root,
+                    });
+                Err(acp::Error::invalid_params().with_data(data))
+            }
+            Err(err) => Err(acp::Error::internal_error()
+                .with_data(format!("failed to resolve {}: {err}", path.display()))),
+        }
+    }
+
+    /// Appends `record` to the audit log, if the daemon keeps one.
+    fn audit(&self, session_id: &acp::SessionId, record: AuditRecord) {
+        if let Some(audit) = &self.audit {
+            let request_id = self.router.request_id(&self.agent, session_id);
+            audit.append(&self.agent, session_id, request_id, record);
+        }
+    }
+
+    fn session_cwd(&self, session_id: &acp::SessionId) -> PathBuf {
+        let cwds = self.session_cwds.lock().unwrap();
+        cwds.get(session_id).unwrap_or(&self.cwd).clone()
+    }
+
+    fn record_terminal(
+        &self,
+        session_id: &acp::SessionId,
+        id: &acp::TerminalId,
+        command: &str,
+        status: &str,
+    ) {
+        self.router
+            .record(&self.agent, session_id, TranscriptEvent::Terminal {
+                id: id.to_string(),
+                command: command.to_string(),
+                status: status.to_string(),
+                exit_code: None,
+                signal: None,
+            });
+    }
+
+    /// Whether the write policy lets the agent write `path` now.
+    async fn approve_write(&self, session_id: &acp::SessionId, path: &Path) -> Result<()> {
+        match self.fs_write {
+            FsWritePolicy::Always => Ok(()),
+            FsWritePolicy::Ask => {
+                let options = [
+                    ("allow", "Allow write", acp::PermissionOptionKind::AllowOnce),
+                    ("reject", "Reject", acp::PermissionOptionKind::RejectOnce),
+                ]
+                .map(|(id, name, kind)| acp::PermissionOption {
+                    id: acp::PermissionOptionId(id.into()),
+                    name: name.to_string(),
+                    kind,
+                    meta: None,
+                });
+                let title = format!("Write {}", path.display());
+                match self
+                    .ask_permission(session_id, title, Some(acp::ToolKind::Edit), None, &options)
+                    .await
+                {
+                    Some(option) if is_allow(option.kind) => Ok(()),
+                    _ => anyhow::bail!("the write was not approved"),
+                }
+            }
+            FsWritePolicy::Never => anyhow::bail!("file writes are disabled"),
+        }
+    }
+
+    /// Asks the user to pick one of `options` and records the decision in the transcript.
+    async fn ask_permission(
+        &self,
+        session_id: &acp::SessionId,
+        title: String,
+        kind: Option<acp::ToolKind>,
+        summary: Option<String>,
+        options: &[acp::PermissionOption],
+    ) -> Option<acp::PermissionOption> {
+        // Requests that name no kind count as `other`, ACP's default kind.
+        let kind_name = kind.map(transcript::tool_kind_name);
+        let kind_or_other = kind_name.as_deref().unwrap_or("other");
+        let (mut decision, mut rule) = self.permissions.policy.decide(kind_or_other);
+        let mut stored = false;
+        if decision == PermissionDecision::Ask
+            && let Some(remembered) = self.permissions.rules.matching(kind_or_other, &title)
+        {
+            decision = match remembered.decision {
+                RuleDecision::Allow => PermissionDecision::Allow,
+                RuleDecision::Deny => PermissionDecision::Deny,
+            };
+            rule = Some(remembered.to_string());
+            stored = true;
+        }
+        let source = if stored { "stored rule" } else { "policy" };
+        let mut timed_out = false;
+        let (selected, reason) = match decision {
+            PermissionDecision::Allow => match pick_option(options, true) {
+                Some(option) => (Some(option), format!("allowed by {source}")),
+                None => (
+                    None,
+                    format!("allowed by {source}, but no allow option was offered"),
+                ),
+            },
+            PermissionDecision::Deny => {
+                (pick_option(options, false), format!("denied by {source}"))
+            }
+            PermissionDecision::Ask => {
+                let shown = summary
+                    .as_deref()
+                    .and_then(|summary| shorten(summary, self.permissions.summary_chars));
+                let prompt = self.router.request_id(&self.agent, session_id);
+                // A prompt client that went away leaves the question to Kakoune.
+                let asked_prompt = match self.router.questions(&self.agent, session_id) {
+                    Some(questions) => {
+                        self.permissions
+                            .ask_prompt(
+                                &questions,
+                                kind_or_other,
+                                &title,
+                                shown.as_deref(),
+                                options,
+                                prompt,
+                            )
+                            .await
+                    }
+                    None => None,
+                };
+                let (answer, place) = match asked_prompt {
+                    Some(answer) => (answer, "at the prompt"),
+                    None => {
+                        let origin = Origin {
+                            kak: self.router.kak(&self.agent, session_id),
+                            prompt,
+                        };
+                        let answer = self
+                            .permissions
+                            .ask(
+                                origin,
+                                kind_or_other,
+                                &title,
+                                shown.as_deref(),
+                                options,
+                                true,
+                            )
+                            .await;
+                        (answer, "in Kakoune")
+                    }
+                };
+                match answer {
+                    Answer::Chosen(option, None) => (Some(option), format!("chosen {place}")),
+                    Answer::Chosen(option, Some(remembered)) => {
+                        rule = Some(remembered.to_string());
+                        (Some(option), format!("chosen {place} and stored"))
+                    }
+                    Answer::Refused(reason) => (None, reason),
+                    Answer::TimedOut(after) => {
+                        timed_out = true;
+                        let after = format!("timed out after {}s", after.as_secs());
+                        match self.permissions.timeout_default {
+                            PermissionDefault::Cancel => (None, after),
+                            PermissionDefault::Deny => (
+                                pick_option(options, false),
+                                format!("{after}; denied by default"),
+                            ),
+                            PermissionDefault::AllowOnce => (
+                                options
+                                    .iter()
+                                    .find(|option| {
+                                        option.kind == acp::PermissionOptionKind::AllowOnce
+                                    })
+                                    .cloned(),
+                                format!("{after}; allowed once by default"),
+                            ),
+                        }
+                    }
+                }
+            }
+        };
+        tracing::info!(
+            agent = self.agent,
+            title,
+            rule,
+            option = ?selected.as_ref().map(|option| &option.id),
+            reason,
+            "permission request decided"
+        );
+        let outcome = match &selected {
+            Some(option) => option.id.to_string(),
+            None => "cancelled".to_string(),
+        };
+        self.audit(
+            session_id,
+            AuditRecord::new("permission", title.clone(), outcome).detail(reason.clone()),
+        );
+        self.router
+            .record(&self.agent, session_id, TranscriptEvent::Permission {
+                title,
+                tool_kind: kind_name,
+                outcome: if selected.is_some() {
+                    "selected"
+                } else {
+                    "cancelled"
+                }
+                .to_string(),
+                option: selected.as_ref().map(|option| option.name.clone()),
+                reason,
+                rule,
+                timed_out,
+                summary,
+            });
+        selected
+    }
+}
+
+/// The first `max_chars` characters of `text`, with an ellipsis when some were cut.
+fn shorten(text: &str, max_chars: usize) -> Option<String> {
+    if max_chars == 0 {
+        return None;
+    }
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => Some(format!("{}…", text[..end].trim_end())),
+        None => Some(text.to_string()),
+    }
+}
+
+/// The option a policy decision stands for, preferring the ones that only apply once.
+fn pick_option(options: &[acp::PermissionOption], allow: bool) -> Option<acp::PermissionOption> {
+    let preferred = if allow {
+        [
+            acp::PermissionOptionKind::AllowOnce,
+            acp::PermissionOptionKind::AllowAlways,
+        ]
+    } else {
+        [
+            acp::PermissionOptionKind::RejectOnce,
+            acp::PermissionOptionKind::RejectAlways,
+        ]
+    };
+    preferred
+        .iter()
+        .find_map(|kind| options.iter().find(|option| option.kind == *kind))
+        .cloned()
+}
+
+fn is_allow(kind: acp::PermissionOptionKind) -> bool {
+    matches!(
+        kind,
+        acp::PermissionOptionKind::AllowOnce | acp::PermissionOptionKind::AllowAlways
+    )
+}
+
+/// A permission menu entry.
+enum ReplyChoice<'a> {
+    Option(&'a acp::PermissionOptionId),
+    Always(RuleDecision),
+    Cancel,
+}
+
+/// How a permission request put to the user ended.
+enum Answer {
+    /// With the rule stored when the user answered "always".
+    Chosen(acp::PermissionOption, Option<PermissionRule>),
+    /// Not answered with an option, for the given reason.
+    Refused(String),
+    /// Nobody answered within this long.
+    TimedOut(Duration),
+}
+
+/// A request waiting for the user: what it offers, the key a stored rule would use, and where
+/// its answer goes.
+struct PendingPermission {
+    /// Asked of the prompt client through `PermissionRequest` rather than in a Kakoune menu.
+    at_prompt: bool,
+    /// Kakoune session and client showing the menu.
+    kak: KakAddress,
+    /// Request id of the prompt whose turn the request belongs to; it is cancelled when the
+    /// turn ends.
+    prompt: Option<u64>,
+    options: Vec<acp::PermissionOption>,
+    tool_kind: String,
+    title: String,
+    answer: oneshot::Sender<Reply>,
+}
+
+/// The user's answer to a pending request; no option cancels it.
+struct Reply {
+    option_id: Option<acp::PermissionOptionId>,
+    stored: Option<PermissionRule>,
+    /// Why the request was cancelled, when it was not the user's choice.
+    reason: Option<String>,
+}
+
+impl Reply {
+    fn answer(self, options: &[acp::PermissionOption], cancelled: &str) -> Answer {
+        match self.option_id {
+            Some(option_id) => Answer::Chosen(
+                options
+                    .iter()
+                    .find(|option| option.id == option_id)
+                    .cloned()
+                    .expect("answers are checked against the offered options"),
+                self.stored,
+            ),
+            None => Answer::Refused(self.reason.unwrap_or_else(|| cancelled.to_string())),
+        }
+    }
+}
+
+/// Cancels a prompt's outstanding permission requests once its turn is over, however the
+/// prompt ends.
+struct TurnPermissions<'a> {
+    broker: &'a PermissionBroker,
+    request_id: u64,
+}
+
+impl Drop for TurnPermissions<'_> {
+    fn drop(&mut self) {
+        self.broker.end_turn(self.request_id, "the turn ended");
+    }
+}
+
+/// Where a permission request shown in Kakoune comes from.
+#[derive(Default)]
+struct Origin {
+    /// Kakoune session and client to show the menu in.
+    kak: KakAddress,
+    /// Request id of the prompt whose turn asked.
+    prompt: Option<u64>,
+}
+
+/// The Kakoune session and client a prompt came from, where what concerns it is shown.
+#[derive(Clone, Debug, Default)]
+struct KakAddress {
+    /// The daemon's `--session` when `None`.
+    session: Option<String>,
+    /// The session's first client when `None`.
+    client: Option<String>,
+}
+
+/// The prompt an agent, or the daemon as a whole, is running.
+struct RunningPrompt {
+    request_id: u64,
+    started: Instant,
+    kak: KakAddress,
+}
+
+impl RunningPrompt {
+    fn new(request_id: u64, kak: &KakAddress) -> Self {
+        Self {
+            request_id,
+            started: Instant::now(),
+            kak: kak.clone(),
+        }
+    }
+
+    fn status(&self) -> ipc::ActivePrompt {
+        ipc::ActivePrompt {
+            request_id: self.request_id,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            kak_session: self.kak.session.clone(),
+            kak_client: self.kak.client.clone(),
+        }
+    }
+}
+
+/// A permission request forwarded to the prompt client that offered to answer it.
+struct PermissionQuestion {
+    id: u64,
+    title: String,
+    tool_kind: String,
+    options: Vec<acp::PermissionOption>,
+    preview: Option<String>,
+}
+
+/// Where a prompt's permission questions go on their way to its client.
+type QuestionSender = mpsc::UnboundedSender<PermissionQuestion>;
+
+/// How waiting for a pending request ended.
+enum Waited {
+    Reply(Reply),
+    /// Nobody answered before the timeout, which took the request out of `pending`.
+    TimedOut,
+    /// The request was withdrawn without an answer.
+    Withdrawn,
+}
+
+/// Puts agents' permission requests to the user as menus in the Kakoune session.
+///
+/// The menu entries run `kakoune-acp permission-reply`, whose answer comes back over the
+/// socket and completes the waiting request.
+struct PermissionBroker {
+    policy: PermissionPolicy,
+    /// What requests nobody answers within `timeout` resolve to.
+    timeout_default: PermissionDefault,
+    /// Longest input summary shown with a request.
+    summary_chars: usize,
+    kak_session: Option<String>,
+    socket_path: PathBuf,
+    timeout: Duration,
+    /// Rules stored by "always" answers, checked before anyone is asked.
+    rules: RuleStore,
+    next_id: AtomicU64,
+    pending: std::sync::Mutex<HashMap<u64, PendingPermission>>,
+    /// Requests recently cancelled because their turn ended, whose late answers are ignored.
+    ended: std::sync::Mutex<VecDeque<u64>>,
+}
+
+/// Ended requests remembered for ignoring late answers.
+const MAX_ENDED_PERMISSIONS: usize = 64;
+
+impl PermissionBroker {
+    fn new(
+        kak_session: Option<String>,
+        socket_path: PathBuf,
+        timeout: Duration,
+        timeout_default: PermissionDefault,
+        summary_chars: usize,
+        policy: PermissionPolicy,
+        rules: RuleStore,
+    ) -> Self {
+        Self {
+            policy,
+            timeout_default,
+            summary_chars,
+            kak_session,
+            socket_path,
+            timeout,
+            rules,
+            next_id: AtomicU64::new(1),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            ended: std::sync::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Waits for the user to pick one of `options`, or returns why no option was picked.
+    ///
+    /// `preview` is shown under the title; `offer_rules` adds the entries storing a rule.
+    async fn ask(
+        &self,
+        origin: Origin,
+        tool_kind: &str,
+        title: &str,
+        preview: Option<&str>,
+        options: &[acp::PermissionOption],
+        offer_rules: bool,
+    ) -> Answer {
+        let Origin { kak, prompt } = origin;
+        let Some(session) = kak.session.clone().or_else(|| self.kak_session.clone()) else {
+            return Answer::Refused("no Kakoune session to ask".to_string());
+        };
+        let client = kak.client.clone();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, PendingPermission {
+            at_prompt: false,
+            kak,
+            prompt,
+            options: options.to_vec(),
+            tool_kind: tool_kind.to_string(),
+            title: title.to_string(),
+            answer: answer_tx,
+        });
+
+        let mut choices = options
+            .iter()
+            .map(|option| {
+                (
+                    option.name.clone(),
+                    self.reply_command(id, ReplyChoice::Option(&option.id)),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (label, decision) in [
+            ("Always allow this tool", RuleDecision::Allow),
+            ("Always deny this tool", RuleDecision::Deny),
+        ] {
+            if offer_rules && pick_option(options, decision == RuleDecision::Allow).is_some() {
+                choices.push((
+                    label.to_string(),
+                    self.reply_command(id, ReplyChoice::Always(decision)),
+                ));
+            }
+        }
+        choices.push((
+            "Cancel".to_string(),
+            self.reply_command(id, ReplyChoice::Cancel),
+        ));
+        let shown = match preview {
+            Some(preview) => format!("{title}\n\n{preview}"),
+            None => title.to_string(),
+        };
+        let command = kakoune::format_permission_menu(client.as_deref(), &shown, &choices);
+        let menu_session = session.clone();
+        let sent =
+            tokio::task::spawn_blocking(move || kakoune::send_to_kak(&menu_session, &command))
+                .await;
+        if let Some(err) = match sent {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(format!("{err:#}")),
+            Err(err) => Some(err.to_string()),
+        } {
+            self.pending.lock().unwrap().remove(&id);
+            return Answer::Refused(format!("could not ask Kakoune: {err}"));
+        }
+
+        match self.wait(id, answer_rx).await {
+            Waited::Reply(reply) => reply.answer(options, "cancelled in Kakoune"),
+            Waited::TimedOut => {
+                let note = format!("No answer after {}s", self.timeout.as_secs());
+                let command = kakoune::format_permission_dismiss(client.as_deref(), &note);
+                tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command));
+                Answer::TimedOut(self.timeout)
+            }
+            Waited::Withdrawn => Answer::Refused("the request was dropped".to_string()),
+        }
+    }
+
+    /// Asks the prompt client behind `questions`; `None` when it went away without answering.
+    async fn ask_prompt(
+        &self,
+        questions: &QuestionSender,
+        tool_kind: &str,
+        title: &str,
+        preview: Option<&str>,
+        options: &[acp::PermissionOption],
+        prompt: Option<u64>,
+    ) -> Option<Answer> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, PendingPermission {
+            at_prompt: true,
+            kak: KakAddress::default(),
+            prompt,
+            options: options.to_vec(),
+            tool_kind: tool_kind.to_string(),
+            title: title.to_string(),
+            answer: answer_tx,
+        });
+        let question = PermissionQuestion {
+            id,
+            title: title.to_string(),
+            tool_kind: tool_kind.to_string(),
+            options: options.to_vec(),
+            preview: preview.map(str::to_string),
+        };
+        if questions.send(question).is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return None;
+        }
+        match self.wait(id, answer_rx).await {
+            Waited::Reply(reply) => Some(reply.answer(options, "cancelled at the prompt")),
+            Waited::TimedOut => Some(Answer::TimedOut(self.timeout)),
+            Waited::Withdrawn => None,
+        }
+    }
+
+    /// Waits up to `timeout` for the answer to pending request `id`.
+    async fn wait(&self, id: u64, mut answer_rx: oneshot::Receiver<Reply>) -> Waited {
+        match tokio::time::timeout(self.timeout, &mut answer_rx).await {
+            Ok(Ok(reply)) => Waited::Reply(reply),
+            Ok(Err(_)) => Waited::Withdrawn,
+            // Whoever takes the request out of `pending` first decides it: a reply that got
+            // there before the timeout is already waiting in the channel.
+            Err(_) if self.pending.lock().unwrap().remove(&id).is_some() => Waited::TimedOut,
+            Err(_) => match answer_rx.try_recv() {
+                Ok(reply) => Waited::Reply(reply),
+                Err(_) => Waited::Withdrawn,
+            },
+        }
+    }
+
+    /// Gives up on asking the prompt client about request `id`, e.g. because it disconnected.
+    fn withdraw(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    /// Cancels the requests still waiting on the turn of prompt `request_id` and closes their
+    /// menus, since answering them could no longer make a difference.
+    fn end_turn(&self, request_id: u64, reason: &str) {
+        let ended = self
+            .pending
+            .lock()
+            .unwrap()
+            .extract_if(|_, request| request.prompt == Some(request_id))
+            .collect::<Vec<_>>();
+        for (id, request) in ended {
+            tracing::info!(id, request_id, reason, "cancelling a permission request");
+            {
+                let mut recent = self.ended.lock().unwrap();
+                if recent.len() == MAX_ENDED_PERMISSIONS {
+                    recent.pop_front();
+                }
+                recent.push_back(id);
+            }
+            let _ = request.answer.send(Reply {
+                option_id: None,
+                stored: None,
+                reason: Some(reason.to_string()),
+            });
+            if !request.at_prompt
+                && let Some(session) = request.kak.session.or_else(|| self.kak_session.clone())
+            {
+                let note = format!("{}: {reason}", request.title);
+                let command =
+                    kakoune::format_permission_dismiss(request.kak.client.as_deref(), &note);
+                tokio::task::spawn_blocking(move || kakoune::send_to_kak(&session, &command));
+            }
+        }
+    }
+
+    /// Completes the pending request `id` with `option_id`, or cancels it without one.
+    ///
+    /// `always` picks the option itself and stores a rule settling later requests for the same
+    /// tool the same way.
+    ///
+    /// `at_prompt` says whether the answer comes from a prompt client rather than a Kakoune
+    /// menu; each may only answer the requests put to it.
+    fn answer(
+        &self,
+        id: u64,
+        option_id: Option<String>,
+        always: Option<RuleDecision>,
+        at_prompt: bool,
+    ) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(request) = pending
+            .get(&id)
+            .filter(|request| request.at_prompt == at_prompt)
+        else {
+            if self.ended.lock().unwrap().contains(&id) {
+                tracing::debug!(id, "ignoring an answer to a request whose turn ended");
+                return Ok(());
+            }
+            anyhow::bail!("no permission request {id} is waiting for an answer");
+        };
+        let option_id = match always {
+            Some(decision) => {
+                let Some(option) = pick_option(&request.options, decision == RuleDecision::Allow)
+                else {
+                    anyhow::bail!("permission request {id} offers no option to {decision}");
+                };
+                Some(option.id)
+            }
+            None => option_id.map(|option_id| acp::PermissionOptionId(option_id.into())),
+        };
+        if let Some(option_id) = &option_id
+            && !request.options.iter().any(|option| option.id == *option_id)
+        {
+            let offered = request
+                .options
+                .iter()
+                .map(|option| option.id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            anyhow::bail!("permission request {id} has no option {option_id} (offered: {offered})");
+        }
+        let request = pending.remove(&id).expect("request is pending");
+        let stored = always.map(|decision| {
+            self.rules
+                .remember(&request.tool_kind, &request.title, decision)
+        });
+        let _ = request.answer.send(Reply {
+            option_id,
+            stored,
+            reason: None,
+        });
+        Ok(())
+    }
+
+    /// Shell command a menu entry runs to report the user's choice.
+    fn reply_command(&self, id: u64, choice: ReplyChoice) -> String {
+        let program = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("kakoune-acp"));
+        let mut command = format!(
+            "{} permission-reply --socket {} --id {id}",
+            kakoune::sh_quote(&program.to_string_lossy()),
+            kakoune::sh_quote(&self.socket_path.to_string_lossy())
+        );
+        match choice {
+            ReplyChoice::Option(option_id) => {
+                command.push_str(&format!(" --option {}", kakoune::sh_quote(&option_id.0)))
+            }
+            ReplyChoice::Always(decision) => command.push_str(&format!(" --always {decision}")),
+            ReplyChoice::Cancel => {}
+        }
+        command
+    }
+}
+
+fn unknown_terminal(error: anyhow::Error) -> acp::Error {
+    acp::Error::resource_not_found(Some(error.to_string()))
+}
+
+/// What `read_text_file` returned.
+struct TextRead {
+    content: String,
+    /// The requested lines went on past `max_bytes`.
+    truncated: bool,
+    /// Size of the whole file.
+    total_bytes: u64,
+}
+
+/// Reads a UTF-8 file for `fs/read_text_file`, keeping `limit` lines from the 1-based `line`.
+///
+/// The file is streamed, so skipping to a late line of a huge file costs no memory; at most
+/// `max_bytes` are kept, cut back to the last whole character.
+async fn read_text_file(
+    path: &Path,
+    line: Option<u32>,
+    limit: Option<u32>,
+    max_bytes: usize,
+) -> Result<TextRead> {
+    use tokio::io::AsyncBufReadExt;
+
+    let context = || format!("cannot read {}", path.display());
+    let file = tokio::fs::File::open(path).await.with_context(context)?;
+    let total_bytes = file.metadata().await.with_context(context)?.len();
+    let mut reader = tokio::io::BufReader::new(file);
+    let mut skip = line.unwrap_or(1).saturating_sub(1);
+    let mut lines_left = limit;
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    while lines_left != Some(0) && !truncated {
+        let buffer = reader.fill_buf().await.with_context(context)?;
+        if buffer.is_empty() {
+            break;
+        }
+        let mut used = 0;
+        while skip > 0 && used < buffer.len() {
+            match buffer[used..].iter().position(|&byte| byte == b'\n') {
+                Some(newline) => {
+                    used += newline + 1;
+                    skip -= 1;
+                }
+                None => used = buffer.len(),
+            }
+        }
+        while skip == 0 && used < buffer.len() && lines_left != Some(0) {
+            let rest = &buffer[used..];
+            let end = match rest.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => {
+                    lines_left = lines_left.map(|left| left - 1);
+                    newline + 1
+                }
+                None => rest.len(),
+            };
+            kept.extend_from_slice(&rest[..end]);
+            used += end;
+            if kept.len() > max_bytes {
+                truncated = true;
+                break;
+            }
+        }
+        reader.consume(used);
+    }
+    if truncated {
+        kept.truncate(max_bytes);
+        // A character cut in two by the limit goes; anything else invalid is reported below.
+        if let Err(error) = std::str::from_utf8(&kept)
+            && error.error_len().is_none()
+        {
+            kept.truncate(error.valid_up_to());
+        }
+    }
+    let content = String::from_utf8(kept)
+        .map_err(|_| anyhow::anyhow!("{} is not UTF-8 text", path.display()))?;
+    Ok(TextRead {
+        content,
+        truncated,
+        total_bytes,
+    })
+}
+
+#[async_trait::async_trait(?Send)]
+impl acp::Client for KakouneClient {
+    async fn request_permission(
+        &self,
+        args: acp::RequestPermissionRequest,
+    ) -> Result<acp::RequestPermissionResponse, acp::Error> {
+        let fields = &args.tool_call.fields;
+        let title = fields
+            .title
+            .clone()
+            .unwrap_or_else(|| args.tool_call.id.0.to_string());
+        let summary = transcript::permission_summary(fields);
+        let selected = self
+            .ask_permission(&args.session_id, title, fields.kind, summary, &args.options)
+            .await;
+        Ok(acp::RequestPermissionResponse {
+            outcome: match selected {
+                Some(option) => acp::RequestPermissionOutcome::Selected {
+                    option_id: option.id,
+                },
+                None => acp::RequestPermissionOutcome::Cancelled,
+            },
+            meta: None,
+        })
+    }
+
+    async fn read_text_file(
+        &self,
+        args: acp::ReadTextFileRequest,
+    ) -> Result<acp::ReadTextFileResponse, acp::Error> {
+        if !self.allow_fs_read {
+            return Err(acp::Error::method_not_found());
+        }
+        let path = self.resolve_path(&args.session_id, "read", &args.path)?;
+        let read = match read_text_file(&path, args.line, args.limit, self.max_read_bytes).await {
+            Ok(read) => read,
+            Err(error) => {
+                tracing::debug!(agent = self.agent, ?error, "agent file read failed");
+                self.audit(
+                    &args.session_id,
+                    AuditRecord::new("read", path.display().to_string(), "failed")
+                        .detail(format!("{error:#}")),
+                );
+                let not_found = error
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound);
+                return Err(if not_found {
+                    acp::Error::resource_not_found(Some(path.display().to_string()))
+                } else {
+                    acp::Error::internal_error().with_data(format!("{error:#}"))
+                });
+            }
+        };
+        tracing::info!(
+            agent = self.agent,
+            path = %path.display(),
+            truncated = read.truncated,
+            "agent read a file"
+        );
+        let bytes = read.content.len() as u64;
+        let mut record = AuditRecord::new("read", path.display().to_string(), "ok").bytes(bytes);
+        if read.truncated {
+            record = record.detail(format!("truncated, {} bytes in the file", read.total_bytes));
+        }
+        self.audit(&args.session_id, record);
+        self.router
+            .record(&self.agent, &args.session_id, TranscriptEvent::FileRead {
+                path,
+                truncated: read.truncated,
+                bytes,
+            });
+        // Agents that understand it can page through the rest with `line` and `limit`.
+        let meta = read.truncated.then(|| {
+            json!({
+                "truncated": true,
+                "returned_bytes": bytes,
+                "total_bytes": read.total_bytes,
+            })
+        });
+        Ok(acp::ReadTextFileResponse {
+            content: read.content,
+            meta,
+        })
+    }
+
+    async fn write_text_file(
+        &self,
+        args: acp::WriteTextFileRequest,
+    ) -> Result<acp::WriteTextFileResponse, acp::Error> {
+        if self.fs_write == FsWritePolicy::Never {
+            return Err(acp::Error::method_not_found());
+        }
+        let path = self.resolve_path(&args.session_id, "write", &args.path)?;
+        let mut old = None;
+        let written = match self.approve_write(&args.session_id, &path).await {
+            Ok(()) => {
+                let _writing = self.write_locks.lock(&path).await;
+                // Missing or non-UTF-8 files diff as new ones.
+                old = tokio::fs::read_to_string(&path).await.ok();
+                diffs::write_text_file(&path, &args.content).await
+            }
+            Err(refusal) => Err(refusal),
+        };
+        match written {
+            Ok((byte_delta, backup)) => {
+                tracing::info!(agent = self.agent, path = %path.display(), byte_delta, "agent wrote a file");
+                self.audit(
+                    &args.session_id,
+                    AuditRecord::new("write", path.display().to_string(), "ok")
+                        .bytes(args.content.len() as u64),
+                );
+                self.router
+                    .record(&self.agent, &args.session_id, TranscriptEvent::FileWrite {
+                        path: path.clone(),
+                        byte_delta,
+                        backup,
+                    });
+                let edit =
+                    transcript::file_edit(&path, old.as_deref(), &args.content, &self.diff_format);
+                self.router.record(&self.agent, &args.session_id, edit);
+                Ok(acp::WriteTextFileResponse { meta: None })
+            }
+            Err(error) => {
+                tracing::info!(agent = self.agent, ?error, "agent file write refused");
+                self.audit(
+                    &args.session_id,
+                    AuditRecord::new("write", path.display().to_string(), "failed")
+                        .detail(format!("{error:#}")),
+                );
+                // `ObservedClient` notes the error in the transcript.
+                Err(acp::Error::internal_error()
+                    .with_data(format!("did not write {}: {error:#}", path.display())))
+            }
+        }
+    }
+
+    async fn create_terminal(
+        &self,
+        args: acp::CreateTerminalRequest,
+    ) -> Result<acp::CreateTerminalResponse, acp::Error> {
+        if !self.allow_terminal {
+            return Err(acp::Error::method_not_found());
+        }
+        let session_id = args.session_id;
+        let cwd = self.resolve_path(
+            &session_id,
+            "terminal",
+            args.cwd.as_deref().unwrap_or(Path::new(".")),
+        )?;
+        let requested = std::iter::once(&args.command)
+            .chain(&args.args)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let launch = terminal::Launch {
+            session_id: session_id.clone(),
+            command: args.command,
+            args: args.args,
+            env: args
+                .env
+                .into_iter()
+                .map(|var| (var.name, var.value))
+                .collect(),
+            cwd,
+            output_byte_limit: args.output_byte_limit,
+        };
+        let (terminal_id, mut exit) = self.terminals.create(launch).map_err(|error| {
+            tracing::info!(agent = self.agent, ?error, "agent terminal failed to start");
+            self.audit(
+                &session_id,
+                AuditRecord::new("terminal", requested.clone(), "failed")
+                    .detail(format!("{error:#}")),
+            );
+            acp::Error::internal_error().with_data(format!("{error:#}"))
+        })?;
+        self.audit(
+            &session_id,
+            AuditRecord::new("terminal", requested, "ok").detail(format!("terminal {terminal_id}")),
+        );
+        let command = self
+            .terminals
+            .command_line(&session_id, &terminal_id)
+            .map_err(unknown_terminal)?;
+        tracing::info!(agent = self.agent, %terminal_id, command, "agent started a terminal");
+        self.record_terminal(&session_id, &terminal_id, &command, "created");
+
+        let router = self.router.clone();
+        let agent = self.agent.clone();
+        let id = terminal_id.clone();
+        tokio::task::spawn_local(async move {
+            let Ok(status) = exit.wait_for(Option::is_some).await else {
+                return;
+            };
+            let status = status.clone().expect("waited for an exit status");
+            router.record(&agent, &session_id, TranscriptEvent::Terminal {
+                id: id.to_string(),
+                command,
+                status: "exited".to_string(),
+                exit_code: status.exit_code,
+                signal: status.signal,
+            });
+        });
+        Ok(acp::CreateTerminalResponse {
+            terminal_id,
+            meta: None,
+        })
+    }
+
+    async fn terminal_output(
+        &self,
+        args: acp::TerminalOutputRequest,
+    ) -> Result<acp::TerminalOutputResponse, acp::Error> {
+        self.terminals
+            .output(&args.session_id, &args.terminal_id)
+            .map_err(unknown_terminal)
+    }
+
+    async fn wait_for_terminal_exit(
+        &self,
+        args: acp::WaitForTerminalExitRequest,
+    ) -> Result<acp::WaitForTerminalExitResponse, acp::Error> {
+        let exit_status = self
+            .terminals
+            .wait_for_exit(&args.session_id, &args.terminal_id)
+            .await
+            .map_err(unknown_terminal)?;
+        Ok(acp::WaitForTerminalExitResponse {
+            exit_status,
+            meta: None,
+        })
+    }
+
+    async fn kill_terminal_command(
+        &self,
+        args: acp::KillTerminalCommandRequest,
+    ) -> Result<acp::KillTerminalCommandResponse, acp::Error> {
+        let (session_id, id) = (&args.session_id, &args.terminal_id);
+        let command = self
+            .terminals
+            .command_line(session_id, id)
+            .map_err(unknown_terminal)?;
+        if self
+            .terminals
+            .kill(session_id, id)
+            .map_err(unknown_terminal)?
+        {
+            self.record_terminal(session_id, id, &command, "killed");
+        }
+        Ok(acp::KillTerminalCommandResponse { meta: None })
+    }
+
+    async fn release_terminal(
+        &self,
+        args: acp::ReleaseTerminalRequest,
+    ) -> Result<acp::ReleaseTerminalResponse, acp::Error> {
+        let (session_id, id) = (&args.session_id, &args.terminal_id);
+        let command = self
+            .terminals
+            .command_line(session_id, id)
+            .map_err(unknown_terminal)?;
+        if let Ok(output) = self.terminals.output(session_id, id) {
+            self.router.record(
+                &self.agent,
+                session_id,
+                transcript::terminal_output(id, &command, output),
+            );
+        }
+        if self
+            .terminals
+            .release(session_id, id)
+            .map_err(unknown_terminal)?
+        {
+            self.record_terminal(session_id, id, &command, "killed");
+        }
+        self.record_terminal(session_id, id, &command, "released");
+        Ok(acp::ReleaseTerminalResponse { meta: None })
+    }
+
+    async fn session_notification(&self, args: acp::SessionNotification) -> Result<(), acp::Error> {
+        self.stats
+            .notifications_received
+            .fetch_add(1, Ordering::Relaxed);
+        self.stats.record_update(&args.update);
+        self.router.dispatch(&self.agent, args);
+        Ok(())
+    }
+
+    async fn ext_method(&self, args: acp::ExtRequest) -> Result<acp::ExtResponse, acp::Error> {
+        match &self.ext_handler {
+            Some(handler) => handler.call(&self.agent, &args).await,
+            None if self.ext_ack => {
+                tracing::info!(agent = self.agent, method = %args.method, "acknowledged extension call");
+                Ok(ext::empty_object())
+            }
+            None => {
+                tracing::info!(agent = self.agent, method = %args.method, "refused extension call");
+                Err(acp::Error::method_not_found())
+            }
+        }
+    }
+
+    async fn ext_notification(&self, args: acp::ExtNotification) -> Result<(), acp::Error> {
+        tracing::debug!(agent = self.agent, method = %args.method, "extension notification");
+        let params = serde_json::from_str::<serde_json::Value>(args.params.get()).ok();
+        // Notifications reporting an error, such as a failed tool, are told apart from the
+        // rest.
+        let error = params.as_ref().and_then(|params| params.get("error"));
+        let event = match error {
+            Some(error) => TranscriptEvent::Error {
+                source: ErrorSource::Extension,
+                message: format!(
+                    "{}: {}",
+                    args.method,
+                    error
+                        .get("message")
+                        .unwrap_or(error)
+                        .as_str()
+                        .map_or_else(|| error.to_string(), str::to_string)
+                ),
+                count: None,
+            },
+            None => TranscriptEvent::SystemMessage {
+                text: format!("{}: {}", args.method, args.params.get()),
+            },
+        };
+        self.record_for(ext_session_id(params.as_ref()).as_ref(), event);
+        Ok(())
+    }
+}
+
+impl KakouneClient {
+    /// Adds `event` to the prompt running on `session_id`, or to every prompt running on the
+    /// agent when the message it comes from names no session.
+    fn record_for(&self, session_id: Option<&acp::SessionId>, event: TranscriptEvent) {
+        match session_id {
+            Some(session_id) => self.router.record(&self.agent, session_id, event),
+            None => self.router.record_all(&self.agent, event),
+        }
+    }
+
+    /// Notes in the transcript of the prompt the agent's `method` request came from that it
+    /// was answered with `result`'s error, if it was.
+    fn observe<T>(
+        &self,
+        session_id: Option<&acp::SessionId>,
+        method: &str,
+        result: Result<T, acp::Error>,
+    ) -> Result<T, acp::Error> {
+        let Err(error) = &result else {
+            return result;
+        };
+        // Requests for paths outside the workspace have an `access_denied` event already.
+        let reason = error.data.as_ref().and_then(|data| data.get("reason"));
+        if reason.and_then(serde_json::Value::as_str) == Some("outside_workspace") {
+            return result;
+        }
+        let mut message = format!("{method}: {}", error.message);
+        if let Some(serde_json::Value::String(data)) = &error.data {
+            message.push_str(&format!(" ({data})"));
+        }
+        let event = TranscriptEvent::Error {
+            source: ErrorSource::Protocol,
+            message,
+            count: None,
+        };
+        self.record_for(session_id, event);
+        result
+    }
+}
+
+/// The session an extension message's `params` name, if any.
+fn ext_session_id(params: Option<&serde_json::Value>) -> Option<acp::SessionId> {
+    let session_id = params?.get("sessionId")?.as_str()?;
+    Some(acp::SessionId(session_id.into()))
+}
+
+/// The client the agent's connection talks to: a [`KakouneClient`] whose errors in answer to
+/// the agent's requests are noted in the transcripts of the prompts they came during, so a
+/// turn cut short by them says why.
+struct ObservedClient(KakouneClient);
+
+#[async_trait::async_trait(?Send)]
+impl acp::Client for ObservedClient {
+    async fn request_permission(
+        &self,
+        args: acp::RequestPermissionRequest,
+    ) -> Result<acp::RequestPermissionResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.request_permission(args).await;
+        self.0
+            .observe(Some(&session_id), "session/request_permission", result)
+    }
+
+    async fn read_text_file(
+        &self,
+        args: acp::ReadTextFileRequest,
+    ) -> Result<acp::ReadTextFileResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.read_text_file(args).await;
+        self.0
+            .observe(Some(&session_id), "fs/read_text_file", result)
+    }
+
+    async fn write_text_file(
+        &self,
+        args: acp::WriteTextFileRequest,
+    ) -> Result<acp::WriteTextFileResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.write_text_file(args).await;
+        self.0
+            .observe(Some(&session_id), "fs/write_text_file", result)
+    }
+
+    async fn create_terminal(
+        &self,
+        args: acp::CreateTerminalRequest,
+    ) -> Result<acp::CreateTerminalResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.create_terminal(args).await;
+        self.0.observe(Some(&session_id), "terminal/create", result)
+    }
+
+    async fn terminal_output(
+        &self,
+        args: acp::TerminalOutputRequest,
+    ) -> Result<acp::TerminalOutputResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.terminal_output(args).await;
+        self.0.observe(Some(&session_id), "terminal/output", result)
+    }
+
+    async fn wait_for_terminal_exit(
+        &self,
+        args: acp::WaitForTerminalExitRequest,
+    ) -> Result<acp::WaitForTerminalExitResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.wait_for_terminal_exit(args).await;
+        self.0
+            .observe(Some(&session_id), "terminal/wait_for_exit", result)
+    }
+
+    async fn kill_terminal_command(
+        &self,
+        args: acp::KillTerminalCommandRequest,
+    ) -> Result<acp::KillTerminalCommandResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.kill_terminal_command(args).await;
+        self.0.observe(Some(&session_id), "terminal/kill", result)
+    }
+
+    async fn release_terminal(
+        &self,
+        args: acp::ReleaseTerminalRequest,
+    ) -> Result<acp::ReleaseTerminalResponse, acp::Error> {
+        let session_id = args.session_id.clone();
+        let result = self.0.release_terminal(args).await;
+        self.0
+            .observe(Some(&session_id), "terminal/release", result)
+    }
+
+    async fn session_notification(&self, args: acp::SessionNotification) -> Result<(), acp::Error> {
+        self.0.session_notification(args).await
+    }
+
+    async fn ext_method(&self, args: acp::ExtRequest) -> Result<acp::ExtResponse, acp::Error> {
+        let params = serde_json::from_str::<serde_json::Value>(args.params.get()).ok();
+        let session_id = ext_session_id(params.as_ref());
+        let method = args.method.clone();
+        let result = self.0.ext_method(args).await;
+        self.0.observe(session_id.as_ref(), &method, result)
+    }
+
+    async fn ext_notification(&self, args: acp::ExtNotification) -> Result<(), acp::Error> {
+        self.0.ext_notification(args).await
     }
 }
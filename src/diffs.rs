@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result};
+use tokio::{io::AsyncWriteExt, sync::OwnedMutexGuard};
+
+use crate::ipc::{DiffOutcome, DiffStatus};
+
+/// One lock per file, so writes to the same path take turns while writes to different paths
+/// go ahead in parallel.
+#[derive(Default)]
+pub struct PathLocks {
+    locks: std::sync::Mutex<HashMap<PathBuf, Weak<tokio::sync::Mutex<()>>>>,
+}
+
+impl PathLocks {
+    /// Waits for the other writers of `path` to finish and holds it until the guard drops.
+    pub async fn lock(&self, path: &Path) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Locks nobody holds or waits for are forgotten.
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(path).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(path.to_path_buf(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Writes the new text of an agent's diff to `path`, the diff's path resolved inside the
+/// workspace, unless [`check`] finds it cannot apply.
+pub async fn apply(diff: &acp::Diff, path: &Path) -> DiffOutcome {
+    if let Some(outcome) = check(diff, path).await {
+        return outcome;
+    }
+    match write_text_file(path, &diff.new_text).await {
+        Ok((_, backup)) => DiffOutcome {
+            backup,
+            ..outcome(path, DiffStatus::Applied, None)
+        },
+        Err(error) => outcome(path, DiffStatus::Failed, Some(format!("{error:#}"))),
+    }
+}
+
+/// Why `diff` cannot be applied to `path` as it is now: the file no longer holds the diff's
+/// old text, or already holds its new text.
+pub async fn check(diff: &acp::Diff, path: &Path) -> Option<DiffOutcome> {
+    // Missing or non-UTF-8 files count as absent, which only a diff creating the file expects.
+    let current = tokio::fs::read_to_string(path).await.ok();
+    if current.as_deref() == Some(diff.new_text.as_str()) {
+        return Some(outcome(
+            path,
+            DiffStatus::Skipped,
+            Some("already applied".to_string()),
+        ));
+    }
+    if current == diff.old_text {
+        return None;
+    }
+    let reason = match (&current, &diff.old_text) {
+        (None, _) => "the file does not exist",
+        (Some(_), None) => "the diff creates a file that already exists",
+        (Some(_), Some(_)) => "the file no longer matches the diff's old text",
+    };
+    Some(outcome(
+        path,
+        DiffStatus::Conflict,
+        Some(reason.to_string()),
+    ))
+}
+
+/// The outcome of a diff that was not written.
+pub fn outcome(path: &Path, status: DiffStatus, reason: Option<String>) -> DiffOutcome {
+    DiffOutcome {
+        path: path.to_path_buf(),
+        status,
+        reason,
+        backup: None,
+    }
+}
+
+/// Replaces `path` with `content`, for `fs/write_text_file` and applied diffs.
+///
+/// The new contents go to a temporary file in the same directory, synced to disk and given
+/// the old file's mode, that is renamed over `path`, so readers never see a partial write. An
+/// existing file is first copied to `FILE.kakoune-acp.bak`. Returns the change in size and
+/// the backup, if one was made.
+///
+/// Callers hold the path's [`PathLocks`] lock, since concurrent writers would share the
+/// temporary file and the backup.
+pub async fn write_text_file(path: &Path, content: &str) -> Result<(i64, Option<PathBuf>)> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} does not name a file", path.display()))?
+        .to_string_lossy();
+    let previous = match tokio::fs::metadata(path).await {
+        Ok(metadata) => Some(metadata),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(err).with_context(|| format!("cannot write {}", path.display()));
+        }
+    };
+    let backup = match &previous {
+        Some(_) => {
+            let backup = path.with_file_name(format!("{name}.kakoune-acp.bak"));
+            tokio::fs::copy(path, &backup)
+                .await
+                .with_context(|| format!("cannot back up {}", path.display()))?;
+            Some(backup)
+        }
+        None => None,
+    };
+    let temporary = path.with_file_name(format!(".{name}.kakoune-acp.tmp"));
+    let written = async {
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        file.write_all(content.as_bytes()).await?;
+        if let Some(previous) = &previous {
+            file.set_permissions(previous.permissions()).await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary, path).await
+    };
+    if let Err(err) = written.await {
+        let _ = tokio::fs::remove_file(&temporary).await;
+        return Err(err).with_context(|| format!("cannot write {}", path.display()));
+    }
+    let before = previous.map_or(0, |previous| previous.len());
+    Ok((content.len() as i64 - before as i64, backup))
+}
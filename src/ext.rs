@@ -0,0 +1,103 @@
+use std::{process::Stdio, sync::Arc, time::Duration};
+
+use agent_client_protocol as acp;
+use serde_json::{json, value::RawValue};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Stderr kept in the error an agent gets back from a failing handler.
+const MAX_STDERR_BYTES: usize = 4096;
+
+/// Runs `--ext-handler` for the agents' extension method calls.
+///
+/// The command runs through `sh -c` with the method name as its first argument and the
+/// params as JSON on stdin; whatever JSON it prints is the response, `{}` when it prints
+/// nothing.
+pub struct ExtHandler {
+    command: String,
+    timeout: Duration,
+}
+
+impl ExtHandler {
+    pub fn new(command: String, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    pub async fn call(
+        &self,
+        agent: &str,
+        request: &acp::ExtRequest,
+    ) -> Result<acp::ExtResponse, acp::Error> {
+        let method = &*request.method;
+        let failed = |reason: &str, details: serde_json::Value| {
+            let mut data = json!({ "method": method, "reason": reason });
+            if let (Some(data), serde_json::Value::Object(details)) =
+                (data.as_object_mut(), details)
+            {
+                data.extend(details);
+            }
+            acp::Error::internal_error().with_data(data)
+        };
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", self.command))
+            .arg("sh")
+            .arg(method)
+            .env("KAKOUNE_ACP_AGENT", agent)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| failed("spawn_failed", json!({ "error": err.to_string() })))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let params = request.params.get().to_string();
+        let output = async move {
+            // A handler that never reads its params must not wedge the call.
+            let _ = stdin.write_all(params.as_bytes()).await;
+            drop(stdin);
+            child.wait_with_output().await
+        };
+        let output = match tokio::time::timeout(self.timeout, output).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => return Err(failed("io_error", json!({ "error": err.to_string() }))),
+            Err(_) => {
+                tracing::warn!(agent, method, "extension handler timed out");
+                return Err(failed(
+                    "timeout",
+                    json!({ "timeout_seconds": self.timeout.as_secs() }),
+                ));
+            }
+        };
+
+        if !output.status.success() {
+            let mut stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            if stderr.len() > MAX_STDERR_BYTES {
+                let mut end = MAX_STDERR_BYTES;
+                while !stderr.is_char_boundary(end) {
+                    end -= 1;
+                }
+                stderr.truncate(end);
+            }
+            tracing::warn!(agent, method, status = %output.status, "extension handler failed");
+            return Err(failed(
+                "handler_failed",
+                json!({ "exit_code": output.status.code(), "stderr": stderr.trim_end() }),
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stdout = stdout.trim();
+        if stdout.is_empty() {
+            return Ok(empty_object());
+        }
+        match RawValue::from_string(stdout.to_string()) {
+            Ok(response) => Ok(Arc::from(response)),
+            Err(err) => Err(failed("invalid_json", json!({ "error": err.to_string() }))),
+        }
+    }
+}
+
+/// The `{}` response acknowledging an extension call nobody handles.
+pub fn empty_object() -> acp::ExtResponse {
+    Arc::from(RawValue::from_string("{}".to_string()).expect("{} is valid JSON"))
+}
@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    cli::{PromptOutput, TranscriptOptions},
+    config,
+    ipc::{PlanProgress, PromptResultPayload, RESULT_SCHEMA_VERSION, SchemaProbe},
+    kakoune,
+    prompt::{self, Delivery},
+    redact::Redactor,
+    transcript,
+};
+
+/// Numbered JSON transcripts stored under the session's state directory.
+pub struct TranscriptStore {
+    directory: PathBuf,
+    max_files: usize,
+}
+
+impl TranscriptStore {
+    pub fn new(directory: PathBuf, max_files: usize) -> Self {
+        Self {
+            directory,
+            max_files,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Writes the result as the next numbered file and prunes the oldest ones.
+    pub async fn save(&self, result: &PromptResultPayload) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to create transcript directory {}",
+                    self.directory.display()
+                )
+            })?;
+        let next = self.indices().await?.last().map_or(1, |last| last + 1);
+        let path = self.path_for(next);
+        let json = serde_json::to_vec_pretty(result)?;
+        tokio::fs::write(&path, json)
+            .await
+            .with_context(|| format!("failed to write transcript {}", path.display()))?;
+        self.prune().await?;
+        Ok(path)
+    }
+
+    pub async fn load(&self, index: u64) -> Result<PromptResultPayload> {
+        let path = self.path_for(index);
+        let json = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read transcript {}", path.display()))?;
+        let probe: SchemaProbe = serde_json::from_slice(&json)
+            .with_context(|| format!("invalid transcript file {}", path.display()))?;
+        if probe.schema_version > RESULT_SCHEMA_VERSION {
+            return Err(anyhow!(
+                "transcript {} has result schema {}, but this kakoune-acp only reads up to \
+                 schema {RESULT_SCHEMA_VERSION}; upgrade it to render the transcript",
+                path.display(),
+                probe.schema_version
+            ));
+        }
+        let mut result: PromptResultPayload = serde_json::from_slice(&json)
+            .with_context(|| format!("invalid transcript file {}", path.display()))?;
+        if probe.answer.is_none() {
+            (result.answer, result.answer_truncated) = transcript::answer(&result.transcript);
+        }
+        if result.final_plan_progress.is_none() {
+            result.final_plan_progress = result.final_plan.as_deref().map(PlanProgress::of);
+        }
+        Ok(result)
+    }
+
+    pub async fn latest_index(&self) -> Result<Option<u64>> {
+        Ok(self.indices().await?.last().copied())
+    }
+
+    /// Sorted indices of every stored transcript.
+    pub async fn indices(&self) -> Result<Vec<u64>> {
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to list transcripts in {}", self.directory.display())
+                });
+            }
+        };
+        let mut indices = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(index) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                indices.push(index);
+            }
+        }
+        indices.sort_unstable();
+        Ok(indices)
+    }
+
+    fn path_for(&self, index: u64) -> PathBuf {
+        self.directory.join(format!("{index:03}.json"))
+    }
+
+    async fn prune(&self) -> Result<()> {
+        let indices = self.indices().await?;
+        let excess = indices.len().saturating_sub(self.max_files);
+        for index in &indices[..excess] {
+            let path = self.path_for(*index);
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                tracing::warn!(?err, "failed to prune transcript {}", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn run(options: TranscriptOptions) -> Result<()> {
+    let directory = kakoune::resolve_state_dir(options.session.as_deref())?;
+    let store = TranscriptStore::new(directory, usize::MAX);
+
+    let index = match options.index {
+        Some(index) => index,
+        None => store
+            .latest_index()
+            .await?
+            .ok_or_else(|| anyhow!("no transcripts stored in {}", store.directory().display()))?,
+    };
+    let mut result = store.load(index).await?;
+    Redactor::new(&options.redact)?.redact_result(&mut result)?;
+
+    let delivery = Delivery {
+        output: options.output.unwrap_or(PromptOutput::Plain),
+        quiet: false,
+        send_to_kak: options.send_to_kak,
+        session: options.session.as_deref(),
+        client: options.client.as_deref(),
+        title: options.title.as_deref().unwrap_or(config::DEFAULT_TITLE),
+        wrap_width: options.wrap_width,
+        kak_target: std::slice::from_ref(&options.kak_target),
+        info_style: None,
+        info_max_lines: None,
+        kak_buffer: &options.kak_buffer,
+        kak_append: false,
+        kak_insert: false,
+        kak_register: None,
+        kak_locations: false,
+        commands_menu: false,
+        socket: None,
+    };
+    prompt::deliver_result(&delivery, &result).await
+}
+
+/// Prints the JSON schema of `PromptResultPayload`.
+pub fn run_schema() -> Result<()> {
+    let schema = schemars::schema_for!(PromptResultPayload);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    cli::InitOptions,
+    kakoune::{self, FOCUSED_CLIENT_OPTION, TRANSCRIPT_FILETYPE, kak_block, kak_quote},
+};
+
+/// Shell functions every generated `%sh{}` block starts with.
+///
+/// `acp` runs a subcommand of the binary in `acp_bin` against this session's daemon, and
+/// `kak_escape` doubles single quotes for text put between them. Kakoune only exports the
+/// `kak_*` variables a block mentions, and these mention all they use.
+const SH_PRELUDE: &str = r#"acp() {
+    command=$1
+    shift
+    if [ -n "$kak_opt_acp_socket" ]; then
+        set -- --socket "$kak_opt_acp_socket" "$@"
+    fi
+    if [ -n "$kak_opt_acp_socket_scope" ]; then
+        set -- --socket-scope "$kak_opt_acp_socket_scope" "$@"
+    fi
+    "$kak_opt_acp_bin" "$command" --session "$kak_session" "$@"
+}
+kak_escape() {
+    printf '%s' "$1" | sed "s/'/''/g"
+}
+"#;
+
+/// The line prefixes of plain transcripts the `acp-transcript` highlighters colour, with the
+/// face for each and whether it covers the whole line or only the prefix.
+///
+/// `render::PlainRenderer` writes these; a test checks they still match what it prints.
+const TRANSCRIPT_LINES: &[(&str, &str, bool)] = &[
+    ("=== ", "title", true),
+    ("[user] ", "variable", false),
+    ("[agent] ", "keyword", false),
+    ("[thought] ", "comment", true),
+    ("[tool ", "function", true),
+    ("[terminal ", "function", true),
+    ("[plan]", "meta", true),
+    ("[mode] ", "meta", true),
+    ("[permission] ", "attribute", true),
+    ("[denied] ", "error", true),
+    ("[edit] ", "type", true),
+    ("[link] ", "link", true),
+];
+
+/// Prints the Kakoune script `kakoune-acp init` generates.
+pub fn run(options: InitOptions) -> Result<()> {
+    let binary = std::env::current_exe().context("cannot locate the kakoune-acp binary")?;
+    let binary = binary.canonicalize().unwrap_or(binary);
+    print!("{}", script(&options, &binary.to_string_lossy())?);
+    Ok(())
+}
+
+/// The integration script for `binary`: a module holding the options, commands, the `acp`
+/// user mode and the hooks starting and stopping the daemon, then `require-module`.
+///
+/// Kakoune refuses to provide a module again once it is loaded, so the module only runs a
+/// hidden command holding its body; sourcing the script a second time runs the new body
+/// directly. Everything in the body replaces what an earlier run left behind.
+fn script(options: &InitOptions, binary: &str) -> Result<String> {
+    let name = &options.module_name;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-')
+    {
+        return Err(anyhow!(
+            "invalid --module-name `{name}`: use letters, digits, `_` and `-`"
+        ));
+    }
+    let load = format!("{name}-load-module");
+    Ok(format!(
+        "# Generated by `kakoune-acp init`; run it again rather than editing this by hand.\n\
+         define-command -override -hidden {load} {}\n\
+         try %{{ provide-module -override {name} %{{ {load} }} }} catch %{{ {load} }}\n\
+         require-module {name}\n",
+        kak_block(&format!("\n{}\n", indent(&module(options, binary)?)))
+    ))
+}
+
+/// The commands `script` puts in the module.
+fn module(options: &InitOptions, binary: &str) -> Result<String> {
+    let socket = options
+        .socket
+        .as_ref()
+        .map(|socket| socket.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut script = format!(
+        "declare-option -docstring {} str acp_bin {}\n\
+         declare-option -docstring {} str acp_socket {}\n\
+         declare-option -docstring {} str acp_socket_scope {}\n\
+         declare-option -docstring {} str acp_agent {}\n\
+         declare-option -docstring {} str acp_output info\n\
+         declare-option -docstring {} bool acp_keep_daemon false\n\
+         declare-option -docstring {} int acp_context_lines 20\n\
+         try %{{ declare-option -hidden str acp_state }}\n\
+         try %{{ declare-option -hidden str kakoune_acp_agent_exit }}\n\
+         try %{{ declare-option -docstring {} str-list acp_images }}\n\
+         try %{{ declare-option -hidden str {FOCUSED_CLIENT_OPTION} }}\n\
+         remove-hooks global acp-focus\n\
+         hook -group acp-focus global FocusIn .* %{{ set-option global {FOCUSED_CLIENT_OPTION} %val{{client}} }}\n\n",
+        kak_quote("the kakoune-acp binary"),
+        kak_quote(binary),
+        kak_quote("daemon socket; derived from the session name when empty"),
+        kak_quote(&socket),
+        kak_quote("what the daemon socket is derived from: session, cwd or global"),
+        kak_quote(options.socket_scope.as_str()),
+        kak_quote("agent command line a started daemon runs; the config file's agent when empty"),
+        kak_quote(options.agent.as_deref().unwrap_or_default()),
+        kak_quote("where prompts show their answer: info, buffer or info,buffer"),
+        kak_quote("leave the daemon running when Kakoune exits"),
+        kak_quote("lines on each side of the cursor acp-prompt-selection sends as context"),
+        kak_quote("images saved during the last ACP prompt"),
+    );
+
+    script.push_str(&command(
+        "acp-prompt",
+        "..",
+        "acp-prompt [TEXT]: send TEXT to the agent, asking for it when none is given",
+        &format!(
+            "evaluate-commands {}",
+            sh_block(&background_prompt(
+                r#"if [ $# -eq 0 ]; then
+    echo "prompt 'acp: ' %{ acp-prompt %val{text} }"
+    exit
+fi"#,
+                None,
+                r#"--prompt "$*""#
+            ))?
+        ),
+    ));
+    script.push_str(&command(
+        "acp-prompt-selection",
+        "..",
+        "acp-prompt-selection [TEXT]: send the selection as the prompt, after TEXT if given, with the lines around it as context",
+        &format!(
+            "evaluate-commands {}",
+            sh_block(&background_prompt(
+                r#"instruction=$*
+set --
+if [ -f "$kak_buffile" ]; then
+    set -- --context-file "$kak_buffile" --context-around "$kak_cursor_line:$kak_opt_acp_context_lines"
+fi"#,
+                Some(
+                    r#"{ [ -z "$instruction" ] || printf '%s\n\n' "$instruction"; printf '%s' "$kak_selection"; }"#
+                ),
+                r#""$@""#
+            ))?
+        ),
+    ));
+    script.push_str(&command(
+        "acp-status",
+        "0",
+        "acp-status: show the daemon's status",
+        &format!(
+            "evaluate-commands {}",
+            sh_block(
+                r#"output=$(acp status 2>&1)
+printf "info -title 'ACP status' '%s'\n" "$(kak_escape "$output")""#
+            )?
+        ),
+    ));
+    for (name, subcommand, docstring, done) in [
+        (
+            "acp-cancel",
+            "cancel",
+            "acp-cancel: ask the agent to stop its current turn",
+            "ACP: cancelled",
+        ),
+        (
+            "acp-shutdown",
+            "shutdown",
+            "acp-shutdown: shut the daemon down",
+            "ACP: daemon shut down",
+        ),
+    ] {
+        script.push_str(&command(
+            name,
+            "0",
+            docstring,
+            &format!(
+                "evaluate-commands {}",
+                sh_block(&format!(
+                    r#"if output=$(acp {subcommand} 2>&1); then
+    echo "echo '{done}'"
+else
+    printf "fail '%s'\n" "$(kak_escape "$output")"
+fi"#
+                ))?
+            ),
+        ));
+    }
+
+    script.push_str(&command(
+        "acp-start",
+        "0",
+        "acp-start: start the daemon for this session unless one is answering",
+        &format!(
+            "nop {}",
+            sh_block(
+                r#"if acp status >/dev/null 2>&1; then
+    exit
+fi
+set --
+if [ -n "$kak_opt_acp_agent" ]; then
+    eval "set -- -- $kak_opt_acp_agent"
+fi
+(acp daemon --follow-kak-session "$@") >/dev/null 2>&1 </dev/null &"#
+            )?
+        ),
+    ));
+
+    script.push_str(&transcript_highlighters());
+
+    if !options.no_user_mode {
+        script.push_str("try %{ declare-user-mode acp }\n");
+        script.push_str(&format!(
+            "map -docstring {} global user a ':enter-user-mode acp<ret>'\n",
+            kak_quote("kakoune-acp")
+        ));
+        for (key, command, docstring) in [
+            ("p", "acp-prompt", "prompt the agent"),
+            ("s", "acp-prompt-selection", "ask about the selection"),
+            ("i", "acp-status", "show the daemon's status"),
+            ("c", "acp-cancel", "cancel the current turn"),
+            ("q", "acp-shutdown", "shut the daemon down"),
+        ] {
+            script.push_str(&format!(
+                "map -docstring {} global acp {key} {}\n",
+                kak_quote(docstring),
+                kak_quote(&format!(":{command}<ret>"))
+            ));
+        }
+        script.push('\n');
+    }
+
+    script.push_str("remove-hooks global acp-daemon\n");
+    if !options.no_autostop {
+        script.push_str(&format!(
+            "hook -group acp-daemon global KakEnd .* {}\n",
+            kak_block(&format!(
+                "\n{}\n",
+                indent(&format!(
+                    "nop {}",
+                    sh_block(
+                        r#"if [ "$kak_opt_acp_keep_daemon" != true ]; then
+    (acp shutdown) >/dev/null 2>&1 </dev/null &
+fi"#
+                    )?
+                ))
+            ))
+        ));
+    }
+    if !options.no_autostart {
+        // Loaded from the kakrc there are no clients yet and the session is not listening;
+        // loaded later, KakBegin has already gone by.
+        script.push_str(&format!(
+            "evaluate-commands {}\n",
+            sh_block(
+                r#"if [ -n "$kak_client_list" ]; then
+    echo acp-start
+else
+    echo "hook -group acp-daemon global KakBegin .* acp-start"
+fi"#
+            )?
+        ));
+    }
+    Ok(script)
+}
+
+/// Highlighters for the `acp-transcript` filetype: the `TRANSCRIPT_LINES` prefixes, and
+/// added, removed and hunk lines inside the fenced diffs of file edits.
+fn transcript_highlighters() -> String {
+    let name = TRANSCRIPT_FILETYPE;
+    let mut script = format!(
+        "remove-hooks global {name}\n\
+         try %{{ remove-highlighter shared/{name} }}\n\
+         add-highlighter shared/{name} regions\n\
+         add-highlighter shared/{name}/diff region {} {} group\n",
+        kak_quote("^```diff$"),
+        kak_quote("^```$"),
+    );
+    for (regex, face) in [
+        (r"^\+[^\n]*", "green"),
+        (r"^-[^\n]*", "red"),
+        (r"^@@[^\n]*", "cyan"),
+    ] {
+        script.push_str(&format!(
+            "add-highlighter shared/{name}/diff/ regex {} 0:{face}\n",
+            kak_quote(regex)
+        ));
+    }
+    script.push_str(&format!(
+        "add-highlighter shared/{name}/text default-region group\n"
+    ));
+    for (prefix, face, whole_line) in TRANSCRIPT_LINES {
+        let rest = if *whole_line { r"[^\n]*" } else { "" };
+        script.push_str(&format!(
+            "add-highlighter shared/{name}/text/ regex {} 0:{face}\n",
+            kak_quote(&format!(r"^\Q{prefix}\E{rest}"))
+        ));
+    }
+    script.push_str(&format!(
+        "hook -group {name} global WinSetOption filetype={name} {}\n\n",
+        kak_block(&format!(
+            "\n    add-highlighter window/{name} ref {name}\n    \
+             hook -once -always window WinSetOption filetype=.* %{{ remove-highlighter window/{name} }}\n"
+        ))
+    ));
+    script
+}
+
+/// A `define-command` for `name`, taking `params` parameters in `-params` notation.
+fn command(name: &str, params: &str, docstring: &str, body: &str) -> String {
+    format!(
+        "define-command -override -params {params} -docstring {} {name} {}\n\n",
+        kak_quote(docstring),
+        kak_block(&format!("\n{}\n", indent(body)))
+    )
+}
+
+/// Shell that checks its parameters with `check`, then runs `prompt` with `flags` in the
+/// background so Kakoune stays usable while the agent works, reading the prompt from what
+/// `input` prints when given. The answer comes back through `--send-to-kak`; a failure is
+/// shown in an info box.
+fn background_prompt(check: &str, input: Option<&str>, flags: &str) -> String {
+    let input = input.map(|input| format!("{input} | ")).unwrap_or_default();
+    format!(
+        r#"{check}
+client=$kak_client
+session=$kak_session
+(
+    if ! output=$({input}acp prompt --client "$client" {flags} ${{kak_opt_acp_output:+--kak-target "$kak_opt_acp_output"}} --output kak-commands --send-to-kak 2>&1 >/dev/null); then
+        info="info -title 'kakoune-acp prompt failed' '$(kak_escape "$output")'"
+        printf "evaluate-commands -client '%s' '%s'\n" "$client" "$(kak_escape "$info")" | kak -p "$session"
+    fi
+) >/dev/null 2>&1 </dev/null &"#
+    )
+}
+
+/// `%sh{...}` running `body` after `SH_PRELUDE`.
+fn sh_block(body: &str) -> Result<String> {
+    let body = format!("\n{}\n", indent(&format!("{SH_PRELUDE}{body}")));
+    kakoune::sh_block(&body).context("cannot quote the --agent command for Kakoune")
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("    {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
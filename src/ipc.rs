@@ -1,59 +1,785 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
 
 use agent_client_protocol as acp;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+/// Version of the socket protocol spoken by this build.
+///
+/// Bump it whenever a change would make an older daemon misinterpret requests.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Version assumed for peers that predate the `version` field.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+fn legacy_protocol_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// A request to the daemon, sent as one JSON line in a `RequestEnvelope`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
 pub enum DaemonRequest {
-    Prompt(PromptPayload),
+    Prompt(Box<PromptPayload>),
     Status,
-    Shutdown,
+    /// Cheap liveness probe that the daemon answers without taking any locks.
+    Ping,
+    /// Kill the agent process and start a fresh one with a new session.
+    RestartAgent {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent: Option<String>,
+    },
+    /// Ask the agent to stop the turn currently running on its session.
+    Cancel {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent: Option<String>,
+    },
+    Shutdown {
+        /// Skip draining in-flight prompts and stop immediately.
+        #[serde(default)]
+        force: bool,
+    },
+    /// Keep the connection open and stream every session notification.
+    Watch,
+    /// Stream the events of a running prompt, then its result.
+    Attach {
+        request_id: u64,
+    },
+    /// Answer like `Status`, then zero the traffic counters.
+    ResetMetrics,
+    /// Re-read the config file and apply the settings that changed.
+    Reload,
+    /// Answer a permission request the daemon is waiting on; no option cancels it.
+    PermissionReply {
+        permission_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        option_id: Option<String>,
+        /// Settle this request and every later one for the same tool the same way, in place
+        /// of picking an option.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        always: Option<RuleDecision>,
+    },
+    /// Answer a `PermissionRequest` sent to a prompt client; no option cancels it.
+    PermissionAnswer {
+        permission_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        option_id: Option<String>,
+    },
+    /// List the rules stored by "always" permission answers.
+    PermissionRules,
+    /// Forget every stored permission rule.
+    ClearPermissionRules,
+    /// Drop the cached session for a `prompt --cwd` directory.
+    CloseSession {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent: Option<String>,
+        cwd: PathBuf,
+    },
+}
+
+/// A request line on the daemon socket.
+///
+/// Connections stay open for any number of requests. Responses echo the request's `id`
+/// (when one was given) so clients can pipeline requests and correlate the answers, which
+/// may arrive out of order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u32,
+    #[serde(flatten)]
+    pub request: DaemonRequest,
+}
+
+/// A response line on the daemon socket, tagged with the originating request's `id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u32,
+    #[serde(flatten)]
+    pub response: DaemonResponse,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PromptPayload {
     pub prompt: String,
     #[serde(default)]
-    pub context: Vec<ContextSnippet>,
+    pub context: Vec<ContextEntry>,
+    /// Agent to prompt; the daemon's first agent when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// Fail with a `busy` error instead of waiting for the agent's current turn.
+    #[serde(default)]
+    pub no_queue: bool,
+    /// Keep every message and thought chunk as its own transcript event instead of joining
+    /// chunks that follow one another.
+    #[serde(default)]
+    pub no_merge_chunks: bool,
+    /// Record every plan the agent sends in full instead of only what changed since the
+    /// previous one.
+    #[serde(default)]
+    pub full_plans: bool,
+    /// Keep the raw input and output the agent reports for its tool calls in the transcript.
+    #[serde(default)]
+    pub raw_tool_io: bool,
+    /// Fold each tool call's updates into one `tool_call` event with its status history.
+    #[serde(default)]
+    pub group_tool_calls: bool,
+    /// Also record each mode change as the `system_message` older builds wrote.
+    #[serde(default)]
+    pub legacy_mode_messages: bool,
+    /// Record the agent's echo of the prompt even when it repeats the prompt exactly.
+    #[serde(default)]
+    pub keep_user_echo: bool,
+    /// Seconds after which the daemon cancels the turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Directory the prompt's session should be bound to; the daemon's when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Answer with the earlier result of a prompt sent with the same key on the same session
+    /// instead of prompting the agent again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Kakoune client that sent the prompt, where permission requests are asked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Kakoune session that sent the prompt, where its permission requests are asked instead
+    /// of the daemon's `--session`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kak_session: Option<String>,
+    /// Send the agent's permission requests to this connection as `PermissionRequest`
+    /// responses, to be answered with `PermissionAnswer`, instead of asking in Kakoune.
+    #[serde(default)]
+    pub answer_permissions: bool,
+    /// Write the diffs the agent proposes once its turn ends, reporting each in the result's
+    /// `diffs`; when omitted they are only summarized in the transcript.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apply_diffs: Option<ApplyDiffs>,
+    /// Kakoune session to show the turn's progress in while it runs, in `client` or the
+    /// session's first client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    /// Kakoune session to mirror the agent's plan in while the turn runs, in `client` or the
+    /// session's first client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<String>,
+    /// Where to show the agent's answer as it streams in while the turn runs, in `client` or
+    /// the session's first client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamDisplay>,
 }
 
+/// A Kakoune display the agent's answer is shown in as it streams in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamDisplay {
+    pub session: String,
+    /// Title of the info box the answer is shown in.
+    pub title: String,
+    /// Scratch buffer to show the answer in instead of an info box.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer: Option<String>,
+}
+
+/// Whether to write the diffs an agent proposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyDiffs {
+    /// Ask for each diff, at the prompt or in Kakoune like a permission request.
+    Ask,
+    Always,
+    Never,
+}
+
+/// A response from the daemon, received as one JSON line in a `ResponseEnvelope`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
 pub enum DaemonResponse {
-    Prompt { result: PromptResultPayload },
-    Status { status: DaemonStatus },
+    Prompt {
+        result: PromptResultPayload,
+    },
+    /// A prompt result too large to send inline, written to `path` as JSON of `bytes` bytes.
+    ///
+    /// The client reads the file in place of a `Prompt` response and then deletes it.
+    PromptFile {
+        path: PathBuf,
+        bytes: u64,
+    },
+    Status {
+        status: Box<DaemonStatus>,
+    },
+    Notification {
+        notification: acp::SessionNotification,
+        /// Name of the agent that sent the notification.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent: Option<String>,
+    },
+    /// One transcript event of the prompt an `attach` client follows, with its `seq`.
+    PromptEvent {
+        event: TranscriptEntry,
+    },
+    /// Sent to a `watch` client that fell behind, in place of the notifications it missed.
+    NotificationsDropped {
+        count: u64,
+    },
     Ok,
-    Error { message: String },
+    Pong {
+        /// Process id of the daemon.
+        #[serde(default)]
+        pid: Option<u32>,
+        uptime_ms: u64,
+    },
+    AgentRestarted {
+        #[serde(default)]
+        agent: String,
+        /// `None` when the daemon opens sessions lazily and no prompt has arrived yet.
+        #[serde(default)]
+        session_id: Option<String>,
+        agent_pid: Option<u32>,
+    },
+    PermissionRules {
+        rules: Vec<PermissionRule>,
+    },
+    /// A permission request put to the client of a prompt sent with `answer_permissions`,
+    /// streamed before the prompt's result.
+    PermissionRequest {
+        permission_id: u64,
+        title: String,
+        tool_kind: String,
+        options: Vec<acp::PermissionOption>,
+        /// What the request would change, such as a diff to apply.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preview: Option<String>,
+    },
+    Reloaded {
+        /// Config keys whose new values took effect.
+        applied: Vec<String>,
+        /// Changed config keys that only take effect when the daemon restarts.
+        skipped: Vec<String>,
+    },
+    Shutdown {
+        forced: bool,
+        /// Prompts that were still running when the daemon stopped waiting for them.
+        abandoned_prompts: usize,
+    },
+    Error {
+        message: String,
+        /// Machine-readable error kind, e.g. `version_mismatch`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        /// Extra structured details that depend on `code`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<serde_json::Value>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DaemonResponse {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+            code: None,
+            data: None,
+        }
+    }
+
+    pub fn error_with_code(
+        code: &str,
+        message: impl Into<String>,
+        data: Option<serde_json::Value>,
+    ) -> Self {
+        Self::Error {
+            message: message.into(),
+            code: Some(code.to_string()),
+            data,
+        }
+    }
+}
+
+/// A permission decision remembered from an "always" answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionRule {
+    /// Kind of tool the rule covers, e.g. `edit`.
+    pub tool_kind: String,
+    /// Requests whose title starts with this are covered.
+    pub title_prefix: String,
+    pub decision: RuleDecision,
+}
+
+impl fmt::Display for PermissionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}*={}",
+            self.tool_kind, self.title_prefix, self.decision
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleDecision {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for RuleDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        })
+    }
+}
+
+/// Just enough of a request to tell which protocol version the peer speaks, used when the
+/// full request cannot be parsed.
+#[derive(Debug, Deserialize)]
+pub struct VersionProbe {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u32,
+}
+
+/// Version of the `PromptResultPayload` shape, bumped whenever a field is removed, renamed
+/// or changes meaning; new optional fields leave it alone.
+pub const RESULT_SCHEMA_VERSION: u32 = 1;
+
+/// Results stored before `schema_version` existed have the first shape.
+fn legacy_schema_version() -> u32 {
+    1
+}
+
+/// Just the schema version of a stored result, read before trusting the rest of it.
+#[derive(Debug, Deserialize)]
+pub struct SchemaProbe {
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
+    /// Results stored before they had an `answer` get one made from their transcript.
+    #[serde(default)]
+    pub answer: Option<serde::de::IgnoredAny>,
+}
+
+/// The outcome of a prompt: why the agent stopped, its transcript and what it did.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[non_exhaustive]
 pub struct PromptResultPayload {
+    /// `RESULT_SCHEMA_VERSION` of the build that produced the result.
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     pub stop_reason: acp::StopReason,
+    /// True when the turn was cancelled, so the transcript may stop short of an answer.
+    #[serde(default)]
+    pub partial: bool,
+    /// Why the daemon cancelled the turn: `user`, `timeout` or `agent_restart`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel_reason: Option<String>,
     pub user_prompt: String,
     #[serde(default)]
-    pub context: Vec<ContextSnippet>,
-    pub transcript: Vec<TranscriptEvent>,
+    pub context: Vec<ContextEntry>,
+    pub transcript: Vec<TranscriptEntry>,
+    /// The agent's messages joined in order, without the rest of the transcript; empty when
+    /// it sent none.
+    #[serde(default)]
+    pub answer: String,
+    /// `answer` was cut short; the messages are whole in `transcript`.
+    #[serde(default)]
+    pub answer_truncated: bool,
+    /// The agent streamed the prompt back as its first user message and the copy was dropped.
+    #[serde(default)]
+    pub echo_suppressed: bool,
+    /// The transcript has `error` events.
+    #[serde(default)]
+    pub had_errors: bool,
+    /// Number of events in `transcript`; its last event's `seq`.
+    #[serde(default)]
+    pub event_count: u64,
+    /// True when this is a replay of an earlier answer to the same idempotency key.
+    #[serde(default)]
+    pub cached: bool,
+    /// What became of each diff the agent proposed, for prompts sent with `apply_diffs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diffs: Vec<DiffOutcome>,
+    /// Working directory of the session the prompt ran in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// The agent's plan as it stood when the turn ended, if it sent one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_plan: Option<Vec<PlanEntrySummary>>,
+    /// How far `final_plan` got.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_plan_progress: Option<PlanProgress>,
+    /// Id of the session's mode when the turn ended, if the agent has modes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_mode: Option<String>,
+    /// The agent that answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentInfo>,
+    /// The `_meta` of the agent's answer to `session/new` for the prompt's session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_meta: Option<serde_json::Value>,
+    /// The `_meta` of the agent's answer to the prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_meta: Option<serde_json::Value>,
+    /// Counts and sizes of the transcript; none in transcripts stored before they were kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<TranscriptSummary>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ContextSnippet {
-    pub text: String,
+/// How many events a transcript has and how much text they carry, overall and by kind.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct TranscriptSummary {
+    pub events: u64,
+    /// Sum of the events' `text_bytes`.
+    pub text_bytes: u64,
+    /// Events and text bytes for each event `kind` in the transcript.
+    pub kinds: BTreeMap<String, KindSummary>,
+    /// Distinct tool calls the agent made.
+    pub tool_calls: u64,
+    /// Characters of the agent's messages, not counting thoughts.
+    pub agent_message_chars: u64,
+    /// Time from sending the prompt to the end of the turn.
+    pub duration_ms: u64,
+    /// Secrets replaced with `[REDACTED:<rule>]` in the context and the transcript.
     #[serde(default)]
+    pub redactions: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct KindSummary {
+    pub events: u64,
+    pub text_bytes: u64,
+}
+
+/// The agent that answered a prompt, as far as it described itself.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AgentInfo {
+    /// Name from the `_meta` of the agent's `initialize` answer, or the daemon's name for it.
+    pub name: String,
+    /// Version from the `_meta` of the agent's `initialize` answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Model named in the `_meta` of the session or of the agent's `initialize` answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Protocol version the agent agreed to in `initialize`.
+    pub protocol_version: acp::ProtocolVersion,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DiffOutcome {
+    pub path: PathBuf,
+    pub status: DiffStatus,
+    /// Why the diff was skipped or failed, or what it conflicts with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Where the previous contents were kept, for an applied diff to an existing file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffStatus {
+    Applied,
+    Skipped,
+    /// The file no longer holds the text the diff was made against.
+    Conflict,
+    /// The diff could not be written, or its path is outside the workspace.
+    Failed,
+}
+
+impl fmt::Display for DiffStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Applied => "applied",
+            Self::Skipped => "skipped",
+            Self::Conflict => "conflict",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// A piece of context sent along with a prompt.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(from = "ContextEntryRepr")]
+pub struct ContextEntry {
     pub label: Option<String>,
+    pub source: ContextSource,
+    pub text: String,
+    /// Size of the text before `--max-context-bytes` cut it down.
+    pub bytes: usize,
+}
+
+impl ContextEntry {
+    pub fn new(source: ContextSource, label: Option<String>, text: String) -> Self {
+        Self {
+            label,
+            source,
+            bytes: text.len(),
+            text,
+        }
+    }
+
+    /// What the entry is shown as: its label, or where it came from.
+    pub fn heading(&self) -> String {
+        if let Some(label) = &self.label {
+            return label.clone();
+        }
+        match &self.source {
+            ContextSource::Inline => "inline".to_string(),
+            ContextSource::Stdin => "stdin".to_string(),
+            ContextSource::File { path } => format!("file: {}", path.display()),
+            ContextSource::Selection {
+                path,
+                first_line,
+                last_line,
+            } => format!("selection: {}:{first_line}-{last_line}", path.display()),
+        }
+    }
+}
+
+/// Where a context entry came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContextSource {
+    /// Given on the command line with `--context`.
+    #[default]
+    Inline,
+    /// Read with `--context-file -`.
+    Stdin,
+    /// A whole `--context-file`.
+    File { path: PathBuf },
+    /// The lines of a `--context-file` around a position, from `--context-around`, as the
+    /// Kakoune commands send around the selection.
+    Selection {
+        path: PathBuf,
+        first_line: usize,
+        last_line: usize,
+    },
+}
+
+/// Context entries as sent by older clients and stored in older transcripts: bare strings,
+/// or objects without a source or size.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ContextEntryRepr {
+    Text(String),
+    Entry {
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        source: ContextSource,
+        text: String,
+        #[serde(default)]
+        bytes: Option<usize>,
+    },
+}
+
+impl From<ContextEntryRepr> for ContextEntry {
+    fn from(repr: ContextEntryRepr) -> Self {
+        match repr {
+            ContextEntryRepr::Text(text) => Self::new(ContextSource::Inline, None, text),
+            ContextEntryRepr::Entry {
+                label,
+                source,
+                text,
+                bytes,
+            } => Self {
+                label,
+                source,
+                bytes: bytes.unwrap_or(text.len()),
+                text,
+            },
+        }
+    }
 }
 
+/// What `status` reports about the daemon and its agents.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct DaemonStatus {
     pub session_id: Option<String>,
+    #[serde(default)]
+    pub session_state: SessionState,
     pub socket_path: PathBuf,
+    /// What `socket_path` was derived from: `session`, `cwd` or `global`; none when it was
+    /// given with `--socket`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_scope: Option<String>,
+    /// Project directory a `cwd` scope socket is named after.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_root: Option<PathBuf>,
     pub agent_command: Vec<String>,
     pub agent_pid: Option<u32>,
     pub running: bool,
+    #[serde(default)]
+    pub binary_version: String,
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    /// Largest request line the daemon accepts; zero when unknown (older daemons).
+    #[serde(default)]
+    pub max_request_bytes: u64,
+    /// Unix timestamp (seconds) at which the daemon started.
+    #[serde(default)]
+    pub started_at: u64,
+    #[serde(default)]
+    pub uptime_seconds: u64,
+    #[serde(default)]
+    pub prompts_completed: u64,
+    #[serde(default)]
+    pub prompts_failed: u64,
+    #[serde(default)]
+    pub notifications_received: u64,
+    /// Unix timestamp (seconds) at which the most recent prompt started.
+    #[serde(default)]
+    pub last_prompt_at: Option<u64>,
+    #[serde(default)]
+    pub current_prompt: Option<ActivePrompt>,
+    /// True while a graceful shutdown waits for in-flight prompts.
+    #[serde(default)]
+    pub draining: bool,
+    /// Whether the default agent is in the middle of a turn.
+    #[serde(default)]
+    pub busy: bool,
+    /// How the default agent's previous process ended, if one has.
+    #[serde(default)]
+    pub last_exit: Option<AgentExit>,
+    /// Unix timestamp (seconds) of the last config reload.
+    #[serde(default)]
+    pub last_reload_at: Option<u64>,
+    #[serde(default)]
+    pub reload_applied: Vec<String>,
+    #[serde(default)]
+    pub reload_skipped: Vec<String>,
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// Every agent behind the daemon, the default one first.
+    #[serde(default)]
+    pub agents: Vec<AgentStatus>,
+    /// The daemon's `--permission-policy`.
+    #[serde(default)]
+    pub permission_policy: String,
+    /// The daemon's `--audit-log`, when it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<AuditStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditStatus {
+    pub path: PathBuf,
+    /// Entries appended since the daemon started.
+    pub entries: u64,
+    /// Entries that could not be appended.
+    pub failures: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Traffic counters covering every agent, since the daemon started or the last reset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    /// Unix timestamp (seconds) at which counting started.
+    #[serde(default)]
+    pub since: u64,
+    /// Session notifications by update kind, e.g. `agent_message_chunk`.
+    #[serde(default)]
+    pub notifications: BTreeMap<String, u64>,
+    /// Tool call reports by status; updates that leave the status alone are not counted.
+    #[serde(default)]
+    pub tool_calls: BTreeMap<String, u64>,
+    /// Bytes of text in agent message chunks.
+    #[serde(default)]
+    pub agent_message_bytes: u64,
+    /// Wall-clock time spent running prompts, in milliseconds.
+    #[serde(default)]
+    pub prompt_time_ms: u64,
+    /// Notifications that `watch` clients missed by falling behind, summed over clients.
+    #[serde(default)]
+    pub dropped_notifications: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub name: String,
+    pub agent_command: Vec<String>,
+    pub agent_pid: Option<u32>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub session_state: SessionState,
+    /// Working directory of the agent's own session.
+    #[serde(default)]
+    pub cwd: PathBuf,
+    /// Every open session by working directory, the agent's own included.
+    #[serde(default)]
+    pub sessions: BTreeMap<PathBuf, String>,
+    pub capabilities: acp::AgentCapabilities,
+    /// What the daemon advertised to the agent in `initialize`.
+    #[serde(default)]
+    pub client_capabilities: acp::ClientCapabilities,
+    /// The turn this agent is working on, if any.
+    #[serde(default)]
+    pub current_prompt: Option<ActivePrompt>,
+    #[serde(default)]
+    pub last_exit: Option<AgentExit>,
+}
+
+/// How an agent process ended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentExit {
+    /// Exit code, when the process exited normally.
+    pub code: Option<i32>,
+    /// Signal that terminated the process.
+    pub signal: Option<i32>,
+    /// Unix timestamp (seconds) at which the exit was noticed.
+    pub exited_at: u64,
+}
+
+/// Whether an agent's ACP session has been opened yet (see `daemon --lazy-session`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    NotStarted,
+    #[default]
+    Active,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActivePrompt {
+    pub request_id: u64,
+    pub elapsed_ms: u64,
+    /// Kakoune session and client that sent the prompt, when they said.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kak_session: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kak_client: Option<String>,
+}
+
+/// A transcript event and its place in the prompt's transcript.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TranscriptEntry {
+    /// Position of the event in the transcript, counting from 1 in the order the events were
+    /// recorded; streamed events carry the same number as in the final result. Zero in
+    /// transcripts stored before events were numbered.
+    #[serde(default)]
+    pub seq: u64,
+    /// Bytes of text the event carries: messages, tool output, diffs and the like. Zero in
+    /// transcripts stored before it was counted.
+    #[serde(default)]
+    pub text_bytes: u64,
+    #[serde(flatten)]
+    pub event: TranscriptEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "kind", rename_all = "snake_case")]
+#[non_exhaustive]
 pub enum TranscriptEvent {
     UserMessage {
         text: String,
@@ -68,33 +794,316 @@ pub enum TranscriptEvent {
         id: String,
         title: String,
         status: String,
+        /// What sort of tool it is, e.g. `read`, `edit` or `execute`.
+        #[serde(default)]
+        tool_kind: String,
+        /// Files the tool call works on.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        locations: Vec<ToolLocation>,
+        /// The text and terminals the tool call started out with; its diffs follow as
+        /// `FileEdit` events.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// The tool's input as the agent reported it, kept with `raw_tool_io`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw_input: Option<serde_json::Value>,
+        /// The tool's output as the agent reported it, kept with `raw_tool_io`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw_output: Option<serde_json::Value>,
+        /// Every status the tool call went through, when its updates were folded into it.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        status_history: Vec<ToolStatusChange>,
     },
     ToolCallUpdate {
         id: String,
         status: Option<String>,
         message: Option<String>,
+        /// The tool's new kind, when the update changes it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool_kind: Option<String>,
+        /// Files the tool call works on, when the update replaces them.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        locations: Vec<ToolLocation>,
+        /// The tool's new input, kept with `raw_tool_io`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw_input: Option<serde_json::Value>,
+        /// The tool's output, kept with `raw_tool_io`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw_output: Option<serde_json::Value>,
     },
     Plan {
         entries: Vec<PlanEntrySummary>,
     },
+    /// The entries of the previous plan that the agent changed or added when it revised it.
+    PlanUpdate {
+        changes: Vec<PlanEntryChange>,
+        /// Number of entries in the revised plan; entries past it were dropped.
+        len: usize,
+    },
     AvailableCommands {
         commands: Vec<CommandSummary>,
     },
     SystemMessage {
         text: String,
     },
+    /// The agent switched the session to another mode.
+    ModeChange {
+        mode_id: String,
+        /// The mode's name, when the agent listed its modes on opening the session.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode_name: Option<String>,
+    },
+    /// The agent read a file through the client.
+    FileRead {
+        path: PathBuf,
+        /// The read was cut short at `--max-read-bytes`.
+        #[serde(default)]
+        truncated: bool,
+        /// Bytes returned to the agent.
+        #[serde(default)]
+        bytes: u64,
+    },
+    /// How a permission request from the agent was decided.
+    Permission {
+        title: String,
+        /// Kind of tool the request was for, e.g. `edit` or `execute`.
+        #[serde(default)]
+        tool_kind: Option<String>,
+        /// `selected` or `cancelled`.
+        outcome: String,
+        /// Name of the selected option.
+        #[serde(default)]
+        option: Option<String>,
+        /// Who or what made the decision, e.g. `chosen in Kakoune` or `timed out`.
+        reason: String,
+        /// The `--permission-policy` rule that applied, e.g. `execute=deny` or `approve-all`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rule: Option<String>,
+        /// Nobody answered in time, so `--permission-default` decided.
+        #[serde(default)]
+        timed_out: bool,
+        /// What the tool call would do, in full however much of it was shown when asking.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        summary: Option<String>,
+    },
+    /// A command the agent runs through `terminal/create`: one step of its life.
+    Terminal {
+        id: String,
+        /// The command line, program first.
+        command: String,
+        /// `created`, `exited`, `killed` or `released`.
+        status: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<String>,
+    },
+    /// What a terminal printed and how its command ended, taken when the agent released it
+    /// or, for a terminal a tool call showed, when the turn ended.
+    TerminalOutput {
+        terminal_id: String,
+        /// The tool call whose content showed the terminal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool_id: Option<String>,
+        /// The command line, program first.
+        command: String,
+        /// The tail of the command's combined output.
+        output: String,
+        /// Earlier output was dropped, by the terminal's byte limit or to fit the transcript.
+        #[serde(default)]
+        truncated: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signal: Option<String>,
+        /// The command had not exited yet.
+        #[serde(default)]
+        running: bool,
+    },
+    /// A file changed, whether the daemon wrote it or the agent reported a diff.
+    FileEdit {
+        path: PathBuf,
+        /// Unified diff, cut short after a bounded number of lines.
+        diff: String,
+        added: usize,
+        removed: usize,
+        /// Where the whole diff was saved when `diff` had to be truncated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        full_diff: Option<PathBuf>,
+        /// The tool call that reported the diff; none for files the daemon wrote itself.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool_id: Option<String>,
+    },
+    /// An image the agent sent, saved to a file.
+    Image {
+        path: PathBuf,
+        mime_type: String,
+        /// Size of the decoded image.
+        bytes: u64,
+    },
+    /// A link to a resource the agent sent as a content block of its own.
+    ResourceLink {
+        /// The link's title, or its name when it has none.
+        title: String,
+        uri: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mime_type: Option<String>,
+    },
+    /// The agent asked for a path outside the workspace root and was refused.
+    AccessDenied {
+        /// `read`, `write` or `terminal`.
+        operation: String,
+        /// Where the requested path leads, symlinks resolved.
+        path: PathBuf,
+        root: PathBuf,
+    },
+    /// The agent wrote a file through the client.
+    FileWrite {
+        path: PathBuf,
+        /// Size change in bytes; the whole new size for a file that did not exist.
+        byte_delta: i64,
+        /// Where the previous contents were kept, when the file existed.
+        #[serde(default)]
+        backup: Option<PathBuf>,
+    },
+    /// Something went wrong during the turn that would otherwise leave no trace.
+    Error {
+        source: ErrorSource,
+        message: String,
+        /// How many notifications were lost, for `dropped_notifications`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where an `error` event was noticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorSource {
+    /// Session notifications were dropped before they could be shown.
+    DroppedNotifications,
+    /// A request from the agent was answered with an error.
+    Protocol,
+    /// A tool call failed without any content saying why.
+    ToolCall,
+    /// An extension notification reported an error.
+    Extension,
+}
+
+/// A file, and optionally a line in it, that a tool call reported working on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ToolLocation {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+}
+
+/// A status a grouped tool call reported, and when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ToolStatusChange {
+    pub status: String,
+    /// `seq` of the last transcript event before the status arrived.
+    pub seq: u64,
+    /// Unix timestamp in milliseconds.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct PlanEntrySummary {
     pub status: String,
     pub priority: String,
     pub content: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A plan's steps counted by status.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PlanProgress {
+    /// Steps in the plan.
+    pub total: usize,
+    pub counts: PlanStatusCounts,
+    /// Every step is completed; false for an empty plan.
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PlanStatusCounts {
+    pub pending: usize,
+    pub in_progress: usize,
+    pub completed: usize,
+    /// Steps with a status this build does not know.
+    pub other: usize,
+}
+
+impl PlanProgress {
+    pub fn of(entries: &[PlanEntrySummary]) -> Self {
+        let mut counts = PlanStatusCounts::default();
+        for entry in entries {
+            match entry.status.as_str() {
+                "Pending" => counts.pending += 1,
+                "InProgress" => counts.in_progress += 1,
+                "Completed" => counts.completed += 1,
+                _ => counts.other += 1,
+            }
+        }
+        Self {
+            total: entries.len(),
+            completed: !entries.is_empty() && counts.completed == entries.len(),
+            counts,
+        }
+    }
+}
+
+/// A plan entry that is new or differs from the one at the same position before.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PlanEntryChange {
+    pub index: usize,
+    #[serde(flatten)]
+    pub entry: PlanEntrySummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CommandSummary {
     pub name: String,
     pub description: String,
+    /// The hint of an unstructured input, as older builds recorded it.
     pub hint: Option<String>,
+    /// What the command takes after its name, when it takes anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<CommandInputSummary>,
+}
+
+/// The input a command declares.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandInputSummary {
+    /// Whatever is typed after the command name, with a hint shown until it is.
+    Unstructured { hint: String },
+    /// Input described by a schema, kept as the agent sent it.
+    Structured { schema: serde_json::Value },
+}
+
+impl CommandInputSummary {
+    /// Names of the properties a structured input's schema declares.
+    pub fn property_names(&self) -> Vec<&str> {
+        match self {
+            Self::Unstructured { .. } => Vec::new(),
+            Self::Structured { schema } => schema
+                .get("properties")
+                .and_then(serde_json::Value::as_object)
+                .map(|properties| properties.keys().map(String::as_str).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// What to show while the input is being typed.
+    pub fn prompt_hint(&self) -> String {
+        match self {
+            Self::Unstructured { hint } => hint.clone(),
+            Self::Structured { .. } => match self.property_names().as_slice() {
+                [] => "input".to_string(),
+                names => names.join(", "),
+            },
+        }
+    }
 }
@@ -1,35 +1,187 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
 
 use anyhow::{Context, Result, anyhow};
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::UnixStream,
+    net::{
+        UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
 };
 
-use crate::ipc::{DaemonRequest, DaemonResponse};
+use crate::ipc::{
+    DaemonRequest, DaemonResponse, PROTOCOL_VERSION, RequestEnvelope, ResponseEnvelope,
+};
 
+/// Sends a single request on a fresh connection and waits for its response.
 pub async fn roundtrip(path: &Path, request: &DaemonRequest) -> Result<DaemonResponse> {
-    let stream = UnixStream::connect(path)
-        .await
-        .with_context(|| format!("failed to connect to {}", path.display()))?;
-    send_request(stream, request).await
+    let mut connection = Connection::connect(path).await?;
+    connection.request(request).await
+}
+
+pub async fn subscribe(path: &Path, request: &DaemonRequest) -> Result<Subscription> {
+    Connection::connect(path).await?.subscribe(request).await
+}
+
+/// A keep-alive connection to the daemon that can pipeline several requests.
+pub struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+    /// Responses read while waiting for a different request id.
+    stashed: HashMap<u64, VecDeque<DaemonResponse>>,
+    line: String,
+}
+
+impl Connection {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+            next_id: 1,
+            stashed: HashMap::new(),
+            line: String::new(),
+        })
+    }
+
+    /// Writes a request without waiting for the answer and returns its id.
+    pub async fn send(&mut self, request: &DaemonRequest) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let envelope = RequestEnvelope {
+            id: Some(id),
+            version: PROTOCOL_VERSION,
+            request: request.clone(),
+        };
+        let payload = serde_json::to_string(&envelope)?;
+        self.writer.write_all(payload.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.writer.flush().await?;
+        Ok(id)
+    }
+
+    /// Waits for the next response tagged with `id`, stashing any others that arrive first.
+    pub async fn response(&mut self, id: u64) -> Result<DaemonResponse> {
+        match self.next_response(id).await? {
+            Some(DaemonResponse::Error {
+                code: Some(code),
+                data,
+                ..
+            }) if code == "version_mismatch" => Err(stale_daemon_error(data.as_ref())),
+            Some(response) => Ok(response),
+            None => Err(anyhow!("daemon closed the connection")),
+        }
+    }
+
+    pub async fn request(&mut self, request: &DaemonRequest) -> Result<DaemonResponse> {
+        let id = self.send(request).await?;
+        self.response(id).await
+    }
+
+    /// Starts a streaming request such as `Watch`.
+    ///
+    /// The daemon acknowledges the subscription with `DaemonResponse::Ok` before streaming,
+    /// which is consumed here so callers only see the streamed frames.
+    pub async fn subscribe(mut self, request: &DaemonRequest) -> Result<Subscription> {
+        let id = self.send(request).await?;
+        match self.response(id).await? {
+            DaemonResponse::Ok => Ok(Subscription {
+                connection: self,
+                id,
+            }),
+            DaemonResponse::Error { message, .. } => Err(anyhow!(message)),
+            other => Err(anyhow!("unexpected daemon response: {other:?}")),
+        }
+    }
+
+    async fn next_response(&mut self, id: u64) -> Result<Option<DaemonResponse>> {
+        if let Some(response) = self.stashed.get_mut(&id).and_then(VecDeque::pop_front) {
+            return Ok(Some(response));
+        }
+        loop {
+            self.line.clear();
+            let read = self.reader.read_line(&mut self.line).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            let envelope: ResponseEnvelope = match serde_json::from_str(self.line.trim_end()) {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    let version = serde_json::from_str::<serde_json::Value>(&self.line)
+                        .ok()
+                        .and_then(|value| value.get("version").and_then(|v| v.as_u64()));
+                    if version.is_none_or(|version| version < u64::from(PROTOCOL_VERSION)) {
+                        return Err(stale_daemon_error(None));
+                    }
+                    return Err(err)
+                        .with_context(|| format!("invalid response from daemon: {}", self.line));
+                }
+            };
+            let response = read_spilled(envelope.response).await?;
+            match envelope.id {
+                // Untagged responses are errors the daemon could not attribute to a request.
+                None => return Ok(Some(response)),
+                Some(response_id) if response_id == id => return Ok(Some(response)),
+                Some(other) => self.stashed.entry(other).or_default().push_back(response),
+            }
+        }
+    }
 }
 
-async fn send_request(stream: UnixStream, request: &DaemonRequest) -> Result<DaemonResponse> {
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
+/// Turns a result the daemon spilled to a file back into a `Prompt` response.
+///
+/// The file is removed whether or not it could be read.
+async fn read_spilled(response: DaemonResponse) -> Result<DaemonResponse> {
+    let DaemonResponse::PromptFile { path, bytes } = response else {
+        return Ok(response);
+    };
+    let read = tokio::fs::read(&path).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    let json = read.with_context(|| format!("failed to read prompt result {}", path.display()))?;
+    if json.len() as u64 != bytes {
+        return Err(anyhow!(
+            "prompt result {} has {} bytes, expected {bytes}",
+            path.display(),
+            json.len()
+        ));
+    }
+    let result = serde_json::from_slice(&json)
+        .with_context(|| format!("invalid prompt result in {}", path.display()))?;
+    Ok(DaemonResponse::Prompt { result })
+}
 
-    let payload = serde_json::to_string(request)?;
-    writer.write_all(payload.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
-    writer.flush().await?;
+/// A long-lived request that keeps yielding responses after the initial acknowledgement.
+pub struct Subscription {
+    connection: Connection,
+    id: u64,
+}
 
-    let mut line = String::new();
-    let read = reader.read_line(&mut line).await?;
-    if read == 0 {
-        return Err(anyhow!("daemon closed the connection"));
+impl Subscription {
+    /// Returns the next streamed response, or `None` once the daemon closes the stream.
+    pub async fn next(&mut self) -> Result<Option<DaemonResponse>> {
+        self.connection.next_response(self.id).await
     }
-    let response: DaemonResponse = serde_json::from_str(line.trim_end())
-        .with_context(|| format!("invalid response from daemon: {line}"))?;
-    Ok(response)
+}
+
+fn stale_daemon_error(data: Option<&serde_json::Value>) -> anyhow::Error {
+    let version = data
+        .and_then(|data| data.get("binary_version"))
+        .and_then(|version| version.as_str())
+        .map(|version| format!("v{version}"))
+        .or_else(|| {
+            data.and_then(|data| data.get("protocol_version"))
+                .and_then(|version| version.as_u64())
+                .map(|version| format!("protocol v{version}"))
+        })
+        .unwrap_or_else(|| "unknown version".to_string());
+    anyhow!(
+        "daemon is running an older kakoune-acp ({version}); run `kakoune-acp shutdown` and restart"
+    )
 }
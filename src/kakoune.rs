@@ -1,20 +1,56 @@
 use std::{
+    collections::HashMap,
     env, fs,
-    io::Write,
+    io::{self, Write},
+    os::unix::{net::UnixStream, process::ExitStatusExt},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::{
+        Arc, Mutex, OnceLock, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, anyhow};
 
-pub fn resolve_socket_path(explicit: Option<PathBuf>, session: Option<&str>) -> Result<PathBuf> {
+use crate::{
+    cli::{InfoStyle, SocketScope},
+    ipc::{CommandSummary, PlanEntrySummary},
+};
+
+/// Filetype of the scratch buffers transcripts are shown in; `kakoune-acp init` defines its
+/// highlighters.
+pub const TRANSCRIPT_FILETYPE: &str = "acp-transcript";
+
+/// Scratch buffer `--kak-locations` lists the files a prompt touched in.
+pub const LOCATIONS_BUFFER: &str = "*acp-locations*";
+
+/// The daemon socket: `explicit` when given, otherwise named after what `scope` selects.
+///
+/// Derived names never contain `@` except in the `cwd` and `global` scopes and for `oneshot`
+/// daemons, so those sockets cannot collide with a session's.
+pub fn resolve_socket_path(
+    explicit: Option<PathBuf>,
+    session: Option<&str>,
+    scope: SocketScope,
+) -> Result<PathBuf> {
     if let Some(path) = explicit {
         ensure_parent_exists(&path)?;
         return Ok(path);
     }
 
-    let session_name = session.unwrap_or("default");
-    let sanitized = sanitize_session_name(session_name);
+    let name = match scope {
+        SocketScope::Session => sanitize_session_name(session.unwrap_or("default")),
+        SocketScope::Cwd => project_socket_name(&project_root()?),
+        SocketScope::Global => "@global".to_string(),
+    };
+    Ok(socket_directory()?.join(format!("{name}.sock")))
+}
+
+/// `$XDG_RUNTIME_DIR/kakoune-acp`, or the same under the temporary directory, created if
+/// missing.
+pub fn socket_directory() -> Result<PathBuf> {
     let base = env::var_os("XDG_RUNTIME_DIR")
         .map(PathBuf::from)
         .filter(|p| !p.as_os_str().is_empty())
@@ -26,10 +62,243 @@ pub fn resolve_socket_path(explicit: Option<PathBuf>, session: Option<&str>) ->
             directory.display()
         )
     })?;
-    Ok(directory.join(format!("{sanitized}.sock")))
+    Ok(directory)
+}
+
+/// The directory `--socket-scope cwd` names sockets after: the nearest one at or above the
+/// current directory that holds `.git`, or the current directory itself.
+pub fn project_root() -> Result<PathBuf> {
+    let cwd = env::current_dir().context("cannot read the current directory")?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    Ok(cwd
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(&cwd)
+        .to_path_buf())
 }
 
+/// `@NAME-HASH`, with the start of the root's last component for people listing the socket
+/// directory and an FNV-1a hash of the whole path, stable across builds, telling them apart.
+fn project_socket_name(root: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let hash = root
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let name = root
+        .file_name()
+        .map(|name| sanitize_session_name(&name.to_string_lossy()))
+        .unwrap_or_default();
+    let name: String = name.chars().take(32).collect();
+    format!("@{name}-{hash:016x}")
+}
+
+/// Directory holding persisted per-session state such as transcripts.
+///
+/// Follows `$XDG_STATE_HOME`, falling back to `~/.local/state`.
+pub fn resolve_state_dir(session: Option<&str>) -> Result<PathBuf> {
+    let base = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| !p.as_os_str().is_empty())
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(PathBuf::from)
+                .map(|home| home.join(".local").join("state"))
+        })
+        .ok_or_else(|| anyhow!("neither XDG_STATE_HOME nor HOME is set"))?;
+    let session_name = sanitize_session_name(session.unwrap_or("default"));
+    Ok(base.join("kakoune-acp").join(session_name))
+}
+
+/// Runs `command` in the Kakoune session `session`.
+///
+/// The command goes straight to the session's socket; `kak -p` is the fallback when that
+/// fails, for sessions kept somewhere this process does not look. Once [`use_sinks`] has been
+/// called, as the daemon does, it goes through the session's [`KakouneSink`].
 pub fn send_to_kak(session: &str, command: &str) -> Result<()> {
+    if let Some(sinks) = SINKS.get() {
+        let sink = sinks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(session.to_string())
+            .or_insert_with(|| Arc::new(KakouneSink::new(session)))
+            .clone();
+        return sink.send(command);
+    }
+    send_once(session, command)
+}
+
+/// The sink of every session this process has sent to, once [`use_sinks`] was called.
+static SINKS: OnceLock<Mutex<HashMap<String, Arc<KakouneSink>>>> = OnceLock::new();
+
+/// Routes every later [`send_to_kak`] of this process through a [`KakouneSink`] per session.
+pub fn use_sinks() {
+    SINKS.get_or_init(Mutex::default);
+}
+
+/// Sends the commands for one Kakoune session one at a time, sending a command again when
+/// the `kak -p` carrying it is killed.
+///
+/// Kakoune evaluates one command per connection and `kak -p` sends nothing before its input
+/// ends, so neither can be kept open from one command to the next. What the sink keeps is
+/// the order: updates sent from several threads reach the session as they were sent, and a
+/// session that is slow to answer does not gather a `kak -p` per update.
+pub struct KakouneSink {
+    session: String,
+    /// Held for the whole of a send, retry included.
+    writer: Mutex<()>,
+}
+
+impl KakouneSink {
+    pub fn new(session: &str) -> Self {
+        Self {
+            session: session.to_string(),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Runs `command` in the session once the commands sent before it have been.
+    pub fn send(&self, command: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        match send_once(&self.session, command) {
+            Err(err) if err.is::<KakKilled>() => {
+                tracing::debug!(session = self.session, "{err}; sending the command again");
+                send_once(&self.session, command)
+            }
+            sent => sent,
+        }
+    }
+}
+
+/// Writes `command` to the session's socket, or through `kak -p` when that fails.
+fn send_once(session: &str, command: &str) -> Result<()> {
+    let direct = Connection::open(session).and_then(|connection| connection.send(command));
+    let Err(socket_error) = direct else {
+        return Ok(());
+    };
+    tracing::debug!("{socket_error}; falling back to kak -p");
+    send_with_kak_p(session, command).map_err(|err| {
+        // Left as it is so that a sink can tell it apart.
+        if err.is::<KakKilled>() {
+            err
+        } else {
+            anyhow!("{socket_error}; {err:#}")
+        }
+    })
+}
+
+/// `kak -p` died of a signal, so its command may never have reached the session.
+#[derive(Debug)]
+struct KakKilled(i32);
+
+impl std::fmt::Display for KakKilled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "kak -p was killed by signal {}", self.0)
+    }
+}
+
+impl std::error::Error for KakKilled {}
+
+/// Why a command could not be written to a Kakoune session's socket.
+#[derive(Debug)]
+pub enum SocketError {
+    /// No session listens at the path.
+    Missing(PathBuf),
+    /// The session accepted the connection but the command could not be sent.
+    Protocol(PathBuf, io::Error),
+}
+
+impl std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(path) => write!(f, "Kakoune session socket {} not found", path.display()),
+            Self::Protocol(path, err) => write!(
+                f,
+                "failed to send to the Kakoune session socket {}: {err}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SocketError {}
+
+/// A connection to a Kakoune session's socket, speaking the protocol `kak -p` uses.
+///
+/// Kakoune evaluates one command per connection, so `send` consumes it.
+pub struct Connection {
+    path: PathBuf,
+    stream: UnixStream,
+}
+
+impl Connection {
+    /// Kakoune's `MessageType::Command`.
+    const COMMAND: u8 = 2;
+
+    pub fn open(session: &str) -> Result<Self, SocketError> {
+        let path = session_socket_path(session);
+        if session.is_empty() || session.contains('/') {
+            return Err(SocketError::Missing(path));
+        }
+        match UnixStream::connect(&path) {
+            Ok(stream) => Ok(Self { path, stream }),
+            // A socket left behind by a session that died refuses connections.
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Err(SocketError::Missing(path))
+            }
+            Err(err) => Err(SocketError::Protocol(path, err)),
+        }
+    }
+
+    /// Writes `command` as one message: the type, the message's total length, then the
+    /// command as a length-prefixed string, with lengths in native byte order.
+    pub fn send(mut self, command: &str) -> Result<(), SocketError> {
+        let mut message = Vec::with_capacity(command.len() + 9);
+        message.push(Self::COMMAND);
+        message.extend_from_slice(&(command.len() as u32 + 9).to_ne_bytes());
+        message.extend_from_slice(&(command.len() as u32).to_ne_bytes());
+        message.extend_from_slice(command.as_bytes());
+        self.stream
+            .write_all(&message)
+            .map_err(|err| SocketError::Protocol(self.path, err))
+    }
+}
+
+/// Where Kakoune puts the socket of `session`: `$XDG_RUNTIME_DIR/kakoune/SESSION`, or
+/// `$TMPDIR/kakoune-USER/SESSION` without a runtime directory.
+pub fn session_socket_path(session: &str) -> PathBuf {
+    let directory = match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(runtime) => PathBuf::from(runtime).join("kakoune"),
+        None => {
+            let tmp = env::var_os("TMPDIR")
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or_else(|| "/tmp".into());
+            PathBuf::from(tmp).join(format!("kakoune-{}", user_name()))
+        }
+    };
+    directory.join(session)
+}
+
+/// The effective user's login name, as Kakoune looks it up.
+fn user_name() -> String {
+    let entry = unsafe { libc::getpwuid(libc::geteuid()) };
+    if !entry.is_null() {
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).pw_name) };
+        return name.to_string_lossy().into_owned();
+    }
+    env::var("USER").unwrap_or_default()
+}
+
+fn send_with_kak_p(session: &str, command: &str) -> Result<()> {
     let mut child = Command::new("kak")
         .arg("-p")
         .arg(session)
@@ -39,31 +308,540 @@ pub fn send_to_kak(session: &str, command: &str) -> Result<()> {
         .spawn()
         .with_context(|| format!("failed to spawn kak -p {session}"))?;
 
-    child
+    let written = child
         .stdin
-        .as_mut()
+        .take()
         .ok_or_else(|| anyhow!("failed to acquire kak stdin"))?
-        .write_all(command.as_bytes())?;
+        .write_all(command.as_bytes());
     let status = child.wait()?;
+    if let Some(signal) = status.signal() {
+        return Err(KakKilled(signal).into());
+    }
+    written.with_context(|| format!("failed to write to kak -p {session}"))?;
     if !status.success() {
         return Err(anyhow!("kak exited with status {status}"));
     }
     Ok(())
 }
 
-pub fn format_info_command(client: Option<&str>, title: &str, body: &str) -> String {
-    let info = format!("info -title {} {}\n", kak_quote(title), kak_quote(body));
+/// Option the `kakoune-acp init` script sets to the client that last gained focus.
+pub const FOCUSED_CLIENT_OPTION: &str = "acp_focused_client";
+
+/// How long `focused_client` waits for the session to answer.
+const CLIENT_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The client of `session` that last gained focus, or its first client when that is not
+/// known; `None` when the session has no clients.
+///
+/// The session writes `%opt{acp_focused_client}` and `%val{client_list}` to a temporary
+/// file, which is read once the answer is complete.
+pub fn focused_client(session: &str) -> Result<Option<String>> {
+    static QUERIES: AtomicU64 = AtomicU64::new(0);
+    let path = env::temp_dir().join(format!(
+        "kakoune-acp-clients-{}-{}",
+        std::process::id(),
+        QUERIES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let command = format!(
+        "try %{{ declare-option -hidden str {FOCUSED_CLIENT_OPTION} }}\n\
+         echo -quoting kakoune -to-file {} -- %opt{{{FOCUSED_CLIENT_OPTION}}} %val{{client_list}} end\n",
+        kak_quote(&path.to_string_lossy())
+    );
+    send_to_kak(session, &command)?;
+    let deadline = Instant::now() + CLIENT_QUERY_TIMEOUT;
+    // The last word marks an answer that has been written in full.
+    let words = loop {
+        let words = fs::read_to_string(&path)
+            .map(|answer| unquote_words(&answer))
+            .unwrap_or_default();
+        if words.last().is_some_and(|word| word == "end") {
+            break words;
+        }
+        if Instant::now() > deadline {
+            let _ = fs::remove_file(&path);
+            return Err(anyhow!(
+                "Kakoune session {session} did not list its clients within {}s",
+                CLIENT_QUERY_TIMEOUT.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let _ = fs::remove_file(&path);
+    let (focused, clients) = match &words[..words.len() - 1] {
+        [focused, clients @ ..] => (focused, clients),
+        [] => return Err(anyhow!("Kakoune session {session} sent no client list")),
+    };
+    Ok(clients
+        .iter()
+        .find(|client| *client == focused)
+        .or(clients.first())
+        .cloned())
+}
+
+/// `%sh` around `body`, between delimiters that do not clash with it; `None` when every
+/// candidate does.
+pub fn sh_block(body: &str) -> Option<String> {
+    // `%sh` takes the delimiters `kak_block` picks from, but not its single-quote fallback;
+    // any other character that does not occur in the body closes it too.
+    if let Some(delimited) = kak_block(body).strip_prefix('%') {
+        return Some(format!("%sh{delimited}"));
+    }
+    let delimiter = ['|', '~', '^', '@', '!']
+        .into_iter()
+        .find(|delimiter| !body.contains(*delimiter))?;
+    Some(format!("%sh{delimiter}{body}{delimiter}"))
+}
+
+/// Splits text written with `-quoting kakoune` back into its words.
+fn unquote_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\'' {
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    word.push('\'');
+                }
+                '\'' => break,
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+    words
+}
+
+/// Whether `session` is running: its socket accepts connections or, for sessions whose socket
+/// is elsewhere, `kak -l` lists it.
+pub fn session_alive(session: &str) -> io::Result<bool> {
+    if Connection::open(session).is_ok() {
+        return Ok(true);
+    }
+    Ok(list_sessions()?.iter().any(|running| running == session))
+}
+
+/// Fails unless `session` is running, naming the sessions that are.
+pub fn ensure_session(session: &str) -> Result<()> {
+    if Connection::open(session).is_ok() {
+        return Ok(());
+    }
+    let running = match list_sessions() {
+        Ok(sessions) if sessions.iter().any(|running| running == session) => return Ok(()),
+        Ok(sessions) if sessions.is_empty() => "no running sessions".to_string(),
+        Ok(sessions) => format!("running sessions: {}", sessions.join(", ")),
+        Err(err) => format!("cannot list sessions: {err}"),
+    };
+    Err(anyhow!("Kakoune session '{session}' not found ({running})"))
+}
+
+/// Names of the running Kakoune sessions, as listed by `kak -l`.
+///
+/// Sessions `kak` reports as dead are left out.
+pub fn list_sessions() -> std::io::Result<Vec<String>> {
+    let output = Command::new("kak")
+        .arg("-l")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "kak -l exited with status {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.ends_with("(dead)"))
+        .map(str::to_string)
+        .collect())
+}
+
+pub fn format_info_command(
+    client: Option<&str>,
+    title: &str,
+    body: &str,
+    style: Option<InfoStyle>,
+) -> String {
+    // The cursor styles anchor the box where the client's cursor is when the command runs.
+    let flags = match style {
+        None => "",
+        Some(InfoStyle::Modal) => "-style modal ",
+        Some(InfoStyle::Prompt) => "-style prompt ",
+        Some(InfoStyle::AboveCursor) => {
+            "-style above -anchor \"%val{cursor_line}.%val{cursor_column}\" "
+        }
+        Some(InfoStyle::BelowCursor) => {
+            "-style below -anchor \"%val{cursor_line}.%val{cursor_column}\" "
+        }
+    };
+    let info = format!(
+        "info {flags}-title {} {}\n",
+        kak_quote(title),
+        kak_quote(body)
+    );
     match client {
-        Some(client) => format!("eval -client {} %{{{info}}}\n", kak_quote(client)),
+        Some(client) => format!("eval -client {} {}\n", kak_quote(client), kak_block(&info)),
         None => info,
     }
 }
 
+/// Commands that show every client of a session that the agent died, with `detail` in an
+/// info box.
+///
+/// `kak -p` runs without a client, so the message is fanned out over `%val{client_list}` and
+/// also written to `*debug*` for sessions that have no client attached.
+pub fn format_agent_exit_command(detail: &str) -> String {
+    const TEMPLATE: &str = r#"try %{ declare-option -hidden str kakoune_acp_agent_exit }
+set-option global kakoune_acp_agent_exit DETAIL
+echo -debug "kakoune-acp: %opt{kakoune_acp_agent_exit}"
+evaluate-commands %sh{
+    for client in $kak_client_list; do
+        printf 'evaluate-commands -client %s %%{ echo -markup "{Error}ACP agent exited"; info -title "ACP agent exited" %%opt{kakoune_acp_agent_exit} }\n' "$client"
+    done
+}
+"#;
+    TEMPLATE.replacen("DETAIL", &kak_quote(detail), 1)
+}
+
+/// Sets the `acp_state` option, declaring it first if needed, for modeline indicators.
+pub fn format_state_command(state: &str) -> String {
+    format!(
+        "try %{{ declare-option -hidden str acp_state }}\nset-option global acp_state {}\n",
+        kak_quote(state)
+    )
+}
+
+/// Commands showing a permission request as a menu, one entry per choice.
+///
+/// Each choice pairs a label with the shell command that reports it back to the daemon.
+/// Without a `client` the menu opens in the session's first client. Choices whose command
+/// cannot be put in a shell block are left out.
+pub fn format_permission_menu(
+    client: Option<&str>,
+    title: &str,
+    choices: &[(String, String)],
+) -> String {
+    let mut menu = format!(
+        "info -title {} {}\nmenu",
+        kak_quote("ACP permission"),
+        kak_quote(title)
+    );
+    for (label, command) in choices {
+        // The command carries the agent's option id, which may hold any delimiter.
+        let Some(shell) = sh_block(&format!(" {command} ")) else {
+            continue;
+        };
+        let action = format!("nop {shell}");
+        menu.push_str(&format!(" {} {}", kak_quote(label), kak_quote(&action)));
+    }
+    in_client(client, &menu)
+}
+
+/// Commands closing a permission menu nobody answered and saying what was decided instead.
+///
+/// The menu is closed with `<esc>`, as a user would.
+pub fn format_permission_dismiss(client: Option<&str>, note: &str) -> String {
+    let commands = format!(
+        "execute-keys <esc>\ninfo -title {} {}",
+        kak_quote("ACP permission"),
+        kak_quote(note)
+    );
+    in_client(client, &commands)
+}
+
+/// Commands showing `status` in `client`'s status line, or clearing it with `None`.
+pub fn format_progress_command(client: Option<&str>, status: Option<&str>) -> String {
+    let echo = match status {
+        // `{` starts a face in markup unless escaped.
+        Some(status) => format!(
+            "echo -markup {}",
+            kak_quote(&format!("{{Information}}{}", status.replace('{', "\\{")))
+        ),
+        None => "echo ''".to_string(),
+    };
+    in_client(client, &echo)
+}
+
+/// An info box in `client` showing `entries` as a checklist, followed by `footer` when given.
+/// High-priority steps are in the `Error` face and low-priority ones in `comment`.
+pub fn format_plan_command(
+    client: Option<&str>,
+    entries: &[PlanEntrySummary],
+    footer: Option<&str>,
+) -> String {
+    let mut text = String::new();
+    for entry in entries {
+        let check = match entry.status.as_str() {
+            "Completed" => "[x]",
+            "InProgress" => "[>]",
+            _ => "[ ]",
+        };
+        let face = match entry.priority.as_str() {
+            "High" => "Error",
+            "Low" => "comment",
+            _ => "Default",
+        };
+        // `{` starts a face in markup unless escaped.
+        text.push_str(&format!(
+            "{{{face}}}{check} {}\n",
+            entry.content.replace('{', "\\{")
+        ));
+    }
+    if let Some(footer) = footer {
+        text.push_str(&format!("{{Default}}{}", footer.replace('{', "\\{")));
+    }
+    in_client(
+        client,
+        &format!(
+            "info -markup -title {} {}",
+            kak_quote("ACP plan"),
+            kak_quote(text.trim_end())
+        ),
+    )
+}
+
+/// Runs `commands` in `client`, or in the session's first client when none is given.
+///
+/// The first client is only known to the shell, but `%sh` blocks end at the first closing
+/// delimiter whatever the quoting, so the commands wait in an option rather than pass
+/// through it.
+pub fn in_client(client: Option<&str>, commands: &str) -> String {
+    match client {
+        Some(client) => format!(
+            "evaluate-commands -client {} {}\n",
+            kak_quote(client),
+            kak_block(commands)
+        ),
+        None => {
+            const FIRST_CLIENT: &str = r#"try %{ declare-option -hidden str kakoune_acp_commands }
+set-option global kakoune_acp_commands COMMANDS
+evaluate-commands %sh{
+    set -- $kak_client_list
+    [ -n "$1" ] && printf 'evaluate-commands -client %s %%opt{kakoune_acp_commands}\n' "$1"
+}
+"#;
+            FIRST_CLIENT.replacen("COMMANDS", &kak_quote(commands), 1)
+        }
+    }
+}
+
+/// Commands showing `body` in the scratch buffer `buffer` in `client`, creating the buffer
+/// or replacing what it held.
+///
+/// The text goes through the `"` register, which is saved and restored around it, and the
+/// buffer gets the `acp-transcript` filetype.
+pub fn format_buffer_command(client: Option<&str>, buffer: &str, body: &str) -> String {
+    fill_scratch(client, buffer, body, TRANSCRIPT_FILETYPE)
+}
+
+/// Commands showing `lines`, in `path:line:col: text` form, in the `grep` filetype buffer
+/// `LOCATIONS_BUFFER`, where `<ret>` jumps to the location on the current line.
+pub fn format_locations_command(client: Option<&str>, lines: &str) -> String {
+    fill_scratch(client, LOCATIONS_BUFFER, lines, "grep")
+}
+
+fn fill_scratch(client: Option<&str>, buffer: &str, body: &str, filetype: &str) -> String {
+    let fill = format!(
+        "edit -scratch {}\nset-register dquote {}\nexecute-keys '%Rgg'\nset-option buffer filetype {filetype}",
+        kak_quote(buffer),
+        kak_quote(body)
+    );
+    let commands = format!("evaluate-commands -save-regs '\"' {}", kak_block(&fill));
+    match client {
+        Some(client) => format!(
+            "eval -client {} {}\n",
+            kak_quote(client),
+            kak_block(&commands)
+        ),
+        None => format!("{commands}\n"),
+    }
+}
+
+/// Sets `register` to `value` in `client`.
+pub fn format_register_command(client: Option<&str>, register: &str, value: &str) -> String {
+    let set = format!("set-register {} {}", kak_quote(register), kak_quote(value));
+    match client {
+        Some(client) => format!("eval -client {} {}\n", kak_quote(client), kak_block(&set)),
+        None => format!("{set}\n"),
+    }
+}
+
+/// Commands adding the contents of `chunk` to the end of the scratch buffer `buffer` in
+/// `client`, creating the buffer first if needed; the shell that reads the file deletes it.
+pub fn format_buffer_append_command(client: Option<&str>, buffer: &str, chunk: &Path) -> String {
+    let chunk = sh_quote(&chunk.to_string_lossy());
+    let read = format!("cat {chunk}; rm -f {chunk}<ret>");
+    let append = format!(
+        "try {} catch {}\nset-option buffer filetype {TRANSCRIPT_FILETYPE}",
+        kak_block(&format!(
+            "buffer {}\nexecute-keys {}",
+            kak_quote(buffer),
+            kak_quote(&format!("ge<a-!>{read}"))
+        )),
+        kak_block(&format!(
+            "edit -scratch {}\nexecute-keys {}",
+            kak_quote(buffer),
+            kak_quote(&format!("%|{read}"))
+        )),
+    );
+    match client {
+        Some(client) => format!(
+            "eval -client {} {}\n",
+            kak_quote(client),
+            kak_block(&append)
+        ),
+        None => format!("{append}\n"),
+    }
+}
+
+/// Commands inserting the contents of `file` before the main cursor of `client`, leaving its
+/// selections where they were; the shell that reads the file deletes it.
+///
+/// Going through `!` rather than typing the text in insert mode keeps `<`, newlines and the
+/// like from being read as keys.
+pub fn format_insert_command(client: Option<&str>, file: &Path) -> String {
+    let file = sh_quote(&file.to_string_lossy());
+    let insert = format!(
+        "execute-keys -draft {}",
+        kak_quote(&format!(",;!cat {file}; rm -f {file}<ret>"))
+    );
+    match client {
+        Some(client) => format!(
+            "eval -client {} {}\n",
+            kak_quote(client),
+            kak_block(&insert)
+        ),
+        None => format!("{insert}\n"),
+    }
+}
+
+/// Commands emptying the scratch buffer `buffer`, if it exists.
+pub fn format_buffer_clear_command(buffer: &str) -> String {
+    format!(
+        "try %{{ evaluate-commands -buffer {} %{{ execute-keys -draft '%d' }} }}\n",
+        kak_quote(buffer)
+    )
+}
+
+/// Defines `acp-open-diff`, which shows `diff` in a `*acp-diff*` scratch buffer.
+pub fn format_diff_command(diff: &str) -> String {
+    let body = format!(
+        "edit -scratch *acp-diff*\nset-register dquote {}\nexecute-keys '%R'\nset-option buffer filetype diff",
+        kak_quote(diff)
+    );
+    format!(
+        "define-command -override -docstring {} acp-open-diff {}\n",
+        kak_quote("show the file edits of the last ACP prompt"),
+        kak_quote(&body)
+    )
+}
+
+/// Most agent commands `format_commands_menu` lists.
+pub const MAX_MENU_COMMANDS: usize = 20;
+
+/// A `menu` of the commands an agent advertised; choosing one runs it through `binary
+/// command` in the background, asking for its input first when it takes some.
+///
+/// Commands whose name cannot be put in a shell block are left out.
+pub fn format_commands_menu(
+    client: Option<&str>,
+    binary: &Path,
+    socket: Option<&Path>,
+    commands: &[CommandSummary],
+) -> String {
+    let socket = socket
+        .map(|socket| format!("--socket {} ", sh_quote(&socket.to_string_lossy())))
+        .unwrap_or_default();
+    let mut menu = "menu".to_string();
+    for command in commands.iter().take(MAX_MENU_COMMANDS) {
+        let hint = match &command.input {
+            Some(input) => Some(input.prompt_hint()),
+            None => command.hint.clone(),
+        };
+        let input = match hint {
+            Some(_) => r#" --input "$kak_text""#,
+            None => "",
+        };
+        let shell = format!(
+            r#"({} command {socket}--session "$kak_session" --client "$kak_client" --name {}{input} --output kak-commands --send-to-kak) >/dev/null 2>&1 </dev/null &"#,
+            sh_quote(&binary.to_string_lossy()),
+            sh_quote(&command.name)
+        );
+        let Some(shell) = sh_block(&format!(" {shell} ")) else {
+            continue;
+        };
+        let mut action = format!("nop {shell}");
+        if let Some(hint) = &hint {
+            action = format!(
+                "prompt {} {}",
+                kak_quote(&format!("/{} ({hint}): ", command.name)),
+                kak_block(&action)
+            );
+        }
+        let label = format!("/{}: {}", command.name, command.description);
+        menu.push_str(&format!(" {} {}", kak_quote(&label), kak_quote(&action)));
+    }
+    in_client(client, &menu)
+}
+
+/// Sets the `acp_images` option to the images saved during the last prompt, so previewer
+/// hooks can pick them up.
+pub fn format_images_command(paths: &[&Path]) -> String {
+    let paths = paths
+        .iter()
+        .map(|path| kak_quote(&path.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "try %{{ declare-option -docstring {} str-list acp_images }}\nset-option global acp_images {paths}\n",
+        kak_quote("images saved during the last ACP prompt")
+    )
+}
+
+/// Quotes `value` as a single POSIX shell word.
+pub fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quotes `value` as a single Kakoune word. Newlines are kept as they are; inside single
+/// quotes only `'` needs escaping.
 pub fn kak_quote(value: &str) -> String {
     let escaped = value.replace('\'', "''");
     format!("'{}'", escaped)
 }
 
+/// Wraps the commands in `body` as one Kakoune word in the most readable way that is safe.
+///
+/// `%{...}` strings take no escapes and end at the brace that balances the opening one, so
+/// the first of `{}`, `[]`, `()` and `<>` that nests properly within `body` is used, and
+/// `kak_quote` when none does.
+pub fn kak_block(body: &str) -> String {
+    for (open, close) in [('{', '}'), ('[', ']'), ('(', ')'), ('<', '>')] {
+        let mut depth = 0usize;
+        let nested = body.chars().all(|ch| {
+            if ch == open {
+                depth += 1;
+            } else if ch == close {
+                let Some(outer) = depth.checked_sub(1) else {
+                    return false;
+                };
+                depth = outer;
+            }
+            true
+        });
+        if nested && depth == 0 {
+            return format!("%{open}{body}{close}");
+        }
+    }
+    kak_quote(body)
+}
+
 fn sanitize_session_name(name: &str) -> String {
     name.chars()
         .map(|ch| match ch {
@@ -74,11 +852,11 @@ fn sanitize_session_name(name: &str) -> String {
 }
 
 fn ensure_parent_exists(path: &Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("failed to create directory {}", parent.display()))?;
-        }
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory {}", parent.display()))?;
     }
     Ok(())
 }
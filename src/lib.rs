@@ -0,0 +1,93 @@
+//! Kakoune integration for the Agent Client Protocol.
+//!
+//! The `kakoune-acp` binary is a thin wrapper around [`run`]. Editor integrations can use
+//! the modules directly instead: [`daemon::run`] serves agents on a socket, [`prompt::execute`]
+//! sends a prompt to it and returns the result, [`ipc`] and [`ipc_client`] speak the socket
+//! protocol, [`transcript`] turns ACP session updates into transcript events and [`kakoune`]
+//! quotes and sends Kakoune commands.
+
+mod audit;
+pub mod cli;
+mod config;
+pub mod daemon;
+mod diffs;
+mod ext;
+mod history;
+mod init;
+pub mod ipc;
+pub mod ipc_client;
+pub mod kakoune;
+mod logging;
+mod media;
+mod oneshot;
+mod permission_rules;
+pub mod prompt;
+mod redact;
+mod render;
+mod sandbox;
+mod status;
+mod terminal;
+pub mod transcript;
+mod watch;
+
+use anyhow::{Context, Result};
+
+/// Runs a parsed command line as the `kakoune-acp` binary does, config file included.
+pub async fn run(mut cli: cli::Cli) -> Result<()> {
+    // Health checks and shutdown keep working even when the config file is broken.
+    let config = match cli.command {
+        cli::Command::Daemon(_)
+        | cli::Command::Prompt(_)
+        | cli::Command::Run(_)
+        | cli::Command::Oneshot(_)
+        | cli::Command::Transcript(_)
+        | cli::Command::Config(_) => config::Config::load(&config::resolve_config_path()?)?,
+        _ => config::Config::default(),
+    };
+    // Reloading re-applies the config file to the daemon's original command line.
+    let daemon_command_line = match &cli.command {
+        cli::Command::Daemon(options) => Some(options.clone()),
+        _ => None,
+    };
+    config.apply(&mut cli.command);
+
+    match &cli.command {
+        cli::Command::Daemon(options) => {
+            logging::init(options.log_file.as_deref(), options.log_level.as_deref())?
+        }
+        _ => logging::init(None, None)?,
+    }
+
+    match cli.command {
+        cli::Command::Daemon(options) => {
+            let command_line = daemon_command_line.context("daemon command line was not kept")?;
+            daemon::run_with_command_line(options, command_line).await
+        }
+        cli::Command::Prompt(options) => prompt::run(options).await,
+        cli::Command::Run(options) => prompt::run_command(options).await,
+        cli::Command::Oneshot(options) => oneshot::run(options, &config).await,
+        cli::Command::Status(options) => status::run_status(options).await,
+        cli::Command::Shutdown(options) => status::run_shutdown(options).await,
+        cli::Command::Ping(options) => status::run_ping(options).await,
+        cli::Command::RestartAgent(options) => status::run_restart_agent(options).await,
+        cli::Command::Cancel(options) => status::run_cancel(options).await,
+        cli::Command::Reload(options) => status::run_reload(options).await,
+        cli::Command::PermissionReply(options) => status::run_permission_reply(options).await,
+        cli::Command::Permissions(cli::PermissionsCommand::List(options)) => {
+            status::run_permissions_list(options).await
+        }
+        cli::Command::Permissions(cli::PermissionsCommand::Clear(options)) => {
+            status::run_permissions_clear(options).await
+        }
+        cli::Command::Audit(options) => audit::run(options).await,
+        cli::Command::Init(options) => init::run(options),
+        cli::Command::Watch(options) => watch::run(options).await,
+        cli::Command::Attach(options) => watch::run_attach(options).await,
+        cli::Command::Transcript(options) => history::run(options).await,
+        cli::Command::Schema => history::run_schema(),
+        cli::Command::Config(options) => config::run(options, config),
+        cli::Command::Session(cli::SessionCommand::Close(options)) => {
+            status::run_session_close(options).await
+        }
+    }
+}
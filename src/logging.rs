@@ -0,0 +1,64 @@
+use std::{
+    fs::OpenOptions,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{Context, Result, anyhow};
+use tracing_subscriber::{EnvFilter, reload};
+
+type SetFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+/// Swaps the active filter; set once `init` has installed the subscriber.
+static SET_FILTER: OnceLock<SetFilter> = OnceLock::new();
+
+/// Installs the global subscriber, writing to `log_file` when given and to stderr otherwise.
+///
+/// `log_level` takes an `EnvFilter` directive such as `info` or `kakoune_acp=debug`; without
+/// one, `RUST_LOG` decides.
+pub fn init(log_file: Option<&Path>, log_level: Option<&str>) -> Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(log_level)?)
+        .with_target(false)
+        .compact();
+    match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?;
+            let builder = builder
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = SET_FILTER.set(Box::new(move |filter| handle.reload(filter)));
+            builder.init();
+        }
+        None => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = SET_FILTER.set(Box::new(move |filter| handle.reload(filter)));
+            builder.init();
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the log filter of the running process, as `init` would have set it up.
+pub fn set_level(log_level: Option<&str>) -> Result<()> {
+    let set_filter = SET_FILTER
+        .get()
+        .ok_or_else(|| anyhow!("logging is not initialised"))?;
+    set_filter(filter(log_level)?).context("failed to swap the log filter")
+}
+
+fn filter(log_level: Option<&str>) -> Result<EnvFilter> {
+    match log_level {
+        Some(level) => {
+            EnvFilter::try_new(level).with_context(|| format!("invalid log level {level:?}"))
+        }
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result};
+
+use crate::ipc::TranscriptEvent;
+
+/// Largest image saved when `--max-media-bytes` is not given.
+pub const DEFAULT_MAX_MEDIA_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Saves the images agents send as numbered files, `images/001.png` and so on.
+pub struct MediaStore {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl MediaStore {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    /// Decodes `image` into the next free file and returns the event describing it.
+    pub fn save_image(&self, image: &acp::ImageContent) -> Result<TranscriptEvent> {
+        // Four base64 characters carry three bytes; refuse oversized images before decoding.
+        let estimate = image.data.len() as u64 / 4 * 3;
+        anyhow::ensure!(
+            estimate <= self.max_bytes.saturating_add(3),
+            "image is larger than the {}-byte limit",
+            self.max_bytes
+        );
+        let bytes = decode_base64(&image.data).context("image data is not valid base64")?;
+        anyhow::ensure!(
+            bytes.len() as u64 <= self.max_bytes,
+            "{}-byte image is larger than the {}-byte limit",
+            bytes.len(),
+            self.max_bytes
+        );
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let extension = extension(&image.mime_type);
+        let mut number = next_number(&self.dir)?;
+        // Prompts on other agents save into the same directory; never overwrite their files.
+        let (path, mut file) = loop {
+            let path = self.dir.join(format!("{number:03}.{extension}"));
+            match std::fs::File::create_new(&path) {
+                Ok(file) => break (path, file),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to create {}", path.display()));
+                }
+            }
+        };
+        file.write_all(&bytes)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(TranscriptEvent::Image {
+            path,
+            mime_type: image.mime_type.clone(),
+            bytes: bytes.len() as u64,
+        })
+    }
+}
+
+/// One past the highest number among the files in `dir`.
+fn next_number(dir: &std::path::Path) -> Result<u64> {
+    let mut highest = 0;
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))?
+    {
+        let name = entry?.file_name();
+        if let Some(number) = name
+            .to_str()
+            .and_then(|name| name.split('.').next())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            highest = highest.max(number);
+        }
+    }
+    Ok(highest + 1)
+}
+
+fn extension(mime_type: &str) -> &str {
+    match mime_type {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/bmp" => "bmp",
+        "image/tiff" => "tiff",
+        "image/avif" => "avif",
+        _ => "bin",
+    }
+}
+
+/// Decodes standard base64, padded or not, skipping whitespace.
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let mut padding = 0;
+    for byte in data.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                padding += 1;
+                continue;
+            }
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        // Data after padding is malformed.
+        if padding > 0 {
+            return None;
+        }
+        buffer = buffer << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    (padding <= 2 && bits < 6).then_some(bytes)
+}
@@ -0,0 +1,106 @@
+//! `oneshot`: one prompt to an agent started for it, sent through a daemon of its own that
+//! stops, and stops the agent, once the prompt has been answered.
+
+use std::{ffi::OsString, path::Path, time::Duration};
+
+use anyhow::{Result, anyhow};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    cli::{DaemonOptions, OneshotOptions},
+    config::Config,
+    daemon, ipc, ipc_client, kakoune, prompt,
+};
+
+/// How often the daemon's socket is tried while the agent starts.
+const STARTUP_POLL: Duration = Duration::from_millis(20);
+
+pub async fn run(options: OneshotOptions, config: &Config) -> Result<()> {
+    let OneshotOptions {
+        mut prompt,
+        daemon_args,
+        agent_command,
+    } = options;
+    if prompt.socket.is_some() {
+        return Err(anyhow!(
+            "oneshot starts a daemon of its own; --socket does not apply"
+        ));
+    }
+    let socket_path =
+        kakoune::socket_directory()?.join(format!("@oneshot-{}.sock", std::process::id()));
+    let mut daemon_options = daemon_options(
+        &socket_path,
+        prompt.agent.as_deref(),
+        &daemon_args,
+        agent_command,
+    )?;
+    config.daemon.apply(&mut daemon_options);
+    prompt.socket = Some(socket_path.clone());
+
+    let stopped = CancellationToken::new();
+    let daemon = async {
+        let result = daemon::run(daemon_options).await;
+        stopped.cancel();
+        result
+    };
+    let client = async {
+        if !wait_for_daemon(&socket_path, &stopped).await {
+            return None;
+        }
+        let outcome = prompt::run(prompt).await;
+        // The agent goes with the daemon, whether or not the prompt went through.
+        let request = ipc::DaemonRequest::Shutdown { force: false };
+        if let Err(err) = ipc_client::roundtrip(&socket_path, &request).await {
+            tracing::warn!("failed to stop the oneshot daemon: {err:#}");
+        }
+        Some(outcome)
+    };
+    let (stopped_with, outcome) = tokio::join!(daemon, client);
+    match outcome {
+        Some(outcome) => outcome.and(stopped_with),
+        None => stopped_with.and(Err(anyhow!(
+            "the daemon stopped before the prompt was sent"
+        ))),
+    }
+}
+
+/// The daemon's options, parsed as `daemon` parses them so they get its defaults.
+fn daemon_options(
+    socket_path: &Path,
+    agent_name: Option<&str>,
+    daemon_args: &[String],
+    agent_command: Vec<OsString>,
+) -> Result<DaemonOptions> {
+    let mut args: Vec<OsString> = vec![
+        "--socket".into(),
+        socket_path.into(),
+        "--no-kak-notifications".into(),
+    ];
+    if let Some(name) = agent_name {
+        args.extend(["--agent-name".into(), name.into()]);
+    }
+    args.extend(daemon_args.iter().map(OsString::from));
+    args.push("--".into());
+    args.extend(agent_command);
+    DaemonOptions::from_args(args).map_err(|err| {
+        let message = err.to_string();
+        let reason = message.lines().next().unwrap_or_default();
+        anyhow!(
+            "invalid --daemon-arg: {}",
+            reason.trim_start_matches("error: ")
+        )
+    })
+}
+
+/// Waits until the daemon listens on `socket_path`, or returns `false` if it stops first.
+async fn wait_for_daemon(socket_path: &Path, stopped: &CancellationToken) -> bool {
+    loop {
+        if tokio::net::UnixStream::connect(socket_path).await.is_ok() {
+            return true;
+        }
+        tokio::select! {
+            _ = stopped.cancelled() => return false,
+            _ = tokio::time::sleep(STARTUP_POLL) => {}
+        }
+    }
+}
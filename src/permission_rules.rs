@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::ipc::{PermissionRule, RuleDecision};
+
+/// Permission rules stored by "always allow" and "always deny" answers, optionally kept in a
+/// JSON file so they outlive the daemon.
+pub struct RuleStore {
+    rules: std::sync::Mutex<Vec<PermissionRule>>,
+    file: Option<PathBuf>,
+}
+
+impl RuleStore {
+    /// A store that only lives as long as the daemon.
+    pub fn in_memory() -> Self {
+        Self {
+            rules: std::sync::Mutex::new(Vec::new()),
+            file: None,
+        }
+    }
+
+    /// A store backed by `file`, starting from the rules saved there.
+    pub fn load(file: PathBuf) -> Result<Self> {
+        let rules = match std::fs::read(&file) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("failed to parse {}", file.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", file.display()));
+            }
+        };
+        Ok(Self {
+            rules: std::sync::Mutex::new(rules),
+            file: Some(file),
+        })
+    }
+
+    pub fn rules(&self) -> Vec<PermissionRule> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// The first rule covering a request for a `tool_kind` tool titled `title`.
+    pub fn matching(&self, tool_kind: &str, title: &str) -> Option<PermissionRule> {
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|rule| rule.tool_kind == tool_kind && title.starts_with(&rule.title_prefix))
+            .cloned()
+    }
+
+    /// Stores `decision` for requests like the one for a `tool_kind` tool titled `title`,
+    /// replacing any rule with the same key.
+    pub fn remember(&self, tool_kind: &str, title: &str, decision: RuleDecision) -> PermissionRule {
+        let rule = PermissionRule {
+            tool_kind: tool_kind.to_string(),
+            title_prefix: title_prefix(title).to_string(),
+            decision,
+        };
+        let mut rules = self.rules.lock().unwrap();
+        rules.retain(|stored| {
+            stored.tool_kind != rule.tool_kind || stored.title_prefix != rule.title_prefix
+        });
+        rules.push(rule.clone());
+        self.save(&rules);
+        rule
+    }
+
+    /// Forgets every rule, returning the ones that were stored.
+    pub fn clear(&self) -> Vec<PermissionRule> {
+        let mut rules = self.rules.lock().unwrap();
+        let cleared = std::mem::take(&mut *rules);
+        self.save(&rules);
+        cleared
+    }
+
+    /// Writes `rules` to the backing file. Failures are only logged: the rules still apply
+    /// until the daemon stops.
+    fn save(&self, rules: &[PermissionRule]) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let saved = serde_json::to_vec_pretty(rules)
+            .map_err(std::io::Error::other)
+            .and_then(|json| {
+                if let Some(parent) = file.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let temporary = file.with_extension("json.tmp");
+                std::fs::write(&temporary, json)?;
+                std::fs::rename(&temporary, file)
+            });
+        if let Err(err) = saved {
+            tracing::warn!(
+                "failed to save permission rules to {}: {err}",
+                file.display()
+            );
+        }
+    }
+}
+
+/// The part of a request title a rule is keyed by: its first word, which names the action
+/// (`Write` in `Write src/main.rs`) without the target that changes from one request to the
+/// next.
+fn title_prefix(title: &str) -> &str {
+    let title = title.trim_start();
+    title.split_whitespace().next().unwrap_or(title)
+}
@@ -1,41 +1,437 @@
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use agent_client_protocol as acp;
 use anyhow::{Context, Result, anyhow};
 use tokio::io::AsyncReadExt;
 
 use crate::{
-    cli::{PromptOptions, PromptOutput},
+    cli::{CommandOptions, InfoStyle, KakTarget, PromptOptions, PromptOutput, SocketScope},
+    config,
     ipc::{
-        self, ContextSnippet, DaemonResponse, PromptPayload, PromptResultPayload, TranscriptEvent,
+        self, ContextEntry, ContextSource, DaemonResponse, PromptPayload, PromptResultPayload,
+        StreamDisplay,
     },
     ipc_client, kakoune,
+    redact::Redactor,
+    render::{self, JsonRenderer, KakInfoRenderer, PlainRenderer},
 };
 
-pub async fn run(options: PromptOptions) -> Result<()> {
-    let socket_path =
-        kakoune::resolve_socket_path(options.socket.clone(), options.session.as_deref())?;
-    let prompt_text = read_prompt(&options).await?;
+/// `--client` value asking for the session's focused client.
+const AUTO_CLIENT: &str = "auto";
+
+pub async fn run(mut options: PromptOptions) -> Result<()> {
+    // Better refused now than after the agent has done the work.
+    if options.send_to_kak
+        && !options.no_session_check
+        && let Some(session) = options.session.as_deref()
+    {
+        kakoune::ensure_session(session)?;
+    }
+    if options.kak_clear {
+        return clear_buffer(&options);
+    }
+    if options.kak_plan && options.session.is_none() {
+        return Err(anyhow!("--kak-plan needs --session"));
+    }
+    if options.kak_stream && options.session.is_none() {
+        return Err(anyhow!("--kak-stream needs --session"));
+    }
+    // Commands run from Kakoune, like the --kak-commands-menu entries, may run in another
+    // directory; give them the socket itself.
+    if options.socket.is_none() && options.socket_scope != SocketScope::Session {
+        options.socket = Some(kakoune::resolve_socket_path(
+            None,
+            options.session.as_deref(),
+            options.socket_scope,
+        )?);
+    }
+    options.client = resolve_client(&options)?;
+    let outcome = send_prompt(&options).await;
+    if let Some(command) = &options.on_complete {
+        let stop_reason = match &outcome {
+            Ok(stop_reason) => stop_reason_name(stop_reason),
+            Err(_) => "failed".to_string(),
+        };
+        if let Err(err) = run_on_complete(&options, command, &stop_reason) {
+            eprintln!("--on-complete command was not delivered: {err:#}");
+        }
+    }
+    outcome.map(|_| ())
+}
+
+/// `end_turn`, `cancelled` and so on, as ACP spells the stop reason.
+pub fn stop_reason_name(stop_reason: &acp::StopReason) -> String {
+    serde_json::to_value(stop_reason)
+        .ok()
+        .and_then(|name| name.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{stop_reason:?}"))
+}
+
+/// Empties the --kak-buffer buffer for `--kak-clear`, in the session or on stdout.
+fn clear_buffer(options: &PromptOptions) -> Result<()> {
+    let command = kakoune::format_buffer_clear_command(&options.kak_buffer);
+    if !options.send_to_kak {
+        print!("{command}");
+        return Ok(());
+    }
+    let session = options
+        .session
+        .as_deref()
+        .ok_or_else(|| anyhow!("--send-to-kak requires a Kakoune session (set kak_session)"))?;
+    kakoune::send_to_kak(session, &command)
+}
+
+/// Runs an advertised agent command by prompting with `/NAME INPUT`.
+pub async fn run_command(options: CommandOptions) -> Result<()> {
+    let CommandOptions {
+        name,
+        input,
+        mut prompt,
+    } = options;
+    let mut text = format!("/{}", name.trim_start_matches('/'));
+    if let Some(input) = input.filter(|input| !input.trim().is_empty()) {
+        text.push(' ');
+        text.push_str(&input);
+    }
+    prompt.prompt = Some(text);
+    prompt.prompt_file = None;
+    run(prompt).await
+}
+
+/// The client the prompt is shown in: the one `--client` names, or, given `auto` or no
+/// client for a --send-to-kak prompt, the session's focused client. It is looked up once,
+/// so every message of the prompt goes to the same client.
+fn resolve_client(options: &PromptOptions) -> Result<Option<String>> {
+    let auto = options.client.as_deref() == Some(AUTO_CLIENT);
+    if options.client.is_some() && !auto {
+        return Ok(options.client.clone());
+    }
+    let session = match options.session.as_deref() {
+        Some(session) if auto || options.send_to_kak => session,
+        _ if auto => {
+            return Err(anyhow!(
+                "--client auto requires a Kakoune session (set kak_session)"
+            ));
+        }
+        _ => return Ok(None),
+    };
+    match kakoune::focused_client(session) {
+        Ok(Some(client)) => Ok(Some(client)),
+        Ok(None) => Err(anyhow!("Kakoune session {session} has no clients")),
+        Err(err) if auto => Err(err),
+        // Without `auto`, commands still reach the session's first client.
+        Err(err) => {
+            tracing::debug!("cannot find the focused client: {err:#}");
+            Ok(None)
+        }
+    }
+}
+
+/// Runs the `--on-complete` command in the prompt's client with its placeholders filled in.
+fn run_on_complete(options: &PromptOptions, command: &str, stop_reason: &str) -> Result<()> {
+    let session = options
+        .session
+        .as_deref()
+        .ok_or_else(|| anyhow!("a Kakoune session is required (set kak_session)"))?;
+    let title = options.title.as_deref().unwrap_or(config::DEFAULT_TITLE);
+    let command = command
+        .replace("{stop_reason}", &kakoune::kak_block(stop_reason))
+        .replace("{title}", &kakoune::kak_block(title))
+        .replace("{buffer}", &kakoune::kak_block(&options.kak_buffer));
+    kakoune::send_to_kak(
+        session,
+        &kakoune::in_client(options.client.as_deref(), &command),
+    )
+}
+
+/// Sends the prompt and delivers its result, returning why the agent stopped.
+async fn send_prompt(options: &PromptOptions) -> Result<acp::StopReason> {
+    let result = execute(options).await?;
+    if let Some(path) = &options.transcript_file {
+        let json = serde_json::to_vec_pretty(&result)?;
+        tokio::fs::write(path, json)
+            .await
+            .with_context(|| format!("failed to write transcript {}", path.display()))?;
+    }
+    deliver_result(&Delivery::from(options), &result).await?;
+    Ok(result.stop_reason)
+}
+
+/// Sends the prompt `options` describe to the daemon and returns its result, secrets
+/// redacted, without printing or sending it anywhere.
+///
+/// The agent's permission requests are put to the terminal when `options` would have
+/// `prompt` answer them, and to Kakoune otherwise.
+pub async fn execute(options: &PromptOptions) -> Result<PromptResultPayload> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let prompt_text = read_prompt(options).await?;
 
     if prompt_text.trim().is_empty() {
         return Err(anyhow!("prompt is empty"));
     }
 
+    let redactor = Redactor::new(&options.redact)?;
+    let (context, context_redactions) = collect_context_entries(options, &redactor).await?;
+    let idempotency_key = match &options.idempotency_key {
+        Some(key) => Some(key.clone()),
+        None if options.idempotent => Some(derive_idempotency_key(&prompt_text, &context)),
+        None => None,
+    };
     let payload = PromptPayload {
         prompt: prompt_text.clone(),
-        context: collect_context_snippets(&options).await?,
+        context,
+        agent: options.agent.clone(),
+        no_queue: options.no_queue,
+        no_merge_chunks: options.no_merge_chunks,
+        full_plans: options.full_plans,
+        raw_tool_io: options.raw_tool_io,
+        group_tool_calls: options.group_tool_calls,
+        legacy_mode_messages: options.legacy_mode_messages,
+        keep_user_echo: options.keep_user_echo,
+        timeout: options.timeout,
+        cwd: options
+            .cwd
+            .as_deref()
+            .map(|cwd| {
+                std::fs::canonicalize(cwd)
+                    .with_context(|| format!("failed to resolve --cwd {}", cwd.display()))
+            })
+            .transpose()?,
+        idempotency_key,
+        client: options.client.clone(),
+        kak_session: options.session.clone(),
+        answer_permissions: answers_permissions(options),
+        apply_diffs: options.apply_diffs,
+        progress: options
+            .session
+            .clone()
+            .filter(|_| options.send_to_kak && !options.no_progress),
+        plan: options.session.clone().filter(|_| options.kak_plan),
+        stream: options
+            .session
+            .clone()
+            .filter(|_| options.kak_stream)
+            .map(|session| StreamDisplay {
+                session,
+                title: options
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| config::DEFAULT_TITLE.to_string()),
+                buffer: (options.kak_target == [KakTarget::Buffer])
+                    .then(|| options.kak_buffer.clone()),
+            }),
     };
 
-    let response =
-        ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::Prompt(payload)).await?;
+    let request = ipc::DaemonRequest::Prompt(Box::new(payload));
+    let request_bytes = serde_json::to_vec(&request)?.len() as u64;
+    let mut connection = ipc_client::Connection::connect(&socket_path).await?;
+    if request_bytes > SIZE_PRECHECK_THRESHOLD
+        && let DaemonResponse::Status { status } =
+            connection.request(&ipc::DaemonRequest::Status).await?
+        && status.max_request_bytes > 0
+        && request_bytes > status.max_request_bytes
+    {
+        return Err(request_too_large(request_bytes, status.max_request_bytes));
+    }
+
+    let id = connection.send(&request).await?;
+    let mut answerer = None;
+    let response = loop {
+        match connection.response(id).await? {
+            DaemonResponse::PermissionRequest {
+                permission_id,
+                title,
+                tool_kind,
+                options: choices,
+                preview,
+            } => {
+                let answerer = answerer
+                    .get_or_insert_with(|| PermissionAnswerer::start(&socket_path, options));
+                answerer.ask(Question {
+                    permission_id,
+                    title,
+                    tool_kind,
+                    options: choices,
+                    preview,
+                });
+            }
+            response => break response,
+        }
+    };
     match response {
-        DaemonResponse::Prompt { result } => handle_prompt_result(&options, result).await?,
-        DaemonResponse::Error { message } => return Err(anyhow!(message)),
-        other => {
-            return Err(anyhow!(format!(
-                "unexpected response from daemon: {other:?}"
-            )));
+        DaemonResponse::Prompt { mut result } => {
+            redactor.redact_result(&mut result)?;
+            if let Some(summary) = &mut result.summary {
+                summary.redactions += context_redactions;
+            }
+            Ok(result)
         }
+        DaemonResponse::Error {
+            code: Some(code),
+            data: Some(data),
+            ..
+        } if code == "request_too_large" => {
+            let limit = data["limit"].as_u64().unwrap_or_default();
+            Err(request_too_large(request_bytes, limit))
+        }
+        DaemonResponse::Error { message, .. } => Err(anyhow!(message)),
+        other => Err(anyhow!(format!(
+            "unexpected response from daemon: {other:?}"
+        ))),
     }
+}
 
-    Ok(())
+/// Whether to ask the daemon for the prompt's permission requests: always with
+/// `--answer-permissions`, otherwise when a user is at the terminal to answer them.
+fn answers_permissions(options: &PromptOptions) -> bool {
+    options.answer_permissions
+        || (!options.send_to_kak
+            && options.output != Some(PromptOutput::KakCommands)
+            && std::io::stdin().is_terminal()
+            && std::io::stderr().is_terminal())
+}
+
+/// A permission request waiting for the user at the terminal.
+struct Question {
+    permission_id: u64,
+    title: String,
+    tool_kind: String,
+    options: Vec<acp::PermissionOption>,
+    preview: Option<String>,
+}
+
+/// Puts permission requests to the user on stderr, one at a time, and sends their answers
+/// back to the daemon.
+///
+/// Choices are read on a plain thread rather than a blocking task so the CLI can exit while
+/// a read is still waiting for input.
+struct PermissionAnswerer {
+    questions: std::sync::mpsc::Sender<Question>,
+}
+
+impl PermissionAnswerer {
+    fn start(socket_path: &std::path::Path, options: &PromptOptions) -> Self {
+        let (questions_tx, questions_rx) = std::sync::mpsc::channel::<Question>();
+        let (answers_tx, mut answers_rx) = tokio::sync::mpsc::unbounded_channel();
+        // A prompt read from stdin used it up; choices come from the terminal instead.
+        let prompt_from_stdin = options.prompt.is_none() && options.prompt_file.is_none();
+        std::thread::spawn(move || {
+            let input: Box<dyn BufRead> = if prompt_from_stdin {
+                match std::fs::File::open("/dev/tty") {
+                    Ok(tty) => Box::new(std::io::BufReader::new(tty)),
+                    Err(err) => {
+                        eprintln!("cannot answer permission requests: /dev/tty: {err}");
+                        return;
+                    }
+                }
+            } else {
+                Box::new(std::io::stdin().lock())
+            };
+            let mut input = input;
+            for question in questions_rx {
+                let option_id = read_choice(&mut input, &question);
+                if answers_tx
+                    .send((question.permission_id, option_id))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        let socket_path = socket_path.to_path_buf();
+        tokio::spawn(async move {
+            while let Some((permission_id, option_id)) = answers_rx.recv().await {
+                let request = ipc::DaemonRequest::PermissionAnswer {
+                    permission_id,
+                    option_id,
+                };
+                match ipc_client::roundtrip(&socket_path, &request).await {
+                    Ok(DaemonResponse::Error { message, .. }) => {
+                        eprintln!("permission answer was not accepted: {message}")
+                    }
+                    Ok(_) => {}
+                    Err(err) => eprintln!("failed to send permission answer: {err:#}"),
+                }
+            }
+        });
+        Self {
+            questions: questions_tx,
+        }
+    }
+
+    fn ask(&self, question: Question) {
+        let _ = self.questions.send(question);
+    }
+}
+
+/// Shows `question` on stderr and reads a choice until one is valid. `c`, an empty line or
+/// the end of the input cancels the request.
+fn read_choice(input: &mut dyn BufRead, question: &Question) -> Option<String> {
+    let mut stderr = std::io::stderr();
+    let _ = writeln!(
+        stderr,
+        "Permission requested: {} ({})",
+        question.title, question.tool_kind
+    );
+    if let Some(preview) = &question.preview {
+        let _ = writeln!(stderr, "{}", preview.trim_end());
+    }
+    for (index, option) in question.options.iter().enumerate() {
+        let _ = writeln!(stderr, "  {}) {}", index + 1, option.name);
+    }
+    let _ = writeln!(stderr, "  c) Cancel");
+    loop {
+        let _ = write!(stderr, "Choice: ");
+        let _ = stderr.flush();
+        let mut line = String::new();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            return None;
+        }
+        let choice = line.trim();
+        if choice.is_empty() || choice.eq_ignore_ascii_case("c") {
+            return None;
+        }
+        if let Some(option) = choice
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| question.options.get(number.checked_sub(1)?))
+        {
+            return Some(option.id.0.to_string());
+        }
+        let _ = writeln!(stderr, "Enter 1-{} or c to cancel.", question.options.len());
+    }
+}
+
+/// An idempotency key for `--idempotent`, so repeating a prompt with the same context
+/// reuses its answer.
+fn derive_idempotency_key(prompt: &str, context: &[ContextEntry]) -> String {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    prompt.hash(&mut hasher);
+    for entry in context {
+        entry.label.hash(&mut hasher);
+        entry.text.hash(&mut hasher);
+    }
+    format!("prompt-{:016x}", hasher.finish())
+}
+
+/// Payloads below this size skip asking the daemon for its request limit.
+const SIZE_PRECHECK_THRESHOLD: u64 = 64 * 1024;
+
+fn request_too_large(bytes: u64, limit: u64) -> anyhow::Error {
+    anyhow!(
+        "prompt request is {bytes} bytes but the daemon accepts at most {limit}; \
+         shrink the context with --max-context-bytes or restart the daemon with a larger \
+         --max-request-bytes"
+    )
 }
 
 async fn read_prompt(options: &PromptOptions) -> Result<String> {
@@ -47,6 +443,15 @@ async fn read_prompt(options: &PromptOptions) -> Result<String> {
             .await
             .with_context(|| format!("failed to read prompt file {}", path.display()));
     }
+    if options
+        .context_files
+        .iter()
+        .any(|path| path.as_os_str() == "-")
+    {
+        return Err(anyhow!(
+            "--context-file - reads stdin, so the prompt must come from --prompt or --prompt-file"
+        ));
+    }
     let mut buffer = String::new();
     tokio::io::stdin()
         .read_to_string(&mut buffer)
@@ -55,156 +460,175 @@ async fn read_prompt(options: &PromptOptions) -> Result<String> {
     Ok(buffer)
 }
 
-async fn collect_context_snippets(options: &PromptOptions) -> Result<Vec<ContextSnippet>> {
-    let mut snippets = Vec::new();
+/// The context to send, with its secrets redacted, and the number of redactions.
+async fn collect_context_entries(
+    options: &PromptOptions,
+    redactor: &Redactor,
+) -> Result<(Vec<ContextEntry>, u64)> {
+    let mut entries = Vec::new();
 
     for snippet in &options.context {
         if snippet.trim().is_empty() {
             continue;
         }
-        snippets.push(ContextSnippet {
-            text: snippet.clone(),
-            label: None,
-        });
+        entries.push(ContextEntry::new(
+            ContextSource::Inline,
+            None,
+            snippet.clone(),
+        ));
     }
 
     for path in &options.context_files {
+        if path.as_os_str() == "-" {
+            let mut text = String::new();
+            tokio::io::stdin()
+                .read_to_string(&mut text)
+                .await
+                .context("failed to read context from stdin")?;
+            entries.push(ContextEntry::new(ContextSource::Stdin, None, text));
+            continue;
+        }
         let text = tokio::fs::read_to_string(path)
             .await
             .with_context(|| format!("failed to read context file {}", path.display()))?;
-        snippets.push(ContextSnippet {
-            text,
-            label: Some(format!("file: {}", path.display())),
-        });
+        let entry = match options.context_around {
+            Some(around) => {
+                let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+                let (first, last) = around.range(lines.len());
+                let window = lines
+                    .get(first - 1..last)
+                    .map(<[&str]>::concat)
+                    .unwrap_or_default();
+                ContextEntry::new(
+                    ContextSource::Selection {
+                        path: path.clone(),
+                        first_line: first,
+                        last_line: last,
+                    },
+                    Some(format!("file: {}:{first}-{last}", path.display())),
+                    window,
+                )
+            }
+            None => ContextEntry::new(
+                ContextSource::File { path: path.clone() },
+                Some(format!("file: {}", path.display())),
+                text,
+            ),
+        };
+        entries.push(entry);
     }
 
-    Ok(snippets)
-}
-
-async fn handle_prompt_result(options: &PromptOptions, result: PromptResultPayload) -> Result<()> {
-    let plain_text = render_plain_text(&result);
+    let mut redactions = 0;
+    for entry in &mut entries {
+        redactions += redactor.redact(&mut entry.text);
+        entry.bytes = entry.text.len();
+    }
 
-    match options.output {
-        PromptOutput::Plain => {
-            print!("{}", plain_text);
-            if !plain_text.ends_with('\n') {
-                println!();
-            }
-            if options.send_to_kak {
-                send_to_kakoune(options, &plain_text).await?;
-            }
-        }
-        PromptOutput::Json => {
-            let json = serde_json::to_string_pretty(&result)?;
-            println!("{}", json);
-            if options.send_to_kak {
-                send_to_kakoune(options, &plain_text).await?;
-            }
-        }
-        PromptOutput::KakCommands => {
-            let command = kakoune::format_info_command(
-                options.client.as_deref(),
-                &options.title,
-                &plain_text,
-            );
-            if options.send_to_kak {
-                send_to_kakoune(options, &plain_text).await?;
-            } else {
-                print!("{}", command);
-            }
+    if let Some(limit) = options.max_context_bytes {
+        for entry in &mut entries {
+            truncate_at_char_boundary(&mut entry.text, limit);
         }
     }
 
-    Ok(())
+    Ok((entries, redactions))
 }
 
-async fn send_to_kakoune(options: &PromptOptions, body: &str) -> Result<()> {
-    let session = options
-        .session
-        .as_deref()
-        .ok_or_else(|| anyhow!("--send-to-kak requires a Kakoune session (set kak_session)"))?;
-    let command = kakoune::format_info_command(options.client.as_deref(), &options.title, body);
-    kakoune::send_to_kak(session, &command)
+pub fn truncate_at_char_boundary(text: &mut String, limit: usize) {
+    if text.len() <= limit {
+        return;
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
 }
 
-fn render_plain_text(result: &PromptResultPayload) -> String {
-    let mut output = String::new();
-    output.push_str("=== Prompt ===\n");
-    output.push_str(result.user_prompt.trim_end());
-    output.push_str("\n");
-    if !result.context.is_empty() {
-        output.push('\n');
-        output.push_str("=== Context ===\n");
-        for (index, snippet) in result.context.iter().enumerate() {
-            let display_index = index + 1;
-            if let Some(label) = &snippet.label {
-                output.push_str(&format!("[{display_index}] {label}\n"));
-            } else {
-                output.push_str(&format!("[{display_index}]\n"));
-            }
-            output.push_str(snippet.text.trim_end());
-            output.push_str("\n\n");
+/// How a finished prompt result should be presented.
+pub struct Delivery<'a> {
+    pub output: PromptOutput,
+    /// Print only the agent's answer instead of the plain transcript.
+    pub quiet: bool,
+    pub send_to_kak: bool,
+    pub session: Option<&'a str>,
+    pub client: Option<&'a str>,
+    pub title: &'a str,
+    pub wrap_width: Option<usize>,
+    pub kak_target: &'a [KakTarget],
+    pub info_style: Option<InfoStyle>,
+    /// Lines of the info box beyond which the rest is left to the buffer.
+    pub info_max_lines: Option<usize>,
+    /// Scratch buffer shown with `KakTarget::Buffer`.
+    pub kak_buffer: &'a str,
+    /// Add to the end of `kak_buffer` instead of replacing it.
+    pub kak_append: bool,
+    /// Insert the agent's messages at the client's main cursor instead of showing the
+    /// transcript.
+    pub kak_insert: bool,
+    /// Register the agent's messages are also put in.
+    pub kak_register: Option<&'a str>,
+    /// Start with the `*acp-locations*` buffer of the files the prompt touched.
+    pub kak_locations: bool,
+    /// Follow the transcript with a menu of the agent's advertised commands.
+    pub commands_menu: bool,
+    /// Daemon socket the menu's commands use; derived from their session when `None`.
+    pub socket: Option<&'a Path>,
+}
+
+impl<'a> From<&'a PromptOptions> for Delivery<'a> {
+    fn from(options: &'a PromptOptions) -> Self {
+        Self {
+            output: options.output.unwrap_or(PromptOutput::Plain),
+            quiet: options.quiet,
+            send_to_kak: options.send_to_kak,
+            session: options.session.as_deref(),
+            client: options.client.as_deref(),
+            title: options.title.as_deref().unwrap_or(config::DEFAULT_TITLE),
+            wrap_width: options.wrap_width,
+            kak_target: &options.kak_target,
+            info_style: options.info_style,
+            info_max_lines: options.info_max_lines,
+            kak_buffer: &options.kak_buffer,
+            kak_append: options.kak_append,
+            kak_insert: options.kak_insert,
+            kak_register: options
+                .kak_register
+                .as_ref()
+                .map(|register| register.0.as_str()),
+            kak_locations: options.kak_locations,
+            commands_menu: options.kak_commands_menu,
+            socket: options.socket.as_deref(),
         }
     }
+}
 
-    output.push('\n');
-
-    for event in &result.transcript {
-        match event {
-            TranscriptEvent::UserMessage { text } => {
-                output.push_str("[user] ");
-                output.push_str(text);
-                output.push('\n');
-            }
-            TranscriptEvent::AgentMessage { text } => {
-                output.push_str("[agent] ");
-                output.push_str(text);
-                output.push('\n');
-            }
-            TranscriptEvent::AgentThought { text } => {
-                output.push_str("[thought] ");
-                output.push_str(text);
-                output.push('\n');
-            }
-            TranscriptEvent::ToolCall { id, title, status } => {
-                output.push_str(&format!("[tool {id}] {status}: {title}\n"));
-            }
-            TranscriptEvent::ToolCallUpdate {
-                id,
-                status,
-                message,
-            } => {
-                let status = status.as_deref().map(|s| s.as_ref()).unwrap_or("update");
-                output.push_str(&format!("[tool {id}] {status}\n"));
-                if let Some(message) = message {
-                    output.push_str(message);
-                    output.push('\n');
-                }
-            }
-            TranscriptEvent::Plan { entries } => {
-                output.push_str("[plan]\n");
-                for entry in entries {
-                    output.push_str(&format!(
-                        "  - ({}/{}) {}\n",
-                        entry.status, entry.priority, entry.content
-                    ));
-                }
-            }
-            TranscriptEvent::AvailableCommands { commands } => {
-                output.push_str("[commands]\n");
-                for command in commands {
-                    output.push_str(&format!("  - {}: {}\n", command.name, command.description));
-                    if let Some(hint) = &command.hint {
-                        output.push_str(&format!("      hint: {}\n", hint));
-                    }
-                }
-            }
-            TranscriptEvent::SystemMessage { text } => {
-                output.push_str(&format!("[system] {}\n", text));
-            }
+pub async fn deliver_result(options: &Delivery<'_>, result: &PromptResultPayload) -> Result<()> {
+    let printed = match options.output {
+        PromptOutput::Plain if options.quiet => Some(result.answer.clone()),
+        PromptOutput::Plain => Some(render::render(
+            &mut PlainRenderer::new(options.wrap_width),
+            result,
+        )?),
+        PromptOutput::Json => Some(render::render(&mut JsonRenderer, result)?),
+        PromptOutput::KakCommands => None,
+    };
+    if let Some(text) = printed {
+        print!("{text}");
+        if !text.is_empty() && !text.ends_with('\n') {
+            println!();
+        }
+    }
+    if options.send_to_kak || options.output == PromptOutput::KakCommands {
+        let commands = render::render(&mut KakInfoRenderer::new(options), result)?;
+        if options.send_to_kak {
+            let session = options.session.ok_or_else(|| {
+                anyhow!("--send-to-kak requires a Kakoune session (set kak_session)")
+            })?;
+            kakoune::send_to_kak(session, &commands)?;
+        } else {
+            print!("{commands}");
         }
     }
 
-    output.push_str(&format!("\nStop reason: {:?}\n", result.stop_reason));
-    output
+    Ok(())
 }
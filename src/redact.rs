@@ -0,0 +1,115 @@
+//! Hiding secrets from agents and transcripts: text matching a rule is replaced with
+//! `[REDACTED:<rule>]`.
+
+use anyhow::{Context, Result};
+use regex_automata::meta::Regex;
+
+use crate::{
+    cli::RedactOptions,
+    ipc::{PromptResultPayload, TranscriptEntry},
+    transcript,
+};
+
+/// Rules applied unless `--no-default-redactions` is given, by name.
+const DEFAULT_RULES: &[(&str, &str)] = &[
+    ("aws-access-key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "aws-secret-key",
+        r"(?i)\baws_secret_access_key\b\s*[=:]\s*[A-Za-z0-9/+=]{40}",
+    ),
+    ("github-token", r"\bgh[pousr]_[A-Za-z0-9]{36,255}\b"),
+    (
+        "private-key",
+        r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----",
+    ),
+];
+
+pub struct Redactor {
+    rules: Vec<(String, Regex)>,
+}
+
+impl Redactor {
+    /// The default rules, unless turned off, then each `--redact` pattern as `pattern-N`.
+    pub fn new(options: &RedactOptions) -> Result<Self> {
+        let mut rules = Vec::new();
+        if !options.no_default_redactions {
+            for (name, pattern) in DEFAULT_RULES {
+                let regex = Regex::new(pattern).expect("the default redaction rules are valid");
+                rules.push((name.to_string(), regex));
+            }
+        }
+        for (index, pattern) in options.patterns.iter().enumerate() {
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("invalid --redact pattern {pattern:?}"))?;
+            rules.push((format!("pattern-{}", index + 1), regex));
+        }
+        Ok(Self { rules })
+    }
+
+    /// Replaces every match in `text`, returning how many there were.
+    pub fn redact(&self, text: &mut String) -> u64 {
+        let mut count = 0;
+        for (name, regex) in &self.rules {
+            let mut redacted = String::new();
+            let mut end = 0;
+            for found in regex.find_iter(text.as_str()) {
+                if found.is_empty() {
+                    continue;
+                }
+                redacted.push_str(&text[end..found.start()]);
+                redacted.push_str(&format!("[REDACTED:{name}]"));
+                end = found.end();
+                count += 1;
+            }
+            if end > 0 {
+                redacted.push_str(&text[end..]);
+                *text = redacted;
+            }
+        }
+        count
+    }
+
+    /// Redacts every string in `value`, leaving object keys alone.
+    pub fn redact_value(&self, value: &mut serde_json::Value) -> u64 {
+        match value {
+            serde_json::Value::String(text) => self.redact(text),
+            serde_json::Value::Array(values) => values
+                .iter_mut()
+                .map(|value| self.redact_value(value))
+                .sum(),
+            serde_json::Value::Object(fields) => fields
+                .values_mut()
+                .map(|value| self.redact_value(value))
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    /// Redacts all the text of `result`, counting the redactions in its summary.
+    pub fn redact_result(&self, result: &mut PromptResultPayload) -> Result<()> {
+        let mut value = serde_json::to_value(&*result)?;
+        // The answer repeats the agent's messages; it is made again from the redacted ones
+        // rather than counted twice.
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("answer");
+        }
+        let count = self.redact_value(&mut value);
+        if count > 0 {
+            *result = serde_json::from_value(value)?;
+            (result.answer, result.answer_truncated) = transcript::answer(&result.transcript);
+        }
+        if let Some(summary) = &mut result.summary {
+            summary.redactions += count;
+        }
+        Ok(())
+    }
+
+    /// Redacts all the text of a streamed transcript event.
+    pub fn redact_entry(&self, entry: &mut TranscriptEntry) -> Result<()> {
+        let mut value = serde_json::to_value(&*entry)?;
+        if self.redact_value(&mut value) > 0 {
+            *entry = serde_json::from_value(value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,820 @@
+//! Turning a prompt result into what the user sees: plain text, JSON or Kakoune commands.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    audit,
+    cli::KakTarget,
+    ipc::{
+        CommandInputSummary, PlanEntrySummary, PlanProgress, PromptResultPayload, ToolLocation,
+        TranscriptEntry, TranscriptEvent,
+    },
+    kakoune,
+    prompt::{self, Delivery},
+    transcript,
+};
+
+/// Longest answer `--kak-register` puts in a register.
+const MAX_REGISTER_BYTES: usize = 256 * 1024;
+
+/// Longest answer `--kak-insert` types into a buffer.
+const MAX_INSERT_BYTES: usize = 64 * 1024;
+
+/// Writes a prompt result piece by piece, so a transcript can be shown as it streams in as
+/// well as once it is done.
+pub trait TranscriptRenderer {
+    /// Writes what comes before the transcript, like the prompt and its context.
+    fn begin(&mut self, output: &mut String, result: &PromptResultPayload);
+
+    /// Writes one transcript event.
+    fn event(&mut self, output: &mut String, entry: &TranscriptEntry);
+
+    /// Writes what comes after the transcript, like the stop reason.
+    fn finish(&mut self, output: &mut String, result: &PromptResultPayload) -> Result<()>;
+}
+
+/// Renders all of `result` at once.
+pub fn render(
+    renderer: &mut dyn TranscriptRenderer,
+    result: &PromptResultPayload,
+) -> Result<String> {
+    let mut output = String::new();
+    renderer.begin(&mut output, result);
+    for entry in &result.transcript {
+        renderer.event(&mut output, entry);
+    }
+    renderer.finish(&mut output, result)?;
+    Ok(output)
+}
+
+/// The plain-text transcript printed by default and shown in Kakoune.
+#[derive(Default)]
+pub struct PlainRenderer {
+    /// Columns lines are wrapped at, if any.
+    wrap_width: Option<usize>,
+    /// Whether the text written so far left a fenced block open.
+    fenced: bool,
+    /// The resource links seen, each once, for the closing list.
+    links: Vec<String>,
+    /// The plan as the events so far left it, for the progress of each revision.
+    plan: Vec<PlanEntrySummary>,
+}
+
+impl PlainRenderer {
+    pub fn new(wrap_width: Option<usize>) -> Self {
+        Self {
+            wrap_width,
+            ..Self::default()
+        }
+    }
+
+    /// Appends `text`, wrapped when a width is set.
+    fn push(&mut self, output: &mut String, text: &str) {
+        match self.wrap_width {
+            Some(width) => wrap_text(output, text, width, &mut self.fenced),
+            None => output.push_str(text),
+        }
+    }
+}
+
+impl TranscriptRenderer for PlainRenderer {
+    fn begin(&mut self, output: &mut String, result: &PromptResultPayload) {
+        let mut text = String::new();
+        if result.partial {
+            match &result.cancel_reason {
+                Some(reason) => text.push_str(&format!("*** turn cancelled ({reason})")),
+                None => text.push_str("*** turn cancelled"),
+            }
+            text.push_str(" — transcript may be incomplete ***\n");
+        }
+        if let Some(agent) = &result.agent {
+            text.push_str(&format!("Agent: {}", agent.name));
+            if let Some(model) = &agent.model {
+                text.push_str(&format!(" ({model})"));
+            }
+            text.push('\n');
+        }
+        text.push_str("=== Prompt ===\n");
+        text.push_str(result.user_prompt.trim_end());
+        text.push('\n');
+        if !result.context.is_empty() {
+            text.push('\n');
+            text.push_str("=== Context ===\n");
+            for (index, entry) in result.context.iter().enumerate() {
+                text.push_str(&format!("[context #{}] {}", index + 1, entry.heading()));
+                if entry.bytes > entry.text.len() {
+                    let kept = entry.text.len();
+                    text.push_str(&format!(" (cut to {kept} of {} bytes)", entry.bytes));
+                }
+                text.push('\n');
+                text.push_str(entry.text.trim_end());
+                text.push_str("\n\n");
+            }
+        }
+        text.push('\n');
+        self.push(output, &text);
+    }
+
+    fn event(&mut self, output: &mut String, entry: &TranscriptEntry) {
+        if let TranscriptEvent::ResourceLink { title, uri, .. } = &entry.event {
+            let link = transcript::link_text(title, uri);
+            if !self.links.contains(&link) {
+                self.links.push(link);
+            }
+        }
+        let mut text = String::new();
+        let plan_changed = match &entry.event {
+            TranscriptEvent::Plan { entries } => {
+                self.plan = entries.clone();
+                true
+            }
+            TranscriptEvent::PlanUpdate { changes, len } => {
+                transcript::apply_plan_update(&mut self.plan, changes, *len);
+                true
+            }
+            _ => false,
+        };
+        if plan_changed {
+            let progress = PlanProgress::of(&self.plan);
+            text.push_str(&format!(
+                "[plan] {}/{} complete\n",
+                progress.counts.completed, progress.total
+            ));
+        }
+        render_event(&mut text, &entry.event);
+        self.push(output, &text);
+    }
+
+    fn finish(&mut self, output: &mut String, result: &PromptResultPayload) -> Result<()> {
+        let mut text = String::new();
+        for diff in &result.diffs {
+            text.push_str(&format!("[diff] {}: {}", diff.path.display(), diff.status));
+            if let Some(reason) = &diff.reason {
+                text.push_str(&format!(" ({reason})"));
+            }
+            if let Some(backup) = &diff.backup {
+                text.push_str(&format!(", backup {}", backup.display()));
+            }
+            text.push('\n');
+        }
+        if !self.links.is_empty() {
+            text.push_str("\n=== Resources ===\n");
+            for link in &self.links {
+                text.push_str(&format!("- {link}\n"));
+            }
+        }
+        text.push('\n');
+        text.push_str(&stop_line(result));
+        text.push('\n');
+        self.push(output, &text);
+        Ok(())
+    }
+}
+
+/// `Stop reason: …`, followed by the transcript's size and duration when the result has a
+/// summary.
+pub fn stop_line(result: &PromptResultPayload) -> String {
+    let mut line = format!("Stop reason: {:?}", result.stop_reason);
+    if let Some(summary) = &result.summary {
+        line.push_str(&format!(
+            " ({}, {}, {}, {:.1} s)",
+            plural(summary.events, "event"),
+            format_bytes(summary.text_bytes),
+            plural(summary.tool_calls, "tool call"),
+            summary.duration_ms as f64 / 1000.0
+        ));
+    }
+    line
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    match count {
+        1 => format!("1 {noun}"),
+        count => format!("{count} {noun}s"),
+    }
+}
+
+/// `bytes` in B, KiB or MiB.
+fn format_bytes(bytes: u64) -> String {
+    const KIB: f64 = 1024.0;
+    match bytes as f64 {
+        size if size < KIB => format!("{bytes} B"),
+        size if size < KIB * KIB => format!("{:.1} KiB", size / KIB),
+        size => format!("{:.1} MiB", size / (KIB * KIB)),
+    }
+}
+
+/// The whole result as pretty-printed JSON, for `--output json`.
+pub struct JsonRenderer;
+
+impl TranscriptRenderer for JsonRenderer {
+    fn begin(&mut self, _output: &mut String, _result: &PromptResultPayload) {}
+
+    fn event(&mut self, _output: &mut String, _entry: &TranscriptEntry) {}
+
+    fn finish(&mut self, output: &mut String, result: &PromptResultPayload) -> Result<()> {
+        output.push_str(&serde_json::to_string_pretty(result)?);
+        output.push('\n');
+        Ok(())
+    }
+}
+
+/// The Kakoune commands showing the plain transcript the way `delivery` asks, for
+/// `--output kak-commands` and `--send-to-kak`.
+pub struct KakInfoRenderer<'a> {
+    delivery: &'a Delivery<'a>,
+    plain: PlainRenderer,
+    /// The plain transcript so far; the commands need all of it.
+    body: String,
+}
+
+impl<'a> KakInfoRenderer<'a> {
+    pub fn new(delivery: &'a Delivery<'a>) -> Self {
+        Self {
+            delivery,
+            plain: PlainRenderer::new(delivery.wrap_width),
+            body: String::new(),
+        }
+    }
+}
+
+impl TranscriptRenderer for KakInfoRenderer<'_> {
+    fn begin(&mut self, _output: &mut String, result: &PromptResultPayload) {
+        self.plain.begin(&mut self.body, result);
+    }
+
+    fn event(&mut self, _output: &mut String, entry: &TranscriptEntry) {
+        self.plain.event(&mut self.body, entry);
+    }
+
+    fn finish(&mut self, output: &mut String, result: &PromptResultPayload) -> Result<()> {
+        self.plain.finish(&mut self.body, result)?;
+        output.push_str(&kak_commands(self.delivery, &self.body, result)?);
+        Ok(())
+    }
+}
+
+/// Appends the plain-text rendering of a single transcript event.
+fn render_event(output: &mut String, event: &TranscriptEvent) {
+    match event {
+        TranscriptEvent::UserMessage { text } => push_message(output, "[user] ", text),
+        TranscriptEvent::AgentMessage { text } => push_message(output, "[agent] ", text),
+        TranscriptEvent::AgentThought { text } => push_message(output, "[thought] ", text),
+        TranscriptEvent::ToolCall {
+            id,
+            title,
+            status,
+            tool_kind,
+            message,
+            status_history,
+            ..
+        } => {
+            if tool_kind.is_empty() {
+                output.push_str(&format!("[tool {id}] {status}: {title}"));
+            } else {
+                output.push_str(&format!("[tool {id}/{tool_kind}] {status}: {title}"));
+            }
+            if status_history.len() > 1 {
+                let trail = status_history
+                    .iter()
+                    .map(|change| change.status.as_str())
+                    .collect::<Vec<_>>();
+                output.push_str(&format!(" ({})", trail.join("→")));
+            }
+            output.push('\n');
+            if let Some(message) = message {
+                push_message(output, "  ", message);
+            }
+        }
+        TranscriptEvent::ToolCallUpdate {
+            id,
+            status,
+            message,
+            ..
+        } => {
+            let status = status.as_deref().unwrap_or("update");
+            output.push_str(&format!("[tool {id}] {status}\n"));
+            if let Some(message) = message {
+                push_message(output, "  ", message);
+            }
+        }
+        // `PlainRenderer` heads plans with their progress.
+        TranscriptEvent::Plan { entries } => {
+            for entry in entries {
+                output.push_str(&format!(
+                    "  - ({}/{}) {}\n",
+                    entry.status, entry.priority, entry.content
+                ));
+            }
+        }
+        TranscriptEvent::PlanUpdate { changes, .. } => {
+            for change in changes {
+                output.push_str(&format!(
+                    "[plan] task {:?} -> {}\n",
+                    change.entry.content, change.entry.status
+                ));
+            }
+        }
+        TranscriptEvent::AvailableCommands { commands } => {
+            output.push_str("[commands]\n");
+            for command in commands {
+                output.push_str(&format!("  - {}: {}\n", command.name, command.description));
+                match &command.input {
+                    Some(CommandInputSummary::Unstructured { hint }) => {
+                        output.push_str(&format!("      hint: {}\n", hint));
+                    }
+                    Some(input @ CommandInputSummary::Structured { .. }) => {
+                        output.push_str(&format!("      input: {}\n", input.prompt_hint()));
+                    }
+                    // Stored before inputs were recorded.
+                    None => {
+                        if let Some(hint) = &command.hint {
+                            output.push_str(&format!("      hint: {}\n", hint));
+                        }
+                    }
+                }
+            }
+        }
+        TranscriptEvent::SystemMessage { text } => push_message(output, "[system] ", text),
+        TranscriptEvent::ModeChange { mode_id, .. } => {
+            output.push_str(&format!("[mode] {mode_id}\n"));
+        }
+        TranscriptEvent::Permission {
+            title,
+            option,
+            reason,
+            rule,
+            summary,
+            ..
+        } => {
+            let decision = option.as_deref().unwrap_or("cancelled");
+            output.push_str(&format!("[permission] {title}: {decision} ({reason}"));
+            if let Some(rule) = rule {
+                output.push_str(&format!(", rule {rule}"));
+            }
+            output.push_str(")\n");
+            for line in summary.iter().flat_map(|summary| summary.lines()) {
+                output.push_str(&format!("  {line}\n"));
+            }
+        }
+        TranscriptEvent::Terminal {
+            id,
+            command,
+            status,
+            exit_code,
+            signal,
+        } => {
+            output.push_str(&format!("[terminal {id}] {status}"));
+            match (exit_code, signal) {
+                (Some(code), _) => output.push_str(&format!(" with code {code}")),
+                (None, Some(signal)) => output.push_str(&format!(" by {signal}")),
+                (None, None) if status == "created" => output.push_str(&format!(": {command}")),
+                (None, None) => {}
+            }
+            output.push('\n');
+        }
+        TranscriptEvent::TerminalOutput {
+            terminal_id,
+            command,
+            output: captured,
+            truncated,
+            exit_code,
+            signal,
+            running,
+            ..
+        } => {
+            output.push_str(&format!("  [terminal {terminal_id}] $ {command}"));
+            match (exit_code, signal) {
+                _ if *running => output.push_str(" (still running)"),
+                (Some(code), _) => output.push_str(&format!(" (exit code {code})")),
+                (None, Some(signal)) => output.push_str(&format!(" (killed by {signal})")),
+                (None, None) => {}
+            }
+            if *truncated {
+                output.push_str(" (output truncated)");
+            }
+            output.push('\n');
+            for line in captured.lines() {
+                output.push_str(&format!("    {line}\n"));
+            }
+        }
+        TranscriptEvent::FileEdit {
+            path,
+            diff,
+            added,
+            removed,
+            ..
+        } => {
+            output.push_str(&format!(
+                "[edit] {} (+{added} -{removed})\n```diff\n{diff}```\n",
+                path.display()
+            ));
+        }
+        TranscriptEvent::Image {
+            path,
+            mime_type,
+            bytes,
+        } => {
+            output.push_str(&format!(
+                "[image saved to {} ({mime_type}, {bytes} bytes)]\n",
+                path.display()
+            ));
+        }
+        TranscriptEvent::ResourceLink { title, uri, .. } => {
+            output.push_str(&format!("[link] {}\n", transcript::link_text(title, uri)));
+        }
+        TranscriptEvent::AccessDenied {
+            operation,
+            path,
+            root,
+        } => {
+            output.push_str(&format!(
+                "[denied] {operation} {} (outside {})\n",
+                path.display(),
+                root.display()
+            ));
+        }
+        TranscriptEvent::FileRead {
+            path,
+            truncated,
+            bytes,
+        } => {
+            output.push_str(&format!("[read] {}", path.display()));
+            if *truncated {
+                output.push_str(&format!(" (truncated at {bytes} bytes)"));
+            }
+            output.push('\n');
+        }
+        TranscriptEvent::FileWrite {
+            path,
+            byte_delta,
+            backup,
+        } => {
+            output.push_str(&format!("[write] {} ({byte_delta:+} bytes", path.display()));
+            if let Some(backup) = backup {
+                output.push_str(&format!(", backup {}", backup.display()));
+            }
+            output.push_str(")\n");
+        }
+        TranscriptEvent::Error { message, .. } => push_message(output, "[error] ", message),
+    }
+}
+
+/// Appends `text`, hard-wrapping every line longer than `width` columns at word boundaries.
+///
+/// Continuation lines line up under the text after the line's `[prefix] ` or `- ` bullet,
+/// or keep its leading indentation; words longer than the width are left intact rather than
+/// split. Fenced code blocks and diffs are never wrapped; `fenced` carries whether one is
+/// open from one call to the next.
+fn wrap_text(output: &mut String, text: &str, width: usize, fenced: &mut bool) {
+    for line in text.split_inclusive('\n') {
+        let (body, newline) = match line.strip_suffix('\n') {
+            Some(body) => (body, "\n"),
+            None => (line, ""),
+        };
+        if is_fence(body) {
+            *fenced = !*fenced;
+        }
+        if *fenced || is_fence(body) || width == 0 || body.chars().count() <= width {
+            output.push_str(line);
+            continue;
+        }
+        let leading = &body[..body.len() - body.trim_start().len()];
+        // A prefix too wide to leave room for the text gets the indentation of tool content.
+        let hanging = match hanging_indent(body) {
+            hanging if hanging * 2 > width => leading.chars().count() + 2,
+            hanging => hanging,
+        };
+        let indent = " ".repeat(hanging);
+        output.push_str(leading);
+        let mut column = leading.chars().count();
+        let mut line_start = true;
+        for word in body.split_whitespace() {
+            let word_len = word.chars().count();
+            if !line_start && column + 1 + word_len > width {
+                output.push('\n');
+                output.push_str(&indent);
+                column = hanging;
+                line_start = true;
+            }
+            if !line_start {
+                output.push(' ');
+                column += 1;
+            }
+            output.push_str(word);
+            column += word_len;
+            line_start = false;
+        }
+        output.push_str(newline);
+    }
+}
+
+/// Whether `line` opens or closes a fenced code block.
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+/// Columns the continuation lines of `line` are indented by when it is wrapped.
+fn hanging_indent(line: &str) -> usize {
+    let rest = line.trim_start();
+    let indent = line.len() - rest.len();
+    let prefix = if rest.starts_with('[') {
+        rest.find("] ")
+            .map_or(0, |end| rest[..end + 2].chars().count())
+    } else if rest.starts_with("- ") {
+        2
+    } else {
+        0
+    };
+    indent + prefix
+}
+
+/// Appends `text` after `prefix`, with its further lines indented to line up under the first.
+///
+/// Blank lines stay empty and fenced code blocks are passed through as they are.
+fn push_message(output: &mut String, prefix: &str, text: &str) {
+    let text = text.trim_matches('\n');
+    let indent = " ".repeat(prefix.chars().count());
+    let mut fenced = false;
+    for (index, line) in text.lines().enumerate() {
+        let fence = is_fence(line);
+        if index == 0 {
+            // A fence must start its own line to keep the block intact.
+            match (fence, prefix.trim_end()) {
+                (false, _) => output.push_str(prefix),
+                (true, "") => {}
+                (true, label) => {
+                    output.push_str(label);
+                    output.push('\n');
+                }
+            }
+        } else if !fenced && !fence && !line.is_empty() {
+            output.push_str(&indent);
+        }
+        output.push_str(line);
+        output.push('\n');
+        if fence {
+            fenced = !fenced;
+        }
+    }
+    if text.is_empty() && !prefix.trim().is_empty() {
+        output.push_str(prefix.trim_end());
+        output.push('\n');
+    }
+}
+
+/// The info box or scratch buffer for `body`, plus `acp-open-diff` when the prompt edited
+/// files.
+fn kak_commands(
+    options: &Delivery<'_>,
+    body: &str,
+    result: &PromptResultPayload,
+) -> Result<String> {
+    let mut command = String::new();
+    // First, so whatever shows the transcript ends up in front of it.
+    if options.kak_locations {
+        let lines = locations(result);
+        if !lines.is_empty() {
+            command.push_str(&kakoune::format_locations_command(options.client, &lines));
+        }
+    }
+    if options.kak_insert {
+        let answer = &result.answer;
+        if answer.len() > MAX_INSERT_BYTES {
+            return Err(anyhow!(
+                "the {}-byte answer is larger than the {MAX_INSERT_BYTES}-byte --kak-insert limit; \
+                 nothing was inserted",
+                answer.len()
+            ));
+        }
+        if !answer.is_empty() {
+            let file = write_temp_file(answer)?;
+            command.push_str(&kakoune::format_insert_command(options.client, &file));
+        }
+    } else if options.kak_append {
+        let chunk = write_chunk(options.title, body, &result.stop_reason)?;
+        command.push_str(&kakoune::format_buffer_append_command(
+            options.client,
+            options.kak_buffer,
+            &chunk,
+        ));
+    } else {
+        let info = options.kak_target.contains(&KakTarget::Info);
+        let shown = options
+            .info_max_lines
+            .filter(|_| info)
+            .and_then(|max_lines| truncate_info(body, max_lines, options.kak_buffer));
+        // The buffer goes first: filling it switches the client, and the box shows over it.
+        if options.kak_target.contains(&KakTarget::Buffer) && (!info || shown.is_some()) {
+            command.push_str(&kakoune::format_buffer_command(
+                options.client,
+                options.kak_buffer,
+                body,
+            ));
+        }
+        if info {
+            command.push_str(&kakoune::format_info_command(
+                options.client,
+                options.title,
+                shown.as_deref().unwrap_or(body),
+                options.info_style,
+            ));
+        }
+    }
+    if let Some(register) = options.kak_register {
+        command.push_str(&kakoune::format_register_command(
+            options.client,
+            register,
+            &register_answer(result),
+        ));
+    }
+    let diffs = result
+        .transcript
+        .iter()
+        .map(|entry| &entry.event)
+        .filter_map(|event| match event {
+            TranscriptEvent::FileEdit { diff, .. } => Some(diff.as_str()),
+            _ => None,
+        })
+        .collect::<String>();
+    if !diffs.is_empty() {
+        command.push_str(&kakoune::format_diff_command(&diffs));
+    }
+    let images = result
+        .transcript
+        .iter()
+        .map(|entry| &entry.event)
+        .filter_map(|event| match event {
+            TranscriptEvent::Image { path, .. } => Some(path.as_path()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !images.is_empty() {
+        command.push_str(&kakoune::format_images_command(&images));
+    }
+    let advertised = result
+        .transcript
+        .iter()
+        .map(|entry| &entry.event)
+        .rev()
+        .find_map(|event| match event {
+            TranscriptEvent::AvailableCommands { commands } => Some(commands),
+            _ => None,
+        });
+    if options.commands_menu
+        && let Some(commands) = advertised.filter(|commands| !commands.is_empty())
+    {
+        let binary = std::env::current_exe().unwrap_or_else(|_| "kakoune-acp".into());
+        command.push_str(&kakoune::format_commands_menu(
+            options.client,
+            &binary,
+            options.socket,
+            commands,
+        ));
+    }
+    Ok(command)
+}
+
+/// `path:line:col: what` lines for `--kak-locations`: the tool calls' locations and the files
+/// edited or written, each once, with paths relative to the session's directory.
+fn locations(result: &PromptResultPayload) -> String {
+    let mut titles = std::collections::HashMap::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut lines = String::new();
+    for entry in &result.transcript {
+        let (found, what) = match &entry.event {
+            TranscriptEvent::ToolCall {
+                id,
+                title,
+                locations,
+                ..
+            } => {
+                titles.insert(id.as_str(), title.as_str());
+                (locations_of(locations), title.clone())
+            }
+            TranscriptEvent::ToolCallUpdate { id, locations, .. } => (
+                locations_of(locations),
+                titles.get(id.as_str()).copied().unwrap_or(id).to_string(),
+            ),
+            TranscriptEvent::FileEdit {
+                path,
+                diff,
+                added,
+                removed,
+                ..
+            } => (
+                vec![(path.as_path(), first_hunk_line(diff))],
+                format!("edit (+{added} -{removed})"),
+            ),
+            TranscriptEvent::FileWrite { path, .. } => {
+                (vec![(path.as_path(), None)], "write".to_string())
+            }
+            _ => continue,
+        };
+        for (path, line) in found {
+            let path = result
+                .cwd
+                .as_deref()
+                .and_then(|cwd| path.strip_prefix(cwd).ok())
+                .unwrap_or(path);
+            let line = line.unwrap_or(1).max(1);
+            if seen.insert((path.to_path_buf(), line)) {
+                lines.push_str(&format!("{}:{line}:1: {what}\n", path.display()));
+            }
+        }
+    }
+    lines
+}
+
+fn locations_of(locations: &[ToolLocation]) -> Vec<(&Path, Option<u32>)> {
+    locations
+        .iter()
+        .map(|location| (location.path.as_path(), location.line))
+        .collect()
+}
+
+/// The first line of the new file a unified diff changes, from its first `@@ -a,b +c,d @@`.
+fn first_hunk_line(diff: &str) -> Option<u32> {
+    let header = diff.lines().find(|line| line.starts_with("@@ "))?;
+    let new = header.split_whitespace().nth(2)?.strip_prefix('+')?;
+    new.split(',').next()?.parse().ok()
+}
+
+/// The agent's messages for `--kak-register`, cut to `MAX_REGISTER_BYTES` with a warning.
+fn register_answer(result: &PromptResultPayload) -> String {
+    let mut answer = result.answer.clone();
+    if answer.len() > MAX_REGISTER_BYTES {
+        eprintln!(
+            "warning: the {}-byte answer was cut to {MAX_REGISTER_BYTES} bytes for --kak-register",
+            answer.len()
+        );
+        prompt::truncate_at_char_boundary(&mut answer, MAX_REGISTER_BYTES);
+    }
+    answer
+}
+
+/// The first `max_lines` lines of `body` and a line counting the rest, or `None` when it
+/// fits.
+fn truncate_info(body: &str, max_lines: usize, buffer: &str) -> Option<String> {
+    let lines: Vec<&str> = body.trim_end().lines().collect();
+    if lines.len() <= max_lines {
+        return None;
+    }
+    let mut shown = lines[..max_lines].join("\n");
+    if !shown.is_empty() {
+        shown.push('\n');
+    }
+    shown.push_str(&format!(
+        "(+{} more lines, see {buffer} buffer)",
+        lines.len() - max_lines
+    ));
+    Some(shown)
+}
+
+/// Writes what `--kak-append` adds to the buffer to a new file only the user can read: a
+/// separator line, then `body` and a blank line.
+fn write_chunk(title: &str, body: &str, stop_reason: &acp::StopReason) -> Result<PathBuf> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default();
+    write_temp_file(&format!(
+        "=== {} UTC · {title} · {} ===\n{}\n\n",
+        audit::format_utc(now),
+        prompt::stop_reason_name(stop_reason),
+        body.trim_end()
+    ))
+}
+
+/// Writes `contents` to a new file in the temporary directory only the user can read, for
+/// Kakoune to pick up with `cat`.
+fn write_temp_file(contents: &str) -> Result<PathBuf> {
+    use std::{
+        os::unix::fs::OpenOptionsExt,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    static CHUNKS: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "kakoune-acp-chunk-{}-{}",
+        std::process::id(),
+        CHUNKS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
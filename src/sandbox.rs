@@ -0,0 +1,156 @@
+use std::{
+    env, io,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Where agents may read, write and run commands: the workspace root plus `--allow-path`
+/// exceptions.
+pub struct Sandbox {
+    /// Fixed root from `--workspace-root`; otherwise each session's working directory.
+    root: Option<PathBuf>,
+    allowed: Vec<String>,
+}
+
+/// A path that resolved outside the sandbox.
+#[derive(Debug)]
+pub struct Escape {
+    /// Where the requested path actually leads, symlinks resolved.
+    pub resolved: PathBuf,
+    pub root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: Option<&Path>, allowed: &[String]) -> Result<Self> {
+        let root = root
+            .map(|root| {
+                std::fs::canonicalize(root).with_context(|| {
+                    format!("failed to resolve --workspace-root {}", root.display())
+                })
+            })
+            .transpose()?;
+        let allowed = allowed
+            .iter()
+            .map(|pattern| {
+                let pattern = match pattern.strip_prefix("~/") {
+                    Some(rest) => {
+                        let home = env::var_os("HOME")
+                            .context("--allow-path starts with ~ but HOME is not set")?;
+                        format!("{}/{rest}", Path::new(&home).display())
+                    }
+                    None => pattern.clone(),
+                };
+                anyhow::ensure!(
+                    pattern.starts_with('/'),
+                    "--allow-path {pattern} must be an absolute path or start with ~/"
+                );
+                Ok(pattern)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { root, allowed })
+    }
+
+    /// The canonical form of `path`, resolved against `cwd`, if it stays inside the
+    /// workspace or matches an `--allow-path` pattern.
+    ///
+    /// Symlinks are followed as far as the path exists, so a link pointing out of the
+    /// workspace does not get through.
+    pub fn confine(&self, cwd: &Path, path: &Path) -> io::Result<Result<PathBuf, Escape>> {
+        let root = match &self.root {
+            Some(root) => root.clone(),
+            None => std::fs::canonicalize(cwd)?,
+        };
+        let resolved = canonicalize_lenient(&cwd.join(path))?;
+        if resolved.starts_with(&root)
+            || self
+                .allowed
+                .iter()
+                .any(|pattern| pattern_matches(pattern, &resolved.to_string_lossy()))
+        {
+            Ok(Ok(resolved))
+        } else {
+            Ok(Err(Escape { resolved, root }))
+        }
+    }
+}
+
+/// Canonicalizes the longest existing prefix of `path` and appends the rest.
+///
+/// A `..` in the rest cancels the component before it, after which the path is resolved
+/// again: what is left may exist, through symlinks of its own.
+fn canonicalize_lenient(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        match std::fs::canonicalize(existing) {
+            Ok(mut resolved) => {
+                let mut climbed = false;
+                for component in rest.iter().rev() {
+                    match component {
+                        Component::Normal(part) => resolved.push(part),
+                        Component::CurDir => {}
+                        Component::ParentDir => {
+                            resolved.pop();
+                            climbed = true;
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("cannot resolve {}", path.display()),
+                            ));
+                        }
+                    }
+                }
+                // The rest has no `..` left, so this resolves without climbing.
+                return if climbed {
+                    canonicalize_lenient(&resolved)
+                } else {
+                    Ok(resolved)
+                };
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let (Some(parent), Some(last)) =
+                    (existing.parent(), existing.components().next_back())
+                else {
+                    return Err(err);
+                };
+                rest.push(last);
+                existing = parent;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Matches `path` against an `--allow-path` pattern.
+///
+/// `*` and `?` stay within one path component and `**` crosses them. A pattern without
+/// wildcards also covers everything below it.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        let pattern = pattern.trim_end_matches('/');
+        return path == pattern
+            || path
+                .strip_prefix(pattern)
+                .is_some_and(|rest| rest.starts_with('/'));
+    }
+    glob(pattern.as_bytes(), path.as_bytes())
+}
+
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        [b'*', rest @ ..] => {
+            let component = text.iter().position(|&b| b == b'/').unwrap_or(text.len());
+            (0..=component).any(|skip| glob(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(text, [first, tail @ ..] if *first != b'/' && glob(rest, tail))
+        }
+        [first, rest @ ..] => {
+            matches!(text, [head, tail @ ..] if head == first && glob(rest, tail))
+        }
+    }
+}
@@ -1,50 +1,549 @@
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::Path,
+    time::{Duration, Instant},
+};
+
 use anyhow::{Result, anyhow};
-use serde_json;
 
 use crate::{
-    cli::{ShutdownOptions, StatusOptions},
-    ipc::{self, DaemonResponse},
+    cli::{
+        CancelOptions, PermissionReplyOptions, PermissionsOptions, PingOptions, ReloadOptions,
+        RestartAgentOptions, SessionCloseOptions, ShutdownOptions, StatusOptions,
+    },
+    ipc::{self, DaemonResponse, DaemonStatus, Metrics, SessionState},
     ipc_client, kakoune,
 };
 
 pub async fn run_status(options: StatusOptions) -> Result<()> {
-    let socket_path =
-        kakoune::resolve_socket_path(options.socket.clone(), options.session.as_deref())?;
-    let response = ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::Status).await?;
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    if options.wait {
+        wait_until_ready(&socket_path, Duration::from_secs(options.timeout)).await?;
+    }
+    let request = if options.reset_metrics {
+        ipc::DaemonRequest::ResetMetrics
+    } else {
+        ipc::DaemonRequest::Status
+    };
+    let response = ipc_client::roundtrip(&socket_path, &request).await?;
     match response {
-        DaemonResponse::Status { status } => {
+        DaemonResponse::Status { mut status } => {
+            if let Some(name) = &options.agent {
+                select_agent(&mut status, name)?;
+            }
             if options.json {
                 println!("{}", serde_json::to_string_pretty(&status)?);
+            } else if options.metrics {
+                print!("{}", render_metrics(&status.metrics));
             } else {
-                println!("Socket: {}", status.socket_path.display());
-                if let Some(session) = status.session_id {
-                    println!("Session ID: {}", session);
-                }
-                println!("Agent running: {}", status.running);
-                if let Some(pid) = status.agent_pid {
-                    println!("Agent PID: {}", pid);
-                }
-                if !status.agent_command.is_empty() {
-                    println!("Agent command: {}", status.agent_command.join(" "));
-                }
+                print!("{}", render_status_table(&status));
             }
         }
-        DaemonResponse::Error { message } => return Err(anyhow!(message)),
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
         other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
     }
     Ok(())
 }
 
+/// How often `status --wait` checks on the daemon.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Polls until the daemon answers `Status` as running with its default agent's session open
+/// (or deliberately not yet opened, with `--lazy-session`).
+async fn wait_until_ready(socket_path: &Path, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut progress = format!("no socket at {}", socket_path.display());
+    loop {
+        if socket_path.exists() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let status = tokio::time::timeout(
+                remaining,
+                ipc_client::roundtrip(socket_path, &ipc::DaemonRequest::Status),
+            )
+            .await;
+            progress = match status {
+                Ok(Ok(DaemonResponse::Status { status })) => {
+                    let session_ready = status.session_id.is_some()
+                        || status.session_state == SessionState::NotStarted;
+                    if status.running && session_ready {
+                        return Ok(());
+                    }
+                    if status.running {
+                        "daemon answering but its agent has no session yet".into()
+                    } else {
+                        "daemon answering but shutting down".into()
+                    }
+                }
+                Ok(Ok(DaemonResponse::Error { message, .. })) => {
+                    format!("daemon answering with an error: {message}")
+                }
+                Ok(Ok(other)) => format!("unexpected daemon response: {other:?}"),
+                Ok(Err(_)) | Err(_) => "socket exists but daemon not answering".into(),
+            };
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "daemon not ready after {}s: {progress}",
+                timeout.as_secs()
+            ));
+        }
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Points the top-level agent fields at the named agent instead of the default one.
+fn select_agent(status: &mut DaemonStatus, name: &str) -> Result<()> {
+    let agent = status
+        .agents
+        .iter()
+        .find(|agent| agent.name == name)
+        .ok_or_else(|| anyhow!("daemon has no agent named {name:?}"))?;
+    status.session_id = agent.session_id.clone();
+    status.session_state = agent.session_state;
+    status.agent_pid = agent.agent_pid;
+    status.agent_command = agent.agent_command.clone();
+    Ok(())
+}
+
+/// `shutdown` exit code when the daemon stopped without draining.
+const SHUTDOWN_FORCED: i32 = 2;
+
 pub async fn run_shutdown(options: ShutdownOptions) -> Result<()> {
-    let socket_path =
-        kakoune::resolve_socket_path(options.socket.clone(), options.session.as_deref())?;
-    let response = ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::Shutdown).await?;
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let request = ipc::DaemonRequest::Shutdown {
+        force: options.force,
+    };
+    let response = ipc_client::roundtrip(&socket_path, &request).await?;
     match response {
-        DaemonResponse::Ok => {
+        DaemonResponse::Shutdown {
+            forced: true,
+            abandoned_prompts,
+        } => {
+            println!("daemon shut down (forced, {abandoned_prompts} prompts interrupted)");
+            std::process::exit(SHUTDOWN_FORCED);
+        }
+        DaemonResponse::Shutdown {
+            abandoned_prompts: 0,
+            ..
+        } => {
             println!("daemon shut down");
         }
-        DaemonResponse::Error { message } => return Err(anyhow!(message)),
+        DaemonResponse::Shutdown {
+            abandoned_prompts, ..
+        } => {
+            println!(
+                "daemon shut down after the drain timeout ({abandoned_prompts} prompts interrupted)"
+            );
+        }
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+pub async fn run_restart_agent(options: RestartAgentOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let request = ipc::DaemonRequest::RestartAgent {
+        agent: options.agent.clone(),
+    };
+    let response = ipc_client::roundtrip(&socket_path, &request).await?;
+    match response {
+        DaemonResponse::AgentRestarted {
+            agent,
+            session_id,
+            agent_pid,
+        } => {
+            let pid = agent_pid.map_or_else(|| "unknown".to_string(), |pid| pid.to_string());
+            let session_id = session_id.unwrap_or_else(|| "not started".into());
+            println!("agent {agent} restarted (pid {pid}, session {session_id})");
+        }
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+pub async fn run_cancel(options: CancelOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let request = ipc::DaemonRequest::Cancel {
+        agent: options.agent.clone(),
+    };
+    match ipc_client::roundtrip(&socket_path, &request).await? {
+        DaemonResponse::Ok => println!("cancel requested"),
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+pub async fn run_permission_reply(options: PermissionReplyOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let request = ipc::DaemonRequest::PermissionReply {
+        permission_id: options.id,
+        option_id: options.option_id.clone(),
+        always: options.always,
+    };
+    match ipc_client::roundtrip(&socket_path, &request).await? {
+        DaemonResponse::Ok => {}
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+pub async fn run_permissions_list(options: PermissionsOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    match ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::PermissionRules).await? {
+        DaemonResponse::PermissionRules { rules } if rules.is_empty() => {
+            println!("no stored permission rules")
+        }
+        DaemonResponse::PermissionRules { rules } => {
+            for rule in rules {
+                println!(
+                    "{:<5}  {:<7}  {}*",
+                    rule.decision, rule.tool_kind, rule.title_prefix
+                );
+            }
+        }
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+pub async fn run_permissions_clear(options: PermissionsOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    match ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::ClearPermissionRules).await? {
+        DaemonResponse::PermissionRules { rules } => {
+            println!("cleared {} permission rule(s)", rules.len())
+        }
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
         other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
     }
     Ok(())
 }
+
+pub async fn run_reload(options: ReloadOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    match ipc_client::roundtrip(&socket_path, &ipc::DaemonRequest::Reload).await? {
+        DaemonResponse::Reloaded { applied, skipped } => {
+            println!("{}", describe_reload(&applied, &skipped))
+        }
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+fn describe_reload(applied: &[String], skipped: &[String]) -> String {
+    if applied.is_empty() && skipped.is_empty() {
+        return "reloaded: nothing changed".into();
+    }
+    let mut parts = Vec::new();
+    if !applied.is_empty() {
+        parts.push(format!("applied {}", applied.join(", ")));
+    }
+    if !skipped.is_empty() {
+        parts.push(format!(
+            "skipped {} (restart the daemon to apply)",
+            skipped.join(", ")
+        ));
+    }
+    format!("reloaded: {}", parts.join("; "))
+}
+
+pub async fn run_session_close(options: SessionCloseOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    // The directory may already be gone; the daemon matches on the path it was given then.
+    let cwd = std::fs::canonicalize(&options.cwd).unwrap_or(options.cwd);
+    let request = ipc::DaemonRequest::CloseSession {
+        agent: options.agent,
+        cwd: cwd.clone(),
+    };
+    match ipc_client::roundtrip(&socket_path, &request).await? {
+        DaemonResponse::Ok => println!("closed the session for {}", cwd.display()),
+        DaemonResponse::Error { message, .. } => return Err(anyhow!(message)),
+        other => return Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+    Ok(())
+}
+
+/// `ping` exit codes, so statusline scripts can tell why the daemon is unavailable.
+const PING_UNRESPONSIVE: i32 = 1;
+const PING_SOCKET_MISSING: i32 = 2;
+const PING_CONNECTION_REFUSED: i32 = 3;
+
+pub async fn run_ping(options: PingOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let started = Instant::now();
+    let timeout = Duration::from_millis(options.timeout);
+    let code = match tokio::time::timeout(timeout, ping(&socket_path)).await {
+        Ok(Ok(uptime_ms)) => {
+            let latency = started.elapsed().as_secs_f64() * 1000.0;
+            println!(
+                "pong from {} in {latency:.1}ms (up {})",
+                socket_path.display(),
+                format_duration(uptime_ms / 1000)
+            );
+            return Ok(());
+        }
+        Ok(Err(err)) => {
+            eprintln!("{err:#}");
+            ping_failure_code(&err)
+        }
+        Err(_) => {
+            eprintln!(
+                "daemon at {} did not answer within {}ms",
+                socket_path.display(),
+                options.timeout
+            );
+            PING_UNRESPONSIVE
+        }
+    };
+    std::process::exit(code)
+}
+
+async fn ping(socket_path: &Path) -> Result<u64> {
+    match ipc_client::roundtrip(socket_path, &ipc::DaemonRequest::Ping).await? {
+        DaemonResponse::Pong { uptime_ms, .. } => Ok(uptime_ms),
+        DaemonResponse::Error { message, .. } => Err(anyhow!(message)),
+        other => Err(anyhow!(format!("unexpected daemon response: {other:?}"))),
+    }
+}
+
+fn ping_failure_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<std::io::Error>().map(|err| err.kind()) {
+        Some(ErrorKind::NotFound) => PING_SOCKET_MISSING,
+        Some(ErrorKind::ConnectionRefused) => PING_CONNECTION_REFUSED,
+        _ => PING_UNRESPONSIVE,
+    }
+}
+
+fn describe_session(session_id: Option<&str>, state: SessionState) -> String {
+    match (session_id, state) {
+        (Some(session_id), _) => session_id.to_string(),
+        (None, SessionState::NotStarted) => "not started".into(),
+        (None, SessionState::Active) => "-".into(),
+    }
+}
+
+/// The socket path, and what it was derived from when not given.
+fn describe_socket(status: &DaemonStatus) -> String {
+    let path = status.socket_path.display();
+    match (&status.socket_scope, &status.socket_root) {
+        (Some(scope), Some(root)) => format!("{path} ({scope} scope, root {})", root.display()),
+        (Some(scope), None) => format!("{path} ({scope} scope)"),
+        (None, _) => path.to_string(),
+    }
+}
+
+fn render_status_table(status: &DaemonStatus) -> String {
+    let mut rows = vec![
+        ("Socket", describe_socket(status)),
+        (
+            "Session ID",
+            describe_session(status.session_id.as_deref(), status.session_state),
+        ),
+        ("Agent running", status.running.to_string()),
+        (
+            "Agent PID",
+            status
+                .agent_pid
+                .map(|pid| pid.to_string())
+                .unwrap_or_else(|| "-".into()),
+        ),
+    ];
+    rows.push((
+        "Version",
+        format!(
+            "{} (protocol v{})",
+            status.binary_version, status.protocol_version
+        ),
+    ));
+    if !status.agent_command.is_empty() {
+        rows.push(("Agent command", status.agent_command.join(" ")));
+    }
+    rows.extend([
+        ("Started at", status.started_at.to_string()),
+        ("Uptime", format_duration(status.uptime_seconds)),
+        ("Draining", status.draining.to_string()),
+        ("Busy", status.busy.to_string()),
+        ("Permission policy", status.permission_policy.clone()),
+        ("Audit log", match &status.audit_log {
+            Some(audit) if audit.failures > 0 => format!(
+                "{} ({} entries, {} failed: {})",
+                audit.path.display(),
+                audit.entries,
+                audit.failures,
+                audit.last_error.as_deref().unwrap_or("unknown error")
+            ),
+            Some(audit) => format!("{} ({} entries)", audit.path.display(), audit.entries),
+            None => "-".into(),
+        }),
+        ("Last reload", match status.last_reload_at {
+            Some(at) => format!(
+                "{at} ({})",
+                describe_reload(&status.reload_applied, &status.reload_skipped)
+            ),
+            None => "-".into(),
+        }),
+        ("Last agent exit", match &status.last_exit {
+            Some(exit) => match (exit.code, exit.signal) {
+                (Some(code), _) => format!("code {code} at {}", exit.exited_at),
+                (None, Some(signal)) => format!("signal {signal} at {}", exit.exited_at),
+                (None, None) => format!("unknown at {}", exit.exited_at),
+            },
+            None => "-".into(),
+        }),
+        (
+            "Prompts",
+            format!(
+                "{} completed, {} failed",
+                status.prompts_completed, status.prompts_failed
+            ),
+        ),
+        ("Notifications", status.notifications_received.to_string()),
+        (
+            "Last prompt at",
+            status
+                .last_prompt_at
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_else(|| "-".into()),
+        ),
+        ("Current prompt", match &status.current_prompt {
+            Some(active) => {
+                let mut line = format!(
+                    "#{} ({:.1}s elapsed",
+                    active.request_id,
+                    active.elapsed_ms as f64 / 1000.0
+                );
+                if let Some(session) = &active.kak_session {
+                    line.push_str(&format!(", from {session}"));
+                    if let Some(client) = &active.kak_client {
+                        line.push_str(&format!(":{client}"));
+                    }
+                }
+                line.push(')');
+                line
+            }
+            None => "-".into(),
+        }),
+    ]);
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut output = String::new();
+    for (key, value) in rows {
+        output.push_str(&format!("{key:<width$}  {value}\n"));
+    }
+    if status.agents.len() > 1 {
+        output.push_str("Agents\n");
+        let width = status
+            .agents
+            .iter()
+            .map(|agent| agent.name.len())
+            .max()
+            .unwrap_or(0);
+        for agent in &status.agents {
+            let pid = agent
+                .agent_pid
+                .map_or_else(|| "-".to_string(), |pid| pid.to_string());
+            output.push_str(&format!(
+                "  {:<width$}  pid {pid}, session {}\n",
+                agent.name,
+                describe_session(agent.session_id.as_deref(), agent.session_state)
+            ));
+        }
+    }
+    output
+}
+
+fn render_metrics(metrics: &Metrics) -> String {
+    let mut rows = vec![
+        ("Since", metrics.since.to_string()),
+        (
+            "Prompt time",
+            format!("{:.1}s", metrics.prompt_time_ms as f64 / 1000.0),
+        ),
+        (
+            "Agent message bytes",
+            metrics.agent_message_bytes.to_string(),
+        ),
+        (
+            "Dropped notifications",
+            metrics.dropped_notifications.to_string(),
+        ),
+    ];
+    let counts = |counts: &BTreeMap<String, u64>| {
+        if counts.is_empty() {
+            return "-".to_string();
+        }
+        counts
+            .iter()
+            .map(|(key, count)| format!("{key} {count}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    rows.push(("Notifications", counts(&metrics.notifications)));
+    rows.push(("Tool calls", counts(&metrics.tool_calls)));
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut output = String::new();
+    for (key, value) in rows {
+        output.push_str(&format!("{key:<width$}  {value}\n"));
+    }
+    output
+}
+
+fn format_duration(total_seconds: u64) -> String {
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
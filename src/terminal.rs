@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use agent_client_protocol as acp;
+use anyhow::{Context, Result, anyhow};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::watch,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// Output kept per terminal when the agent does not set `outputByteLimit`.
+pub const DEFAULT_OUTPUT_LIMIT: u64 = 1024 * 1024;
+
+/// Commands the agent started through `terminal/create`, by terminal id.
+#[derive(Default)]
+pub struct Terminals {
+    next_id: AtomicU64,
+    terminals: std::sync::Mutex<HashMap<acp::TerminalId, Arc<Terminal>>>,
+}
+
+/// What `create` needs to start a command.
+pub struct Launch {
+    pub session_id: acp::SessionId,
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub cwd: PathBuf,
+    pub output_byte_limit: Option<u64>,
+}
+
+struct Terminal {
+    session_id: acp::SessionId,
+    /// The program and its arguments, for the transcript.
+    command_line: String,
+    output: Arc<std::sync::Mutex<OutputBuffer>>,
+    exit: watch::Receiver<Option<acp::TerminalExitStatus>>,
+    kill: CancellationToken,
+    /// Kills the command once the terminal is released or the agent goes away.
+    _kill_on_drop: DropGuard,
+}
+
+impl Terminals {
+    /// Starts `launch.command` and returns its id with a receiver that sees it exit.
+    pub fn create(
+        &self,
+        launch: Launch,
+    ) -> Result<(
+        acp::TerminalId,
+        watch::Receiver<Option<acp::TerminalExitStatus>>,
+    )> {
+        let command_line = std::iter::once(&launch.command)
+            .chain(&launch.args)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut child = Command::new(&launch.command)
+            .args(&launch.args)
+            .envs(launch.env)
+            .current_dir(&launch.cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to run {}", launch.command))?;
+
+        let limit = launch.output_byte_limit.unwrap_or(DEFAULT_OUTPUT_LIMIT) as usize;
+        let output = Arc::new(std::sync::Mutex::new(OutputBuffer::new(limit)));
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(tokio::spawn(capture(stdout, output.clone())));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(tokio::spawn(capture(stderr, output.clone())));
+        }
+
+        let (exit_tx, exit_rx) = watch::channel(None);
+        let kill = CancellationToken::new();
+        let killed = kill.clone();
+        tokio::spawn(async move {
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = killed.cancelled() => {
+                    let _ = child.start_kill();
+                    child.wait().await
+                }
+            };
+            // Let the readers drain what the command wrote before reporting the exit.
+            for reader in readers {
+                let _ = reader.await;
+            }
+            let status = match status {
+                Ok(status) => acp::TerminalExitStatus {
+                    exit_code: status.code().map(|code| code as u32),
+                    signal: status.signal().map(signal_name),
+                    meta: None,
+                },
+                Err(_) => acp::TerminalExitStatus {
+                    exit_code: None,
+                    signal: None,
+                    meta: None,
+                },
+            };
+            let _ = exit_tx.send(Some(status));
+        });
+
+        let id = acp::TerminalId(
+            format!("term-{}", self.next_id.fetch_add(1, Ordering::Relaxed) + 1).into(),
+        );
+        let terminal = Terminal {
+            session_id: launch.session_id,
+            command_line,
+            output,
+            exit: exit_rx.clone(),
+            _kill_on_drop: kill.clone().drop_guard(),
+            kill,
+        };
+        self.terminals
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::new(terminal));
+        Ok((id, exit_rx))
+    }
+
+    /// The program and arguments the terminal runs.
+    pub fn command_line(
+        &self,
+        session_id: &acp::SessionId,
+        id: &acp::TerminalId,
+    ) -> Result<String> {
+        Ok(self.get(session_id, id)?.command_line.clone())
+    }
+
+    /// Output so far and the exit status, if the command has exited.
+    pub fn output(
+        &self,
+        session_id: &acp::SessionId,
+        id: &acp::TerminalId,
+    ) -> Result<acp::TerminalOutputResponse> {
+        let terminal = self.get(session_id, id)?;
+        let exit_status = terminal.exit.borrow().clone();
+        let output = terminal.output.lock().unwrap();
+        Ok(acp::TerminalOutputResponse {
+            output: output.text(),
+            truncated: output.truncated,
+            exit_status,
+            meta: None,
+        })
+    }
+
+    /// Waits for the command to exit.
+    pub async fn wait_for_exit(
+        &self,
+        session_id: &acp::SessionId,
+        id: &acp::TerminalId,
+    ) -> Result<acp::TerminalExitStatus> {
+        let mut exit = self.get(session_id, id)?.exit.clone();
+        let status = exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow!("terminal {id} went away"))?;
+        Ok(status.clone().expect("waited for an exit status"))
+    }
+
+    /// Kills the command but keeps the terminal around; returns whether it was still running.
+    pub fn kill(&self, session_id: &acp::SessionId, id: &acp::TerminalId) -> Result<bool> {
+        let terminal = self.get(session_id, id)?;
+        let running = terminal.exit.borrow().is_none();
+        terminal.kill.cancel();
+        Ok(running)
+    }
+
+    /// Forgets the terminal, killing the command if it is still running; returns whether it
+    /// was.
+    pub fn release(&self, session_id: &acp::SessionId, id: &acp::TerminalId) -> Result<bool> {
+        let terminal = self.get(session_id, id)?;
+        let running = terminal.exit.borrow().is_none();
+        self.terminals.lock().unwrap().remove(id);
+        Ok(running)
+    }
+
+    /// Forgets every terminal of `session_id`, killing the commands still running; returns how
+    /// many there were.
+    pub fn release_session(&self, session_id: &acp::SessionId) -> usize {
+        let mut terminals = self.terminals.lock().unwrap();
+        let before = terminals.len();
+        terminals.retain(|_, terminal| {
+            if terminal.session_id != *session_id {
+                return true;
+            }
+            // Someone waiting for it to exit may keep it alive past the drop.
+            terminal.kill.cancel();
+            false
+        });
+        before - terminals.len()
+    }
+
+    fn get(&self, session_id: &acp::SessionId, id: &acp::TerminalId) -> Result<Arc<Terminal>> {
+        self.terminals
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|terminal| terminal.session_id == *session_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("no terminal {id} in session {session_id}"))
+    }
+}
+
+async fn capture(mut stream: impl AsyncRead + Unpin, output: Arc<std::sync::Mutex<OutputBuffer>>) {
+    let mut chunk = [0; 8192];
+    while let Ok(read) = stream.read(&mut chunk).await
+        && read > 0
+    {
+        output.lock().unwrap().push(&chunk[..read]);
+    }
+}
+
+/// The tail of a command's combined output, at most `limit` bytes long.
+struct OutputBuffer {
+    bytes: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+impl OutputBuffer {
+    fn new(limit: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            limit,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+        if self.bytes.len() > self.limit {
+            let mut start = self.bytes.len() - self.limit;
+            // Never keep half a character at the front.
+            while start < self.bytes.len() && self.bytes[start] & 0xC0 == 0x80 {
+                start += 1;
+            }
+            self.bytes.drain(..start);
+            self.truncated = true;
+        }
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
+
+fn signal_name(signal: i32) -> String {
+    match signal {
+        libc::SIGHUP => "SIGHUP".into(),
+        libc::SIGINT => "SIGINT".into(),
+        libc::SIGKILL => "SIGKILL".into(),
+        libc::SIGPIPE => "SIGPIPE".into(),
+        libc::SIGTERM => "SIGTERM".into(),
+        other => format!("signal {other}"),
+    }
+}
@@ -1,50 +1,247 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
 use agent_client_protocol as acp;
 
-use crate::ipc::{CommandSummary, PlanEntrySummary, TranscriptEvent};
+use crate::{
+    ipc::{
+        CommandInputSummary, CommandSummary, ErrorSource, PlanEntryChange, PlanEntrySummary,
+        ToolLocation, ToolStatusChange, TranscriptEntry, TranscriptEvent, TranscriptSummary,
+    },
+    media::MediaStore,
+};
+
+/// Diff lines kept in a `FileEdit` event unless `--max-diff-lines` says otherwise.
+pub const DEFAULT_MAX_DIFF_LINES: usize = 200;
+
+/// Unchanged lines shown around each change unless `--diff-context` says otherwise.
+pub const DEFAULT_DIFF_CONTEXT: usize = 3;
+
+/// Longest `answer` a prompt result carries; the messages stay whole in the transcript.
+const MAX_ANSWER_BYTES: usize = 1024 * 1024;
 
+/// Bytes of a command's output kept in a `TerminalOutput` event, from the end.
+const MAX_TERMINAL_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// Turns the session updates of one prompt turn into transcript events.
 pub struct TranscriptCollector {
-    events: Vec<TranscriptEvent>,
+    events: Vec<TranscriptEntry>,
+    /// How `FileEdit` diffs are written.
+    diff_format: DiffFormat,
+    /// Where images are saved; without one they are only mentioned by MIME type.
+    media: Option<Arc<MediaStore>>,
+    /// The diffs reported in tool calls, in order, for `--apply-diffs`.
+    diffs: Vec<acp::Diff>,
+    /// Join message and thought chunks following one another into one event.
+    merge_chunks: bool,
+    /// Index of the last event when a chunk made it, so the next chunk can extend it.
+    chunk_tail: Option<usize>,
+    /// Record only what changed in each plan after the first.
+    plan_updates: bool,
+    /// The latest plan the agent sent.
+    plan: Option<Vec<PlanEntrySummary>>,
+    /// Keep tool calls' `raw_input` and `raw_output`.
+    raw_tool_io: bool,
+    /// Terminals shown in tool call content, with the id of the first tool call showing each.
+    terminal_tools: Vec<(String, String)>,
+    /// The session's modes and the one it is in, when the agent has modes.
+    modes: Option<acp::SessionModeState>,
+    /// Record mode changes as system messages too.
+    legacy_mode_messages: bool,
+    /// Fold tool call updates into their tool call, recorded once it completes or fails.
+    group_tool_calls: bool,
+    /// Grouped tool calls still running, in the order they started.
+    open_tools: Vec<TranscriptEvent>,
+    /// When the turn started, for the summary's duration.
+    started: Instant,
+    /// The prompt, trimmed, while the agent's first user message may still be an echo of it.
+    prompt_echo: Option<String>,
+    /// The agent's first user message so far, held back while it matches `prompt_echo`.
+    echo: String,
+    /// Keep the agent's echo of the prompt as a user message of its own.
+    keep_user_echo: bool,
+    /// The agent's echo of the prompt was dropped.
+    echo_suppressed: bool,
+}
+
+impl Default for TranscriptCollector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TranscriptCollector {
+    /// A collector for a new turn, merging chunks and recording revised plans as updates.
     pub fn new() -> Self {
-        Self { events: Vec::new() }
+        Self {
+            events: Vec::new(),
+            diff_format: DiffFormat::default(),
+            media: None,
+            diffs: Vec::new(),
+            merge_chunks: true,
+            chunk_tail: None,
+            plan_updates: true,
+            plan: None,
+            raw_tool_io: false,
+            terminal_tools: Vec::new(),
+            modes: None,
+            legacy_mode_messages: false,
+            group_tool_calls: false,
+            open_tools: Vec::new(),
+            started: Instant::now(),
+            prompt_echo: None,
+            echo: String::new(),
+            keep_user_echo: false,
+            echo_suppressed: false,
+        }
+    }
+
+    pub fn with_diff_format(mut self, diff_format: DiffFormat) -> Self {
+        self.diff_format = diff_format;
+        self
+    }
+
+    pub fn with_media(mut self, media: Option<Arc<MediaStore>>) -> Self {
+        self.media = media;
+        self
+    }
+
+    /// Keeps each message and thought chunk as an event of its own when `merge` is false.
+    pub fn with_merged_chunks(mut self, merge: bool) -> Self {
+        self.merge_chunks = merge;
+        self
+    }
+
+    /// Records every plan in full when `updates` is false, rather than only its changes.
+    pub fn with_plan_updates(mut self, updates: bool) -> Self {
+        self.plan_updates = updates;
+        self
+    }
+
+    pub fn with_raw_tool_io(mut self, raw_tool_io: bool) -> Self {
+        self.raw_tool_io = raw_tool_io;
+        self
+    }
+
+    /// Starts from the modes the agent listed for the session, to name the modes it switches
+    /// to.
+    pub fn with_modes(mut self, modes: Option<acp::SessionModeState>) -> Self {
+        self.modes = modes;
+        self
+    }
+
+    /// Records each mode change as a `SystemMessage` as well as a `ModeChange`.
+    pub fn with_legacy_mode_messages(mut self, legacy: bool) -> Self {
+        self.legacy_mode_messages = legacy;
+        self
+    }
+
+    /// Records each tool call as a single `ToolCall` event with its status history.
+    pub fn with_grouped_tool_calls(mut self, group: bool) -> Self {
+        self.group_tool_calls = group;
+        self
+    }
+
+    /// Records the agent's echo of the prompt as a user message even when it repeats the
+    /// prompt word for word.
+    pub fn with_user_echo(mut self, keep: bool) -> Self {
+        self.keep_user_echo = keep;
+        self
     }
 
     pub fn push_user_prompt(&mut self, text: String) {
         if !text.is_empty() {
-            self.events.push(TranscriptEvent::UserMessage { text });
+            if !self.keep_user_echo {
+                self.prompt_echo = Some(text.trim().to_string());
+            }
+            self.add(TranscriptEvent::UserMessage { text });
         }
     }
 
     pub fn record_notification(&mut self, notification: acp::SessionNotification) {
         use acp::SessionUpdate;
 
+        if !matches!(notification.update, SessionUpdate::UserMessageChunk { .. }) {
+            self.settle_echo();
+        }
         match notification.update {
             SessionUpdate::AgentMessageChunk { content } => {
-                self.events.push(TranscriptEvent::AgentMessage {
-                    text: render_content(content),
-                });
+                if let Some(event) = self
+                    .save_image(&content)
+                    .or_else(|| resource_link(&content))
+                {
+                    self.add(event);
+                } else {
+                    self.push_chunk(TranscriptEvent::AgentMessage {
+                        text: render_content(content),
+                    });
+                }
             }
             SessionUpdate::AgentThoughtChunk { content } => {
-                self.events.push(TranscriptEvent::AgentThought {
-                    text: render_content(content),
-                });
+                if let Some(event) = self
+                    .save_image(&content)
+                    .or_else(|| resource_link(&content))
+                {
+                    self.add(event);
+                } else {
+                    self.push_chunk(TranscriptEvent::AgentThought {
+                        text: render_content(content),
+                    });
+                }
             }
             SessionUpdate::UserMessageChunk { content } => {
-                self.events.push(TranscriptEvent::UserMessage {
-                    text: render_content(content),
-                });
+                if let Some(text) = self.hold_echo(render_content(content)) {
+                    self.push_chunk(TranscriptEvent::UserMessage { text });
+                }
             }
             SessionUpdate::ToolCall(tool_call) => {
-                self.events.push(TranscriptEvent::ToolCall {
-                    id: tool_call.id.0.to_string(),
+                let id = tool_call.id.0.to_string();
+                let failed_silently =
+                    tool_call.status == acp::ToolCallStatus::Failed && tool_call.content.is_empty();
+                let status = format!("{:?}", tool_call.status);
+                let status_history = match self.group_tool_calls {
+                    true => vec![self.status_change(&status)],
+                    false => Vec::new(),
+                };
+                let event = TranscriptEvent::ToolCall {
+                    id: id.clone(),
                     title: tool_call.title,
-                    status: format!("{:?}", tool_call.status),
-                });
+                    status,
+                    tool_kind: tool_kind_name(tool_call.kind),
+                    locations: tool_locations(&tool_call.locations),
+                    message: content_message(Vec::new(), tool_call.content.clone()),
+                    raw_input: tool_call.raw_input.filter(|_| self.raw_tool_io),
+                    raw_output: tool_call.raw_output.filter(|_| self.raw_tool_io),
+                    status_history,
+                };
+                if self.group_tool_calls && !finished(tool_call.status) {
+                    self.open_tools.push(event);
+                } else {
+                    self.add(event);
+                }
+                self.push_attachments(&id, &tool_call.content);
+                if failed_silently {
+                    self.push_silent_failure(id);
+                }
             }
             SessionUpdate::ToolCallUpdate(update) => {
-                self.events.push(summarize_tool_call_update(update));
+                let id = update.id.0.to_string();
+                let content = update.fields.content.clone().unwrap_or_default();
+                let failed_silently =
+                    update.fields.status == Some(acp::ToolCallStatus::Failed) && content.is_empty();
+                if let Some(index) = self.open_tool(&id) {
+                    self.fold_tool_call_update(index, update.fields);
+                } else if let Some(event) = summarize_tool_call_update(update, self.raw_tool_io) {
+                    // An update carrying only a diff is told by its `FileEdit` event alone.
+                    self.add(event);
+                }
+                self.push_attachments(&id, &content);
+                if failed_silently {
+                    self.push_silent_failure(id);
+                }
             }
             SessionUpdate::Plan(plan) => {
                 let entries = plan
@@ -55,37 +252,768 @@ impl TranscriptCollector {
                         priority: format!("{:?}", entry.priority),
                         content: entry.content,
                     })
-                    .collect();
-                self.events.push(TranscriptEvent::Plan { entries });
+                    .collect::<Vec<_>>();
+                match self.plan.as_deref().filter(|_| self.plan_updates) {
+                    Some(previous) => {
+                        let changes = plan_changes(previous, &entries);
+                        // A refresh that changes nothing is left out.
+                        if !changes.is_empty() || entries.len() != previous.len() {
+                            self.add(TranscriptEvent::PlanUpdate {
+                                changes,
+                                len: entries.len(),
+                            });
+                        }
+                    }
+                    None => self.add(TranscriptEvent::Plan {
+                        entries: entries.clone(),
+                    }),
+                }
+                self.plan = Some(entries);
             }
             SessionUpdate::AvailableCommandsUpdate { available_commands } => {
                 let commands = available_commands
                     .into_iter()
-                    .map(|command| CommandSummary {
-                        name: command.name,
-                        description: command.description,
-                        hint: match command.input {
-                            Some(acp::AvailableCommandInput::Unstructured { hint }) => Some(hint),
-                            None => None,
-                        },
+                    .map(|command| {
+                        let input = command.input.map(command_input);
+                        let hint = match &input {
+                            Some(CommandInputSummary::Unstructured { hint }) => Some(hint.clone()),
+                            _ => None,
+                        };
+                        CommandSummary {
+                            name: command.name,
+                            description: command.description,
+                            hint,
+                            input,
+                        }
                     })
                     .collect();
-                self.events
-                    .push(TranscriptEvent::AvailableCommands { commands });
+                self.add(TranscriptEvent::AvailableCommands { commands });
             }
             SessionUpdate::CurrentModeUpdate { current_mode_id } => {
-                self.events.push(TranscriptEvent::SystemMessage {
-                    text: format!("Current mode: {}", current_mode_id.0),
+                if self.legacy_mode_messages {
+                    self.add(TranscriptEvent::SystemMessage {
+                        text: format!("Current mode: {}", current_mode_id.0),
+                    });
+                }
+                let mode_name = self.modes.as_ref().and_then(|modes| {
+                    modes
+                        .available_modes
+                        .iter()
+                        .find(|mode| mode.id == current_mode_id)
+                        .map(|mode| mode.name.clone())
+                });
+                self.add(TranscriptEvent::ModeChange {
+                    mode_id: current_mode_id.0.to_string(),
+                    mode_name,
                 });
+                match &mut self.modes {
+                    Some(modes) => modes.current_mode_id = current_mode_id,
+                    None => {
+                        self.modes = Some(acp::SessionModeState {
+                            current_mode_id,
+                            available_modes: Vec::new(),
+                            meta: None,
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    /// Index in `open_tools` of the grouped tool call `id`, if it is still running.
+    fn open_tool(&self, id: &str) -> Option<usize> {
+        self.open_tools.iter().position(
+            |event| matches!(event, TranscriptEvent::ToolCall { id: open, .. } if open == id),
+        )
+    }
+
+    /// Applies an update to the grouped tool call at `index`, recording the tool call once
+    /// the update finishes it.
+    fn fold_tool_call_update(&mut self, index: usize, fields: acp::ToolCallUpdateFields) {
+        let change = fields
+            .status
+            .map(|status| (finished(status), self.status_change(&format!("{status:?}"))));
+        let TranscriptEvent::ToolCall {
+            title,
+            status,
+            tool_kind,
+            locations,
+            message,
+            raw_input,
+            raw_output,
+            status_history,
+            ..
+        } = &mut self.open_tools[index]
+        else {
+            unreachable!("open_tools holds ToolCall events");
+        };
+        if let Some(new_title) = fields.title {
+            *title = new_title;
+        }
+        if let Some(kind) = fields.kind {
+            *tool_kind = tool_kind_name(kind);
+        }
+        if let Some(new_locations) = fields.locations {
+            *locations = tool_locations(&new_locations);
+        }
+        // Updates often repeat what the tool call already showed.
+        if let Some(text) = fields
+            .content
+            .and_then(|content| content_message(Vec::new(), content))
+        {
+            match message {
+                Some(message) if message.contains(&text) => {}
+                Some(message) => {
+                    message.push('\n');
+                    message.push_str(&text);
+                }
+                None => *message = Some(text),
+            }
+        }
+        if self.raw_tool_io {
+            if let Some(input) = fields.raw_input {
+                *raw_input = Some(input);
+            }
+            if let Some(output) = fields.raw_output {
+                *raw_output = Some(output);
+            }
+        }
+        let Some((done, change)) = change else {
+            return;
+        };
+        *status = change.status.clone();
+        if status_history.last().map(|last| &last.status) != Some(&change.status) {
+            status_history.push(change);
+        }
+        if done {
+            let event = self.open_tools.remove(index);
+            self.add(event);
+        }
+    }
+
+    /// A `ToolStatusChange` to `status`, placed after the events recorded so far.
+    fn status_change(&self, status: &str) -> ToolStatusChange {
+        ToolStatusChange {
+            status: status.to_string(),
+            seq: self.events.len() as u64,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+
+    /// Holds `text` back while the agent's first user message could still be an echo of the
+    /// prompt; once it cannot, gives it back with what was held before it.
+    fn hold_echo(&mut self, text: String) -> Option<String> {
+        let Some(prompt) = &self.prompt_echo else {
+            return Some(text);
+        };
+        self.echo.push_str(&text);
+        if prompt.starts_with(self.echo.trim()) {
+            return None;
+        }
+        self.prompt_echo = None;
+        Some(std::mem::take(&mut self.echo))
+    }
+
+    /// Drops the agent's first user message if it repeated the prompt, or records it if it
+    /// did not, once something else shows it is complete.
+    pub fn settle_echo(&mut self) {
+        if self.echo.is_empty() {
+            return;
+        }
+        let Some(prompt) = self.prompt_echo.take() else {
+            return;
+        };
+        let echo = std::mem::take(&mut self.echo);
+        if echo.trim() == prompt {
+            self.echo_suppressed = true;
+        } else {
+            self.push_chunk(TranscriptEvent::UserMessage { text: echo });
+        }
+    }
+
+    /// Notes that tool call `id` failed without content saying why, which would otherwise
+    /// show only as its status.
+    fn push_silent_failure(&mut self, id: String) {
+        self.add(TranscriptEvent::Error {
+            source: ErrorSource::ToolCall,
+            message: format!("tool call {id} failed without saying why"),
+            count: None,
+        });
+    }
+
+    /// Whether the transcript has `error` events.
+    pub fn had_errors(&self) -> bool {
+        self.events
+            .iter()
+            .any(|entry| matches!(entry.event, TranscriptEvent::Error { .. }))
+    }
+
+    /// Whether the agent's echo of the prompt was dropped.
+    pub fn echo_suppressed(&self) -> bool {
+        self.echo_suppressed
+    }
+
+    /// Records the grouped tool calls that never completed or failed, as they stand.
+    pub fn flush_tool_calls(&mut self) {
+        for event in std::mem::take(&mut self.open_tools) {
+            self.add(event);
+        }
+    }
+
+    /// Adds the text of a message or thought chunk to the last event when a chunk of the same
+    /// kind made it, and as a new event otherwise.
+    fn push_chunk(&mut self, event: TranscriptEvent) {
+        use TranscriptEvent::{AgentMessage, AgentThought, UserMessage};
+
+        let tail = self.events.len().checked_sub(1);
+        if self.merge_chunks
+            && tail.is_some()
+            && self.chunk_tail == tail
+            && let Some(last) = self.events.last_mut()
+        {
+            match (&mut last.event, &event) {
+                (AgentMessage { text }, AgentMessage { text: more })
+                | (AgentThought { text }, AgentThought { text: more })
+                | (UserMessage { text }, UserMessage { text: more }) => {
+                    text.push_str(more);
+                    last.text_bytes += more.len() as u64;
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.add(event);
+        self.chunk_tail = Some(self.events.len() - 1);
+    }
+
+    /// Adds events for the diffs and images in the content of tool call `tool_id`.
+    fn push_attachments(&mut self, tool_id: &str, content: &[acp::ToolCallContent]) {
+        for entry in content {
+            match entry {
+                acp::ToolCallContent::Diff { diff } => {
+                    let mut edit = file_edit(
+                        &diff.path,
+                        diff.old_text.as_deref(),
+                        &diff.new_text,
+                        &self.diff_format,
+                    );
+                    if let TranscriptEvent::FileEdit { tool_id: id, .. } = &mut edit {
+                        *id = Some(tool_id.to_string());
+                    }
+                    self.add(edit);
+                    // Updates often repeat a tool call's content; one copy is enough to apply.
+                    if !self.diffs.contains(diff) {
+                        self.diffs.push(diff.clone());
+                    }
+                }
+                acp::ToolCallContent::Content { content } => {
+                    if let Some(image) = self.save_image(content) {
+                        self.add(image);
+                    }
+                }
+                acp::ToolCallContent::Terminal { terminal_id } => {
+                    let terminal_id = terminal_id.0.to_string();
+                    if self.terminal_tools.iter().any(|(id, _)| *id == terminal_id) {
+                        continue;
+                    }
+                    // The agent may release a terminal before showing it in a tool call.
+                    for entry in &mut self.events {
+                        if let TranscriptEvent::TerminalOutput {
+                            terminal_id: id,
+                            tool_id: tool @ None,
+                            ..
+                        } = &mut entry.event
+                            && *id == terminal_id
+                        {
+                            *tool = Some(tool_id.to_string());
+                        }
+                    }
+                    self.terminal_tools.push((terminal_id, tool_id.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Saves `content` if it is an image, returning the event that points at the file.
+    fn save_image(&self, content: &acp::ContentBlock) -> Option<TranscriptEvent> {
+        let (acp::ContentBlock::Image(image), Some(media)) = (content, &self.media) else {
+            return None;
+        };
+        match media.save_image(image) {
+            Ok(event) => Some(event),
+            Err(error) => {
+                tracing::warn!("did not save a {} image: {error:#}", image.mime_type);
+                None
+            }
+        }
+    }
+
+    /// Adds an event the daemon observed itself rather than one the agent reported.
+    pub fn push_event(&mut self, mut event: TranscriptEvent) {
+        self.settle_echo();
+        if let TranscriptEvent::TerminalOutput {
+            terminal_id,
+            tool_id: tool_id @ None,
+            ..
+        } = &mut event
+        {
+            *tool_id = self
+                .terminal_tools
+                .iter()
+                .find(|(id, _)| id == terminal_id)
+                .map(|(_, tool)| tool.clone());
+        }
+        self.add(event);
+    }
+
+    /// Terminals shown in tool call content whose output is not in the transcript yet.
+    pub fn unresolved_terminals(&self) -> Vec<String> {
+        self.terminal_tools
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| {
+                !self.events.iter().any(|entry| {
+                    matches!(&entry.event, TranscriptEvent::TerminalOutput { terminal_id, .. }
+                        if terminal_id == *id)
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Appends `event`, numbering it after the events before it.
+    fn add(&mut self, event: TranscriptEvent) {
+        let seq = self.events.len() as u64 + 1;
+        let text_bytes = text_bytes(&event);
+        self.events.push(TranscriptEntry {
+            seq,
+            text_bytes,
+            event,
+        });
+    }
+
+    /// The distinct diffs the agent proposed, in the order they arrived.
+    pub fn diffs(&self) -> &[acp::Diff] {
+        &self.diffs
+    }
+
+    /// The session's modes, with the one it is in now.
+    pub fn modes(&self) -> Option<&acp::SessionModeState> {
+        self.modes.as_ref()
+    }
+
+    /// The latest plan the agent sent.
+    pub fn plan(&self) -> Option<&[PlanEntrySummary]> {
+        self.plan.as_deref()
+    }
+
+    /// Events collected so far.
+    pub fn events(&self) -> &[TranscriptEntry] {
+        &self.events
+    }
+
+    /// The agent's messages so far, joined, and whether they had to be cut short.
+    pub fn answer(&self) -> (String, bool) {
+        answer(&self.events)
+    }
+
+    /// Counts and sizes of the events collected so far, and the time since the turn started.
+    pub fn summary(&self) -> TranscriptSummary {
+        let mut summary = TranscriptSummary {
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            ..TranscriptSummary::default()
+        };
+        for entry in &self.events {
+            summary.events += 1;
+            summary.text_bytes += entry.text_bytes;
+            let kind = summary
+                .kinds
+                .entry(kind_name(&entry.event).to_string())
+                .or_default();
+            kind.events += 1;
+            kind.text_bytes += entry.text_bytes;
+            match &entry.event {
+                TranscriptEvent::ToolCall { .. } => summary.tool_calls += 1,
+                TranscriptEvent::AgentMessage { text } => {
+                    summary.agent_message_chars += text.chars().count() as u64;
+                }
+                _ => {}
             }
         }
+        summary
     }
 
-    pub fn finish(self) -> Vec<TranscriptEvent> {
+    pub fn finish(mut self) -> Vec<TranscriptEntry> {
+        self.settle_echo();
+        self.flush_tool_calls();
         self.events
     }
 }
 
+/// The agent's messages in `events` joined in order, cut to `MAX_ANSWER_BYTES`, and whether
+/// they were cut.
+pub fn answer(events: &[TranscriptEntry]) -> (String, bool) {
+    let mut answer = String::new();
+    for entry in events {
+        let TranscriptEvent::AgentMessage { text } = &entry.event else {
+            continue;
+        };
+        if answer.len() + text.len() > MAX_ANSWER_BYTES {
+            let mut end = MAX_ANSWER_BYTES - answer.len();
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            answer.push_str(&text[..end]);
+            return (answer, true);
+        }
+        answer.push_str(text);
+    }
+    (answer, false)
+}
+
+/// The `kind` `event` is tagged with in JSON.
+fn kind_name(event: &TranscriptEvent) -> &'static str {
+    match event {
+        TranscriptEvent::UserMessage { .. } => "user_message",
+        TranscriptEvent::AgentMessage { .. } => "agent_message",
+        TranscriptEvent::AgentThought { .. } => "agent_thought",
+        TranscriptEvent::ToolCall { .. } => "tool_call",
+        TranscriptEvent::ToolCallUpdate { .. } => "tool_call_update",
+        TranscriptEvent::Plan { .. } => "plan",
+        TranscriptEvent::PlanUpdate { .. } => "plan_update",
+        TranscriptEvent::AvailableCommands { .. } => "available_commands",
+        TranscriptEvent::SystemMessage { .. } => "system_message",
+        TranscriptEvent::ModeChange { .. } => "mode_change",
+        TranscriptEvent::FileRead { .. } => "file_read",
+        TranscriptEvent::Permission { .. } => "permission",
+        TranscriptEvent::Terminal { .. } => "terminal",
+        TranscriptEvent::TerminalOutput { .. } => "terminal_output",
+        TranscriptEvent::FileEdit { .. } => "file_edit",
+        TranscriptEvent::Image { .. } => "image",
+        TranscriptEvent::ResourceLink { .. } => "resource_link",
+        TranscriptEvent::AccessDenied { .. } => "access_denied",
+        TranscriptEvent::FileWrite { .. } => "file_write",
+        TranscriptEvent::Error { .. } => "error",
+    }
+}
+
+/// Bytes of text `event` carries, leaving out ids, paths and the raw tool input and output.
+fn text_bytes(event: &TranscriptEvent) -> u64 {
+    let optional = |text: &Option<String>| text.as_deref().map_or(0, str::len);
+    let bytes = match event {
+        TranscriptEvent::UserMessage { text }
+        | TranscriptEvent::AgentMessage { text }
+        | TranscriptEvent::AgentThought { text }
+        | TranscriptEvent::SystemMessage { text }
+        | TranscriptEvent::Error { message: text, .. } => text.len(),
+        TranscriptEvent::ToolCall { title, message, .. } => title.len() + optional(message),
+        TranscriptEvent::ToolCallUpdate { message, .. } => optional(message),
+        TranscriptEvent::Plan { entries } => entries.iter().map(|entry| entry.content.len()).sum(),
+        TranscriptEvent::PlanUpdate { changes, .. } => changes
+            .iter()
+            .map(|change| change.entry.content.len())
+            .sum(),
+        TranscriptEvent::AvailableCommands { commands } => commands
+            .iter()
+            .map(|command| command.name.len() + command.description.len())
+            .sum(),
+        TranscriptEvent::Permission { title, summary, .. } => title.len() + optional(summary),
+        TranscriptEvent::Terminal { command, .. } => command.len(),
+        TranscriptEvent::TerminalOutput {
+            command, output, ..
+        } => command.len() + output.len(),
+        TranscriptEvent::FileEdit { diff, .. } => diff.len(),
+        TranscriptEvent::ResourceLink { title, uri, .. } => title.len() + uri.len(),
+        TranscriptEvent::ModeChange { .. }
+        | TranscriptEvent::FileRead { .. }
+        | TranscriptEvent::Image { .. }
+        | TranscriptEvent::AccessDenied { .. }
+        | TranscriptEvent::FileWrite { .. } => 0,
+    };
+    bytes as u64
+}
+
+/// A command's input as the agent declared it. It is read back from its JSON, so kinds of
+/// input later protocol versions add are kept as structured ones rather than refused.
+fn command_input(input: acp::AvailableCommandInput) -> CommandInputSummary {
+    let schema = serde_json::to_value(input).unwrap_or_default();
+    match schema.as_object() {
+        Some(fields) if fields.len() == 1 && fields.contains_key("hint") => {
+            match fields["hint"].as_str() {
+                Some(hint) => CommandInputSummary::Unstructured {
+                    hint: hint.to_string(),
+                },
+                None => CommandInputSummary::Structured { schema },
+            }
+        }
+        _ => CommandInputSummary::Structured { schema },
+    }
+}
+
+/// The entries of `next` that are new or differ from those at the same position in
+/// `previous`.
+fn plan_changes(previous: &[PlanEntrySummary], next: &[PlanEntrySummary]) -> Vec<PlanEntryChange> {
+    next.iter()
+        .enumerate()
+        .filter(|(index, entry)| previous.get(*index) != Some(entry))
+        .map(|(index, entry)| PlanEntryChange {
+            index,
+            entry: entry.clone(),
+        })
+        .collect()
+}
+
+/// The plan as it stands after `events`, replaying `PlanUpdate`s onto the last full `Plan`.
+pub fn current_plan(events: &[TranscriptEntry]) -> Option<Vec<PlanEntrySummary>> {
+    let mut plan: Option<Vec<PlanEntrySummary>> = None;
+    for entry in events {
+        match &entry.event {
+            TranscriptEvent::Plan { entries } => plan = Some(entries.clone()),
+            TranscriptEvent::PlanUpdate { changes, len } => {
+                if let Some(plan) = &mut plan {
+                    apply_plan_update(plan, changes, *len);
+                }
+            }
+            _ => {}
+        }
+    }
+    plan
+}
+
+/// Revises `plan` the way a `PlanUpdate` with `changes` and `len` says.
+pub fn apply_plan_update(
+    plan: &mut Vec<PlanEntrySummary>,
+    changes: &[PlanEntryChange],
+    len: usize,
+) {
+    plan.truncate(len);
+    for change in changes {
+        match plan.get_mut(change.index) {
+            Some(entry) => *entry = change.entry.clone(),
+            None => plan.push(change.entry.clone()),
+        }
+    }
+}
+
+/// How `FileEdit` diffs are written: the unified diff plain output, `kak-commands` and the
+/// diff buffer all show.
+#[derive(Debug, Clone)]
+pub struct DiffFormat {
+    /// Unchanged lines shown around each change.
+    pub context: usize,
+    /// Most diff lines kept in the event; the rest are cut off with a note.
+    pub max_lines: usize,
+    /// Where diffs that were cut short are saved in full.
+    pub full_diff_dir: Option<PathBuf>,
+}
+
+impl Default for DiffFormat {
+    fn default() -> Self {
+        Self {
+            context: DEFAULT_DIFF_CONTEXT,
+            max_lines: DEFAULT_MAX_DIFF_LINES,
+            full_diff_dir: None,
+        }
+    }
+}
+
+/// A `FileEdit` event for `path` going from `old` (`None` for a new file) to `new`.
+///
+/// A diff longer than `format.max_lines` is cut short with a note; the whole diff is written
+/// to `format.full_diff_dir` when there is one.
+pub fn file_edit(
+    path: &Path,
+    old: Option<&str>,
+    new: &str,
+    format: &DiffFormat,
+) -> TranscriptEvent {
+    let old_lines = old
+        .unwrap_or_default()
+        .split_inclusive('\n')
+        .collect::<Vec<_>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<_>>();
+    let hunks = difflib::unified_diff(&old_lines, &new_lines, "", "", "", "", format.context);
+    let from = match old {
+        Some(_) => format!("a/{}", path.display()),
+        None => "/dev/null".to_string(),
+    };
+    let mut lines = vec![
+        format!("--- {from}\n"),
+        format!("+++ b/{}\n", path.display()),
+    ];
+    let (mut added, mut removed) = (0, 0);
+    // difflib's own file headers come first.
+    for mut line in hunks.into_iter().skip(2) {
+        if line.starts_with('+') {
+            added += 1;
+        } else if line.starts_with('-') {
+            removed += 1;
+        }
+        if !line.ends_with('\n') {
+            line.push_str("\n\\ No newline at end of file\n");
+        }
+        lines.push(line);
+    }
+    if added + removed == 0 {
+        lines.clear();
+    }
+
+    let mut full_diff = None;
+    let diff = if lines.len() > format.max_lines {
+        let whole = lines.concat();
+        full_diff = format
+            .full_diff_dir
+            .as_deref()
+            .and_then(|dir| save_diff(dir, path, &whole));
+        let mut diff = lines[..format.max_lines].concat();
+        let more = lines.len() - format.max_lines;
+        match &full_diff {
+            Some(saved) => diff.push_str(&format!(
+                "... {more} more lines; full diff in {}\n",
+                saved.display()
+            )),
+            None => diff.push_str(&format!("... {more} more lines\n")),
+        }
+        diff
+    } else {
+        lines.concat()
+    };
+    TranscriptEvent::FileEdit {
+        path: path.to_path_buf(),
+        diff,
+        added,
+        removed,
+        full_diff,
+        tool_id: None,
+    }
+}
+
+/// A `TerminalOutput` event for terminal `terminal_id` running `command`, keeping the last
+/// `MAX_TERMINAL_OUTPUT_BYTES` of its output.
+pub fn terminal_output(
+    terminal_id: &acp::TerminalId,
+    command: &str,
+    response: acp::TerminalOutputResponse,
+) -> TranscriptEvent {
+    let mut output = response.output;
+    let mut truncated = response.truncated;
+    if output.len() > MAX_TERMINAL_OUTPUT_BYTES {
+        let mut start = output.len() - MAX_TERMINAL_OUTPUT_BYTES;
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        output.drain(..start);
+        truncated = true;
+    }
+    let exit = response.exit_status;
+    TranscriptEvent::TerminalOutput {
+        terminal_id: terminal_id.0.to_string(),
+        tool_id: None,
+        command: command.to_string(),
+        output,
+        truncated,
+        running: exit.is_none(),
+        exit_code: exit.as_ref().and_then(|exit| exit.exit_code),
+        signal: exit.and_then(|exit| exit.signal),
+    }
+}
+
+/// What a permission request's tool call would do, for the person deciding: the command
+/// line it runs, or the files it touches with a diff stat for the ones it edits.
+///
+/// Taken from the call's diffs and `raw_input`, falling back to `raw_input` as compact JSON.
+pub fn permission_summary(fields: &acp::ToolCallUpdateFields) -> Option<String> {
+    let input = fields.raw_input.as_ref();
+    let mut lines = Vec::new();
+    if let Some(command) = input.and_then(|input| input.get("command")) {
+        let mut words = string_list(command);
+        if let Some(args) = input.and_then(|input| input.get("args")) {
+            words.extend(string_list(args));
+        }
+        if !words.is_empty() {
+            lines.push(format!("$ {}", words.join(" ")));
+        }
+    }
+    for content in fields.content.iter().flatten() {
+        if let acp::ToolCallContent::Diff { diff } = content
+            && let TranscriptEvent::FileEdit { added, removed, .. } = file_edit(
+                &diff.path,
+                diff.old_text.as_deref(),
+                &diff.new_text,
+                &DiffFormat::default(),
+            )
+        {
+            lines.push(format!("{} (+{added} -{removed})", diff.path.display()));
+        }
+    }
+    if lines.is_empty() {
+        let named = ["path", "file_path", "abs_path", "filePath"]
+            .iter()
+            .find_map(|key| input?.get(key)?.as_str().map(PathBuf::from));
+        let paths = named.into_iter().chain(
+            fields
+                .locations
+                .iter()
+                .flatten()
+                .map(|location| location.path.clone()),
+        );
+        for path in paths {
+            let path = path.display().to_string();
+            if !lines.contains(&path) {
+                lines.push(path);
+            }
+        }
+    }
+    if lines.is_empty() {
+        match input? {
+            serde_json::Value::Null => return None,
+            serde_json::Value::String(text) => lines.push(text.trim().to_string()),
+            other => lines.push(other.to_string()),
+        }
+    }
+    let summary = lines.join("\n");
+    (!summary.is_empty()).then_some(summary)
+}
+
+/// A command given as one string or as a list of words.
+fn string_list(value: &serde_json::Value) -> Vec<String> {
+    match value {
+        serde_json::Value::String(word) => vec![word.clone()],
+        serde_json::Value::Array(words) => words
+            .iter()
+            .filter_map(|word| word.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn save_diff(dir: &Path, path: &Path, diff: &str) -> Option<PathBuf> {
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "edit".to_string());
+    let target = dir.join(format!("{stamp}-{name}.diff"));
+    let saved = std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&target, diff));
+    match saved {
+        Ok(()) => Some(target),
+        Err(error) => {
+            tracing::warn!(
+                ?error,
+                "failed to save the full diff to {}",
+                target.display()
+            );
+            None
+        }
+    }
+}
+
 fn render_content(block: acp::ContentBlock) -> String {
     match block {
         acp::ContentBlock::Text(text) => text.text,
@@ -93,10 +1021,9 @@ fn render_content(block: acp::ContentBlock) -> String {
             .uri
             .unwrap_or_else(|| format!("<image:{}>", image.mime_type)),
         acp::ContentBlock::Audio(audio) => format!("<audio:{}>", audio.mime_type),
-        acp::ContentBlock::ResourceLink(link) => link
-            .description
-            .or(link.title)
-            .unwrap_or_else(|| format!("<resource:{}>", link.uri)),
+        acp::ContentBlock::ResourceLink(link) => {
+            link_text(link.title.as_deref().unwrap_or(&link.name), &link.uri)
+        }
         acp::ContentBlock::Resource(resource) => match resource.resource {
             acp::EmbeddedResourceResource::TextResourceContents(text) => text.text,
             acp::EmbeddedResourceResource::BlobResourceContents(blob) => {
@@ -106,33 +1033,99 @@ fn render_content(block: acp::ContentBlock) -> String {
     }
 }
 
-fn summarize_tool_call_update(update: acp::ToolCallUpdate) -> TranscriptEvent {
-    let status = update.fields.status.map(|status| format!("{:?}", status));
-    let mut message_parts = Vec::new();
-    if let Some(title) = update.fields.title.clone() {
-        message_parts.push(title);
-    }
-    if let Some(content) = update.fields.content.clone() {
-        for entry in content {
-            message_parts.push(match entry {
-                acp::ToolCallContent::Content { content } => render_content(content),
-                acp::ToolCallContent::Diff { diff } => {
-                    format!("diff for {}", diff.path.display())
-                }
-                acp::ToolCallContent::Terminal { terminal_id } => {
-                    format!("terminal {}", terminal_id.0)
-                }
-            });
-        }
-    }
-    let message = if message_parts.is_empty() {
-        None
-    } else {
-        Some(message_parts.join("\n"))
+/// Whether a tool call in `status` is over.
+fn finished(status: acp::ToolCallStatus) -> bool {
+    matches!(
+        status,
+        acp::ToolCallStatus::Completed | acp::ToolCallStatus::Failed
+    )
+}
+
+/// The `ResourceLink` event for `content` if it is a link.
+fn resource_link(content: &acp::ContentBlock) -> Option<TranscriptEvent> {
+    let acp::ContentBlock::ResourceLink(link) = content else {
+        return None;
     };
-    TranscriptEvent::ToolCallUpdate {
+    Some(TranscriptEvent::ResourceLink {
+        title: link.title.clone().unwrap_or_else(|| link.name.clone()),
+        uri: link.uri.clone(),
+        mime_type: link.mime_type.clone(),
+    })
+}
+
+/// A link as text: `Design doc <https://example.com/design>`, or just `<URI>` when the title
+/// says nothing more.
+pub fn link_text(title: &str, uri: &str) -> String {
+    if title.is_empty() || title == uri {
+        format!("<{uri}>")
+    } else {
+        format!("{title} <{uri}>")
+    }
+}
+
+/// The `ToolCallUpdate` event for `update`, or `None` when all it brings is diffs.
+fn summarize_tool_call_update(
+    update: acp::ToolCallUpdate,
+    raw_tool_io: bool,
+) -> Option<TranscriptEvent> {
+    let fields = update.fields;
+    let status = fields.status.map(|status| format!("{:?}", status));
+    let only_content = fields.content.is_some();
+    let message = content_message(
+        fields.title.into_iter().collect(),
+        fields.content.unwrap_or_default(),
+    );
+    let tool_kind = fields.kind.map(tool_kind_name);
+    let locations = tool_locations(fields.locations.as_deref().unwrap_or_default());
+    let raw_input = fields.raw_input.filter(|_| raw_tool_io);
+    let raw_output = fields.raw_output.filter(|_| raw_tool_io);
+    let only_diffs = only_content
+        && status.is_none()
+        && message.is_none()
+        && tool_kind.is_none()
+        && locations.is_empty()
+        && raw_input.is_none()
+        && raw_output.is_none();
+    (!only_diffs).then(|| TranscriptEvent::ToolCallUpdate {
         id: update.id.0.to_string(),
         status,
         message,
+        tool_kind,
+        locations,
+        raw_input,
+        raw_output,
+    })
+}
+
+/// `parts` followed by the text and terminals of a tool call's `content`, one per line.
+fn content_message(mut parts: Vec<String>, content: Vec<acp::ToolCallContent>) -> Option<String> {
+    for entry in content {
+        parts.push(match entry {
+            acp::ToolCallContent::Content { content } => render_content(content),
+            // Diffs get `FileEdit` events of their own.
+            acp::ToolCallContent::Diff { .. } => continue,
+            acp::ToolCallContent::Terminal { terminal_id } => {
+                format!("terminal {}", terminal_id.0)
+            }
+        });
     }
+    (!parts.is_empty()).then(|| parts.join("\n"))
+}
+
+/// The protocol's name for a tool kind, e.g. `edit`.
+pub fn tool_kind_name(kind: acp::ToolKind) -> String {
+    serde_json::to_value(kind)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn tool_locations(locations: &[acp::ToolCallLocation]) -> Vec<ToolLocation> {
+    locations
+        .iter()
+        .map(|location| ToolLocation {
+            path: location.path.clone(),
+            line: location.line,
+        })
+        .collect()
 }
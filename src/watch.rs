@@ -0,0 +1,166 @@
+use std::io::Write;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    cli::{AttachOptions, KakTarget, PromptOutput, WatchOptions},
+    config,
+    ipc::{DaemonRequest, DaemonResponse, ErrorSource, TranscriptEvent},
+    ipc_client, kakoune, prompt,
+    redact::Redactor,
+    render::{self, PlainRenderer, TranscriptRenderer},
+    transcript::TranscriptCollector,
+};
+
+pub async fn run(options: WatchOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let redactor = Redactor::new(&options.redact)?;
+    let mut subscription = ipc_client::subscribe(&socket_path, &DaemonRequest::Watch).await?;
+    eprintln!("watching {}", socket_path.display());
+
+    loop {
+        let response = tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            response = subscription.next() => response?,
+        };
+        match response {
+            Some(DaemonResponse::Notification { notification, .. }) => {
+                let line = if options.json {
+                    let mut notification = serde_json::to_value(&notification)?;
+                    redactor.redact_value(&mut notification);
+                    let mut line = serde_json::to_string(&notification)?;
+                    line.push('\n');
+                    line
+                } else {
+                    let mut collector = TranscriptCollector::new();
+                    collector.record_notification(notification);
+                    let mut renderer = PlainRenderer::new(None);
+                    let mut output = String::new();
+                    for mut entry in collector.finish() {
+                        redactor.redact_entry(&mut entry)?;
+                        renderer.event(&mut output, &entry);
+                    }
+                    output
+                };
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()?;
+            }
+            Some(DaemonResponse::NotificationsDropped { count }) => {
+                eprintln!(
+                    "warning: missed {count} notifications by falling behind; restart the daemon \
+                     with a larger --notification-buffer to keep up"
+                );
+                // The gap is marked where it happened among the events.
+                if !options.json {
+                    let mut collector = TranscriptCollector::new();
+                    collector.push_event(TranscriptEvent::Error {
+                        source: ErrorSource::DroppedNotifications,
+                        message: format!("{count} notifications were dropped here"),
+                        count: Some(count),
+                    });
+                    let mut output = String::new();
+                    for entry in collector.finish() {
+                        PlainRenderer::new(None).event(&mut output, &entry);
+                    }
+                    print!("{output}");
+                    std::io::stdout().flush()?;
+                }
+            }
+            Some(DaemonResponse::Error { message, .. }) => return Err(anyhow!(message)),
+            Some(other) => {
+                return Err(anyhow!(format!("unexpected daemon response: {other:?}")));
+            }
+            None => {
+                tracing::info!("daemon closed the watch stream");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_attach(options: AttachOptions) -> Result<()> {
+    let socket_path = kakoune::resolve_socket_path(
+        options.socket.clone(),
+        options.session.as_deref(),
+        options.socket_scope,
+    )?;
+    let request = DaemonRequest::Attach {
+        request_id: options.request_id,
+    };
+    let redactor = Redactor::new(&options.redact)?;
+    let mut subscription = ipc_client::subscribe(&socket_path, &request).await?;
+    let mut streamed = false;
+    let mut renderer = PlainRenderer::new(None);
+
+    loop {
+        let response = tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            response = subscription.next() => response?,
+        };
+        match response {
+            Some(DaemonResponse::PromptEvent { mut event }) => {
+                streamed = true;
+                redactor.redact_entry(&mut event)?;
+                let line = if options.json {
+                    let mut line = serde_json::to_string(&event)?;
+                    line.push('\n');
+                    line
+                } else {
+                    let mut output = String::new();
+                    renderer.event(&mut output, &event);
+                    output
+                };
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()?;
+            }
+            Some(DaemonResponse::Prompt { mut result }) => {
+                redactor.redact_result(&mut result)?;
+                if options.json {
+                    println!("{}", serde_json::to_string(&result)?);
+                } else if streamed {
+                    // The events are already on screen; only the ending is left to show.
+                    println!("\n{}", render::stop_line(&result));
+                } else {
+                    let delivery = prompt::Delivery {
+                        output: PromptOutput::Plain,
+                        quiet: false,
+                        send_to_kak: false,
+                        session: None,
+                        client: None,
+                        title: config::DEFAULT_TITLE,
+                        wrap_width: None,
+                        kak_target: &[KakTarget::Info],
+                        info_style: None,
+                        info_max_lines: None,
+                        kak_buffer: config::DEFAULT_KAK_BUFFER,
+                        kak_append: false,
+                        kak_insert: false,
+                        kak_register: None,
+                        kak_locations: false,
+                        commands_menu: false,
+                        socket: None,
+                    };
+                    prompt::deliver_result(&delivery, &result).await?;
+                }
+                return Ok(());
+            }
+            Some(DaemonResponse::Error { message, .. }) => return Err(anyhow!(message)),
+            Some(other) => {
+                return Err(anyhow!(format!("unexpected daemon response: {other:?}")));
+            }
+            None => {
+                return Err(anyhow!(
+                    "daemon closed the stream before the prompt finished"
+                ));
+            }
+        }
+    }
+}